@@ -0,0 +1,87 @@
+use more_asserts::{assert_gt, assert_lt};
+use std::mem::MaybeUninit;
+
+const MILLIS: wasi::Timestamp = 1_000_000;
+
+unsafe fn cputime(clock_id: wasi::Clockid) -> wasi::Timestamp {
+    wasi::clock_time_get(clock_id, 0).expect("CPU-time clocks should be supported")
+}
+
+unsafe fn busy_loop(duration: wasi::Timestamp) {
+    let start = wasi::clock_time_get(wasi::CLOCKID_MONOTONIC, 0).expect("monotonic clock");
+    let mut counter = 0u64;
+    while wasi::clock_time_get(wasi::CLOCKID_MONOTONIC, 0).expect("monotonic clock") - start
+        < duration
+    {
+        for _ in 0..1000 {
+            counter = std::ptr::read_volatile(&counter).wrapping_add(1);
+        }
+    }
+}
+
+unsafe fn sleep(duration: wasi::Timestamp) {
+    let r#in = [wasi::Subscription {
+        userdata: 0,
+        r#type: wasi::EVENTTYPE_CLOCK,
+        u: wasi::SubscriptionU {
+            clock: wasi::SubscriptionClock {
+                id: wasi::CLOCKID_MONOTONIC,
+                timeout: duration,
+                precision: 0,
+                flags: 0,
+            },
+        },
+    }];
+    let mut out = [MaybeUninit::<wasi::Event>::zeroed().assume_init()];
+    let nevents =
+        wasi::poll_oneoff(r#in.as_ptr(), out.as_mut_ptr(), 1).expect("poll_oneoff should succeed");
+    assert_eq!(nevents, 1, "poll_oneoff should return the timeout");
+}
+
+unsafe fn test_cputime_resolution() {
+    for &clock_id in &[
+        wasi::CLOCKID_PROCESS_CPUTIME_ID,
+        wasi::CLOCKID_THREAD_CPUTIME_ID,
+    ] {
+        let res = wasi::clock_res_get(clock_id).expect("CPU-time clocks should be supported");
+        assert_gt!(res, 0, "resolution should be nonzero");
+        assert_lt!(res, 1000 * MILLIS, "resolution should be finer than 1s");
+    }
+}
+
+unsafe fn test_cputime_busy_loop() {
+    let process = cputime(wasi::CLOCKID_PROCESS_CPUTIME_ID);
+    let thread = cputime(wasi::CLOCKID_THREAD_CPUTIME_ID);
+    busy_loop(50 * MILLIS);
+    assert_gt!(
+        cputime(wasi::CLOCKID_PROCESS_CPUTIME_ID),
+        process,
+        "process CPU time should advance while busy"
+    );
+    assert_gt!(
+        cputime(wasi::CLOCKID_THREAD_CPUTIME_ID),
+        thread,
+        "thread CPU time should advance while busy"
+    );
+}
+
+unsafe fn test_cputime_sleep() {
+    // Only check the thread clock here, as other threads in the host process may well be
+    // busy while this one sleeps.
+    let thread = cputime(wasi::CLOCKID_THREAD_CPUTIME_ID);
+    sleep(100 * MILLIS);
+    assert_lt!(
+        cputime(wasi::CLOCKID_THREAD_CPUTIME_ID) - thread,
+        50 * MILLIS,
+        "thread CPU time should barely advance while sleeping"
+    );
+}
+
+fn main() {
+    // Run the tests.
+    unsafe {
+        test_cputime_resolution();
+        test_cputime_busy_loop();
+        test_cputime_sleep();
+    }
+}
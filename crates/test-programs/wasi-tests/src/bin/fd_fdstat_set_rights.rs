@@ -0,0 +1,135 @@
+use std::{env, process};
+use wasi_tests::{create_file, drop_rights, fd_get_rights, open_scratch_directory};
+
+unsafe fn open_subdir(dir_fd: wasi::Fd, name: &str) -> wasi::Fd {
+    let (base, inheriting) = fd_get_rights(dir_fd);
+    wasi::path_create_directory(dir_fd, name).expect("creating a directory");
+    wasi::path_open(dir_fd, 0, name, wasi::OFLAGS_DIRECTORY, base, inheriting, 0)
+        .expect("opening a directory")
+}
+
+unsafe fn test_drop_write(dir_fd: wasi::Fd) {
+    let file_fd = wasi::path_open(
+        dir_fd,
+        0,
+        "file",
+        wasi::OFLAGS_CREAT,
+        wasi::RIGHTS_FD_READ | wasi::RIGHTS_FD_WRITE,
+        0,
+        0,
+    )
+    .expect("opening a file");
+    let data = [0u8; 16];
+    let iovs = [wasi::Ciovec {
+        buf: data.as_ptr(),
+        buf_len: data.len(),
+    }];
+    wasi::fd_write(file_fd, &iovs).expect("writing to a file");
+
+    drop_rights(file_fd, wasi::RIGHTS_FD_WRITE, 0);
+    let (base, inheriting) = fd_get_rights(file_fd);
+    assert_eq!(
+        base & wasi::RIGHTS_FD_WRITE,
+        0,
+        "fd_write right should be gone"
+    );
+    assert_eq!(
+        wasi::fd_write(file_fd, &iovs)
+            .expect_err("writing without the fd_write right")
+            .raw_error(),
+        wasi::ERRNO_NOTCAPABLE,
+        "errno should be ERRNO_NOTCAPABLE",
+    );
+
+    // Rights can only ever be dropped.
+    assert_eq!(
+        wasi::fd_fdstat_set_rights(file_fd, base | wasi::RIGHTS_FD_WRITE, inheriting)
+            .expect_err("re-adding the fd_write right")
+            .raw_error(),
+        wasi::ERRNO_NOTCAPABLE,
+        "errno should be ERRNO_NOTCAPABLE",
+    );
+    assert_eq!(
+        fd_get_rights(file_fd),
+        (base, inheriting),
+        "a failed fd_fdstat_set_rights shouldn't change the rights",
+    );
+
+    wasi::fd_close(file_fd).expect("closing a file");
+    wasi::path_unlink_file(dir_fd, "file").expect("removing a file");
+}
+
+unsafe fn test_drop_create(dir_fd: wasi::Fd) {
+    let subdir_fd = open_subdir(dir_fd, "subdir");
+    create_file(subdir_fd, "existing");
+
+    drop_rights(subdir_fd, wasi::RIGHTS_PATH_CREATE_FILE, 0);
+    assert_eq!(
+        wasi::path_open(subdir_fd, 0, "new", wasi::OFLAGS_CREAT, 0, 0, 0)
+            .expect_err("creating a file without the path_create_file right")
+            .raw_error(),
+        wasi::ERRNO_NOTCAPABLE,
+        "errno should be ERRNO_NOTCAPABLE",
+    );
+    let file_fd = wasi::path_open(subdir_fd, 0, "existing", 0, 0, 0, 0)
+        .expect("opening an existing file without the path_create_file right");
+    wasi::fd_close(file_fd).expect("closing a file");
+
+    wasi::fd_close(subdir_fd).expect("closing a directory");
+    wasi::path_unlink_file(dir_fd, "subdir/existing").expect("removing a file");
+    wasi::path_remove_directory(dir_fd, "subdir").expect("removing a directory");
+}
+
+unsafe fn test_drop_inheriting(dir_fd: wasi::Fd) {
+    let subdir_fd = open_subdir(dir_fd, "subdir");
+    create_file(subdir_fd, "file");
+
+    drop_rights(subdir_fd, 0, wasi::RIGHTS_FD_WRITE);
+    assert_eq!(
+        wasi::path_open(subdir_fd, 0, "file", 0, wasi::RIGHTS_FD_WRITE, 0, 0)
+            .expect_err("opening a file for writing without inheriting fd_write")
+            .raw_error(),
+        wasi::ERRNO_NOTCAPABLE,
+        "errno should be ERRNO_NOTCAPABLE",
+    );
+    let file_fd = wasi::path_open(subdir_fd, 0, "file", 0, wasi::RIGHTS_FD_READ, 0, 0)
+        .expect("opening a file for reading");
+    let (base, _) = fd_get_rights(file_fd);
+    assert_eq!(
+        base & wasi::RIGHTS_FD_WRITE,
+        0,
+        "file shouldn't be writable"
+    );
+    wasi::fd_close(file_fd).expect("closing a file");
+
+    wasi::fd_close(subdir_fd).expect("closing a directory");
+    wasi::path_unlink_file(dir_fd, "subdir/file").expect("removing a file");
+    wasi::path_remove_directory(dir_fd, "subdir").expect("removing a directory");
+}
+
+fn main() {
+    let mut args = env::args();
+    let prog = args.next().unwrap();
+    let arg = if let Some(arg) = args.next() {
+        arg
+    } else {
+        eprintln!("usage: {} <scratch directory>", prog);
+        process::exit(1);
+    };
+
+    // Open scratch directory
+    let dir_fd = match open_scratch_directory(&arg) {
+        Ok(dir_fd) => dir_fd,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1)
+        }
+    };
+
+    // Run the tests.
+    unsafe {
+        test_drop_write(dir_fd);
+        test_drop_create(dir_fd);
+        test_drop_inheriting(dir_fd);
+    }
+}
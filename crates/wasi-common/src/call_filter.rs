@@ -0,0 +1,140 @@
+//! The hostcalls a `WasiCtx` refuses to make, as set with `WasiCtxBuilder::deny_calls`.
+use crate::hostcalls;
+
+/// A set of hostcalls, by their index in `hostcalls::NAMES`.
+#[derive(Debug, Default)]
+pub(crate) struct CallSet {
+    bits: Vec<u64>,
+}
+
+impl CallSet {
+    /// The hostcalls whose names match any of `patterns`, in which a `*` stands for any run of
+    /// characters, as in `"sock_*"`.
+    ///
+    /// Patterns which match none of the hostcalls are ignored, as they may name calls this
+    /// version doesn't have.
+    pub(crate) fn matching<S: AsRef<str>>(patterns: &[S]) -> Self {
+        let mut set = Self::default();
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            let mut matched = false;
+            for (index, name) in hostcalls::NAMES.iter().enumerate() {
+                if glob_match(pattern, name) {
+                    set.insert(index);
+                    matched = true;
+                }
+            }
+            if !matched {
+                log::warn!("no hostcall matches {:?} in deny_calls", pattern);
+            }
+        }
+        set
+    }
+
+    fn insert(&mut self, index: usize) {
+        let word = index / 64;
+        if self.bits.len() <= word {
+            self.bits.resize(word + 1, 0);
+        }
+        self.bits[word] |= 1 << (index % 64);
+    }
+
+    pub(crate) fn contains(&self, index: usize) -> bool {
+        match self.bits.get(index / 64) {
+            Some(word) => word & (1 << (index % 64)) != 0,
+            None => false,
+        }
+    }
+}
+
+/// Whether `name` matches `pattern`, in which `*` matches any run of characters, including none.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == name;
+    }
+    // The parts around the first and last `*` have to be at either end of `name`, without
+    // overlapping, and the ones in between in order somewhere in the middle.
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if name.len() < first.len() + last.len() || !name.starts_with(first) || !name.ends_with(last) {
+        return false;
+    }
+    let mut rest = &name[first.len()..name.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::with_memory;
+    use crate::{wasi, CallRecord, WasiCtxBuilder, WritePipe};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob_match("path_open", "path_open"));
+        assert!(!glob_match("path_open", "path_open_x"));
+        assert!(glob_match("sock_*", "sock_recv"));
+        assert!(glob_match("*", "fd_write"));
+        assert!(glob_match("fd_*_get", "fd_fdstat_get"));
+        assert!(glob_match("*_get*", "fd_filestat_get"));
+        assert!(!glob_match("fd_*_get", "fd_get"));
+        assert!(!glob_match("sock_*", "fd_sock"));
+
+        let set = CallSet::matching(&["path_*", "crypto_*"]);
+        for (index, name) in hostcalls::NAMES.iter().enumerate() {
+            assert_eq!(set.contains(index), name.starts_with("path_"), "{}", name);
+        }
+    }
+
+    #[test]
+    fn denied_calls_fail() {
+        let stdout = WritePipe::new();
+        let records = Arc::new(Mutex::new(Vec::<CallRecord>::new()));
+        let sink = records.clone();
+        let mut ctx = WasiCtxBuilder::new()
+            .stdout_virtual(stdout.clone())
+            .deny_calls(&["path_open", "sock_*", "crypto_*"])
+            .call_log(Box::new(move |record| sink.lock().unwrap().push(record)))
+            .build()
+            .expect("build WasiCtx");
+        // A ciovec for "hi" at 8, and a spare `size_t` at 0.
+        with_memory(24, |memory| {
+            memory[8..12].copy_from_slice(&16u32.to_le_bytes());
+            memory[12..16].copy_from_slice(&2u32.to_le_bytes());
+            memory[16..18].copy_from_slice(b"hi");
+
+            let errno = unsafe { crate::hostcalls::fd_write(&mut ctx, memory, 1, 8, 1, 0) };
+            assert_eq!(errno, wasi::__WASI_ERRNO_SUCCESS);
+            assert_eq!(stdout.contents(), b"hi");
+            let errno = unsafe {
+                crate::hostcalls::path_open(&mut ctx, memory, 3, 0, 16, 2, 0, 0, 0, 0, 0)
+            };
+            assert_eq!(errno, wasi::__WASI_ERRNO_NOTCAPABLE);
+            // Not even the out-pointer for the `fd` was written.
+            assert_eq!(&memory[..4], &2u32.to_le_bytes());
+            let errno = unsafe { crate::hostcalls::sock_recv(&mut ctx, memory, 1, 8, 1, 0, 0, 4) };
+            assert_eq!(errno, wasi::__WASI_ERRNO_NOTCAPABLE);
+            assert_eq!(stdout.contents(), b"hi");
+        });
+
+        let records = records.lock().unwrap();
+        let calls: Vec<_> = records.iter().map(|r| (r.name, r.denied)).collect();
+        assert_eq!(
+            calls,
+            [
+                ("fd_write", false),
+                ("path_open", true),
+                ("sock_recv", true)
+            ]
+        );
+        assert_eq!(records[1].errno, wasi::__WASI_ERRNO_NOTCAPABLE);
+        assert_eq!(records[1].args, "");
+    }
+}
@@ -0,0 +1,165 @@
+//! A record of the hostcalls a guest makes, for the embedder to collect with
+//! `WasiCtxBuilder::call_log`.
+use crate::{wasi, wasi32};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// A hostcall the guest made, along with its outcome.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CallRecord {
+    /// The name of the hostcall, e.g. `"fd_write"`.
+    pub name: &'static str,
+    /// A summary of the arguments: the scalars, the lengths of any buffers, and paths, unless
+    /// they're redacted. The guest's buffers themselves are never included.
+    pub args: String,
+    /// The errno the guest got back.
+    pub errno: wasi::__wasi_errno_t,
+    /// When the call started, relative to when the `WasiCtx` was built.
+    pub start: Duration,
+    /// How long the call took.
+    pub duration: Duration,
+    /// Whether the call was denied by `WasiCtxBuilder::deny_calls`, in which case it never ran,
+    /// and its arguments aren't summarized.
+    pub denied: bool,
+}
+
+/// The sink installed with `WasiCtxBuilder::call_log`.
+pub(crate) struct CallLog {
+    sink: Box<dyn Fn(CallRecord) + Send + Sync>,
+    redact_paths: bool,
+    created: Instant,
+}
+
+impl CallLog {
+    pub(crate) fn new(sink: Box<dyn Fn(CallRecord) + Send + Sync>, redact_paths: bool) -> Self {
+        Self {
+            sink,
+            redact_paths,
+            created: Instant::now(),
+        }
+    }
+
+    /// Summarize the path the guest passed at `ptr`, as it makes it into the call's `args`.
+    pub(crate) fn path(
+        &self,
+        memory: &[u8],
+        ptr: wasi32::uintptr_t,
+        len: wasi32::size_t,
+    ) -> String {
+        if self.redact_paths {
+            return format!("<{} bytes>", len);
+        }
+        let start = ptr as usize;
+        match memory.get(start..start.saturating_add(len as usize)) {
+            Some(path) => format!("{:?}", String::from_utf8_lossy(path)),
+            None => "<out of bounds>".to_string(),
+        }
+    }
+
+    /// Pass the record of a call which started at `start` to the sink.
+    pub(crate) fn record(
+        &self,
+        name: &'static str,
+        args: String,
+        start: Instant,
+        errno: wasi::__wasi_errno_t,
+    ) {
+        (self.sink)(CallRecord {
+            name,
+            args,
+            errno,
+            start: start.duration_since(self.created),
+            duration: start.elapsed(),
+            denied: false,
+        })
+    }
+
+    /// Pass the record of a call denied by `WasiCtxBuilder::deny_calls` to the sink.
+    pub(crate) fn record_denied(&self, name: &'static str) {
+        (self.sink)(CallRecord {
+            name,
+            args: String::new(),
+            errno: wasi::__WASI_ERRNO_NOTCAPABLE,
+            start: self.created.elapsed(),
+            duration: Duration::from_secs(0),
+            denied: true,
+        })
+    }
+}
+
+impl fmt::Debug for CallLog {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("CallLog")
+            .field("redact_paths", &self.redact_paths)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::with_memory;
+    use crate::virtfile::WritePipe;
+    use crate::WasiCtxBuilder;
+    use std::sync::{Arc, Mutex};
+
+    fn collect(records: &Arc<Mutex<Vec<CallRecord>>>) -> Box<dyn Fn(CallRecord) + Send + Sync> {
+        let records = records.clone();
+        Box::new(move |record| records.lock().unwrap().push(record))
+    }
+
+    /// Run `f` against a fake guest memory holding a ciovec for `"hi"` at 8, a spare `size_t`
+    /// at 0, and `"some/dir"` at 32.
+    fn with_guest_memory<T>(f: impl FnOnce(&mut [u8]) -> T) -> T {
+        with_memory(40, |memory| {
+            memory[8..12].copy_from_slice(&16u32.to_le_bytes());
+            memory[12..16].copy_from_slice(&2u32.to_le_bytes());
+            memory[16..18].copy_from_slice(b"hi");
+            memory[32..40].copy_from_slice(b"some/dir");
+            f(memory)
+        })
+    }
+
+    #[test]
+    fn records_calls() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let mut ctx = WasiCtxBuilder::new()
+            .stdout_virtual(WritePipe::new())
+            .call_log(collect(&records))
+            .build()
+            .expect("build WasiCtx");
+
+        with_guest_memory(|memory| unsafe {
+            crate::hostcalls::fd_write(&mut ctx, memory, 1, 8, 1, 0);
+            crate::hostcalls::path_open(&mut ctx, memory, 9, 0, 32, 8, 0, 0, 0, 0, 0);
+        });
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].name, "fd_write");
+        assert_eq!(records[0].args, "fd=1, iovs_len=1");
+        assert_eq!(records[0].errno, wasi::__WASI_ERRNO_SUCCESS);
+        assert_eq!(records[1].name, "path_open");
+        assert!(records[1].args.contains("path=\"some/dir\""));
+        assert_eq!(records[1].errno, wasi::__WASI_ERRNO_BADF);
+        assert!(records[0].start + records[0].duration <= records[1].start);
+    }
+
+    #[test]
+    fn redacts_paths() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let mut ctx = WasiCtxBuilder::new()
+            .call_log(collect(&records))
+            .redact_call_log_paths(true)
+            .build()
+            .expect("build WasiCtx");
+
+        with_guest_memory(|memory| unsafe {
+            crate::hostcalls::path_open(&mut ctx, memory, 9, 0, 32, 8, 0, 0, 0, 0, 0)
+        });
+
+        let records = records.lock().unwrap();
+        assert!(records[0].args.contains("path=<8 bytes>"));
+        assert!(!records[0].args.contains("some/dir"));
+    }
+}
@@ -0,0 +1,127 @@
+//! Cancelling a guest's blocking hostcalls from the host, with a `CancelToken`.
+use crate::virtfile::PollWaker;
+use crate::{Error, Result};
+#[cfg(unix)]
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::{io::AsRawFd, io::RawFd, net::UnixStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// A handle with which the embedder cuts short whatever the guest is blocked on, as installed
+/// with `WasiCtxBuilder::cancel_token`.
+///
+/// Once cancelled, a token stays cancelled: the blocking hostcall the guest is in returns
+/// `EINTR` promptly, and so does every potentially blocking hostcall it makes from then on.
+/// Clones share their state, so the embedder can keep one and cancel it from any thread.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    inner: Arc<CancelState>,
+}
+
+#[derive(Debug, Default)]
+struct CancelState {
+    cancelled: Mutex<bool>,
+    condvar: Condvar,
+    /// The waker of the `poll_oneoff` currently waiting, if any.
+    waiter: Mutex<Option<PollWaker>>,
+    /// A connected pair of sockets, the first of which becomes readable once this is
+    /// cancelled, made when the host poller first asks for it.
+    #[cfg(unix)]
+    wakeup: Mutex<Option<(UnixStream, UnixStream)>>,
+}
+
+impl CancelToken {
+    /// Create a token which isn't cancelled yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel the guest's blocking hostcalls, now and from then on.
+    pub fn cancel(&self) {
+        *self.inner.cancelled.lock().unwrap() = true;
+        self.inner.condvar.notify_all();
+        if let Some(waker) = &*self.inner.waiter.lock().unwrap() {
+            waker.wake();
+        }
+        #[cfg(unix)]
+        {
+            if let Some((_, writer)) = &*self.inner.wakeup.lock().unwrap() {
+                wake_up(writer);
+            }
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.inner.cancelled.lock().unwrap()
+    }
+
+    /// Fail with `Error::EINTR` if this was cancelled.
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(Error::EINTR)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sleep for `timeout`, unless this is cancelled in the meantime, in which case this fails
+    /// with `Error::EINTR` right away.
+    pub(crate) fn sleep(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut cancelled = self.inner.cancelled.lock().unwrap();
+        while !*cancelled {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            cancelled = self
+                .inner
+                .condvar
+                .wait_timeout(cancelled, deadline - now)
+                .unwrap()
+                .0;
+        }
+        Err(Error::EINTR)
+    }
+
+    /// The fd for the host poller to wait on for reading, alongside the guest's own, which
+    /// becomes readable once this is cancelled.
+    #[cfg(unix)]
+    pub(crate) fn wakeup_fd(&self) -> Result<RawFd> {
+        let mut wakeup = self.inner.wakeup.lock().unwrap();
+        if wakeup.is_none() {
+            let (reader, writer) = UnixStream::pair()?;
+            writer.set_nonblocking(true)?;
+            // `cancel` only writes to the pair once it exists.
+            if self.is_cancelled() {
+                wake_up(&writer);
+            }
+            *wakeup = Some((reader, writer));
+        }
+        Ok(wakeup.as_ref().unwrap().0.as_raw_fd())
+    }
+
+    /// Have `waker` woken if this is cancelled, until the returned guard is dropped.
+    ///
+    /// The caller should `check` afterwards, in case this was cancelled just before.
+    pub(crate) fn watch(&self, waker: &PollWaker) -> CancelWatch<'_> {
+        *self.inner.waiter.lock().unwrap() = Some(waker.clone());
+        CancelWatch(self)
+    }
+}
+
+/// Make the reading end of the wakeup pair readable, for good, as nothing ever reads from it.
+#[cfg(unix)]
+fn wake_up(mut writer: &UnixStream) {
+    // The writer doesn't block, and if its buffer is full, the reader is readable already.
+    let _ = writer.write(&[1]);
+}
+
+pub(crate) struct CancelWatch<'a>(&'a CancelToken);
+
+impl Drop for CancelWatch<'_> {
+    fn drop(&mut self) {
+        *self.0.inner.waiter.lock().unwrap() = None;
+    }
+}
@@ -0,0 +1,376 @@
+//! Clocks which can be installed into a `WasiCtx` in place of the host's.
+//!
+//! By default the guest reads the host's clocks directly. Installing a `ManualClock` instead
+//! lets the embedder decide exactly when, and by how much, time passes, which is useful for
+//! simulations and for testing guests which sleep.
+use crate::cancel::CancelToken;
+use crate::sys::hostcalls_impl;
+use crate::{wasi, Error, Result};
+use std::convert::TryInto;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// A source of wall-clock time, backing `CLOCKID_REALTIME`.
+pub trait WasiSystemClock: fmt::Debug + Send + Sync {
+    /// The resolution reported by `clock_res_get`.
+    fn resolution(&self) -> Duration;
+
+    /// The current time.
+    fn now(&self) -> SystemTime;
+}
+
+/// A source of monotonic time, backing `CLOCKID_MONOTONIC` and the timeouts of `poll_oneoff`.
+pub trait WasiMonotonicClock: fmt::Debug + Send + Sync {
+    /// The resolution reported by `clock_res_get`.
+    fn resolution(&self) -> Duration;
+
+    /// The time elapsed since some arbitrary, fixed point in the past.
+    fn now(&self) -> Duration;
+
+    /// Block the calling thread until `now` reaches `deadline`.
+    fn sleep_until(&self, deadline: Duration);
+
+    /// Move the clock straight to `deadline`, if it's in the future.
+    ///
+    /// Returns `false` if the clock can't be moved by the guest, in which case the caller
+    /// should fall back to `sleep_until`.
+    fn advance_to(&self, _deadline: Duration) -> bool {
+        false
+    }
+}
+
+#[derive(Debug)]
+struct ManualClockState {
+    system: SystemTime,
+    monotonic: Duration,
+}
+
+/// A clock which only moves when told to, either by the embedder calling `advance` or `set`,
+/// or by a guest sleeping in `poll_oneoff` when auto-advance is enabled with
+/// `WasiCtxBuilder::clock_auto_advance`.
+///
+/// The same clock backs both the system and the monotonic clock; clones share their state,
+/// so the embedder can keep one handle while another is installed into the `WasiCtx`.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    state: Arc<(Mutex<ManualClockState>, Condvar)>,
+}
+
+impl ManualClock {
+    /// Create a clock whose system time starts out at `start`, and whose monotonic time
+    /// starts out at zero.
+    pub fn new(start: SystemTime) -> Self {
+        let state = ManualClockState {
+            system: start,
+            monotonic: Duration::from_secs(0),
+        };
+        Self {
+            state: Arc::new((Mutex::new(state), Condvar::new())),
+        }
+    }
+
+    /// Let `duration` pass, on both the system and the monotonic clock.
+    pub fn advance(&self, duration: Duration) {
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        state.system += duration;
+        state.monotonic += duration;
+        cvar.notify_all();
+    }
+
+    /// Set the system clock to `time`. The monotonic clock is unaffected.
+    pub fn set(&self, time: SystemTime) {
+        let (lock, _) = &*self.state;
+        lock.lock().unwrap().system = time;
+    }
+}
+
+impl WasiSystemClock for ManualClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+
+    fn now(&self) -> SystemTime {
+        self.state.0.lock().unwrap().system
+    }
+}
+
+impl WasiMonotonicClock for ManualClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+
+    fn now(&self) -> Duration {
+        self.state.0.lock().unwrap().monotonic
+    }
+
+    fn sleep_until(&self, deadline: Duration) {
+        // Wait for the embedder to `advance` the clock from some other thread.
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        while state.monotonic < deadline {
+            state = cvar.wait(state).unwrap();
+        }
+    }
+
+    fn advance_to(&self, deadline: Duration) -> bool {
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        if let Some(delta) = deadline.checked_sub(state.monotonic) {
+            state.system += delta;
+            state.monotonic = deadline;
+            cvar.notify_all();
+        }
+        true
+    }
+}
+
+/// A system clock which is stuck at a single point in time.
+#[derive(Debug)]
+pub(crate) struct FixedClock(pub(crate) SystemTime);
+
+impl WasiSystemClock for FixedClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+/// Which way `WasiCtxBuilder::system_clock_offset` shifts the system clock.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OffsetSign {
+    /// Into the future.
+    Plus,
+    /// Into the past.
+    Minus,
+}
+
+/// A constant shift applied to timestamps.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ClockOffset {
+    pub(crate) sign: OffsetSign,
+    pub(crate) amount: Duration,
+}
+
+impl ClockOffset {
+    /// The offset which turns `from` into `to`.
+    pub(crate) fn between(from: SystemTime, to: SystemTime) -> Self {
+        match to.duration_since(from) {
+            Ok(amount) => Self {
+                sign: OffsetSign::Plus,
+                amount,
+            },
+            Err(err) => Self {
+                sign: OffsetSign::Minus,
+                amount: err.duration(),
+            },
+        }
+    }
+
+    pub(crate) fn apply(&self, time: wasi::__wasi_timestamp_t) -> Result<wasi::__wasi_timestamp_t> {
+        let amount = to_timestamp(self.amount)?;
+        match self.sign {
+            OffsetSign::Plus => time.checked_add(amount),
+            OffsetSign::Minus => time.checked_sub(amount),
+        }
+        .ok_or(Error::EOVERFLOW)
+    }
+
+    pub(crate) fn apply_to_system_time(&self, time: SystemTime) -> Result<SystemTime> {
+        match self.sign {
+            OffsetSign::Plus => time.checked_add(self.amount),
+            OffsetSign::Minus => time.checked_sub(self.amount),
+        }
+        .ok_or(Error::EOVERFLOW)
+    }
+}
+
+/// The clocks of a `WasiCtx`, falling back to the host's for anything not overridden.
+#[derive(Debug, Default)]
+pub(crate) struct WasiCtxClocks {
+    pub(crate) system: Option<Arc<dyn WasiSystemClock>>,
+    pub(crate) monotonic: Option<Arc<dyn WasiMonotonicClock>>,
+    /// Whether a guest sleeping on a virtual monotonic clock moves it forward, rather than
+    /// waiting for the embedder to.
+    pub(crate) auto_advance: bool,
+    /// The granularity, in nanoseconds, to round the guest's clock readings to.
+    pub(crate) coarsening: Option<wasi::__wasi_timestamp_t>,
+    /// The host thread's CPU time when the `WasiCtx` was built, if the CPU-time clocks are
+    /// scoped to the instance.
+    pub(crate) cputime_base: Option<wasi::__wasi_timestamp_t>,
+    /// The monotonic clock's reading when the `WasiCtx` was built, if the guest's monotonic
+    /// clock starts from zero.
+    pub(crate) creation_time: Option<wasi::__wasi_timestamp_t>,
+    /// The shift applied to every reading of the system clock, whether the host's or `system`.
+    pub(crate) system_offset: Option<ClockOffset>,
+    /// The shift applied to file timestamps, so that they stay consistent with a shifted or
+    /// fixed system clock.
+    pub(crate) file_time_offset: Option<ClockOffset>,
+}
+
+impl WasiCtxClocks {
+    pub(crate) fn resolution(
+        &self,
+        clock_id: wasi::__wasi_clockid_t,
+    ) -> Result<wasi::__wasi_timestamp_t> {
+        let resolution = match (clock_id, &self.system, &self.monotonic) {
+            (wasi::__WASI_CLOCKID_REALTIME, Some(clock), _) => to_timestamp(clock.resolution())?,
+            (wasi::__WASI_CLOCKID_MONOTONIC, _, Some(clock)) => to_timestamp(clock.resolution())?,
+            _ => hostcalls_impl::clock_res_get(clock_id)?,
+        };
+        Ok(match self.coarsening {
+            Some(coarsening) => resolution.max(coarsening),
+            None => resolution,
+        })
+    }
+
+    pub(crate) fn time(
+        &self,
+        clock_id: wasi::__wasi_clockid_t,
+    ) -> Result<wasi::__wasi_timestamp_t> {
+        let time = self.uncoarsened_time(clock_id)?;
+        Ok(match self.coarsening {
+            Some(coarsening) => time - time % coarsening,
+            None => time,
+        })
+    }
+
+    /// The time as seen by the guest, but at the full resolution of the underlying clock.
+    fn uncoarsened_time(
+        &self,
+        clock_id: wasi::__wasi_clockid_t,
+    ) -> Result<wasi::__wasi_timestamp_t> {
+        Ok(match (clock_id, &self.system, &self.monotonic) {
+            (wasi::__WASI_CLOCKID_REALTIME, system, _) => {
+                let time = match system {
+                    Some(clock) => clock
+                        .now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .map_err(|_| Error::EOVERFLOW)
+                        .and_then(to_timestamp)?,
+                    None => hostcalls_impl::clock_time_get(clock_id)?,
+                };
+                match &self.system_offset {
+                    Some(offset) => offset.apply(time)?,
+                    None => time,
+                }
+            }
+            (wasi::__WASI_CLOCKID_MONOTONIC, _, _) => {
+                let time = self.raw_monotonic_time()?;
+                time.saturating_sub(self.creation_time.unwrap_or_default())
+            }
+            (wasi::__WASI_CLOCKID_PROCESS_CPUTIME_ID, _, _)
+            | (wasi::__WASI_CLOCKID_THREAD_CPUTIME_ID, _, _)
+                if self.cputime_base.is_some() =>
+            {
+                let now = hostcalls_impl::clock_time_get(wasi::__WASI_CLOCKID_THREAD_CPUTIME_ID)?;
+                now.saturating_sub(self.cputime_base.unwrap_or_default())
+            }
+            _ => hostcalls_impl::clock_time_get(clock_id)?,
+        })
+    }
+
+    /// The monotonic clock's reading, without rebasing it onto `creation_time`.
+    fn raw_monotonic_time(&self) -> Result<wasi::__wasi_timestamp_t> {
+        match &self.monotonic {
+            Some(clock) => to_timestamp(clock.now()),
+            None => hostcalls_impl::clock_time_get(wasi::__WASI_CLOCKID_MONOTONIC),
+        }
+    }
+
+    /// Start the guest's monotonic clock from zero.
+    pub(crate) fn rebase_monotonic(&mut self) -> Result<()> {
+        self.creation_time = Some(self.raw_monotonic_time()?);
+        Ok(())
+    }
+
+    /// Shift the timestamps of `filestat` to match the guest's system clock.
+    pub(crate) fn adjust_filestat(&self, filestat: &mut wasi::__wasi_filestat_t) -> Result<()> {
+        if let Some(offset) = &self.file_time_offset {
+            filestat.atim = offset.apply(filestat.atim)?;
+            filestat.mtim = offset.apply(filestat.mtim)?;
+            filestat.ctim = offset.apply(filestat.ctim)?;
+        }
+        Ok(())
+    }
+
+    /// Start counting the CPU-time clocks from the current thread's CPU time.
+    pub(crate) fn scope_cputime(&mut self) -> Result<()> {
+        let now = hostcalls_impl::clock_time_get(wasi::__WASI_CLOCKID_THREAD_CPUTIME_ID)?;
+        self.cputime_base = Some(now);
+        Ok(())
+    }
+
+    /// Work out when a `poll_oneoff` clock subscription expires.
+    ///
+    /// Returns the delay in nanoseconds from now, and the matching deadline on the virtual
+    /// monotonic clock, if there is one.
+    pub(crate) fn subscription_deadline(
+        &self,
+        subscription: &wasi::__wasi_subscription_clock_t,
+    ) -> Result<(u128, Option<Duration>)> {
+        let abstime =
+            subscription.flags & wasi::__WASI_SUBCLOCKFLAGS_SUBSCRIPTION_CLOCK_ABSTIME != 0;
+        let delay = if abstime {
+            // Compare against the exact time rather than a coarsened one, which may lag behind
+            // by up to the coarsening, and let deadlines in the past expire straight away.
+            let now = self.uncoarsened_time(subscription.id)?;
+            subscription.timeout.saturating_sub(now)
+        } else {
+            subscription.timeout
+        };
+
+        let deadline = self.monotonic.as_ref().map(|clock| {
+            if abstime && subscription.id == wasi::__WASI_CLOCKID_MONOTONIC {
+                // Use the deadline as is, so that it doesn't drift if the embedder happens to
+                // advance the clock in between here and `sleep_until`.
+                let base = self.creation_time.unwrap_or_default();
+                Duration::from_nanos(subscription.timeout.saturating_add(base))
+            } else {
+                clock.now() + Duration::from_nanos(delay)
+            }
+        });
+
+        Ok((u128::from(delay), deadline))
+    }
+
+    /// Sleep until `deadline` on the virtual monotonic clock, if there is one, failing with
+    /// `Error::EINTR` if `cancel` is cancelled first.
+    ///
+    /// Returns `false` if the host's clock is in use, in which case the caller has to sleep.
+    pub(crate) fn sleep_until(
+        &self,
+        deadline: Option<Duration>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<bool> {
+        let (clock, deadline) = match (&self.monotonic, deadline) {
+            (Some(clock), Some(deadline)) => (clock, deadline),
+            _ => return Ok(false),
+        };
+        if self.auto_advance && clock.advance_to(deadline) {
+            return Ok(true);
+        }
+        match cancel {
+            // The clock's own `sleep_until` can't be interrupted, so keep checking on both the
+            // clock and the token instead.
+            Some(cancel) => {
+                while clock.now() < deadline {
+                    cancel.sleep(CANCELLABLE_SLEEP_INTERVAL)?;
+                }
+            }
+            None => clock.sleep_until(deadline),
+        }
+        Ok(true)
+    }
+}
+
+/// How often a cancellable sleep on a virtual clock checks whether its deadline has passed.
+const CANCELLABLE_SLEEP_INTERVAL: Duration = Duration::from_millis(1);
+
+fn to_timestamp(duration: Duration) -> Result<wasi::__wasi_timestamp_t> {
+    duration.as_nanos().try_into().map_err(|_| Error::EOVERFLOW)
+}
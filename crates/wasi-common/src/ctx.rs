@@ -1,15 +1,33 @@
-use crate::fdentry::FdEntry;
-use crate::{wasi, Error, Result};
+use crate::call_filter::CallSet;
+use crate::call_log::{CallLog, CallRecord};
+use crate::cancel::CancelToken;
+use crate::clock::{
+    ClockOffset, FixedClock, OffsetSign, WasiCtxClocks, WasiMonotonicClock, WasiSystemClock,
+};
+use crate::fdentry::{DescriptorInfo, FdEntry, HostHandleTracker};
+use crate::fdtable::FdTable;
+use crate::hostcalls_impl::{self, SymlinkPolicy};
+use crate::quota::WriteQuota;
+use crate::random::{RandomBudget, RandomBudgetPolicy, RandomSource, WasiCtxRandom};
+use crate::usage::{Usage, UsageReport};
+use crate::virtfile::{Notifier, VirtualFile};
+use crate::{wasi, wasi32, Error, Result};
+use rand_core::RngCore;
 use std::borrow::Borrow;
 use std::collections::HashMap;
+use std::convert::TryInto;
 use std::env;
 use std::ffi::{CString, OsString};
 use std::fs::File;
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 enum PendingFdEntry {
     Thunk(fn() -> Result<FdEntry>),
     File(File),
+    Virtual(Box<dyn VirtualFile>),
 }
 
 impl std::fmt::Debug for PendingFdEntry {
@@ -21,10 +39,21 @@ impl std::fmt::Debug for PendingFdEntry {
                 f as *const fn() -> Result<FdEntry>
             ),
             Self::File(f) => write!(fmt, "PendingFdEntry::File({:?})", f),
+            Self::Virtual(f) => write!(fmt, "PendingFdEntry::Virtual({:?})", f),
         }
     }
 }
 
+/// A preopen added to a `WasiCtxBuilder`, along with the rights it's limited to.
+struct PendingPreopen {
+    guest_path: PathBuf,
+    dir: File,
+    rights_base: wasi::__wasi_rights_t,
+    rights_inheriting: wasi::__wasi_rights_t,
+    /// Whether it was added with `preopened_dir_at`, so that `guest_path` has to be checked.
+    checked: bool,
+}
+
 #[derive(Debug, Eq, Hash, PartialEq)]
 enum PendingCString {
     Bytes(Vec<u8>),
@@ -58,12 +87,41 @@ impl PendingCString {
     }
 }
 
+/// What `sched_yield` calls into, installed with `WasiCtxBuilder::yield_hook`.
+struct YieldHook(Box<dyn Fn() + Send>);
+
+impl std::fmt::Debug for YieldHook {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.write_str("YieldHook")
+    }
+}
+
 /// A builder allowing customizable construction of `WasiCtx` instances.
 pub struct WasiCtxBuilder {
     fds: HashMap<wasi::__wasi_fd_t, PendingFdEntry>,
-    preopens: Vec<(PathBuf, File)>,
+    preopens: Vec<PendingPreopen>,
+    #[cfg(unix)]
+    unix_sockets: Vec<PathBuf>,
     args: Vec<PendingCString>,
     env: HashMap<PendingCString, PendingCString>,
+    stdio_newline_translation: bool,
+    strict_descriptors: bool,
+    clocks: WasiCtxClocks,
+    scoped_cputime: bool,
+    shift_file_timestamps: bool,
+    monotonic_zero_at_creation: bool,
+    random: RandomSource,
+    random_budget: Option<RandomBudget>,
+    yield_hook: Option<YieldHook>,
+    args_env_size_limit: wasi32::size_t,
+    instance_name: Option<String>,
+    call_log: Option<Box<dyn Fn(CallRecord) + Send + Sync>>,
+    redact_call_log_paths: bool,
+    denied_calls: Vec<String>,
+    usage_stats: bool,
+    cancel_token: Option<CancelToken>,
+    write_quota: Option<u64>,
+    symlink_policy: SymlinkPolicy,
 }
 
 impl WasiCtxBuilder {
@@ -72,8 +130,28 @@ impl WasiCtxBuilder {
         let mut builder = Self {
             fds: HashMap::new(),
             preopens: Vec::new(),
+            #[cfg(unix)]
+            unix_sockets: Vec::new(),
             args: vec![],
             env: HashMap::new(),
+            stdio_newline_translation: false,
+            strict_descriptors: false,
+            clocks: WasiCtxClocks::default(),
+            scoped_cputime: false,
+            shift_file_timestamps: false,
+            monotonic_zero_at_creation: false,
+            random: RandomSource::os(),
+            random_budget: None,
+            yield_hook: None,
+            args_env_size_limit: wasi32::size_t::max_value(),
+            instance_name: None,
+            call_log: None,
+            redact_call_log_paths: false,
+            denied_calls: Vec::new(),
+            usage_stats: false,
+            cancel_token: None,
+            write_quota: None,
+            symlink_policy: SymlinkPolicy::default(),
         };
 
         builder.fds.insert(0, PendingFdEntry::Thunk(FdEntry::null));
@@ -182,9 +260,364 @@ impl WasiCtxBuilder {
         self
     }
 
-    /// Add a preopened directory.
-    pub fn preopened_dir<P: AsRef<Path>>(mut self, dir: File, guest_path: P) -> Self {
-        self.preopens.push((guest_path.as_ref().to_owned(), dir));
+    /// Provide a `VirtualFile` to use as stdin, e.g. a `ReadPipe`
+    pub fn stdin_virtual<F: VirtualFile + 'static>(mut self, file: F) -> Self {
+        self.fds.insert(0, PendingFdEntry::Virtual(Box::new(file)));
+        self
+    }
+
+    /// Provide a `VirtualFile` to use as stdout, e.g. a `WritePipe`
+    pub fn stdout_virtual<F: VirtualFile + 'static>(mut self, file: F) -> Self {
+        self.fds.insert(1, PendingFdEntry::Virtual(Box::new(file)));
+        self
+    }
+
+    /// Provide a `VirtualFile` to use as stderr, e.g. a `WritePipe`
+    pub fn stderr_virtual<F: VirtualFile + 'static>(mut self, file: F) -> Self {
+        self.fds.insert(2, PendingFdEntry::Virtual(Box::new(file)));
+        self
+    }
+
+    /// Install the guest's end of `notifier` at `fd`, for the host to wake the guest with.
+    ///
+    /// Keep a clone of `notifier` to call `Notifier::notify` on.
+    pub fn notify_fd(mut self, fd: wasi::__wasi_fd_t, notifier: Notifier) -> Self {
+        self.fds
+            .insert(fd, PendingFdEntry::Virtual(Box::new(notifier)));
+        self
+    }
+
+    /// Translate LF into CRLF in the guest's output to an inherited stdout or stderr, if it's
+    /// connected to a console which doesn't do that by itself (that is, a Windows console).
+    ///
+    /// Output redirected to files or pipes, as well as any stream provided with
+    /// `WasiCtxBuilder::stdout` or similar, is always passed through untouched.
+    pub fn stdio_newline_translation(mut self, enable: bool) -> Self {
+        self.stdio_newline_translation = enable;
+        self
+    }
+
+    /// Tag the guest's descriptors with a generation, so that using one after it's been
+    /// closed fails with `EBADF` rather than reaching whatever descriptor reused its slot.
+    ///
+    /// The generation lives in bits 20-30 of a descriptor, which is only set once its slot has
+    /// been reused; the stdio and preopen descriptors stay 0, 1, 2, 3 and so on, as guest
+    /// startup code expects. At most 2^20 descriptors can be open at once in this mode.
+    pub fn strict_descriptors(mut self, enable: bool) -> Self {
+        self.strict_descriptors = enable;
+        self
+    }
+
+    /// Coarsen the clocks visible to the guest, so that timestamps returned by `clock_time_get`
+    /// are rounded down to a multiple of `resolution`, and `clock_res_get` reports at least
+    /// `resolution`. This makes it harder for the guest to build timing side channels.
+    ///
+    /// A zero `resolution` disables coarsening.
+    pub fn clock_coarsening(mut self, resolution: Duration) -> Self {
+        self.clocks.coarsening = match resolution.as_nanos() {
+            0 => None,
+            ns => Some(
+                ns.try_into()
+                    .unwrap_or(wasi::__wasi_timestamp_t::max_value()),
+            ),
+        };
+        self
+    }
+
+    /// Use `clock` as the guest's `CLOCKID_REALTIME`, instead of the host's system clock.
+    ///
+    /// A `system_clock_offset` still applies, on top of `clock`'s readings.
+    pub fn system_clock<C: WasiSystemClock + 'static>(mut self, clock: C) -> Self {
+        self.clocks.system = Some(Arc::new(clock));
+        self
+    }
+
+    /// Use `clock` as the guest's `CLOCKID_MONOTONIC`, instead of the host's monotonic clock.
+    ///
+    /// A `poll_oneoff` which only waits for timeouts then sleeps on `clock` rather than on the
+    /// host. Timeouts of a `poll_oneoff` which also waits for file descriptors are still
+    /// measured in host time, as that's what the host's poller understands.
+    pub fn monotonic_clock<C: WasiMonotonicClock + 'static>(mut self, clock: C) -> Self {
+        self.clocks.monotonic = Some(Arc::new(clock));
+        self
+    }
+
+    /// Let a guest sleeping on a `monotonic_clock` which supports it, such as a `ManualClock`,
+    /// skip straight to its deadline, instead of waiting for the embedder to advance the clock.
+    pub fn clock_auto_advance(mut self, enable: bool) -> Self {
+        self.clocks.auto_advance = enable;
+        self
+    }
+
+    /// Shift every reading of the guest's system clock by `offset`, in the direction of `sign`.
+    ///
+    /// This is the host's system clock, unless `system_clock` or `system_clock_fixed` replaces
+    /// it, in which case the replacement is shifted instead. The monotonic clock is unaffected,
+    /// so timeouts keep working as usual.
+    pub fn system_clock_offset(mut self, offset: Duration, sign: OffsetSign) -> Self {
+        self.clocks.system_offset = Some(ClockOffset {
+            sign,
+            amount: offset,
+        });
+        self
+    }
+
+    /// Freeze the guest's system clock at `time`.
+    ///
+    /// The monotonic clock is unaffected, so timeouts keep working as usual.
+    pub fn system_clock_fixed(self, time: SystemTime) -> Self {
+        self.system_clock(FixedClock(time))
+    }
+
+    /// Shift the timestamps reported by `fd_filestat_get` and `path_filestat_get` by however
+    /// much the guest's system clock differs from the host's, as configured with
+    /// `system_clock_offset`, `system_clock_fixed` or `system_clock`.
+    ///
+    /// For clocks other than an offset one, the difference is measured once, in
+    /// `WasiCtxBuilder::build`.
+    pub fn shift_file_timestamps(mut self, enable: bool) -> Self {
+        self.shift_file_timestamps = enable;
+        self
+    }
+
+    /// Report the guest's `CLOCKID_MONOTONIC` relative to when `WasiCtxBuilder::build` was
+    /// called, rather than whatever the host's monotonic clock counts from, which is usually
+    /// the host's uptime.
+    ///
+    /// Absolute deadlines on the monotonic clock passed to `poll_oneoff` are interpreted on the
+    /// same timeline.
+    pub fn monotonic_zero_at_creation(mut self, enable: bool) -> Self {
+        self.monotonic_zero_at_creation = enable;
+        self
+    }
+
+    /// Scope the CPU-time clocks to this instance.
+    ///
+    /// By default `CLOCKID_PROCESS_CPUTIME_ID` and `CLOCKID_THREAD_CPUTIME_ID` report the CPU
+    /// time of the host process and thread, which includes whatever the embedder did before
+    /// running the guest. With `scoped` set, both instead report the CPU time the host thread
+    /// has consumed since `WasiCtxBuilder::build` was called. This assumes the instance runs on
+    /// the thread that built it, and counts time spent in hostcalls too.
+    pub fn scoped_cputime(mut self, scoped: bool) -> Self {
+        self.scoped_cputime = scoped;
+        self
+    }
+
+    /// Use `rng` as the source of the guest's `random_get`, instead of the host's CSPRNG.
+    ///
+    /// Passing a seeded generator makes the guest's randomness reproducible; it's up to the
+    /// embedder to make sure that's only done where predictable randomness is acceptable.
+    pub fn random<R: RngCore + Send + 'static>(mut self, rng: R) -> Self {
+        self.random = RandomSource::custom(Box::new(rng));
+        self
+    }
+
+    /// Draw the guest's `random_get` from a CSPRNG seeded with `seed`, making it reproducible.
+    ///
+    /// Contexts forked off with `random_forked_from` stay reproducible too.
+    pub fn random_seed(mut self, seed: [u8; 32]) -> Self {
+        self.random = RandomSource::seeded(seed);
+        self
+    }
+
+    /// Give the new context a generator independent from that of `parent`, so that the two
+    /// never hand the same random bytes to their guests.
+    ///
+    /// If `parent` was set up with `random_seed`, the new context gets a seed derived from the
+    /// parent's seed and the number of contexts forked off from it so far, so that a seeded
+    /// parent forks into a reproducible set of children. Otherwise, the new context draws from
+    /// the host's CSPRNG.
+    pub fn random_forked_from(mut self, parent: &WasiCtx) -> Self {
+        self.random = parent.random.fork();
+        self
+    }
+
+    /// Cap the number of bytes the guest may draw with `random_get` at `bytes`.
+    ///
+    /// What happens once the budget is used up is up to `random_budget_policy`; by default,
+    /// `random_get` fails with `EAGAIN`.
+    pub fn random_budget(mut self, bytes: u64) -> Self {
+        let policy = self.random_budget.map(|b| b.policy).unwrap_or_default();
+        self.random_budget = Some(RandomBudget { bytes, policy });
+        self
+    }
+
+    /// Set what `random_get` does once the budget set with `random_budget` is used up.
+    pub fn random_budget_policy(mut self, policy: RandomBudgetPolicy) -> Self {
+        let bytes = self.random_budget.map_or(u64::max_value(), |b| b.bytes);
+        self.random_budget = Some(RandomBudget { bytes, policy });
+        self
+    }
+
+    /// Call `hook` whenever the guest calls `sched_yield`, instead of yielding the host thread.
+    ///
+    /// This is where an embedder that schedules several guests on one thread gets the chance
+    /// to switch to another one. Without a hook, `sched_yield` just yields the host thread.
+    pub fn yield_hook<F: Fn() + Send + 'static>(mut self, hook: F) -> Self {
+        self.yield_hook = Some(YieldHook(Box::new(hook)));
+        self
+    }
+
+    /// Cap the buffers the guest gets the arguments and the environment in at `bytes` each,
+    /// NUL terminators included.
+    ///
+    /// By default, they may take up anything the `args_sizes_get` and `environ_sizes_get` of a
+    /// 32-bit guest can report.
+    pub fn args_env_size_limit(mut self, bytes: wasi32::size_t) -> Self {
+        self.args_env_size_limit = bytes;
+        self
+    }
+
+    /// Name the instance this context is for, to tell it apart from others in a multi-tenant
+    /// host.
+    ///
+    /// With the `tracing` feature, the hostcalls made on the context are nested under a span
+    /// carrying this name.
+    pub fn instance_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.instance_name = Some(name.into());
+        self
+    }
+
+    /// Pass a `CallRecord` to `sink` for every hostcall the guest makes, once it returns.
+    pub fn call_log(mut self, sink: Box<dyn Fn(CallRecord) + Send + Sync>) -> Self {
+        self.call_log = Some(sink);
+        self
+    }
+
+    /// Leave the paths out of the `CallRecord`s passed to the sink set with `call_log`, giving
+    /// only their lengths.
+    pub fn redact_call_log_paths(mut self, enable: bool) -> Self {
+        self.redact_call_log_paths = enable;
+        self
+    }
+
+    /// Collect statistics of the resources the guest uses, for `WasiCtx::usage_report` to
+    /// summarize.
+    pub fn usage_stats(mut self, enable: bool) -> Self {
+        self.usage_stats = enable;
+        self
+    }
+
+    /// Let the embedder cut short the guest's blocking hostcalls by cancelling `token`, from any
+    /// thread, upon which they fail with `EINTR`.
+    ///
+    /// This covers `poll_oneoff`, whether it waits on the host's clock, a virtual clock, virtual
+    /// files or host descriptors. It doesn't cover reads and writes which block on the host.
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel_token = Some(token);
+        self
+    }
+
+    /// Let the guest write at most `bytes` to the host's regular files, all told, so that it
+    /// can't fill the host's disk.
+    ///
+    /// `fd_write` and `fd_pwrite` count what they write, and once the quota runs short, their
+    /// writes come up short too, as on a nearly full disk. After that they, and `path_open` with
+    /// `__WASI_OFLAGS_CREAT`, fail with `EDQUOT`. Writes to stdio, pipes, sockets and virtual
+    /// files don't count. `fd_allocate` counts what a file grows by, and fails with `EDQUOT`
+    /// unless all of it fits. `WasiCtx::write_quota_remaining` tells what's left.
+    pub fn write_quota(mut self, bytes: u64) -> Self {
+        self.write_quota = Some(bytes);
+        self
+    }
+
+    /// Choose which symlinks the guest's paths may be resolved through, as described for
+    /// `SymlinkPolicy`. By default, only those which stay within the preopen are followed.
+    pub fn symlink_policy(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlink_policy = policy;
+        self
+    }
+
+    /// Deny the guest the hostcalls whose names match any of `patterns`, in which a `*` stands
+    /// for any run of characters, as in `"sock_*"`. A denied call fails with `ENOTCAPABLE`
+    /// without doing anything else, and shows up as denied in the call log.
+    ///
+    /// `proc_exit` has no way to fail, so it can't be denied.
+    pub fn deny_calls<S: AsRef<str>>(mut self, patterns: impl IntoIterator<Item = S>) -> Self {
+        self.denied_calls
+            .extend(patterns.into_iter().map(|p| p.as_ref().to_string()));
+        self
+    }
+
+    /// Add a preopened directory, which the guest sees at `guest_path`, as given by
+    /// `fd_prestat_dir_name`. Nothing of the host's own path for `dir` is shown to the guest.
+    ///
+    /// Preopens take up the `fd`s from 3 on up, in the order they're added. They may be nested,
+    /// like `"/data"` and `"/data/cache"`, in which case guests which map paths to preopens
+    /// themselves, like wasi-libc, resolve a path through the longest matching guest path.
+    ///
+    /// `guest_path` is handed to the guest as is, so it may be relative, like the `"."` which
+    /// wasi-libc resolves the guest's relative paths against. Use `preopened_dir_at` to have it
+    /// checked instead.
+    pub fn preopened_dir<P: AsRef<Path>>(self, dir: File, guest_path: P) -> Self {
+        self.preopened_dir_with_rights(dir, guest_path, wasi::RIGHTS_ALL, wasi::RIGHTS_ALL)
+    }
+
+    /// Add a preopened directory, like `preopened_dir`, at a virtual guest path, like `"/data"`,
+    /// which is checked by `WasiCtxBuilder::build()`.
+    ///
+    /// `guest_path` must be valid Unicode, absolute, starting with a `/`, with no NUL bytes, and
+    /// different from the guest paths of all the other preopens, or else the build fails with
+    /// `Error::EILSEQ`, for bad encoding, or `Error::EINVAL` otherwise.
+    pub fn preopened_dir_at<P: AsRef<Path>>(mut self, dir: File, guest_path: P) -> Self {
+        self.preopens.push(PendingPreopen {
+            guest_path: guest_path.as_ref().to_owned(),
+            dir,
+            rights_base: wasi::RIGHTS_ALL,
+            rights_inheriting: wasi::RIGHTS_ALL,
+            checked: true,
+        });
+        self
+    }
+
+    /// Add a preopened directory, like `preopened_dir`, which only lets the guest look at what's
+    /// in it: the guest can open, list and stat files and directories, and read files, but can't
+    /// create, write, rename, remove or retime any of them.
+    pub fn preopened_dir_readonly<P: AsRef<Path>>(self, dir: File, guest_path: P) -> Self {
+        self.preopened_dir_with_rights(
+            dir,
+            guest_path,
+            wasi::RIGHTS_DIRECTORY_READONLY_BASE,
+            wasi::RIGHTS_DIRECTORY_READONLY_INHERITING,
+        )
+    }
+
+    /// Add a preopened directory, like `preopened_dir`, with at most the rights given, as the
+    /// `__WASI_RIGHTS_*` constants in `wasi`.
+    ///
+    /// `rights_base` applies to the directory itself, like `__WASI_RIGHTS_PATH_CREATE_FILE` to
+    /// create files in it, and `rights_inheriting` to whatever the guest opens through it, and
+    /// so on down the tree, like `__WASI_RIGHTS_FD_WRITE` to write to the files it opens. Rights
+    /// which don't apply to directories are dropped.
+    pub fn preopened_dir_with_rights<P: AsRef<Path>>(
+        mut self,
+        dir: File,
+        guest_path: P,
+        rights_base: wasi::__wasi_rights_t,
+        rights_inheriting: wasi::__wasi_rights_t,
+    ) -> Self {
+        self.preopens.push(PendingPreopen {
+            guest_path: guest_path.as_ref().to_owned(),
+            dir,
+            rights_base,
+            rights_inheriting,
+            checked: false,
+        });
+        self
+    }
+
+    /// Connect to the Unix domain socket at `path`, and hand the connection to the guest.
+    ///
+    /// The guest may read, write, poll and shut down the connection, but gets no access to the
+    /// filesystem through it. The connections take up the `fd`s after the preopens, in the order
+    /// they're added, so a guest with one preopen finds its first socket at `fd` 4. Unlike
+    /// preopens, guests can't discover them with `fd_prestat_get`, so the guest has to be told
+    /// which `fd` is which, through its arguments for instance.
+    ///
+    /// The connection is made by `WasiCtxBuilder::build()`, which fails if it can't be.
+    #[cfg(unix)]
+    pub fn preopened_unix_socket<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.unix_sockets.push(path.as_ref().to_owned());
         self
     }
 
@@ -192,6 +625,9 @@ impl WasiCtxBuilder {
     ///
     /// If any of the arguments or environment variables in this builder cannot be converted into
     /// `CString`s, either due to NUL bytes or Unicode conversions, this returns `Error::EILSEQ`.
+    /// If either of them doesn't fit the limit set with `args_env_size_limit`, this returns
+    /// `Error::E2BIG`. If the guest path of a preopen added with `preopened_dir_at` isn't
+    /// acceptable, this returns `Error::EILSEQ` or `Error::EINVAL`, as described there.
     pub fn build(self) -> Result<WasiCtx> {
         // Process arguments and environment variables into `CString`s, failing quickly if they
         // contain any NUL bytes, or if conversion from `OsString` fails.
@@ -217,53 +653,187 @@ impl WasiCtxBuilder {
             })
             .collect::<Result<Vec<CString>>>()?;
 
-        let mut fds: HashMap<wasi::__wasi_fd_t, FdEntry> = HashMap::new();
+        // Fail here rather than have the guest's `args_sizes_get` or `environ_sizes_get` fail.
+        check_string_array_size(&args, self.args_env_size_limit)?;
+        check_string_array_size(&env, self.args_env_size_limit)?;
+        check_preopen_paths(&self.preopens)?;
+
+        let mut fds = if self.strict_descriptors {
+            FdTable::with_generations()
+        } else {
+            FdTable::new()
+        };
+        let host_handles = HostHandleTracker::default();
         // Populate the non-preopen fds.
         for (fd, pending) in self.fds {
             log::debug!("WasiCtx inserting ({:?}, {:?})", fd, pending);
-            match pending {
-                PendingFdEntry::Thunk(f) => {
-                    fds.insert(fd, f()?);
-                }
-                PendingFdEntry::File(f) => {
-                    fds.insert(fd, FdEntry::from(f)?);
+            let mut fe = match pending {
+                PendingFdEntry::Thunk(f) => f()?,
+                PendingFdEntry::File(f) => FdEntry::from(f)?,
+                PendingFdEntry::Virtual(f) => FdEntry::from_virtual(f),
+            };
+            fe.track(&host_handles);
+            fds.insert_at(fd, fe);
+        }
+        if self.stdio_newline_translation {
+            for fd in 1..=2 {
+                if let Some(entry) = fds.get_mut(fd) {
+                    entry.enable_newline_translation();
                 }
             }
         }
         // Then add the preopen fds. Startup code in the guest starts looking at fd 3 for preopens,
-        // so we start from there. This variable is initially 2, though, because the loop
-        // immediately does the increment and check for overflow.
-        let mut preopen_fd: wasi::__wasi_fd_t = 2;
-        for (guest_path, dir) in self.preopens {
-            // We do the increment at the beginning of the loop body, so that we don't overflow
-            // unnecessarily if we have exactly the maximum number of file descriptors.
-            preopen_fd = preopen_fd.checked_add(1).ok_or(Error::ENFILE)?;
-
-            if !dir.metadata()?.is_dir() {
+        // which is where the table starts allocating from.
+        for preopen in self.preopens {
+            if !preopen.dir.metadata()?.is_dir() {
                 return Err(Error::EBADF);
             }
 
-            // We don't currently allow setting file descriptors other than 0-2, but this will avoid
-            // collisions if we restore that functionality in the future.
-            while fds.contains_key(&preopen_fd) {
-                preopen_fd = preopen_fd.checked_add(1).ok_or(Error::ENFILE)?;
-            }
-            let mut fe = FdEntry::from(dir)?;
-            fe.preopen_path = Some(guest_path);
-            log::debug!("WasiCtx inserting ({:?}, {:?})", preopen_fd, fe);
-            fds.insert(preopen_fd, fe);
-            log::debug!("WasiCtx fds = {:?}", fds);
+            let mut fe = FdEntry::from(preopen.dir)?;
+            fe.rights_base &= preopen.rights_base;
+            fe.rights_inheriting &= preopen.rights_inheriting;
+            fe.preopen_path = Some(preopen.guest_path);
+            fe.track(&host_handles);
+            log::debug!("WasiCtx inserting preopen {:?}", fe);
+            let preopen_fd = fds.insert(fe).map_err(|_| Error::ENFILE)?;
+            log::debug!("WasiCtx inserted preopen at {:?}", preopen_fd);
         }
+        #[cfg(unix)]
+        for path in self.unix_sockets {
+            use crate::error::ErrorContext;
+            use std::os::unix::io::{FromRawFd, IntoRawFd};
+            use std::os::unix::net::UnixStream;
+            let stream =
+                UnixStream::connect(&path).with_context(|| format!("connect {:?}", path))?;
+            let mut fe = FdEntry::from(unsafe { File::from_raw_fd(stream.into_raw_fd()) })?;
+            fe.track(&host_handles);
+            let socket_fd = fds.insert(fe).map_err(|_| Error::ENFILE)?;
+            log::debug!("WasiCtx inserted socket {:?} at {:?}", path, socket_fd);
+        }
+        log::debug!("WasiCtx fds = {:?}", fds);
 
-        Ok(WasiCtx { args, env, fds })
+        let mut clocks = self.clocks;
+        if self.scoped_cputime {
+            clocks.scope_cputime()?;
+        }
+        if self.monotonic_zero_at_creation {
+            clocks.rebase_monotonic()?;
+        }
+        if self.shift_file_timestamps {
+            clocks.file_time_offset = match (&clocks.system, clocks.system_offset) {
+                (Some(clock), offset) => {
+                    let now = match offset {
+                        Some(offset) => offset.apply_to_system_time(clock.now())?,
+                        None => clock.now(),
+                    };
+                    Some(ClockOffset::between(SystemTime::now(), now))
+                }
+                (None, offset) => offset,
+            };
+        }
+
+        #[cfg(feature = "tracing")]
+        let span = tracing::trace_span!(
+            "wasi",
+            instance = self.instance_name.as_deref().unwrap_or_default()
+        );
+
+        let redact_paths = self.redact_call_log_paths;
+        let usage = if self.usage_stats {
+            let mut usage = Usage::new();
+            usage.record_open_fds(fds.len());
+            Some(usage)
+        } else {
+            None
+        };
+
+        Ok(WasiCtx {
+            args,
+            env,
+            fds,
+            host_handles,
+            clocks,
+            random: WasiCtxRandom::new(self.random, self.random_budget),
+            yield_hook: self.yield_hook,
+            instance_name: self.instance_name,
+            call_log: self.call_log.map(|sink| CallLog::new(sink, redact_paths)),
+            denied_calls: CallSet::matching(&self.denied_calls),
+            usage,
+            cancel_token: self.cancel_token,
+            write_quota: self
+                .write_quota
+                .map(|bytes| Arc::new(WriteQuota::new(bytes))),
+            symlink_policy: self.symlink_policy,
+            exit_status: Mutex::new(None),
+            #[cfg(feature = "tracing")]
+            span,
+        })
+    }
+}
+
+/// Fail with `Error::E2BIG` unless the guest can be handed `strings` within `limit` bytes,
+/// along with the array of pointers to them.
+fn check_string_array_size(strings: &[CString], limit: wasi32::size_t) -> Result<()> {
+    let ptr_size = mem::size_of::<wasi32::uintptr_t>() as wasi32::size_t;
+    match hostcalls_impl::string_array_sizes(strings) {
+        Ok((count, size)) if size <= limit && count.checked_mul(ptr_size).is_some() => Ok(()),
+        _ => Err(Error::E2BIG),
     }
 }
 
+/// Fail unless each of the guest paths given to `preopened_dir_at` is absolute, can be handed to
+/// the guest, and tells the preopen apart from all the others.
+fn check_preopen_paths(preopens: &[PendingPreopen]) -> Result<()> {
+    for (i, preopen) in preopens.iter().enumerate() {
+        if !preopen.checked {
+            continue;
+        }
+        let path = preopen.guest_path.to_str().ok_or(Error::EILSEQ)?;
+        // Not `Path::is_absolute`, which on Windows would want a drive in the guest's paths.
+        if !path.starts_with('/') || path.contains('\0') {
+            return Err(Error::EINVAL);
+        }
+        // `Path`s compare by their components, so `"/data/"` is the same as `"/data"`.
+        let duplicate = preopens
+            .iter()
+            .enumerate()
+            .any(|(j, other)| j != i && other.guest_path == preopen.guest_path);
+        if duplicate {
+            return Err(Error::EINVAL);
+        }
+    }
+    Ok(())
+}
+
+/// How the guest ended, as recorded on its `WasiCtx` by `proc_exit` or `proc_raise`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExitStatus {
+    /// The guest called `proc_exit` with this exit code.
+    Exited(wasi::__wasi_exitcode_t),
+    /// The guest raised this signal with `proc_raise`, and its default action is to end the
+    /// guest.
+    Signaled(wasi::__wasi_signal_t),
+}
+
 #[derive(Debug)]
 pub struct WasiCtx {
-    fds: HashMap<wasi::__wasi_fd_t, FdEntry>,
+    fds: FdTable<FdEntry>,
+    host_handles: HostHandleTracker,
     pub(crate) args: Vec<CString>,
     pub(crate) env: Vec<CString>,
+    pub(crate) clocks: WasiCtxClocks,
+    random: WasiCtxRandom,
+    yield_hook: Option<YieldHook>,
+    instance_name: Option<String>,
+    pub(crate) call_log: Option<CallLog>,
+    denied_calls: CallSet,
+    pub(crate) usage: Option<Usage>,
+    cancel_token: Option<CancelToken>,
+    write_quota: Option<Arc<WriteQuota>>,
+    symlink_policy: SymlinkPolicy,
+    exit_status: Mutex<Option<ExitStatus>>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 }
 
 impl WasiCtx {
@@ -282,14 +852,76 @@ impl WasiCtx {
             .build()
     }
 
+    /// Enumerate the entries currently present in the descriptor table, ordered by raw WASI `fd`.
+    ///
+    /// This is meant for diagnostics, e.g. working out why a guest observes `EBADF` for a given
+    /// descriptor.
+    pub fn dump_table(&self) -> Vec<DescriptorInfo> {
+        let mut table: Vec<_> = self.fds.iter().map(|(fd, fe)| fe.describe(fd)).collect();
+        table.sort_by_key(|info| info.fd);
+        table
+    }
+
+    /// Trace the contents of the descriptor table via the `log` crate.
+    pub fn log_table(&self) {
+        for info in self.dump_table() {
+            log::debug!("WasiCtx table entry {:?}", info);
+        }
+    }
+
+    /// Duplicate the raw WASI `fd`, like `dup(2)`, returning the lowest free `fd` which now
+    /// refers to the same open file.
+    ///
+    /// The two share the file offset and status flags, so seeking through one moves the
+    /// other, but their rights can be dropped independently and closing one leaves the other
+    /// open.
+    pub fn dup(&mut self, fd: wasi::__wasi_fd_t) -> Result<wasi::__wasi_fd_t> {
+        let fe = unsafe { self.get_fd_entry(fd)? }.try_clone()?;
+        self.insert_fd_entry(fe)
+    }
+
+    /// Duplicate the raw WASI `fd` onto `to`, like `dup2(2)`, closing whatever `to` referred
+    /// to before.
+    ///
+    /// Like `fd_renumber`, this refuses to replace a preopened directory.
+    pub fn dup_to(&mut self, fd: wasi::__wasi_fd_t, to: wasi::__wasi_fd_t) -> Result<()> {
+        let fe = unsafe { self.get_fd_entry(fd)? };
+        if fd == to {
+            return Ok(());
+        }
+        if let Some(to_fe) = self.fds.get(to) {
+            if to_fe.preopen_path.is_some() {
+                return Err(Error::ENOTSUP);
+            }
+        }
+        let fe = fe.try_clone()?;
+        self.insert_fd_entry_at(to, fe);
+        Ok(())
+    }
+
+    /// Close every descriptor in the table, preopens and stdio included, without waiting for
+    /// the `WasiCtx` to be dropped.
+    ///
+    /// This is meant for tearing a context down early, e.g. once its guest has trapped; the
+    /// guest sees `EBADF` for every descriptor from then on.
+    pub fn close_all(&mut self) {
+        self.fds.clear();
+    }
+
+    /// The number of host handles, such as files, directories and sockets, owned by the
+    /// descriptor table. Inherited stdio and virtual files aren't included.
+    pub fn host_handle_count(&self) -> usize {
+        self.host_handles.count()
+    }
+
     /// Check if `WasiCtx` contains the specified raw WASI `fd`.
     pub(crate) unsafe fn contains_fd_entry(&self, fd: wasi::__wasi_fd_t) -> bool {
-        self.fds.contains_key(&fd)
+        self.fds.contains(fd)
     }
 
     /// Get an immutable `FdEntry` corresponding to the specified raw WASI `fd`.
     pub(crate) unsafe fn get_fd_entry(&self, fd: wasi::__wasi_fd_t) -> Result<&FdEntry> {
-        self.fds.get(&fd).ok_or(Error::EBADF)
+        self.fds.get(fd).ok_or(Error::EBADF)
     }
 
     /// Get a mutable `FdEntry` corresponding to the specified raw WASI `fd`.
@@ -297,24 +929,17 @@ impl WasiCtx {
         &mut self,
         fd: wasi::__wasi_fd_t,
     ) -> Result<&mut FdEntry> {
-        self.fds.get_mut(&fd).ok_or(Error::EBADF)
+        self.fds.get_mut(fd).ok_or(Error::EBADF)
     }
 
     /// Insert the specified `FdEntry` into the `WasiCtx` object.
     ///
-    /// The `FdEntry` will automatically get another free raw WASI `fd` assigned. Note that
-    /// the two subsequent free raw WASI `fd`s do not have to be stored contiguously.
-    pub(crate) fn insert_fd_entry(&mut self, fe: FdEntry) -> Result<wasi::__wasi_fd_t> {
-        // Never insert where stdio handles are expected to be.
-        let mut fd = 3;
-        while self.fds.contains_key(&fd) {
-            if let Some(next_fd) = fd.checked_add(1) {
-                fd = next_fd;
-            } else {
-                return Err(Error::EMFILE);
-            }
-        }
-        self.fds.insert(fd, fe);
+    /// The `FdEntry` will automatically get the lowest free raw WASI `fd` assigned, never
+    /// one of those where stdio handles are expected to be.
+    pub(crate) fn insert_fd_entry(&mut self, mut fe: FdEntry) -> Result<wasi::__wasi_fd_t> {
+        fe.track(&self.host_handles);
+        let fd = self.fds.insert(fe)?;
+        self.record_open_fds();
         Ok(fd)
     }
 
@@ -323,13 +948,661 @@ impl WasiCtx {
     pub(crate) fn insert_fd_entry_at(
         &mut self,
         fd: wasi::__wasi_fd_t,
-        fe: FdEntry,
+        mut fe: FdEntry,
     ) -> Option<FdEntry> {
-        self.fds.insert(fd, fe)
+        fe.track(&self.host_handles);
+        let replaced = self.fds.insert_at(fd, fe);
+        self.record_open_fds();
+        replaced
+    }
+
+    fn record_open_fds(&mut self) {
+        if let Some(usage) = &mut self.usage {
+            usage.record_open_fds(self.fds.len());
+        }
+    }
+
+    /// Count `nbytes` read by the guest towards the `UsageReport`, if there is one.
+    pub(crate) fn record_bytes_read(&self, nbytes: usize) {
+        if let Some(usage) = &self.usage {
+            usage.record_read(nbytes);
+        }
+    }
+
+    /// Count `nbytes` written by the guest towards the `UsageReport`, if there is one.
+    pub(crate) fn record_bytes_written(&self, nbytes: usize) {
+        if let Some(usage) = &self.usage {
+            usage.record_written(nbytes);
+        }
+    }
+
+    /// The token set with `WasiCtxBuilder::cancel_token`, if any.
+    pub(crate) fn cancel_token(&self) -> Option<&CancelToken> {
+        self.cancel_token.as_ref()
+    }
+
+    /// The quota set with `WasiCtxBuilder::write_quota`, if any, shared so that it can be drawn
+    /// from while an entry of the table is borrowed.
+    pub(crate) fn write_quota(&self) -> Option<Arc<WriteQuota>> {
+        self.write_quota.clone()
+    }
+
+    pub(crate) fn symlink_policy(&self) -> SymlinkPolicy {
+        self.symlink_policy
+    }
+
+    /// How many bytes the guest may still write to the host's files, if
+    /// `WasiCtxBuilder::write_quota` set a limit.
+    pub fn write_quota_remaining(&self) -> Option<u64> {
+        self.write_quota.as_ref().map(|quota| quota.remaining())
+    }
+
+    /// A summary of the resources the guest has used so far, if `WasiCtxBuilder::usage_stats`
+    /// was enabled.
+    pub fn usage_report(&self) -> Option<UsageReport> {
+        self.usage.as_ref().map(Usage::report)
+    }
+
+    /// How the guest ended, if it called `proc_exit`, or raised a signal with `proc_raise` whose
+    /// default action is to end it. Only the first of these is kept. A trap in the guest isn't
+    /// recorded here, as the embedder gets that from the call into the guest. The exit code is
+    /// kept whole, so codes from 126 up to `u32::MAX` read back the same.
+    ///
+    /// For now, `proc_exit` still exits the host process right after recording its code, which
+    /// the host may truncate, so `Exited` is only seen by code that runs as the process exits,
+    /// like handlers registered with `atexit`.
+    ///
+    /// `proc_raise` does not stop the guest: hostcalls can't end the instance in this tree, so
+    /// after recording `Signaled` it fails with `ENOTSUP`, and the guest goes on running. The
+    /// embedder has to check this whenever a call into the guest returns, and stop running the
+    /// guest itself. The host process never gets the signal.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        *self.exit_status.lock().unwrap()
+    }
+
+    pub(crate) fn record_exit_status(&self, status: ExitStatus) {
+        self.exit_status.lock().unwrap().get_or_insert(status);
     }
 
     /// Remove `FdEntry` corresponding to the specified raw WASI `fd` from the `WasiCtx` object.
     pub(crate) fn remove_fd_entry(&mut self, fd: wasi::__wasi_fd_t) -> Result<FdEntry> {
-        self.fds.remove(&fd).ok_or(Error::EBADF)
+        self.fds.remove(fd).ok_or(Error::EBADF)
+    }
+
+    /// The number of bytes the guest has drawn with `random_get` so far.
+    pub fn random_bytes_consumed(&self) -> u64 {
+        self.random.consumed()
+    }
+
+    /// Switch the guest's `random_get` over to a CSPRNG seeded with `seed`.
+    pub fn reseed_random(&mut self, seed: [u8; 32]) {
+        self.random.reseed(seed)
+    }
+
+    /// The random number generator all of the guest's randomness has to be drawn from.
+    pub(crate) fn random(&self) -> &WasiCtxRandom {
+        &self.random
+    }
+
+    /// The name set with `WasiCtxBuilder::instance_name`, if any.
+    pub fn instance_name(&self) -> Option<&str> {
+        self.instance_name.as_deref()
+    }
+
+    /// Whether the hostcall at `index` in `hostcalls::NAMES` was denied with
+    /// `WasiCtxBuilder::deny_calls`.
+    pub(crate) fn call_denied(&self, index: usize) -> bool {
+        self.denied_calls.contains(index)
+    }
+
+    /// The span the hostcalls made on this context are nested under.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    /// Give up the host thread on behalf of the guest, through the hook set with
+    /// `WasiCtxBuilder::yield_hook`, if any.
+    pub(crate) fn yield_now(&self) {
+        match &self.yield_hook {
+            Some(YieldHook(hook)) => hook(),
+            None => std::thread::yield_now(),
+        }
+    }
+}
+
+impl Drop for WasiCtx {
+    fn drop(&mut self) {
+        self.close_all();
+        // Every host handle is owned by exactly one entry, so none may outlive the table;
+        // one that does would leak a host descriptor for each context created.
+        if !std::thread::panicking() {
+            debug_assert_eq!(
+                self.host_handle_count(),
+                0,
+                "host handles outlived their WasiCtx"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::WasiError;
+    use crate::fdentry::DescriptorKind;
+    use crate::test_helpers::{scratch_dir, with_memory};
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn dump_table() {
+        let scratch = scratch_dir("dump_table");
+        let dir = scratch.path();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(dir.join("file"))
+            .expect("create scratch file");
+
+        let mut ctx = WasiCtxBuilder::new()
+            .preopened_dir(crate::preopen_dir(&dir).unwrap(), "/tmp")
+            .preopened_dir(crate::preopen_dir(&dir).unwrap(), "/scratch")
+            .build()
+            .expect("build WasiCtx");
+        let fd = ctx
+            .insert_fd_entry(FdEntry::from(file).unwrap())
+            .expect("insert file");
+        assert_eq!(fd, 5);
+
+        let table = ctx.dump_table();
+        let fds: Vec<_> = table.iter().map(|info| info.fd).collect();
+        assert_eq!(fds, vec![0, 1, 2, 3, 4, 5]);
+        for info in &table[..3] {
+            assert_eq!(info.file_type, wasi::__WASI_FILETYPE_CHARACTER_DEVICE);
+            assert_eq!(info.preopen_path, None);
+        }
+        assert_eq!(table[3].kind, DescriptorKind::Dir);
+        assert_eq!(table[3].preopen_path, Some(PathBuf::from("/tmp")));
+        assert_eq!(table[4].kind, DescriptorKind::Dir);
+        assert_eq!(table[4].preopen_path, Some(PathBuf::from("/scratch")));
+        assert_eq!(table[5].kind, DescriptorKind::File);
+        assert_eq!(table[5].file_type, wasi::__WASI_FILETYPE_REGULAR_FILE);
+        assert_eq!(table[5].preopen_path, None);
+        assert!(!table[5].description.is_empty());
+
+        ctx.remove_fd_entry(fd).expect("remove file");
+        let fds: Vec<_> = ctx.dump_table().iter().map(|info| info.fd).collect();
+        assert_eq!(fds, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn newline_translation_skips_redirected_output() {
+        let scratch = scratch_dir("newline_translation");
+        let file = File::create(scratch.path().join("file")).expect("create scratch file");
+
+        let ctx = WasiCtxBuilder::new()
+            .stdout(file)
+            .stdio_newline_translation(true)
+            .build()
+            .expect("build WasiCtx");
+        let entry = unsafe { ctx.get_fd_entry(1) }.expect("stdout entry");
+        assert!(entry.newline_translation.is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    fn open_fds() -> usize {
+        std::fs::read_dir("/proc/self/fd")
+            .expect("list open fds")
+            .count()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn inherited_stdio_survives_ctx_drop() {
+        let before = open_fds();
+        for _ in 0..1000 {
+            let ctx = WasiCtxBuilder::new()
+                .inherit_stdio()
+                .build()
+                .expect("build WasiCtx");
+            drop(ctx);
+        }
+        // Other tests may be opening files concurrently, so allow for some slack; a leak
+        // would show up as thousands of extra descriptors.
+        assert!(open_fds() < before + 100, "descriptors leaked");
+
+        for fd in 0..=2 {
+            assert_ne!(
+                unsafe { libc::fcntl(fd, libc::F_GETFD) },
+                -1,
+                "host fd {} got closed",
+                fd
+            );
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn host_handles_closed_on_drop() {
+        use std::os::unix::io::{FromRawFd, IntoRawFd};
+        use std::os::unix::net::UnixStream;
+
+        let scratch = scratch_dir("close_on_drop");
+        let dir = scratch.path();
+        let file_path = dir.join("file");
+        File::create(&file_path).expect("create scratch file");
+
+        let before = open_fds();
+        for i in 0..10_000 {
+            let mut ctx = WasiCtxBuilder::new()
+                .preopened_dir(crate::preopen_dir(&dir).unwrap(), "/tmp")
+                .build()
+                .expect("build WasiCtx");
+            let file = File::open(&file_path).expect("open scratch file");
+            ctx.insert_fd_entry(FdEntry::from(file).unwrap())
+                .expect("insert file");
+            let (a, b) = UnixStream::pair().expect("socket pair");
+            for sock in &[a, b] {
+                let sock = sock.try_clone().expect("duplicate socket");
+                let sock = unsafe { File::from_raw_fd(sock.into_raw_fd()) };
+                ctx.insert_fd_entry(FdEntry::from(sock).unwrap())
+                    .expect("insert socket");
+            }
+            // Stdio defaults to /dev/null, which is opened for each context too.
+            assert_eq!(ctx.host_handle_count(), 7);
+
+            if i % 2 == 0 {
+                ctx.close_all();
+                assert_eq!(ctx.host_handle_count(), 0);
+                assert!(ctx.dump_table().is_empty());
+            }
+        }
+        // Other tests may be opening files concurrently, so allow for some slack; a leak
+        // would show up as tens of thousands of extra descriptors.
+        assert!(open_fds() < before + 100, "descriptors leaked");
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "host handles outlived their WasiCtx")]
+    fn leaked_host_handle_detected() {
+        let scratch = scratch_dir("leaked_host_handle");
+        let mut ctx = WasiCtxBuilder::new()
+            .preopened_dir(crate::preopen_dir(scratch.path()).unwrap(), "/tmp")
+            .build()
+            .expect("build WasiCtx");
+        let _leaked = ctx.remove_fd_entry(3).expect("remove preopen");
+        drop(ctx);
+    }
+
+    #[test]
+    fn strict_descriptors() {
+        use crate::hostcalls_impl::{fd_close, fd_tell};
+
+        let scratch = scratch_dir("strict");
+        let file_path = scratch.path().join("file");
+        std::fs::write(&file_path, b"contents").expect("write scratch file");
+        let open = || FdEntry::from(File::open(&file_path).unwrap()).unwrap();
+        with_memory(8, |memory| {
+            for &strict in &[false, true] {
+                let mut ctx = WasiCtxBuilder::new()
+                    .preopened_dir(crate::preopen_dir(scratch.path()).unwrap(), "/tmp")
+                    .strict_descriptors(strict)
+                    .build()
+                    .expect("build WasiCtx");
+                // Guests discover preopens by probing upwards from fd 3, so they stay plain.
+                let fds: Vec<_> = ctx.dump_table().iter().map(|info| info.fd).collect();
+                assert_eq!(fds, vec![0, 1, 2, 3]);
+
+                let stale = ctx.insert_fd_entry(open()).expect("insert file");
+                assert_eq!(stale, 4);
+                unsafe { fd_close(&mut ctx, memory, stale) }.expect("fd_close");
+                let fresh = ctx.insert_fd_entry(open()).expect("insert file");
+
+                let res = unsafe { fd_tell(&mut ctx, memory, stale, 0) };
+                if strict {
+                    assert_ne!(fresh, stale);
+                    assert_eq!(res.unwrap_err().as_wasi_error(), WasiError::EBADF);
+                    unsafe { fd_tell(&mut ctx, memory, fresh, 0) }.expect("fd_tell");
+                } else {
+                    // Without generations, the stale fd silently reaches the new file.
+                    assert_eq!(fresh, stale);
+                    res.expect("fd_tell");
+                }
+            }
+        });
+    }
+
+    /// Read up to `len` bytes from `fd` with `fd_read`, through a fake guest memory.
+    fn guest_read(ctx: &mut WasiCtx, fd: wasi::__wasi_fd_t, len: u32) -> Result<Vec<u8>> {
+        with_memory(64, |memory| {
+            memory[0..4].copy_from_slice(&16u32.to_le_bytes());
+            memory[4..8].copy_from_slice(&len.to_le_bytes());
+            unsafe { crate::hostcalls_impl::fd_read(ctx, memory, fd, 0, 1, 8)? };
+            let mut nread = [0; 4];
+            nread.copy_from_slice(&memory[8..12]);
+            let nread = u32::from_le_bytes(nread) as usize;
+            Ok(memory[16..16 + nread].to_vec())
+        })
+    }
+
+    #[test]
+    fn dup_shares_offset() {
+        use crate::hostcalls_impl::{fd_close, fd_fdstat_set_rights, fd_seek};
+
+        let scratch = scratch_dir("dup");
+        let file_path = scratch.path().join("file");
+        std::fs::write(&file_path, b"0123456789").expect("write scratch file");
+        let file = File::open(&file_path).expect("open scratch file");
+
+        let mut ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        let fd = ctx
+            .insert_fd_entry(FdEntry::from(file).unwrap())
+            .expect("insert file");
+        let copy = ctx.dup(fd).expect("dup file");
+        assert_eq!(copy, fd + 1);
+
+        with_memory(8, |memory| {
+            unsafe { fd_seek(&mut ctx, memory, fd, 4, wasi::__WASI_WHENCE_SET, 0) }
+                .expect("fd_seek");
+            assert_eq!(guest_read(&mut ctx, copy, 3).unwrap(), b"456");
+            assert_eq!(guest_read(&mut ctx, fd, 1).unwrap(), b"7");
+
+            // Rights are per entry though.
+            let rights = ctx.fds.get(copy).unwrap().rights_base & !wasi::__WASI_RIGHTS_FD_READ;
+            unsafe { fd_fdstat_set_rights(&mut ctx, memory, copy, rights, 0) }
+                .expect("drop rights");
+            let res = guest_read(&mut ctx, copy, 1);
+            assert_eq!(res.unwrap_err().as_wasi_error(), WasiError::ENOTCAPABLE);
+            assert_eq!(guest_read(&mut ctx, fd, 1).unwrap(), b"8");
+
+            let other = ctx.dup(fd).expect("dup file");
+            unsafe { fd_close(&mut ctx, memory, fd) }.expect("fd_close");
+            assert_eq!(guest_read(&mut ctx, other, 4).unwrap(), b"9");
+            let res = guest_read(&mut ctx, fd, 1);
+            assert_eq!(res.unwrap_err().as_wasi_error(), WasiError::EBADF);
+        });
+    }
+
+    #[test]
+    fn dup_virtual_file() {
+        let stdin = crate::ReadPipe::new("abcdef");
+        let mut ctx = WasiCtxBuilder::new()
+            .stdin_virtual(stdin)
+            .build()
+            .expect("build WasiCtx");
+
+        let copy = ctx.dup(0).expect("dup stdin");
+        assert_eq!(guest_read(&mut ctx, 0, 2).unwrap(), b"ab");
+        assert_eq!(guest_read(&mut ctx, copy, 2).unwrap(), b"cd");
+
+        // `dup_to` replaces whatever was there, which is how a shell would redirect stdin.
+        ctx.dup_to(copy, 0).expect("dup_to stdin");
+        ctx.remove_fd_entry(copy).expect("close copy");
+        assert_eq!(guest_read(&mut ctx, 0, 4).unwrap(), b"ef");
+    }
+
+    #[test]
+    fn args_env_size_limit() {
+        // "0123456789" takes up 11 bytes with its NUL terminator, and so does "KEY=value0".
+        let build = |limit| {
+            WasiCtxBuilder::new()
+                .arg("0123456789")
+                .env("KEY", "value0")
+                .args_env_size_limit(limit)
+                .build()
+        };
+        build(11).expect("build WasiCtx");
+        let err = build(10).unwrap_err();
+        assert_eq!(err.as_wasi_error(), WasiError::E2BIG);
+
+        let err = WasiCtxBuilder::new()
+            .env("KEY", "value0")
+            .args_env_size_limit(10)
+            .build()
+            .unwrap_err();
+        assert_eq!(err.as_wasi_error(), WasiError::E2BIG);
+    }
+
+    /// The spans made while this is the thread's default subscriber, in order, with the
+    /// 1-based index of their explicit parent, if any, and their fields.
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct SpanCollector(Arc<std::sync::Mutex<Vec<CollectedSpan>>>);
+
+    #[cfg(feature = "tracing")]
+    #[derive(Debug)]
+    struct CollectedSpan {
+        name: &'static str,
+        parent: Option<u64>,
+        fields: Vec<(String, String)>,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for CollectedSpan {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.fields
+                .push((field.name().to_string(), format!("{:?}", value)));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for SpanCollector {
+        fn enabled(&self, _: &tracing::Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &tracing::span::Attributes) -> tracing::span::Id {
+            let mut span = CollectedSpan {
+                name: attrs.metadata().name(),
+                parent: attrs.parent().map(|id| id.into_u64()),
+                fields: Vec::new(),
+            };
+            attrs.record(&mut span);
+            let mut spans = self.0.lock().unwrap();
+            spans.push(span);
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, id: &tracing::span::Id, values: &tracing::span::Record) {
+            let mut spans = self.0.lock().unwrap();
+            values.record(&mut spans[id.into_u64() as usize - 1]);
+        }
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, _: &tracing::Event) {}
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn hostcall_spans() {
+        let collector = SpanCollector::default();
+        tracing::subscriber::with_default(collector.clone(), || {
+            let mut ctx = WasiCtxBuilder::new()
+                .instance_name("tenant-1")
+                .build()
+                .expect("build WasiCtx");
+            let errno = unsafe { crate::hostcalls::fd_close(&mut ctx, &mut [], 99) };
+            assert_eq!(errno, wasi::__WASI_ERRNO_BADF);
+        });
+
+        let spans = collector.0.lock().unwrap();
+        assert_eq!(spans.len(), 2, "{:?}", spans);
+        assert_eq!(spans[0].name, "wasi");
+        assert_eq!(spans[0].parent, None);
+        assert_eq!(
+            spans[0].fields,
+            [("instance".to_string(), "\"tenant-1\"".to_string())]
+        );
+        assert_eq!(spans[1].name, "fd_close");
+        assert_eq!(spans[1].parent, Some(1));
+        assert_eq!(
+            spans[1].fields,
+            [
+                ("fd".to_string(), "99".to_string()),
+                ("errno".to_string(), wasi::__WASI_ERRNO_BADF.to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn preopen_guest_paths() {
+        use crate::hostcalls_impl::{fd_prestat_dir_name, fd_prestat_get, path_open};
+
+        let scratch = scratch_dir("guest_paths");
+        let dir = scratch.path();
+        std::fs::create_dir_all(dir.join("cache")).expect("create scratch dir");
+        std::fs::write(dir.join("file.txt"), b"").expect("create scratch file");
+        let mut ctx = WasiCtxBuilder::new()
+            .preopened_dir_at(crate::preopen_dir(&dir).unwrap(), "/data")
+            .preopened_dir_at(
+                crate::preopen_dir(dir.join("cache")).unwrap(),
+                "/data/cache",
+            )
+            .preopened_dir(crate::preopen_dir(&dir).unwrap(), ".")
+            .build()
+            .expect("build WasiCtx");
+        with_memory(64, |memory| {
+            for &(fd, name) in &[(3, "/data"), (4, "/data/cache"), (5, ".")] {
+                unsafe { fd_prestat_get(&ctx, memory, fd, 0) }.expect("fd_prestat_get");
+                assert_eq!(&memory[4..8], &(name.len() as u32).to_le_bytes());
+                let len = name.len() as u32;
+                unsafe { fd_prestat_dir_name(&ctx, memory, fd, 16, len) }
+                    .expect("fd_prestat_dir_name");
+                assert_eq!(&memory[16..16 + name.len()], name.as_bytes());
+            }
+
+            // Guests strip the guest path off of "/data/file.txt" themselves.
+            memory[16..24].copy_from_slice(b"file.txt");
+            unsafe { path_open(&mut ctx, memory, 3, 0, 16, 8, 0, 0, 0, 0, 8) }.expect("path_open");
+        });
+
+        // Virtual guest paths have to be absolute, free of NULs, and unique, even among the
+        // preopens which aren't checked themselves.
+        for &(a, b) in &[
+            ("/data", "/data/"),
+            ("/da\0ta", "/data"),
+            ("data", "/data"),
+            (".", "/data"),
+            ("", "/data"),
+        ] {
+            let res = WasiCtxBuilder::new()
+                .preopened_dir_at(crate::preopen_dir(&dir).unwrap(), a)
+                .preopened_dir_at(crate::preopen_dir(&dir).unwrap(), b)
+                .build();
+            assert_eq!(res.unwrap_err().as_wasi_error(), WasiError::EINVAL);
+        }
+        let res = WasiCtxBuilder::new()
+            .preopened_dir(crate::preopen_dir(&dir).unwrap(), "/data")
+            .preopened_dir_at(crate::preopen_dir(&dir).unwrap(), "/data")
+            .build();
+        assert_eq!(res.unwrap_err().as_wasi_error(), WasiError::EINVAL);
+    }
+
+    #[test]
+    fn readonly_preopens() {
+        use crate::hostcalls_impl::{path_create_directory, path_open};
+
+        let scratch = scratch_dir("readonly");
+        let dir = scratch.path();
+        std::fs::write(dir.join("file.txt"), b"data").expect("create scratch file");
+        let mut ctx = WasiCtxBuilder::new()
+            .preopened_dir_readonly(crate::preopen_dir(&dir).unwrap(), "/data")
+            .build()
+            .expect("build WasiCtx");
+        with_memory(64, |memory| {
+            memory[16..24].copy_from_slice(b"file.txt");
+            memory[24..28].copy_from_slice(b"made");
+            let errno = |res: Result<()>| res.unwrap_err().as_wasi_error();
+
+            let table = ctx.dump_table();
+            assert_eq!(
+                table[3].rights_base & wasi::__WASI_RIGHTS_PATH_CREATE_FILE,
+                0
+            );
+            assert_eq!(table[3].rights_inheriting & wasi::__WASI_RIGHTS_FD_WRITE, 0);
+
+            let read = wasi::__WASI_RIGHTS_FD_READ;
+            unsafe { path_open(&mut ctx, memory, 3, 0, 16, 8, 0, read, 0, 0, 8) }
+                .expect("path_open");
+            let write = wasi::__WASI_RIGHTS_FD_WRITE;
+            let res = unsafe { path_open(&mut ctx, memory, 3, 0, 16, 8, 0, write, 0, 0, 8) };
+            assert_eq!(errno(res), WasiError::ENOTCAPABLE);
+            let create = wasi::__WASI_OFLAGS_CREAT;
+            let res = unsafe { path_open(&mut ctx, memory, 3, 0, 24, 4, create, read, 0, 0, 8) };
+            assert_eq!(errno(res), WasiError::ENOTCAPABLE);
+            let res = unsafe { path_create_directory(&ctx, memory, 3, 24, 4) };
+            assert_eq!(errno(res), WasiError::ENOTCAPABLE);
+        });
+        assert!(!dir.join("made").exists());
+        drop(ctx);
+
+        // Rights are only ever narrowed, to what a directory supports at most.
+        let ctx = WasiCtxBuilder::new()
+            .preopened_dir_with_rights(
+                crate::preopen_dir(&dir).unwrap(),
+                "/data",
+                wasi::RIGHTS_ALL,
+                wasi::__WASI_RIGHTS_FD_READ,
+            )
+            .build()
+            .expect("build WasiCtx");
+        let table = ctx.dump_table();
+        assert_eq!(table[3].rights_base, wasi::RIGHTS_DIRECTORY_BASE);
+        assert_eq!(table[3].rights_inheriting, wasi::__WASI_RIGHTS_FD_READ);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn preopened_unix_sockets() {
+        use crate::hostcalls_impl::{fd_prestat_get, fd_write};
+        use std::io::Read;
+        use std::os::unix::net::UnixListener;
+
+        let scratch = scratch_dir("unix_sockets");
+        let dir = scratch.path();
+        let path = dir.join("service.sock");
+        let listener = UnixListener::bind(&path).expect("bind socket");
+        let mut ctx = WasiCtxBuilder::new()
+            .preopened_dir(crate::preopen_dir(&dir).unwrap(), "/data")
+            .preopened_unix_socket(&path)
+            .build()
+            .expect("build WasiCtx");
+        let (mut service, _) = listener.accept().expect("accept connection");
+        // A ciovec for "query" at 16.
+        with_memory(32, |memory| {
+            memory[8..12].copy_from_slice(&16u32.to_le_bytes());
+            memory[12..16].copy_from_slice(&5u32.to_le_bytes());
+            memory[16..21].copy_from_slice(b"query");
+
+            // The socket follows the preopens, where a guest's scan for them stops.
+            let info: Vec<_> = ctx
+                .dump_table()
+                .into_iter()
+                .map(|i| (i.fd, i.kind))
+                .collect();
+            assert_eq!(
+                info[3..],
+                [(3, DescriptorKind::Dir), (4, DescriptorKind::Socket)]
+            );
+            let res = unsafe { fd_prestat_get(&ctx, memory, 4, 0) };
+            assert_eq!(res.unwrap_err().as_wasi_error(), WasiError::EBADF);
+
+            unsafe { fd_write(&mut ctx, memory, 4, 8, 1, 0) }.expect("fd_write");
+        });
+        drop(ctx);
+        let mut request = vec![];
+        service.read_to_end(&mut request).unwrap();
+        assert_eq!(request, b"query");
+
+        std::fs::remove_file(&path).expect("remove socket");
+        let res = WasiCtxBuilder::new().preopened_unix_socket(&path).build();
+        assert_eq!(res.unwrap_err().as_wasi_error(), WasiError::ENOENT);
     }
 }
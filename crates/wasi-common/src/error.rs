@@ -3,7 +3,7 @@
 use crate::wasi;
 use std::convert::Infallible;
 use std::num::TryFromIntError;
-use std::{ffi, str};
+use std::{ffi, fmt, str};
 use thiserror::Error;
 
 #[derive(Clone, Copy, Debug, Error, Eq, PartialEq)]
@@ -104,6 +104,14 @@ pub enum Error {
     #[cfg(unix)]
     #[error("Yanix error: {0}")]
     Yanix(#[from] yanix::YanixError),
+    /// An error annotated with what the host was doing when it happened, such as the syscall
+    /// which failed and the path it was made on. The guest only ever gets `source`'s errno.
+    #[error("{context}: {source}")]
+    Context {
+        context: String,
+        #[source]
+        source: Box<Error>,
+    },
 }
 
 impl From<TryFromIntError> for Error {
@@ -160,6 +168,15 @@ impl Error {
                 };
                 err.as_wasi_error()
             }
+            Self::Context { source, .. } => source.as_wasi_error(),
+        }
+    }
+
+    /// Annotate this error with `context`, e.g. `openat "foo/bar"`.
+    pub(crate) fn context(self, context: impl fmt::Display) -> Self {
+        Self::Context {
+            context: context.to_string(),
+            source: Box::new(self),
         }
     }
 
@@ -252,6 +269,17 @@ pub(crate) trait AsWasiError {
     fn as_wasi_error(&self) -> WasiError;
 }
 
+pub(crate) trait ErrorContext<T> {
+    /// Annotate the error, if any, with the context `f` returns, as with `Error::context`.
+    fn with_context<C: fmt::Display>(self, f: impl FnOnce() -> C) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> ErrorContext<T> for std::result::Result<T, E> {
+    fn with_context<C: fmt::Display>(self, f: impl FnOnce() -> C) -> Result<T> {
+        self.map_err(|err| err.into().context(f()))
+    }
+}
+
 impl<T> AsWasiError for Result<T> {
     fn as_wasi_error(&self) -> WasiError {
         self.as_ref()
@@ -1,23 +1,76 @@
+use crate::newline_translating_writer::NewlineTranslation;
 use crate::sys::dev_null;
-use crate::sys::fdentry_impl::{
-    descriptor_as_oshandle, determine_type_and_access_rights, OsHandle,
-};
+use crate::sys::fdentry_impl::{descriptor_is_console, determine_type_and_access_rights, OsHandle};
+use crate::virtfile::VirtualFile;
 use crate::{wasi, Error, Result};
-use std::marker::PhantomData;
-use std::mem::ManuallyDrop;
-use std::ops::{Deref, DerefMut};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::{fs, io};
 
+/// The kind of host resource backing an entry in the `WasiCtx` descriptor table.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DescriptorKind {
+    File,
+    Dir,
+    Socket,
+    Stdio,
+    Virtual,
+}
+
+/// A snapshot of a single entry in the `WasiCtx` descriptor table, as returned by
+/// `WasiCtx::dump_table`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DescriptorInfo {
+    pub fd: wasi::__wasi_fd_t,
+    pub kind: DescriptorKind,
+    pub file_type: wasi::__wasi_filetype_t,
+    pub rights_base: wasi::__wasi_rights_t,
+    pub rights_inheriting: wasi::__wasi_rights_t,
+    pub preopen_path: Option<PathBuf>,
+    /// A short, human-readable description of the underlying host handle.
+    pub description: String,
+}
+
+/// Keeps count of the entries of a `WasiCtx` which own a host handle, so that a handle which
+/// outlives its context can be caught.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct HostHandleTracker(Arc<()>);
+
+impl HostHandleTracker {
+    /// The number of live entries which own a host handle and were tracked with this tracker.
+    pub(crate) fn count(&self) -> usize {
+        Arc::strong_count(&self.0) - 1
+    }
+}
+
+/// A host resource backing an `FdEntry`.
+///
+/// The `Stdin`, `Stdout` and `Stderr` variants merely refer to the host process's standard
+/// streams; they don't own the underlying descriptors, so dropping them never closes fd 0-2.
+///
+/// A `VirtualFile` is shared between all of the duplicates made with `Descriptor::try_clone`.
 #[derive(Debug)]
 pub(crate) enum Descriptor {
     OsHandle(OsHandle),
     Stdin,
     Stdout,
     Stderr,
+    VirtualFile(Arc<Mutex<Box<dyn VirtualFile>>>),
 }
 
 impl Descriptor {
+    /// Duplicate this descriptor, like `dup(2)` does: both refer to the same open file, and so
+    /// share its offset and status flags, but either can be closed without affecting the other.
+    pub(crate) fn try_clone(&self) -> Result<Self> {
+        match self {
+            Self::OsHandle(file) => Ok(Self::OsHandle(OsHandle::from(file.try_clone()?))),
+            Self::Stdin => Ok(Self::Stdin),
+            Self::Stdout => Ok(Self::Stdout),
+            Self::Stderr => Ok(Self::Stderr),
+            Self::VirtualFile(file) => Ok(Self::VirtualFile(Arc::clone(file))),
+        }
+    }
+
     /// Return a reference to the `OsHandle` treating it as an actual file/dir, and
     /// allowing operations which require an actual file and not just a stream or
     /// socket file descriptor.
@@ -35,11 +88,6 @@ impl Descriptor {
             _ => Err(Error::EBADF),
         }
     }
-
-    /// Return an `OsHandle`, which may be a stream or socket file descriptor.
-    pub(crate) fn as_os_handle<'descriptor>(&'descriptor self) -> OsHandleRef<'descriptor> {
-        descriptor_as_oshandle(self)
-    }
 }
 
 /// An abstraction struct serving as a wrapper for a host `Descriptor` object which requires
@@ -57,6 +105,10 @@ pub(crate) struct FdEntry {
     pub(crate) rights_base: wasi::__wasi_rights_t,
     pub(crate) rights_inheriting: wasi::__wasi_rights_t,
     pub(crate) preopen_path: Option<PathBuf>,
+    /// Set if writes to this entry are to have LF translated into CRLF.
+    pub(crate) newline_translation: Option<NewlineTranslation>,
+    /// Set once this entry is inserted into a `WasiCtx`, if it owns a host handle.
+    tracker: Option<HostHandleTracker>,
     // TODO: directories
 }
 
@@ -69,18 +121,37 @@ impl FdEntry {
                 rights_base,
                 rights_inheriting,
                 preopen_path: None,
+                newline_translation: None,
+                tracker: None,
             },
         )
     }
 
+    pub(crate) fn from_virtual(file: Box<dyn VirtualFile>) -> Self {
+        let (rights_base, rights_inheriting) = file.rights();
+        Self {
+            file_type: file.filetype(),
+            descriptor: Descriptor::VirtualFile(Arc::new(Mutex::new(file))),
+            rights_base,
+            rights_inheriting,
+            preopen_path: None,
+            newline_translation: None,
+            tracker: None,
+        }
+    }
+
+    // The standard streams are only ever used as streams (they're never handed out by
+    // `Descriptor::as_file`), so only the rights which work on streams are granted for them.
     pub(crate) fn duplicate_stdin() -> Result<Self> {
         unsafe { determine_type_and_access_rights(&io::stdin()) }.map(
             |(file_type, rights_base, rights_inheriting)| Self {
                 file_type,
                 descriptor: Descriptor::Stdin,
-                rights_base,
+                rights_base: rights_base & wasi::RIGHTS_STDIO_BASE,
                 rights_inheriting,
                 preopen_path: None,
+                newline_translation: None,
+                tracker: None,
             },
         )
     }
@@ -90,9 +161,11 @@ impl FdEntry {
             |(file_type, rights_base, rights_inheriting)| Self {
                 file_type,
                 descriptor: Descriptor::Stdout,
-                rights_base,
+                rights_base: rights_base & wasi::RIGHTS_STDIO_BASE,
                 rights_inheriting,
                 preopen_path: None,
+                newline_translation: None,
+                tracker: None,
             },
         )
     }
@@ -102,9 +175,11 @@ impl FdEntry {
             |(file_type, rights_base, rights_inheriting)| Self {
                 file_type,
                 descriptor: Descriptor::Stderr,
-                rights_base,
+                rights_base: rights_base & wasi::RIGHTS_STDIO_BASE,
                 rights_inheriting,
                 preopen_path: None,
+                newline_translation: None,
+                tracker: None,
             },
         )
     }
@@ -113,6 +188,43 @@ impl FdEntry {
         Self::from(dev_null()?)
     }
 
+    /// Duplicate this entry, with the same rights, on top of `Descriptor::try_clone`.
+    ///
+    /// The duplicate is never a preopen, even if this entry is one, so that it can be closed
+    /// and renumbered like any other descriptor.
+    pub(crate) fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            file_type: self.file_type,
+            descriptor: self.descriptor.try_clone()?,
+            rights_base: self.rights_base,
+            rights_inheriting: self.rights_inheriting,
+            preopen_path: None,
+            newline_translation: self.newline_translation,
+            tracker: None,
+        })
+    }
+
+    /// Have this entry counted by `tracker` for as long as it's alive, provided it owns a host
+    /// handle. The standard streams are borrowed from the host process and aren't counted.
+    pub(crate) fn track(&mut self, tracker: &HostHandleTracker) {
+        if let Descriptor::OsHandle(_) = self.descriptor {
+            self.tracker = Some(tracker.clone());
+        }
+    }
+
+    /// Have LF translated into CRLF on writes to this entry, provided it's the host's stdout or
+    /// stderr and is connected to a console which doesn't do that by itself. Output redirected
+    /// to files or pipes is always left alone.
+    pub(crate) fn enable_newline_translation(&mut self) {
+        let is_output_stream = match self.descriptor {
+            Descriptor::Stdout | Descriptor::Stderr => true,
+            _ => false,
+        };
+        if is_output_stream && descriptor_is_console(&self.descriptor) {
+            self.newline_translation = Some(NewlineTranslation::default());
+        }
+    }
+
     /// Convert this `FdEntry` into a host `Descriptor` object provided the specified
     /// `rights_base` and `rights_inheriting` rights are set on this `FdEntry` object.
     ///
@@ -145,6 +257,124 @@ impl FdEntry {
         Ok(&mut self.descriptor)
     }
 
+    /// Like `as_descriptor`, but for hostcalls which only work on directories, such as
+    /// `fd_readdir` and the `path_*` family.
+    ///
+    /// An entry of any other kind fails with `Error::ENOTDIR`, whatever its rights.
+    pub(crate) fn as_dir(
+        &self,
+        rights_base: wasi::__wasi_rights_t,
+        rights_inheriting: wasi::__wasi_rights_t,
+    ) -> Result<&OsHandle> {
+        self.validate_dir()?;
+        self.as_descriptor(rights_base, rights_inheriting)?
+            .as_file()
+    }
+
+    /// Like `as_dir`, but return a mutable reference.
+    pub(crate) fn as_dir_mut(
+        &mut self,
+        rights_base: wasi::__wasi_rights_t,
+        rights_inheriting: wasi::__wasi_rights_t,
+    ) -> Result<&mut OsHandle> {
+        self.validate_dir()?;
+        self.as_descriptor_mut(rights_base, rights_inheriting)?
+            .as_file_mut()
+    }
+
+    /// Like `as_descriptor`, but for hostcalls which access the contents of a file or stream,
+    /// such as `fd_read` and `fd_write`.
+    ///
+    /// A directory fails with `Error::EISDIR`, whatever its rights.
+    pub(crate) fn as_file(
+        &self,
+        rights_base: wasi::__wasi_rights_t,
+        rights_inheriting: wasi::__wasi_rights_t,
+    ) -> Result<&Descriptor> {
+        self.validate_not_dir()?;
+        self.as_descriptor(rights_base, rights_inheriting)
+    }
+
+    /// Like `as_file`, but return a mutable reference.
+    pub(crate) fn as_file_mut(
+        &mut self,
+        rights_base: wasi::__wasi_rights_t,
+        rights_inheriting: wasi::__wasi_rights_t,
+    ) -> Result<&mut Descriptor> {
+        self.validate_not_dir()?;
+        self.as_descriptor_mut(rights_base, rights_inheriting)
+    }
+
+    /// Like `as_descriptor`, but for hostcalls which need a file position, such as `fd_seek`,
+    /// `fd_pread` and `fd_advise`.
+    ///
+    /// Pipes, sockets, the standard streams and virtual files fail with `Error::ESPIPE`,
+    /// whatever their rights.
+    pub(crate) fn as_seekable(
+        &self,
+        rights_base: wasi::__wasi_rights_t,
+        rights_inheriting: wasi::__wasi_rights_t,
+    ) -> Result<&OsHandle> {
+        self.validate_seekable()?;
+        self.as_descriptor(rights_base, rights_inheriting)?
+            .as_file()
+    }
+
+    /// Like `as_seekable`, but return a mutable reference.
+    pub(crate) fn as_seekable_mut(
+        &mut self,
+        rights_base: wasi::__wasi_rights_t,
+        rights_inheriting: wasi::__wasi_rights_t,
+    ) -> Result<&mut OsHandle> {
+        self.validate_seekable()?;
+        self.as_descriptor_mut(rights_base, rights_inheriting)?
+            .as_file_mut()
+    }
+
+    /// Like `as_descriptor`, but for the `sock_*` hostcalls.
+    ///
+    /// An entry which isn't a socket fails with `Error::ENOTSOCK`, whatever its rights.
+    pub(crate) fn as_socket(
+        &self,
+        rights_base: wasi::__wasi_rights_t,
+        rights_inheriting: wasi::__wasi_rights_t,
+    ) -> Result<&Descriptor> {
+        match self.file_type {
+            wasi::__WASI_FILETYPE_SOCKET_DGRAM | wasi::__WASI_FILETYPE_SOCKET_STREAM => {
+                self.as_descriptor(rights_base, rights_inheriting)
+            }
+            _ => Err(Error::ENOTSOCK),
+        }
+    }
+
+    fn validate_dir(&self) -> Result<()> {
+        if self.file_type == wasi::__WASI_FILETYPE_DIRECTORY {
+            Ok(())
+        } else {
+            Err(Error::ENOTDIR)
+        }
+    }
+
+    fn validate_not_dir(&self) -> Result<()> {
+        if self.file_type == wasi::__WASI_FILETYPE_DIRECTORY {
+            Err(Error::EISDIR)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn validate_seekable(&self) -> Result<()> {
+        match (&self.descriptor, self.file_type) {
+            // Host pipes are reported as `FILETYPE_UNKNOWN`, cf. `determine_type_rights`.
+            (Descriptor::OsHandle(_), wasi::__WASI_FILETYPE_UNKNOWN)
+            | (Descriptor::OsHandle(_), wasi::__WASI_FILETYPE_SOCKET_DGRAM)
+            | (Descriptor::OsHandle(_), wasi::__WASI_FILETYPE_SOCKET_STREAM) => Err(Error::ESPIPE),
+            (Descriptor::OsHandle(_), _) => Ok(()),
+            // Neither stdio nor virtual files are ever handed out by `Descriptor::as_file`.
+            _ => Err(Error::ESPIPE),
+        }
+    }
+
     /// Check if this `FdEntry` object satisfies the specified base rights `rights_base`, and
     /// inheriting rights `rights_inheriting`; i.e., if rights attached to this `FdEntry` object
     /// are a superset.
@@ -176,6 +406,30 @@ impl FdEntry {
         }
     }
 
+    /// Describe this `FdEntry`, stored under the raw WASI `fd`, for diagnostic purposes.
+    pub(crate) fn describe(&self, fd: wasi::__wasi_fd_t) -> DescriptorInfo {
+        let kind = match (&self.descriptor, self.file_type) {
+            (Descriptor::Stdin, _) | (Descriptor::Stdout, _) | (Descriptor::Stderr, _) => {
+                DescriptorKind::Stdio
+            }
+            (Descriptor::VirtualFile(_), _) => DescriptorKind::Virtual,
+            (_, wasi::__WASI_FILETYPE_DIRECTORY) => DescriptorKind::Dir,
+            (_, wasi::__WASI_FILETYPE_SOCKET_DGRAM) | (_, wasi::__WASI_FILETYPE_SOCKET_STREAM) => {
+                DescriptorKind::Socket
+            }
+            _ => DescriptorKind::File,
+        };
+        DescriptorInfo {
+            fd,
+            kind,
+            file_type: self.file_type,
+            rights_base: self.rights_base,
+            rights_inheriting: self.rights_inheriting,
+            preopen_path: self.preopen_path.clone(),
+            description: format!("{:?}", self.descriptor),
+        }
+    }
+
     /// Test whether this descriptor is considered a tty within WASI.
     /// Note that since WASI itself lacks an `isatty` syscall and relies
     /// on a conservative approximation, we use the same approximation here.
@@ -185,34 +439,130 @@ impl FdEntry {
     }
 }
 
-/// This allows an `OsHandle` to be temporarily borrowed from a
-/// `Descriptor`. The `Descriptor` continues to own the resource,
-/// and `OsHandleRef`'s lifetime parameter ensures that it doesn't
-/// outlive the `Descriptor`.
-pub(crate) struct OsHandleRef<'descriptor> {
-    handle: ManuallyDrop<OsHandle>,
-    _ref: PhantomData<&'descriptor Descriptor>,
-}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::WasiError;
+    use crate::test_helpers::{scratch_dir, with_memory};
+    use crate::{hostcalls_impl, ReadPipe, WasiCtxBuilder};
+    use tempfile::TempDir;
+
+    /// Whether an entry passes the kind check of each typed accessor, in the order `as_dir`,
+    /// `as_file`, `as_seekable` and `as_socket`.
+    type Expected = [std::result::Result<(), WasiError>; 4];
 
-impl<'descriptor> OsHandleRef<'descriptor> {
-    pub(crate) fn new(handle: ManuallyDrop<OsHandle>) -> Self {
-        OsHandleRef {
-            handle,
-            _ref: PhantomData,
+    fn check(entry: &FdEntry, expected: Expected) {
+        let kinds = |rights: wasi::__wasi_rights_t| {
+            let errno = |res: Result<()>| res.map_err(|e| e.as_wasi_error());
+            [
+                errno(entry.as_dir(rights, 0).map(drop)),
+                errno(entry.as_file(rights, 0).map(drop)),
+                errno(entry.as_seekable(rights, 0).map(drop)),
+                errno(entry.as_socket(rights, 0).map(drop)),
+            ]
+        };
+        assert_eq!(kinds(0), expected, "{:?}", entry);
+
+        // The kind is checked before the rights, so a mismatch is reported as such even when
+        // the rights aren't there either.
+        let mut expected = expected;
+        for res in expected.iter_mut() {
+            *res = res.and(Err(WasiError::ENOTCAPABLE));
         }
+        assert_eq!(kinds(wasi::RIGHTS_ALL), expected, "{:?}", entry);
     }
-}
 
-impl<'descriptor> Deref for OsHandleRef<'descriptor> {
-    type Target = fs::File;
+    /// Create a scratch directory holding a single file, returned opened.
+    fn scratch_file(name: &str) -> (TempDir, fs::File) {
+        let dir = scratch_dir(name);
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(dir.path().join("file"))
+            .expect("create scratch file");
+        (dir, file)
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.handle
+    #[test]
+    fn kind_mismatches() {
+        use WasiError::*;
+
+        let (scratch, file) = scratch_file("kind_mismatches");
+        let file = FdEntry::from(file).expect("file entry");
+        check(&file, [Err(ENOTDIR), Ok(()), Ok(()), Err(ENOTSOCK)]);
+
+        let dir = FdEntry::from(crate::preopen_dir(scratch.path()).unwrap()).expect("dir entry");
+        check(&dir, [Ok(()), Err(EISDIR), Ok(()), Err(ENOTSOCK)]);
+
+        let pipe = FdEntry::from_virtual(Box::new(ReadPipe::new("")));
+        check(&pipe, [Err(ENOTDIR), Ok(()), Err(ESPIPE), Err(ENOTSOCK)]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::{FromRawFd, IntoRawFd};
+            use std::os::unix::net::UnixDatagram;
+
+            let (sock, _peer) = UnixDatagram::pair().expect("socket pair");
+            let sock = unsafe { fs::File::from_raw_fd(sock.into_raw_fd()) };
+            let sock = FdEntry::from(sock).expect("socket entry");
+            check(&sock, [Err(ENOTDIR), Ok(()), Err(ESPIPE), Ok(())]);
+
+            let mut fds = [0; 2];
+            assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+            let (reader, writer) =
+                unsafe { (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1])) };
+            let reader = FdEntry::from(reader).expect("pipe entry");
+            check(&reader, [Err(ENOTDIR), Ok(()), Err(ESPIPE), Err(ENOTSOCK)]);
+            drop(writer);
+        }
     }
-}
 
-impl<'descriptor> DerefMut for OsHandleRef<'descriptor> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.handle
+    #[test]
+    fn hostcalls_report_kind_mismatches() {
+        let (scratch, file) = scratch_file("hostcalls_report_kind_mismatches");
+        let mut ctx = WasiCtxBuilder::new()
+            .stdin_virtual(ReadPipe::new("input"))
+            .preopened_dir(crate::preopen_dir(scratch.path()).unwrap(), "/tmp")
+            .build()
+            .expect("build WasiCtx");
+        let dir_fd = 3;
+        let file_fd = ctx
+            .insert_fd_entry(FdEntry::from(file).unwrap())
+            .expect("insert file");
+        // A one-byte path, "x", at address 0.
+        with_memory(16, |memory| {
+            memory[0] = b'x';
+            let errno = |res: Result<()>| res.unwrap_err().as_wasi_error();
+
+            unsafe {
+                let res = hostcalls_impl::fd_write(&mut ctx, memory, dir_fd, 0, 0, 8);
+                assert_eq!(errno(res), WasiError::EISDIR);
+                let res = hostcalls_impl::fd_read(&mut ctx, memory, dir_fd, 0, 0, 8);
+                assert_eq!(errno(res), WasiError::EISDIR);
+                let res = hostcalls_impl::fd_pread(&ctx, memory, dir_fd, 0, 0, 0, 8);
+                assert_eq!(errno(res), WasiError::EISDIR);
+
+                let res = hostcalls_impl::fd_readdir(&mut ctx, memory, file_fd, 0, 0, 0, 8);
+                assert_eq!(errno(res), WasiError::ENOTDIR);
+                let res = hostcalls_impl::path_create_directory(&ctx, memory, file_fd, 0, 1);
+                assert_eq!(errno(res), WasiError::ENOTDIR);
+
+                let res =
+                    hostcalls_impl::fd_seek(&mut ctx, memory, 0, 1, wasi::__WASI_WHENCE_SET, 8);
+                assert_eq!(errno(res), WasiError::ESPIPE);
+                let res = hostcalls_impl::fd_tell(&mut ctx, memory, 0, 8);
+                assert_eq!(errno(res), WasiError::ESPIPE);
+
+                let res = hostcalls_impl::sock_shutdown(&ctx, memory, file_fd, 0);
+                assert_eq!(errno(res), WasiError::ENOTSOCK);
+
+                // Only an empty slot is a bad descriptor.
+                let res = hostcalls_impl::fd_tell(&mut ctx, memory, 99, 8);
+                assert_eq!(errno(res), WasiError::EBADF);
+                let res = hostcalls_impl::sock_shutdown(&ctx, memory, 99, 0);
+                assert_eq!(errno(res), WasiError::EBADF);
+            }
+        });
     }
 }
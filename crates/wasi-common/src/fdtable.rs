@@ -0,0 +1,300 @@
+//! The descriptor table of a `WasiCtx`.
+use crate::{wasi, Error, Result};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+/// The lowest `fd` handed out by `FdTable::insert`; the ones below are reserved for stdio.
+const FIRST_FREE_FD: wasi::__wasi_fd_t = 3;
+
+/// With generations, the low `SLOT_BITS` bits of an `fd` select its slot in the table, and the
+/// bits above them hold the slot's generation, leaving the sign bit of a C `int` clear.
+const SLOT_BITS: u32 = 20;
+const SLOT_MASK: wasi::__wasi_fd_t = (1 << SLOT_BITS) - 1;
+const GENERATION_MASK: wasi::__wasi_fd_t = (1 << (31 - SLOT_BITS)) - 1;
+
+/// A map from raw WASI `fd`s to their entries, which keeps handing out the lowest free `fd`,
+/// like POSIX `open` does, no matter how many descriptors the guest opens and closes.
+///
+/// A table made with `FdTable::with_generations` mixes a per-slot generation, bumped whenever
+/// the slot is vacated, into the upper bits of the `fd`s it hands out. A stale `fd` kept around
+/// after being closed then no longer matches, rather than referring to whatever reused its slot.
+/// A slot's first `fd` has generation 0, so the stdio and preopen `fd`s stay plain.
+#[derive(Debug)]
+pub(crate) struct FdTable<T> {
+    /// The entries, keyed by slot; without generations, a slot is the same as an `fd`.
+    entries: HashMap<wasi::__wasi_fd_t, T>,
+    /// The current generation of each slot, if generations are used at all. Slots missing
+    /// from the map are at generation 0.
+    generations: Option<HashMap<wasi::__wasi_fd_t, wasi::__wasi_fd_t>>,
+    /// Free slots below `next`, lowest first. This may contain stale slots which have been
+    /// taken since with `insert_at`, which are skipped when popped.
+    free: BinaryHeap<Reverse<wasi::__wasi_fd_t>>,
+    /// Every slot from here on up is free, save for any taken with `insert_at`.
+    next: wasi::__wasi_fd_t,
+}
+
+impl<T> FdTable<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            generations: None,
+            free: BinaryHeap::new(),
+            next: FIRST_FREE_FD,
+        }
+    }
+
+    /// Like `new`, but with generation-tagged `fd`s.
+    pub(crate) fn with_generations() -> Self {
+        Self {
+            generations: Some(HashMap::new()),
+            ..Self::new()
+        }
+    }
+
+    /// The number of entries in the table.
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn contains(&self, fd: wasi::__wasi_fd_t) -> bool {
+        self.slot(fd).is_some()
+    }
+
+    pub(crate) fn get(&self, fd: wasi::__wasi_fd_t) -> Option<&T> {
+        self.entries.get(&self.slot(fd)?)
+    }
+
+    pub(crate) fn get_mut(&mut self, fd: wasi::__wasi_fd_t) -> Option<&mut T> {
+        let slot = self.slot(fd)?;
+        self.entries.get_mut(&slot)
+    }
+
+    /// Iterate over the entries, along with the `fd`s they can currently be accessed with.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (wasi::__wasi_fd_t, &T)> {
+        self.entries
+            .iter()
+            .map(move |(&slot, entry)| (self.fd(slot), entry))
+    }
+
+    /// The `fd` through which the entry in `slot` is accessed.
+    fn fd(&self, slot: wasi::__wasi_fd_t) -> wasi::__wasi_fd_t {
+        match &self.generations {
+            Some(generations) => {
+                let generation = generations.get(&slot).copied().unwrap_or(0);
+                slot | (generation << SLOT_BITS)
+            }
+            None => slot,
+        }
+    }
+
+    /// The slot `fd` refers to, provided there's an entry in it and `fd` isn't stale.
+    fn slot(&self, fd: wasi::__wasi_fd_t) -> Option<wasi::__wasi_fd_t> {
+        let slot = match self.generations {
+            Some(_) => fd & SLOT_MASK,
+            None => fd,
+        };
+        if self.entries.contains_key(&slot) && self.fd(slot) == fd {
+            Some(slot)
+        } else {
+            None
+        }
+    }
+
+    /// Insert `entry` at the lowest free `fd` that isn't reserved for stdio, in amortized
+    /// O(log n) time.
+    pub(crate) fn insert(&mut self, entry: T) -> Result<wasi::__wasi_fd_t> {
+        let slot = loop {
+            match self.free.pop() {
+                Some(Reverse(fd)) if self.entries.contains_key(&fd) => continue,
+                Some(Reverse(fd)) => break fd,
+                None => {
+                    while self.entries.contains_key(&self.next) {
+                        self.next = self.next.checked_add(1).ok_or(Error::EMFILE)?;
+                    }
+                    let fd = self.next;
+                    // Once the last possible `fd` is taken, `next` stays put, and the loop
+                    // above fails with `EMFILE` from then on.
+                    self.next = fd.saturating_add(1);
+                    break fd;
+                }
+            }
+        };
+        if self.generations.is_some() && slot > SLOT_MASK {
+            self.free.push(Reverse(slot));
+            return Err(Error::EMFILE);
+        }
+        self.entries.insert(slot, entry);
+        Ok(self.fd(slot))
+    }
+
+    /// Insert `entry` at `fd`, returning the entry it replaces, if any.
+    ///
+    /// With generations, the slot `fd` refers to takes on the generation in `fd`, so any other
+    /// `fd` for the same slot is stale from then on.
+    pub(crate) fn insert_at(&mut self, fd: wasi::__wasi_fd_t, entry: T) -> Option<T> {
+        let slot = match &mut self.generations {
+            Some(generations) => {
+                let slot = fd & SLOT_MASK;
+                match fd >> SLOT_BITS {
+                    0 => generations.remove(&slot),
+                    generation => generations.insert(slot, generation),
+                };
+                slot
+            }
+            None => fd,
+        };
+        self.entries.insert(slot, entry)
+    }
+
+    pub(crate) fn remove(&mut self, fd: wasi::__wasi_fd_t) -> Option<T> {
+        let slot = self.slot(fd)?;
+        let entry = self.entries.remove(&slot)?;
+        if let Some(generations) = &mut self.generations {
+            let generation = generations.entry(slot).or_insert(0);
+            *generation = (*generation + 1) & GENERATION_MASK;
+        }
+        // Anything from `next` on up is already known to be free.
+        if slot >= FIRST_FREE_FD && slot < self.next {
+            self.free.push(Reverse(slot));
+        }
+        Some(entry)
+    }
+
+    /// Remove every entry, as if by `remove`.
+    pub(crate) fn clear(&mut self) {
+        let fds: Vec<_> = self.iter().map(|(fd, _)| fd).collect();
+        for fd in fds {
+            self.remove(fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn reuses_lowest_fd() {
+        let mut table = FdTable::new();
+        for fd in 0..3 {
+            table.insert_at(fd, ());
+        }
+        // A preopen sitting above the first free `fd`.
+        table.insert_at(5, ());
+
+        assert_eq!(table.insert(()).unwrap(), 3);
+        assert_eq!(table.insert(()).unwrap(), 4);
+        assert_eq!(table.insert(()).unwrap(), 6);
+        table.remove(4);
+        table.remove(3);
+        assert_eq!(table.insert(()).unwrap(), 3);
+        assert_eq!(table.insert(()).unwrap(), 4);
+        assert_eq!(table.insert(()).unwrap(), 7);
+
+        // Closing stdio doesn't make its `fd`s available to `insert`.
+        table.remove(1);
+        assert_eq!(table.insert(()).unwrap(), 8);
+    }
+
+    #[test]
+    fn churn() {
+        let mut table = FdTable::new();
+        let fds: Vec<_> = (0..10).map(|_| table.insert(()).unwrap()).collect();
+        for _ in 0..1_000_000 {
+            let fd = table.insert(()).unwrap();
+            assert_eq!(fd, 13);
+            table.remove(fd);
+        }
+        assert_eq!(table.entries.len(), fds.len());
+        assert!(table.free.len() <= 1);
+    }
+
+    #[test]
+    fn generations_catch_stale_fds() {
+        let mut table = FdTable::with_generations();
+        for fd in 0..3 {
+            table.insert_at(fd, "stdio");
+        }
+        assert_eq!(table.insert("preopen").unwrap(), 3);
+        assert_eq!(table.insert("a").unwrap(), 4);
+        assert_eq!(table.remove(4), Some("a"));
+
+        // The slot is reused, but under a different `fd`.
+        let b = table.insert("b").unwrap();
+        assert_eq!(b, 4 | (1 << SLOT_BITS));
+        assert_eq!(table.get(4), None);
+        assert!(!table.contains(4));
+        assert_eq!(table.remove(4), None);
+        assert_eq!(table.get(b), Some(&"b"));
+        assert_eq!(table.insert("c").unwrap(), 5);
+        let fds: Vec<_> = table.iter().map(|(fd, _)| fd).collect();
+        assert!(fds.contains(&b));
+
+        // Renumbering onto a slot takes on the `fd` it was renumbered to.
+        table.insert_at(4, "d");
+        assert_eq!(table.get(b), None);
+        assert_eq!(table.get(4), Some(&"d"));
+
+        table.clear();
+        assert_eq!(table.get(0), None);
+        assert_eq!(table.insert("e").unwrap(), 3 | (1 << SLOT_BITS));
+    }
+
+    #[test]
+    fn compat_fds_are_reused() {
+        let mut table = FdTable::new();
+        assert_eq!(table.insert("a").unwrap(), 3);
+        table.remove(3);
+        assert_eq!(table.insert("b").unwrap(), 3);
+        // A stale `fd` silently refers to the new entry.
+        assert_eq!(table.get(3), Some(&"b"));
+    }
+
+    #[test]
+    fn generations_wrap_around() {
+        let mut table = FdTable::with_generations();
+        let mut fd = table.insert(()).unwrap();
+        for _ in 0..GENERATION_MASK {
+            table.remove(fd);
+            fd = table.insert(()).unwrap();
+            assert!(fd as i32 >= 0);
+        }
+        assert_eq!(fd, 3 | (GENERATION_MASK << SLOT_BITS));
+        table.remove(fd);
+        assert_eq!(table.insert(()).unwrap(), 3);
+    }
+
+    #[test]
+    fn lowest_free_fd_invariant() {
+        // A fixed xorshift sequence standing in for arbitrary open/close sequences.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let mut table = FdTable::new();
+        let mut open = BTreeSet::new();
+        for _ in 0..100_000 {
+            match random() % 4 {
+                0 => {
+                    let expected = (FIRST_FREE_FD..).find(|fd| !open.contains(fd)).unwrap();
+                    assert_eq!(table.insert(()).unwrap(), expected);
+                    open.insert(expected);
+                }
+                1 | 2 => {
+                    let fd = (random() % 64) as wasi::__wasi_fd_t;
+                    assert_eq!(table.remove(fd).is_some(), open.remove(&fd));
+                }
+                _ => {
+                    let fd = (random() % 64) as wasi::__wasi_fd_t;
+                    table.insert_at(fd, ());
+                    open.insert(fd);
+                }
+            }
+        }
+    }
+}
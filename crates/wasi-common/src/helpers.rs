@@ -1,4 +1,5 @@
 use crate::{Error, Result};
+use std::any::Any;
 use std::str;
 
 /// Creates not-owned WASI path from byte slice.
@@ -8,3 +9,14 @@ use std::str;
 pub(crate) fn path_from_slice<'a>(s: &'a [u8]) -> Result<&'a str> {
     str::from_utf8(s).map_err(|_| Error::EILSEQ)
 }
+
+/// The message a caught panic was raised with, if it's a string, as it is for `panic!`.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<Any>"
+    }
+}
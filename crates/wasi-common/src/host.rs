@@ -6,21 +6,12 @@
 
 use crate::wasi::*;
 use crate::{Error, Result};
-use std::{convert::TryInto, io, mem, slice};
+use num::PrimInt;
+use std::{convert::TryInto, mem, slice};
 use wig::witx_host_types;
 
 witx_host_types!("snapshot" "wasi_snapshot_preview1");
 
-pub(crate) unsafe fn ciovec_to_host(ciovec: &__wasi_ciovec_t) -> io::IoSlice {
-    let slice = slice::from_raw_parts(ciovec.buf as *const u8, ciovec.buf_len);
-    io::IoSlice::new(slice)
-}
-
-pub(crate) unsafe fn iovec_to_host_mut(iovec: &mut __wasi_iovec_t) -> io::IoSliceMut {
-    let slice = slice::from_raw_parts_mut(iovec.buf as *mut u8, iovec.buf_len);
-    io::IoSliceMut::new(slice)
-}
-
 #[allow(dead_code)] // trouble with sockets
 #[derive(Clone, Copy, Debug)]
 #[repr(u8)]
@@ -58,22 +49,19 @@ impl Dirent {
         let dirent_size = mem::size_of::<__wasi_dirent_t>();
         let offset = dirent_size.checked_add(namlen).ok_or(Error::EOVERFLOW)?;
 
-        let mut raw = Vec::<u8>::with_capacity(offset);
-        raw.resize(offset, 0);
-
-        let sys_dirent = raw.as_mut_ptr() as *mut __wasi_dirent_t;
-        unsafe {
-            sys_dirent.write_unaligned(__wasi_dirent_t {
-                d_namlen: namlen.try_into()?,
-                d_ino: self.ino,
-                d_next: self.cookie,
-                d_type: self.ftype.to_wasi(),
-            });
-        }
+        // Zero the struct before setting its fields, so that none of its padding bytes are
+        // left undefined.
+        let mut sys_dirent: __wasi_dirent_t = unsafe { mem::zeroed() };
+        sys_dirent.d_next = PrimInt::to_le(self.cookie);
+        sys_dirent.d_ino = PrimInt::to_le(self.ino);
+        sys_dirent.d_namlen = PrimInt::to_le(namlen.try_into()?);
+        sys_dirent.d_type = PrimInt::to_le(self.ftype.to_wasi());
 
-        let sys_name = unsafe { sys_dirent.offset(1) as *mut u8 };
-        let sys_name = unsafe { slice::from_raw_parts_mut(sys_name, namlen) };
-        sys_name.copy_from_slice(&name);
+        let mut raw = Vec::<u8>::with_capacity(offset);
+        raw.extend_from_slice(unsafe {
+            slice::from_raw_parts(&sys_dirent as *const _ as *const u8, dirent_size)
+        });
+        raw.extend_from_slice(name);
 
         Ok(raw)
     }
@@ -165,4 +153,23 @@ mod test {
             )
         );
     }
+
+    #[test]
+    fn dirent_layout() {
+        let dirent = Dirent {
+            name: "file".to_owned(),
+            ftype: FileType::RegularFile,
+            ino: 0x1112_1314_1516_1718,
+            cookie: 0x0102_0304_0506_0708,
+        };
+        let expected = [
+            &0x0102_0304_0506_0708u64.to_le_bytes()[..],
+            &0x1112_1314_1516_1718u64.to_le_bytes(),
+            &4u32.to_le_bytes(),
+            &[__WASI_FILETYPE_REGULAR_FILE, 0, 0, 0],
+            b"file",
+        ]
+        .concat();
+        assert_eq!(dirent.to_wasi_raw().unwrap(), expected);
+    }
 }
@@ -2,14 +2,17 @@
 use super::fs_helpers::path_get;
 use crate::ctx::WasiCtx;
 use crate::fdentry::{Descriptor, FdEntry};
-use crate::helpers::*;
 use crate::memory::*;
+use crate::newline_translating_writer::NewlineTranslatingWriter;
+use crate::quota::WriteQuota;
 use crate::sandboxed_tty_writer::SandboxedTTYWriter;
 use crate::sys::hostcalls_impl::fs_helpers::path_open_rights;
 use crate::sys::{host_impl, hostcalls_impl};
-use crate::{helpers, host, wasi, wasi32, Error, Result};
+use crate::virtfile::lock_virtual_file;
+use crate::{host, wasi, wasi32, Error, Result};
 use filetime::{set_file_handle_times, FileTime};
 use log::trace;
+use std::convert::TryInto;
 use std::fs::File;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::ops::DerefMut;
@@ -66,33 +69,33 @@ pub(crate) unsafe fn fd_pread(
         nread
     );
 
-    let fd = wasi_ctx
-        .get_fd_entry(fd)?
-        .as_descriptor(wasi::__WASI_RIGHTS_FD_READ | wasi::__WASI_RIGHTS_FD_SEEK, 0)?
-        .as_file()?;
+    let fe = wasi_ctx.get_fd_entry(fd)?;
+    // Like `pread(2)`, fail with `EISDIR` on a directory, even though it can be seeked.
+    fe.as_file(0, 0)?;
+    let fd = fe.as_seekable(wasi::__WASI_RIGHTS_FD_READ | wasi::__WASI_RIGHTS_FD_SEEK, 0)?;
 
-    let iovs = dec_iovec_slice(memory, iovs_ptr, iovs_len)?;
+    let iovs = dec_iovec_views(memory, iovs_ptr, iovs_len)?;
 
     if offset > i64::max_value() as u64 {
         return Err(Error::EIO);
     }
-    let buf_size = iovs.iter().map(|v| v.buf_len).sum();
-    let mut buf = vec![0; buf_size];
-    let host_nread = hostcalls_impl::fd_pread(fd, &mut buf, offset)?;
-    let mut buf_offset = 0;
-    let mut left = host_nread;
-    for iov in &iovs {
-        if left == 0 {
+    // There's no portable `preadv`, so read into the guest's buffers one at a time.
+    let mut host_nread = 0;
+    for iov in iovs {
+        let buf_offset = offset + host_nread as u64;
+        let nread = match hostcalls_impl::fd_pread(fd, iov, buf_offset) {
+            Ok(nread) => nread,
+            Err(_) if host_nread > 0 => break,
+            Err(err) => return Err(err),
+        };
+        host_nread += nread;
+        if nread < iov.len() {
             break;
         }
-        let vec_len = std::cmp::min(iov.buf_len, left);
-        std::slice::from_raw_parts_mut(iov.buf as *mut u8, vec_len)
-            .copy_from_slice(&buf[buf_offset..buf_offset + vec_len]);
-        buf_offset += vec_len;
-        left -= vec_len;
     }
 
     trace!("     | *nread={:?}", host_nread);
+    wasi_ctx.record_bytes_read(host_nread);
 
     enc_usize_byref(memory, nread, host_nread)
 }
@@ -115,29 +118,44 @@ pub(crate) unsafe fn fd_pwrite(
         nwritten
     );
 
-    let fd = wasi_ctx
-        .get_fd_entry(fd)?
-        .as_descriptor(
-            wasi::__WASI_RIGHTS_FD_WRITE | wasi::__WASI_RIGHTS_FD_SEEK,
-            0,
-        )?
-        .as_file()?;
-    let iovs = dec_ciovec_slice(memory, iovs_ptr, iovs_len)?;
+    let fe = wasi_ctx.get_fd_entry(fd)?;
+    fe.as_file(0, 0)?;
+    let quota = wasi_ctx
+        .write_quota()
+        .filter(|_| fe.file_type == wasi::__WASI_FILETYPE_REGULAR_FILE);
+    let fd = fe.as_seekable(
+        wasi::__WASI_RIGHTS_FD_WRITE | wasi::__WASI_RIGHTS_FD_SEEK,
+        0,
+    )?;
+    let iovs = dec_ciovec_views(memory, iovs_ptr, iovs_len)?;
 
     if offset > i64::max_value() as u64 {
         return Err(Error::EIO);
     }
-    let buf_size = iovs.iter().map(|v| v.buf_len).sum();
-    let mut buf = Vec::with_capacity(buf_size);
-    for iov in &iovs {
-        buf.extend_from_slice(std::slice::from_raw_parts(
-            iov.buf as *const u8,
-            iov.buf_len,
-        ));
-    }
-    let host_nwritten = hostcalls_impl::fd_pwrite(fd, &buf, offset)?;
+    // Like `fd_pread`, write from the guest's buffers one at a time.
+    let write = |iovs: &[&[u8]]| {
+        let mut host_nwritten = 0;
+        for iov in iovs {
+            let buf_offset = offset + host_nwritten as u64;
+            let nwritten = match hostcalls_impl::fd_pwrite(fd, iov, buf_offset) {
+                Ok(nwritten) => nwritten,
+                Err(_) if host_nwritten > 0 => break,
+                Err(err) => return Err(err),
+            };
+            host_nwritten += nwritten;
+            if nwritten < iov.len() {
+                break;
+            }
+        }
+        Ok(host_nwritten)
+    };
+    let host_nwritten = match &quota {
+        Some(quota) => write_within_quota(quota, &iovs, write)?,
+        None => write(&iovs)?,
+    };
 
     trace!("     | *nwritten={:?}", host_nwritten);
+    wasi_ctx.record_bytes_written(host_nwritten);
 
     enc_usize_byref(memory, nwritten, host_nwritten)
 }
@@ -158,24 +176,25 @@ pub(crate) unsafe fn fd_read(
         nread
     );
 
-    let mut iovs = dec_iovec_slice(memory, iovs_ptr, iovs_len)?;
-    let mut iovs: Vec<io::IoSliceMut> = iovs
-        .iter_mut()
-        .map(|vec| host::iovec_to_host_mut(vec))
+    let mut iovs: Vec<io::IoSliceMut> = dec_iovec_views(memory, iovs_ptr, iovs_len)?
+        .into_iter()
+        .map(io::IoSliceMut::new)
         .collect();
 
     let maybe_host_nread = match wasi_ctx
         .get_fd_entry_mut(fd)?
-        .as_descriptor_mut(wasi::__WASI_RIGHTS_FD_READ, 0)?
+        .as_file_mut(wasi::__WASI_RIGHTS_FD_READ, 0)?
     {
         Descriptor::OsHandle(file) => file.read_vectored(&mut iovs),
-        Descriptor::Stdin => io::stdin().read_vectored(&mut iovs),
+        Descriptor::Stdin => Ok(hostcalls_impl::stdin_read_vectored(&mut iovs)?),
+        Descriptor::VirtualFile(file) => Ok(lock_virtual_file(file)?.read_vectored(&mut iovs)?),
         _ => return Err(Error::EBADF),
     };
 
     let host_nread = maybe_host_nread?;
 
     trace!("     | *nread={:?}", host_nread);
+    wasi_ctx.record_bytes_read(host_nread);
 
     enc_usize_byref(memory, nread, host_nread)
 }
@@ -232,10 +251,7 @@ pub(crate) unsafe fn fd_seek(
     } else {
         wasi::__WASI_RIGHTS_FD_SEEK | wasi::__WASI_RIGHTS_FD_TELL
     };
-    let fd = wasi_ctx
-        .get_fd_entry_mut(fd)?
-        .as_descriptor_mut(rights, 0)?
-        .as_file_mut()?;
+    let fd = wasi_ctx.get_fd_entry_mut(fd)?.as_seekable_mut(rights, 0)?;
 
     let pos = match whence {
         wasi::__WASI_WHENCE_CUR => SeekFrom::Current(offset),
@@ -260,8 +276,7 @@ pub(crate) unsafe fn fd_tell(
 
     let fd = wasi_ctx
         .get_fd_entry_mut(fd)?
-        .as_descriptor_mut(wasi::__WASI_RIGHTS_FD_TELL, 0)?
-        .as_file_mut()?;
+        .as_seekable_mut(wasi::__WASI_RIGHTS_FD_TELL, 0)?;
 
     let host_offset = fd.seek(SeekFrom::Current(0))?;
 
@@ -279,12 +294,11 @@ pub(crate) unsafe fn fd_fdstat_get(
     trace!("fd_fdstat_get(fd={:?}, fdstat_ptr={:#x?})", fd, fdstat_ptr);
 
     let mut fdstat = dec_fdstat_byref(memory, fdstat_ptr)?;
-    let host_fd = wasi_ctx
-        .get_fd_entry(fd)?
-        .as_descriptor(0, 0)?
-        .as_os_handle();
-
-    let fs_flags = hostcalls_impl::fd_fdstat_get(&host_fd)?;
+    let fs_flags = match wasi_ctx.get_fd_entry(fd)?.as_descriptor(0, 0)? {
+        // Virtual files don't support any of the fdflags.
+        Descriptor::VirtualFile(_) => 0,
+        descriptor => hostcalls_impl::fd_fdstat_get(descriptor)?,
+    };
 
     let fe = wasi_ctx.get_fd_entry(fd)?;
     fdstat.fs_filetype = fe.file_type;
@@ -309,12 +323,27 @@ pub(crate) unsafe fn fd_fdstat_set_flags(
         .get_fd_entry_mut(fd)?
         .as_descriptor_mut(wasi::__WASI_RIGHTS_FD_FDSTAT_SET_FLAGS, 0)?;
 
-    if let Some(new_handle) =
-        hostcalls_impl::fd_fdstat_set_flags(&descriptor.as_os_handle(), fdflags)?
-    {
+    if let Descriptor::VirtualFile(_) = descriptor {
+        return Err(Error::ENOTSUP);
+    }
+
+    if let Some(new_handle) = hostcalls_impl::fd_fdstat_set_flags(&*descriptor, fdflags)? {
         *descriptor = Descriptor::OsHandle(new_handle);
     }
 
+    // The host may silently ignore some of the flags (Linux won't touch O_SYNC after open, for
+    // instance). That's unfortunate but tolerable for files the guest opened itself. The
+    // standard streams are shared with the host though, so make sure the guest isn't told a
+    // change took effect when it didn't.
+    match descriptor {
+        Descriptor::Stdin | Descriptor::Stdout | Descriptor::Stderr => {
+            if hostcalls_impl::fd_fdstat_get(&*descriptor)? != fdflags {
+                return Err(Error::ENOTSUP);
+            }
+        }
+        _ => {}
+    }
+
     Ok(())
 }
 
@@ -374,17 +403,25 @@ pub(crate) unsafe fn fd_write(
         nwritten
     );
 
-    let iovs = dec_ciovec_slice(memory, iovs_ptr, iovs_len)?;
-    let iovs: Vec<io::IoSlice> = iovs.iter().map(|vec| host::ciovec_to_host(vec)).collect();
+    let bufs = dec_ciovec_views(memory, iovs_ptr, iovs_len)?;
+    let iovs: Vec<io::IoSlice> = bufs.iter().map(|buf| io::IoSlice::new(buf)).collect();
 
     // perform unbuffered writes
+    let quota = wasi_ctx.write_quota();
     let entry = wasi_ctx.get_fd_entry_mut(fd)?;
+    let quota = quota.filter(|_| entry.file_type == wasi::__WASI_FILETYPE_REGULAR_FILE);
     let isatty = entry.isatty();
-    let desc = entry.as_descriptor_mut(wasi::__WASI_RIGHTS_FD_WRITE, 0)?;
+    let mut newline_translation = entry.newline_translation;
+    let desc = entry.as_file_mut(wasi::__WASI_RIGHTS_FD_WRITE, 0)?;
     let host_nwritten = match desc {
         Descriptor::OsHandle(file) => {
             if isatty {
                 SandboxedTTYWriter::new(file.deref_mut()).write_vectored(&iovs)?
+            } else if let Some(quota) = &quota {
+                write_within_quota(quota, &bufs, |bufs| {
+                    let iovs: Vec<_> = bufs.iter().map(|buf| io::IoSlice::new(buf)).collect();
+                    Ok(file.write_vectored(&iovs)?)
+                })?
             } else {
                 file.write_vectored(&iovs)?
             }
@@ -394,10 +431,13 @@ pub(crate) unsafe fn fd_write(
             // lock for the duration of the scope
             let stdout = io::stdout();
             let mut stdout = stdout.lock();
-            let nwritten = if isatty {
-                SandboxedTTYWriter::new(&mut stdout).write_vectored(&iovs)?
-            } else {
-                stdout.write_vectored(&iovs)?
+            let nwritten = match newline_translation.as_mut() {
+                Some(state) => write_stream(
+                    &mut NewlineTranslatingWriter::new(&mut stdout, state),
+                    isatty,
+                    &iovs,
+                )?,
+                None => write_stream(&mut stdout, isatty, &iovs)?,
             };
             stdout.flush()?;
             nwritten
@@ -406,14 +446,65 @@ pub(crate) unsafe fn fd_write(
         // because stderr is meant for diagnostics rather than binary output,
         // and may be redirected to a file which could end up being displayed
         // on a tty later.
-        Descriptor::Stderr => SandboxedTTYWriter::new(&mut io::stderr()).write_vectored(&iovs)?,
+        Descriptor::Stderr => {
+            let mut stderr = io::stderr();
+            match newline_translation.as_mut() {
+                Some(state) => write_stream(
+                    &mut NewlineTranslatingWriter::new(&mut stderr, state),
+                    true,
+                    &iovs,
+                )?,
+                None => write_stream(&mut stderr, true, &iovs)?,
+            }
+        }
+        // Virtual files receive the guest's bytes verbatim; it's up to the embedder to sanitize
+        // them if they're going to end up on a terminal.
+        Descriptor::VirtualFile(file) => lock_virtual_file(file)?.write_vectored(&iovs)?,
     };
+    entry.newline_translation = newline_translation;
 
     trace!("     | *nwritten={:?}", host_nwritten);
+    wasi_ctx.record_bytes_written(host_nwritten);
 
     enc_usize_byref(memory, nwritten, host_nwritten)
 }
 
+/// Write as much of `bufs` with `write` as `quota` allows, and give back whatever of it `write`
+/// doesn't use after all.
+fn write_within_quota(
+    quota: &WriteQuota,
+    bufs: &[&[u8]],
+    write: impl FnOnce(&[&[u8]]) -> Result<usize>,
+) -> Result<usize> {
+    let wanted = bufs.iter().map(|buf| buf.len()).sum();
+    let granted = quota.reserve(wanted)?;
+    let mut left = granted;
+    let bufs: Vec<&[u8]> = bufs
+        .iter()
+        .map(|&buf| {
+            let len = buf.len().min(left);
+            left -= len;
+            &buf[..len]
+        })
+        .collect();
+    let res = write(&bufs);
+    quota.release(granted - res.as_ref().map_or(0, |&nwritten| nwritten));
+    res
+}
+
+/// Write `iovs` to one of the host's standard streams, sanitizing the output if requested.
+fn write_stream<W: Write>(
+    stream: &mut W,
+    sanitize: bool,
+    iovs: &[io::IoSlice],
+) -> io::Result<usize> {
+    if sanitize {
+        SandboxedTTYWriter::new(stream).write_vectored(iovs)
+    } else {
+        stream.write_vectored(iovs)
+    }
+}
+
 pub(crate) unsafe fn fd_advise(
     wasi_ctx: &WasiCtx,
     _memory: &mut [u8],
@@ -432,8 +523,7 @@ pub(crate) unsafe fn fd_advise(
 
     let fd = wasi_ctx
         .get_fd_entry(fd)?
-        .as_descriptor(wasi::__WASI_RIGHTS_FD_ADVISE, 0)?
-        .as_file()?;
+        .as_seekable(wasi::__WASI_RIGHTS_FD_ADVISE, 0)?;
 
     hostcalls_impl::fd_advise(fd, advice, offset, len)
 }
@@ -447,10 +537,10 @@ pub(crate) unsafe fn fd_allocate(
 ) -> Result<()> {
     trace!("fd_allocate(fd={:?}, offset={}, len={})", fd, offset, len);
 
+    let quota = wasi_ctx.write_quota();
     let fd = wasi_ctx
         .get_fd_entry(fd)?
-        .as_descriptor(wasi::__WASI_RIGHTS_FD_ALLOCATE, 0)?
-        .as_file()?;
+        .as_seekable(wasi::__WASI_RIGHTS_FD_ALLOCATE, 0)?;
 
     let metadata = fd.metadata()?;
 
@@ -461,11 +551,25 @@ pub(crate) unsafe fn fd_allocate(
         return Err(Error::E2BIG);
     }
 
-    if wanted_size > current_size {
-        fd.set_len(wanted_size).map_err(Into::into)
-    } else {
-        Ok(())
+    // The storage the file grows by counts against the write quota, all of it or none, just as
+    // though it were written.
+    let growth = match quota {
+        Some(ref quota) if wanted_size > current_size => {
+            let wanted = (wanted_size - current_size).try_into()?;
+            let granted = quota.reserve(wanted)?;
+            if granted < wanted {
+                quota.release(granted);
+                return Err(Error::EDQUOT);
+            }
+            granted
+        }
+        _ => 0,
+    };
+    let res = hostcalls_impl::fd_allocate(fd, offset, len);
+    if let (Some(quota), Err(_)) = (&quota, &res) {
+        quota.release(growth);
     }
+    res
 }
 
 pub(crate) unsafe fn path_create_directory(
@@ -482,13 +586,13 @@ pub(crate) unsafe fn path_create_directory(
         path_len,
     );
 
-    let path = dec_slice_of_u8(memory, path_ptr, path_len).and_then(helpers::path_from_slice)?;
+    let path = dec_str(memory, path_ptr, path_len)?;
 
     trace!("     | (path_ptr,path_len)='{}'", path);
 
     let rights = wasi::__WASI_RIGHTS_PATH_OPEN | wasi::__WASI_RIGHTS_PATH_CREATE_DIRECTORY;
     let fe = wasi_ctx.get_fd_entry(dirfd)?;
-    let resolved = path_get(fe, rights, 0, 0, path, false)?;
+    let resolved = path_get(fe, rights, 0, 0, path, false, wasi_ctx.symlink_policy())?;
 
     hostcalls_impl::path_create_directory(resolved)
 }
@@ -515,8 +619,8 @@ pub(crate) unsafe fn path_link(
         new_path_len,
     );
 
-    let old_path = dec_slice_of_u8(memory, old_path_ptr, old_path_len).and_then(path_from_slice)?;
-    let new_path = dec_slice_of_u8(memory, new_path_ptr, new_path_len).and_then(path_from_slice)?;
+    let old_path = dec_str(memory, old_path_ptr, old_path_len)?;
+    let new_path = dec_str(memory, new_path_ptr, new_path_len)?;
 
     trace!("     | (old_path_ptr,old_path_len)='{}'", old_path);
     trace!("     | (new_path_ptr,new_path_len)='{}'", new_path);
@@ -530,6 +634,7 @@ pub(crate) unsafe fn path_link(
         0,
         old_path,
         false,
+        wasi_ctx.symlink_policy(),
     )?;
     let resolved_new = path_get(
         new_fe,
@@ -538,6 +643,7 @@ pub(crate) unsafe fn path_link(
         0,
         new_path,
         false,
+        wasi_ctx.symlink_policy(),
     )?;
 
     hostcalls_impl::path_link(resolved_old, resolved_new)
@@ -572,7 +678,7 @@ pub(crate) unsafe fn path_open(
     // pre-encode fd_out_ptr to -1 in case of error in opening a path
     enc_fd_byref(memory, fd_out_ptr, wasi::__wasi_fd_t::max_value())?;
 
-    let path = dec_slice_of_u8(memory, path_ptr, path_len).and_then(path_from_slice)?;
+    let path = dec_str(memory, path_ptr, path_len)?;
 
     trace!("     | (path_ptr,path_len)='{}'", path);
 
@@ -591,7 +697,14 @@ pub(crate) unsafe fn path_open(
         dirflags,
         path,
         oflags & wasi::__WASI_OFLAGS_CREAT != 0,
+        wasi_ctx.symlink_policy(),
     )?;
+    // Even empty files take up some of the host's disk.
+    if let Some(quota) = wasi_ctx.write_quota() {
+        if oflags & wasi::__WASI_OFLAGS_CREAT != 0 {
+            quota.check()?;
+        }
+    }
 
     // which open mode do we need?
     let read = fs_rights_base & (wasi::__WASI_RIGHTS_FD_READ | wasi::__WASI_RIGHTS_FD_READDIR) != 0;
@@ -643,12 +756,20 @@ pub(crate) unsafe fn path_readlink(
 
     enc_usize_byref(memory, buf_used, 0)?;
 
-    let path = dec_slice_of_u8(memory, path_ptr, path_len).and_then(helpers::path_from_slice)?;
+    let path = dec_str(memory, path_ptr, path_len)?;
 
     trace!("     | (path_ptr,path_len)='{}'", &path);
 
     let fe = wasi_ctx.get_fd_entry(dirfd)?;
-    let resolved = path_get(fe, wasi::__WASI_RIGHTS_PATH_READLINK, 0, 0, &path, false)?;
+    let resolved = path_get(
+        fe,
+        wasi::__WASI_RIGHTS_PATH_READLINK,
+        0,
+        0,
+        &path,
+        false,
+        wasi_ctx.symlink_policy(),
+    )?;
 
     let mut buf = dec_slice_of_mut_u8(memory, buf_ptr, buf_len)?;
 
@@ -680,8 +801,8 @@ pub(crate) unsafe fn path_rename(
         new_path_len,
     );
 
-    let old_path = dec_slice_of_u8(memory, old_path_ptr, old_path_len).and_then(path_from_slice)?;
-    let new_path = dec_slice_of_u8(memory, new_path_ptr, new_path_len).and_then(path_from_slice)?;
+    let old_path = dec_str(memory, old_path_ptr, old_path_len)?;
+    let new_path = dec_str(memory, new_path_ptr, new_path_len)?;
 
     trace!("     | (old_path_ptr,old_path_len)='{}'", old_path);
     trace!("     | (new_path_ptr,new_path_len)='{}'", new_path);
@@ -695,6 +816,7 @@ pub(crate) unsafe fn path_rename(
         0,
         old_path,
         true,
+        wasi_ctx.symlink_policy(),
     )?;
     let resolved_new = path_get(
         new_fe,
@@ -703,6 +825,7 @@ pub(crate) unsafe fn path_rename(
         0,
         new_path,
         true,
+        wasi_ctx.symlink_policy(),
     )?;
 
     log::debug!("path_rename resolved_old={:?}", resolved_old);
@@ -727,7 +850,8 @@ pub(crate) unsafe fn fd_filestat_get(
         .get_fd_entry(fd)?
         .as_descriptor(wasi::__WASI_RIGHTS_FD_FILESTAT_GET, 0)?
         .as_file()?;
-    let host_filestat = hostcalls_impl::fd_filestat_get(fd)?;
+    let mut host_filestat = hostcalls_impl::fd_filestat_get(fd)?;
+    wasi_ctx.clocks.adjust_filestat(&mut host_filestat)?;
 
     trace!("     | *filestat_ptr={:?}", host_filestat);
 
@@ -832,7 +956,7 @@ pub(crate) unsafe fn path_filestat_get(
         filestat_ptr
     );
 
-    let path = dec_slice_of_u8(memory, path_ptr, path_len).and_then(path_from_slice)?;
+    let path = dec_str(memory, path_ptr, path_len)?;
 
     trace!("     | (path_ptr,path_len)='{}'", path);
 
@@ -844,8 +968,10 @@ pub(crate) unsafe fn path_filestat_get(
         dirflags,
         path,
         false,
+        wasi_ctx.symlink_policy(),
     )?;
-    let host_filestat = hostcalls_impl::path_filestat_get(resolved, dirflags)?;
+    let mut host_filestat = hostcalls_impl::path_filestat_get(resolved, dirflags)?;
+    wasi_ctx.clocks.adjust_filestat(&mut host_filestat)?;
 
     trace!("     | *filestat_ptr={:?}", host_filestat);
 
@@ -873,7 +999,7 @@ pub(crate) unsafe fn path_filestat_set_times(
         fst_flags
     );
 
-    let path = dec_slice_of_u8(memory, path_ptr, path_len).and_then(path_from_slice)?;
+    let path = dec_str(memory, path_ptr, path_len)?;
 
     trace!("     | (path_ptr,path_len)='{}'", path);
 
@@ -885,6 +1011,7 @@ pub(crate) unsafe fn path_filestat_set_times(
         dirflags,
         path,
         false,
+        wasi_ctx.symlink_policy(),
     )?;
 
     hostcalls_impl::path_filestat_set_times(resolved, dirflags, st_atim, st_mtim, fst_flags)
@@ -908,14 +1035,22 @@ pub(crate) unsafe fn path_symlink(
         new_path_len
     );
 
-    let old_path = dec_slice_of_u8(memory, old_path_ptr, old_path_len).and_then(path_from_slice)?;
-    let new_path = dec_slice_of_u8(memory, new_path_ptr, new_path_len).and_then(path_from_slice)?;
+    let old_path = dec_str(memory, old_path_ptr, old_path_len)?;
+    let new_path = dec_str(memory, new_path_ptr, new_path_len)?;
 
     trace!("     | (old_path_ptr,old_path_len)='{}'", old_path);
     trace!("     | (new_path_ptr,new_path_len)='{}'", new_path);
 
     let fe = wasi_ctx.get_fd_entry(dirfd)?;
-    let resolved_new = path_get(fe, wasi::__WASI_RIGHTS_PATH_SYMLINK, 0, 0, new_path, true)?;
+    let resolved_new = path_get(
+        fe,
+        wasi::__WASI_RIGHTS_PATH_SYMLINK,
+        0,
+        0,
+        new_path,
+        true,
+        wasi_ctx.symlink_policy(),
+    )?;
 
     hostcalls_impl::path_symlink(old_path, resolved_new)
 }
@@ -934,12 +1069,20 @@ pub(crate) unsafe fn path_unlink_file(
         path_len
     );
 
-    let path = dec_slice_of_u8(memory, path_ptr, path_len).and_then(path_from_slice)?;
+    let path = dec_str(memory, path_ptr, path_len)?;
 
     trace!("     | (path_ptr,path_len)='{}'", path);
 
     let fe = wasi_ctx.get_fd_entry(dirfd)?;
-    let resolved = path_get(fe, wasi::__WASI_RIGHTS_PATH_UNLINK_FILE, 0, 0, path, false)?;
+    let resolved = path_get(
+        fe,
+        wasi::__WASI_RIGHTS_PATH_UNLINK_FILE,
+        0,
+        0,
+        path,
+        false,
+        wasi_ctx.symlink_policy(),
+    )?;
 
     hostcalls_impl::path_unlink_file(resolved)
 }
@@ -958,7 +1101,7 @@ pub(crate) unsafe fn path_remove_directory(
         path_len
     );
 
-    let path = dec_slice_of_u8(memory, path_ptr, path_len).and_then(path_from_slice)?;
+    let path = dec_str(memory, path_ptr, path_len)?;
 
     trace!("     | (path_ptr,path_len)='{}'", path);
 
@@ -970,6 +1113,7 @@ pub(crate) unsafe fn path_remove_directory(
         0,
         path,
         true,
+        wasi_ctx.symlink_policy(),
     )?;
 
     log::debug!("path_remove_directory resolved={:?}", resolved);
@@ -991,7 +1135,9 @@ pub(crate) unsafe fn fd_prestat_get(
 
     // TODO: should we validate any rights here?
     let fe = wasi_ctx.get_fd_entry(fd)?;
-    let po_path = fe.preopen_path.as_ref().ok_or(Error::ENOTSUP)?;
+    // Guests look for preopens from fd 3 up until this fails with EBADF, and take any other
+    // error as fatal, so that's what it fails with past the preopens, even on an open fd.
+    let po_path = fe.preopen_path.as_ref().ok_or(Error::EBADF)?;
     if fe.file_type != wasi::__WASI_FILETYPE_DIRECTORY {
         return Err(Error::ENOTDIR);
     }
@@ -1028,7 +1174,7 @@ pub(crate) unsafe fn fd_prestat_dir_name(
 
     // TODO: should we validate any rights here?
     let fe = wasi_ctx.get_fd_entry(fd)?;
-    let po_path = fe.preopen_path.as_ref().ok_or(Error::ENOTSUP)?;
+    let po_path = fe.preopen_path.as_ref().ok_or(Error::EBADF)?;
     if fe.file_type != wasi::__WASI_FILETYPE_DIRECTORY {
         return Err(Error::ENOTDIR);
     }
@@ -1041,7 +1187,7 @@ pub(crate) unsafe fn fd_prestat_dir_name(
 
     trace!("     | (path_ptr,path_len)='{}'", path);
 
-    enc_slice_of_u8(memory, path.as_bytes(), path_ptr)
+    enc_slice_of_u8(memory, path.as_bytes(), path_ptr, path_len)
 }
 
 pub(crate) unsafe fn fd_readdir(
@@ -1066,8 +1212,7 @@ pub(crate) unsafe fn fd_readdir(
 
     let file = wasi_ctx
         .get_fd_entry_mut(fd)?
-        .as_descriptor_mut(wasi::__WASI_RIGHTS_FD_READDIR, 0)?
-        .as_file_mut()?;
+        .as_dir_mut(wasi::__WASI_RIGHTS_FD_READDIR, 0)?;
     let mut host_buf = dec_slice_of_mut_u8(memory, buf, buf_len)?;
 
     trace!("     | (buf,buf_len)={:?}", host_buf);
@@ -1090,3 +1235,232 @@ pub(crate) unsafe fn fd_readdir(
 
     enc_usize_byref(memory, buf_used, host_bufused)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ctx::WasiCtxBuilder;
+    use crate::error::WasiError;
+    use crate::test_helpers::{scratch_dir, with_memory};
+    use std::fs::OpenOptions;
+
+    const MEMORY_LEN: usize = 64;
+
+    #[test]
+    fn vectored_io_at_end_of_memory() {
+        let scratch = scratch_dir("vectored_io");
+        let file_path = scratch.path().join("file");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&file_path)
+            .expect("create scratch file");
+        let mut ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        let fd = ctx
+            .insert_fd_entry(FdEntry::from(file).unwrap())
+            .expect("insert file");
+
+        // The iovecs point at the last 6 bytes of memory, the final one ending right at its end.
+        with_memory(MEMORY_LEN, |memory| {
+            let end = MEMORY_LEN as u32;
+            for (i, &word) in [end - 6, 2, end - 4, 0, end - 4, 4].iter().enumerate() {
+                enc_int_byref(memory, 4 * i as u32, word).unwrap();
+            }
+            let nbytes = 24;
+
+            memory[MEMORY_LEN - 6..].copy_from_slice(b"abcdef");
+            unsafe { fd_pwrite(&ctx, memory, fd, 0, 3, 1, nbytes) }.expect("fd_pwrite");
+            assert_eq!(dec_int_byref::<u32>(memory, nbytes).unwrap(), 6);
+            assert_eq!(std::fs::read(&file_path).unwrap(), b"\0abcdef");
+
+            memory[MEMORY_LEN - 6..].copy_from_slice(&[0; 6]);
+            unsafe { fd_pread(&ctx, memory, fd, 0, 3, 3, nbytes) }.expect("fd_pread");
+            assert_eq!(dec_int_byref::<u32>(memory, nbytes).unwrap(), 4);
+            assert_eq!(&memory[MEMORY_LEN - 6..], b"cdef\0\0");
+
+            // One byte past the end is out of bounds, for reads and writes alike.
+            enc_int_byref(memory, 20, 5u32).unwrap();
+            let res = unsafe { fd_pread(&ctx, memory, fd, 0, 3, 0, nbytes) };
+            assert_eq!(res.unwrap_err().as_wasi_error(), WasiError::EFAULT);
+            let res = unsafe { fd_write(&mut ctx, memory, fd, 0, 3, nbytes) };
+            assert_eq!(res.unwrap_err().as_wasi_error(), WasiError::EFAULT);
+        });
+    }
+
+    #[test]
+    fn malformed_strings() {
+        let scratch = scratch_dir("malformed");
+        let dir = scratch.path();
+        let mut ctx = WasiCtxBuilder::new()
+            .preopened_dir(crate::preopen_dir(&dir).unwrap(), "/sandbox")
+            .build()
+            .expect("build WasiCtx");
+
+        let cases: &[(&[u8], WasiError)] = &[
+            (b"caf\xe9", WasiError::EILSEQ),
+            // An overlong encoding of '/', which mustn't sneak past the path checks either.
+            (b"..\xc0\xafetc", WasiError::EILSEQ),
+            (b"\xed\xa0\x80", WasiError::EILSEQ),
+            (b"file\0.txt", WasiError::EINVAL),
+            (b"", WasiError::ENOENT),
+        ];
+        for &(path, expected) in cases {
+            with_memory(32, |memory| {
+                memory[8..8 + path.len()].copy_from_slice(path);
+                let len = path.len() as u32;
+
+                let res = unsafe { path_open(&mut ctx, memory, 3, 0, 8, len, 0, 0, 0, 0, 0) };
+                assert_eq!(res.unwrap_err().as_wasi_error(), expected, "{:?}", path);
+                let res = unsafe { path_create_directory(&ctx, memory, 3, 8, len) };
+                assert_eq!(res.unwrap_err().as_wasi_error(), expected, "{:?}", path);
+                let res = unsafe { path_unlink_file(&ctx, memory, 3, 8, len) };
+                assert_eq!(res.unwrap_err().as_wasi_error(), expected, "{:?}", path);
+            });
+        }
+    }
+
+    /// Collects the messages of every log record.
+    #[derive(Default)]
+    struct CapturingLogger(std::sync::Mutex<Vec<String>>);
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn nonblocking_sockets() {
+        use std::os::unix::io::{FromRawFd, IntoRawFd};
+        use std::os::unix::net::UnixStream;
+
+        let mut ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        let (guest, mut host) = UnixStream::pair().expect("socket pair");
+        let guest = unsafe { File::from_raw_fd(guest.into_raw_fd()) };
+        let fd = ctx
+            .insert_fd_entry(FdEntry::from(guest).unwrap())
+            .expect("insert socket");
+        // An iovec for the 4 bytes at 32, and room for an fdstat at 0.
+        with_memory(MEMORY_LEN, |memory| {
+            memory[16..20].copy_from_slice(&32u32.to_le_bytes());
+            memory[20..24].copy_from_slice(&4u32.to_le_bytes());
+            let errno = |res: Result<()>| res.unwrap_err().as_wasi_error();
+
+            unsafe {
+                fd_fdstat_set_flags(&mut ctx, memory, fd, wasi::__WASI_FDFLAGS_NONBLOCK)
+                    .expect("set NONBLOCK");
+                fd_fdstat_get(&ctx, memory, fd, 0).expect("fd_fdstat_get");
+                let fdstat = &*(memory.as_ptr() as *const wasi::__wasi_fdstat_t);
+                assert_eq!(fdstat.fs_filetype, wasi::__WASI_FILETYPE_SOCKET_STREAM);
+                assert_eq!(fdstat.fs_flags, wasi::__WASI_FDFLAGS_NONBLOCK);
+
+                // With nothing to read, the read fails rather than blocking.
+                let res = fd_read(&mut ctx, memory, fd, 16, 1, 8);
+                assert_eq!(errno(res), WasiError::EAGAIN);
+
+                host.write_all(b"ping").unwrap();
+                fd_read(&mut ctx, memory, fd, 16, 1, 8).expect("fd_read");
+                assert_eq!(&memory[8..12], &4u32.to_le_bytes());
+                assert_eq!(&memory[32..36], b"ping");
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn os_error_context_is_logged() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let scratch = scratch_dir("error_context");
+        let dir = scratch.path();
+        let file_path = dir.join("secret");
+        std::fs::write(&file_path, b"").expect("create scratch file");
+        std::fs::set_permissions(&file_path, std::fs::Permissions::from_mode(0o000)).unwrap();
+        // Permissions don't get in the way of root.
+        if std::fs::File::open(&file_path).is_ok() {
+            return;
+        }
+
+        let logger: &'static CapturingLogger = Box::leak(Box::new(CapturingLogger::default()));
+        log::set_logger(logger).expect("install logger");
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let mut ctx = WasiCtxBuilder::new()
+            .preopened_dir(crate::preopen_dir(&dir).unwrap(), "/sandbox")
+            .build()
+            .expect("build WasiCtx");
+        let errno = with_memory(16, |memory| unsafe {
+            memory[..6].copy_from_slice(b"secret");
+            crate::hostcalls::path_open(
+                &mut ctx,
+                memory,
+                3,
+                0,
+                0,
+                6,
+                0,
+                wasi::__WASI_RIGHTS_FD_READ,
+                0,
+                0,
+                8,
+            )
+        });
+
+        // The guest only gets the errno, while the log says what failed, and on which path.
+        assert_eq!(errno, wasi::__WASI_ERRNO_ACCES);
+        let logs = logger.0.lock().unwrap();
+        assert!(
+            logs.iter()
+                .any(|line| line.starts_with("path_open failed: openat \"secret\"")),
+            "{:?}",
+            logs
+        );
+    }
+
+    #[test]
+    fn allocate_grows_file_within_quota() {
+        let scratch = scratch_dir("allocate");
+        let file_path = scratch.path().join("file");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&file_path)
+            .expect("create scratch file");
+        let mut ctx = WasiCtxBuilder::new()
+            .write_quota(100)
+            .build()
+            .expect("build WasiCtx");
+        let fd = ctx
+            .insert_fd_entry(FdEntry::from(file).unwrap())
+            .expect("insert file");
+        let size = || std::fs::metadata(&file_path).unwrap().len();
+
+        unsafe {
+            fd_allocate(&ctx, &mut [], fd, 10, 40).expect("fd_allocate");
+            assert_eq!(size(), 50);
+            assert_eq!(ctx.write_quota_remaining(), Some(50));
+
+            // Allocating what the file already has doesn't change its size, nor cost anything.
+            fd_allocate(&ctx, &mut [], fd, 0, 20).expect("fd_allocate");
+            assert_eq!(size(), 50);
+            assert_eq!(ctx.write_quota_remaining(), Some(50));
+
+            // Growth beyond the quota doesn't happen at all, rather than in part.
+            let res = fd_allocate(&ctx, &mut [], fd, 0, 200);
+            assert_eq!(res.unwrap_err().as_wasi_error(), WasiError::EDQUOT);
+            assert_eq!(size(), 50);
+            assert_eq!(ctx.write_quota_remaining(), Some(50));
+        }
+    }
+}
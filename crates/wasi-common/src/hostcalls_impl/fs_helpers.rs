@@ -1,10 +1,35 @@
 #![allow(non_camel_case_types)]
-use crate::sys::host_impl;
 use crate::sys::hostcalls_impl::fs_helpers::*;
+use crate::sys::{host_impl, preopen_dir};
 use crate::{error::WasiError, fdentry::FdEntry, wasi, Error, Result};
 use std::fs::File;
 use std::path::{Component, Path};
 
+/// Which symlinks path resolution follows, as set with `WasiCtxBuilder::symlink_policy`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Follow symlinks as long as they lead to somewhere within the directory the guest's path
+    /// is resolved from, and fail with `ENOTCAPABLE` on those which lead out of it. This is the
+    /// default.
+    Sandboxed,
+    /// Follow no symlinks at all, and fail with `ENOTCAPABLE` instead of resolving a path
+    /// through one, or opening one with `__WASI_LOOKUPFLAGS_SYMLINK_FOLLOW`. Symlinks can still
+    /// be created, read and removed.
+    Never,
+    /// Follow symlinks wherever they lead on the host, preopen or not, absolute targets
+    /// included. The guest's own paths are still confined to the directory they're resolved
+    /// from.
+    ///
+    /// On Windows, absolute targets keep failing with `ENOTCAPABLE`.
+    Anywhere,
+}
+
+impl Default for SymlinkPolicy {
+    fn default() -> Self {
+        Self::Sandboxed
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct PathGet {
     dirfd: File,
@@ -31,23 +56,17 @@ pub(crate) fn path_get(
     dirflags: wasi::__wasi_lookupflags_t,
     path: &str,
     needs_final_component: bool,
+    symlinks: SymlinkPolicy,
 ) -> Result<PathGet> {
     const MAX_SYMLINK_EXPANSIONS: usize = 128;
 
     if path.contains('\0') {
-        // if contains NUL, return EILSEQ
-        return Err(Error::EILSEQ);
+        // as for `memory::dec_str`, a NUL is an unacceptable value rather than bad encoding
+        return Err(Error::EINVAL);
     }
 
-    if fe.file_type != wasi::__WASI_FILETYPE_DIRECTORY {
-        // if `dirfd` doesn't refer to a directory, return `ENOTDIR`.
-        return Err(Error::ENOTDIR);
-    }
-
-    let dirfd = fe
-        .as_descriptor(rights_base, rights_inheriting)?
-        .as_file()?
-        .try_clone()?;
+    // if `dirfd` doesn't refer to a directory, this fails with `ENOTDIR`.
+    let dirfd = fe.as_dir(rights_base, rights_inheriting)?.try_clone()?;
 
     // Stack of directory file descriptors. Index 0 always corresponds with the directory provided
     // to this function. Entering a directory causes a file descriptor to be pushed, while handling
@@ -56,8 +75,10 @@ pub(crate) fn path_get(
     let mut dir_stack = vec![dirfd];
 
     // Stack of paths left to process. This is initially the `path` argument to this function, but
-    // any symlinks we encounter are processed by pushing them on the stack.
-    let mut path_stack = vec![path.to_owned()];
+    // any symlinks we encounter are processed by pushing them on the stack. Each path is paired
+    // with whether it's (the rest of) a symlink's target, so that `SymlinkPolicy::Anywhere` can
+    // let those out, and those only.
+    let mut path_stack = vec![(path.to_owned(), false)];
 
     // Track the number of symlinks we've expanded, so we can return `ELOOP` after too many.
     let mut symlink_expansions = 0;
@@ -66,7 +87,7 @@ pub(crate) fn path_get(
     // trailing slashes. This version does way too much allocation, and is way too fiddly.
     loop {
         match path_stack.pop() {
-            Some(cur_path) => {
+            Some((cur_path, from_link)) => {
                 log::debug!("path_get cur_path = {:?}", cur_path);
 
                 let ends_with_slash = cur_path.ends_with('/');
@@ -82,12 +103,16 @@ pub(crate) fn path_get(
                     if ends_with_slash {
                         tail.push('/');
                     }
-                    path_stack.push(tail);
+                    path_stack.push((tail, from_link));
                 }
 
                 log::debug!("path_get path_stack = {:?}", path_stack);
 
                 match head {
+                    Component::RootDir if from_link && symlinks == SymlinkPolicy::Anywhere => {
+                        // the symlink is absolute, so carry on from the host's root
+                        dir_stack = vec![preopen_dir(head.as_os_str())?];
+                    }
                     Component::Prefix(_) | Component::RootDir => {
                         // path is absolute!
                         return Err(Error::ENOTCAPABLE);
@@ -97,11 +122,15 @@ pub(crate) fn path_get(
                     }
                     Component::ParentDir => {
                         // ".." so pop a dir
-                        let _ = dir_stack.pop().ok_or(Error::ENOTCAPABLE)?;
+                        let dir = dir_stack.pop().ok_or(Error::ENOTCAPABLE)?;
 
-                        // we're not allowed to pop past the original directory
+                        // we're not allowed to pop past the original directory, unless a symlink
+                        // may lead out of it
                         if dir_stack.is_empty() {
-                            return Err(Error::ENOTCAPABLE);
+                            if !from_link || symlinks != SymlinkPolicy::Anywhere {
+                                return Err(Error::ENOTCAPABLE);
+                            }
+                            dir_stack.push(openat(&dir, "..")?);
                         }
                     }
                     Component::Normal(head) => {
@@ -129,6 +158,9 @@ pub(crate) fn path_get(
                                                 dir_stack.last().ok_or(Error::ENOTCAPABLE)?,
                                                 &head,
                                             )?;
+                                            if symlinks == SymlinkPolicy::Never {
+                                                return Err(Error::ENOTCAPABLE);
+                                            }
 
                                             symlink_expansions += 1;
                                             if symlink_expansions > MAX_SYMLINK_EXPANSIONS {
@@ -144,7 +176,7 @@ pub(crate) fn path_get(
                                                 link_path
                                             );
 
-                                            path_stack.push(link_path);
+                                            path_stack.push((link_path, true));
                                         }
                                         _ => {
                                             return Err(e);
@@ -161,6 +193,9 @@ pub(crate) fn path_get(
                             // symlink expansion
                             match readlinkat(dir_stack.last().ok_or(Error::ENOTCAPABLE)?, &head) {
                                 Ok(mut link_path) => {
+                                    if symlinks == SymlinkPolicy::Never {
+                                        return Err(Error::ENOTCAPABLE);
+                                    }
                                     symlink_expansions += 1;
                                     if symlink_expansions > MAX_SYMLINK_EXPANSIONS {
                                         return Err(Error::ELOOP);
@@ -175,7 +210,7 @@ pub(crate) fn path_get(
                                         link_path
                                     );
 
-                                    path_stack.push(link_path);
+                                    path_stack.push((link_path, true));
                                     continue;
                                 }
                                 Err(e) => {
@@ -211,3 +246,58 @@ pub(crate) fn path_get(
         }
     }
 }
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use crate::test_helpers::scratch_dir;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn symlink_policies() {
+        let scratch = scratch_dir("symlinks");
+        let dir = scratch.path();
+        std::fs::create_dir_all(dir.join("base")).expect("create scratch dir");
+        std::fs::write(dir.join("base/inner.txt"), b"").expect("create scratch file");
+        std::fs::write(dir.join("outside.txt"), b"").expect("create scratch file");
+        std::fs::create_dir_all(dir.join("base/sub")).expect("create scratch dir");
+        symlink("inner.txt", dir.join("base/in")).unwrap();
+        symlink("sub", dir.join("base/link")).unwrap();
+        symlink("../outside.txt", dir.join("base/out")).unwrap();
+        symlink(dir.join("outside.txt"), dir.join("base/abs")).unwrap();
+        let fe = FdEntry::from(crate::preopen_dir(dir.join("base")).unwrap()).unwrap();
+        let follow = wasi::__WASI_LOOKUPFLAGS_SYMLINK_FOLLOW;
+        let resolve = |path: &str, dirflags, policy| {
+            path_get(&fe, 0, 0, dirflags, path, false, policy).map(|resolved| resolved.path)
+        };
+        let errno = |res: Result<String>| res.unwrap_err().as_wasi_error();
+
+        let policy = SymlinkPolicy::Sandboxed;
+        assert_eq!(resolve("in", follow, policy).unwrap(), "inner.txt");
+        assert_eq!(resolve("link/x", 0, policy).unwrap(), "x");
+        assert_eq!(
+            errno(resolve("out", follow, policy)),
+            WasiError::ENOTCAPABLE
+        );
+        assert_eq!(
+            errno(resolve("abs", follow, policy)),
+            WasiError::ENOTCAPABLE
+        );
+
+        let policy = SymlinkPolicy::Never;
+        assert_eq!(errno(resolve("in", follow, policy)), WasiError::ENOTCAPABLE);
+        assert_eq!(errno(resolve("link/x", 0, policy)), WasiError::ENOTCAPABLE);
+        // The symlink itself is still there to be read or removed.
+        assert_eq!(resolve("in", 0, policy).unwrap(), "in");
+
+        let policy = SymlinkPolicy::Anywhere;
+        assert_eq!(resolve("out", follow, policy).unwrap(), "outside.txt");
+        assert_eq!(resolve("abs", follow, policy).unwrap(), "outside.txt");
+        // Only symlinks may lead out of the preopen, not the guest.
+        assert_eq!(
+            errno(resolve("../outside.txt", 0, policy)),
+            WasiError::ENOTCAPABLE
+        );
+        assert_eq!(errno(resolve("/etc", 0, policy)), WasiError::ENOTCAPABLE);
+    }
+}
@@ -1,11 +1,19 @@
 #![allow(non_camel_case_types)]
-use crate::ctx::WasiCtx;
+use crate::cancel::CancelToken;
+use crate::ctx::{ExitStatus, WasiCtx};
+use crate::error::AsWasiError;
 use crate::fdentry::Descriptor;
 use crate::memory::*;
 use crate::sys::hostcalls_impl;
+use crate::virtfile::{lock_virtual_file, PollWaker, Readiness, VirtualFile};
 use crate::{wasi, wasi32, Error, Result};
 use log::{error, trace};
 use std::convert::TryFrom;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 pub(crate) fn args_get(
     wasi_ctx: &WasiCtx,
@@ -19,22 +27,7 @@ pub(crate) fn args_get(
         argv_buf,
     );
 
-    let mut argv_buf_offset = 0;
-    let mut argv = vec![];
-
-    for arg in &wasi_ctx.args {
-        let arg_bytes = arg.as_bytes_with_nul();
-        let arg_ptr = argv_buf + argv_buf_offset;
-
-        enc_slice_of_u8(memory, arg_bytes, arg_ptr)?;
-
-        argv.push(arg_ptr);
-
-        let len = wasi32::uintptr_t::try_from(arg_bytes.len())?;
-        argv_buf_offset = argv_buf_offset.checked_add(len).ok_or(Error::EOVERFLOW)?;
-    }
-
-    enc_slice_of_wasi32_uintptr(memory, argv.as_slice(), argv_ptr)
+    enc_string_array(memory, &wasi_ctx.args, argv_ptr, argv_buf)
 }
 
 pub(crate) fn args_sizes_get(
@@ -49,20 +42,15 @@ pub(crate) fn args_sizes_get(
         argv_buf_size_ptr,
     );
 
-    let argc = wasi_ctx.args.len();
-    let argv_size = wasi_ctx
-        .args
-        .iter()
-        .map(|arg| arg.as_bytes_with_nul().len())
-        .sum();
+    let (argc, argv_size) = string_array_sizes(&wasi_ctx.args)?;
 
     trace!("     | *argc_ptr={:?}", argc);
 
-    enc_usize_byref(memory, argc_ptr, argc)?;
+    enc_int_byref(memory, argc_ptr, argc)?;
 
     trace!("     | *argv_buf_size_ptr={:?}", argv_size);
 
-    enc_usize_byref(memory, argv_buf_size_ptr, argv_size)
+    enc_int_byref(memory, argv_buf_size_ptr, argv_size)
 }
 
 pub(crate) fn environ_get(
@@ -77,24 +65,7 @@ pub(crate) fn environ_get(
         environ_buf,
     );
 
-    let mut environ_buf_offset = 0;
-    let mut environ = vec![];
-
-    for pair in &wasi_ctx.env {
-        let env_bytes = pair.as_bytes_with_nul();
-        let env_ptr = environ_buf + environ_buf_offset;
-
-        enc_slice_of_u8(memory, env_bytes, env_ptr)?;
-
-        environ.push(env_ptr);
-
-        let len = wasi32::uintptr_t::try_from(env_bytes.len())?;
-        environ_buf_offset = environ_buf_offset
-            .checked_add(len)
-            .ok_or(Error::EOVERFLOW)?;
-    }
-
-    enc_slice_of_wasi32_uintptr(memory, environ.as_slice(), environ_ptr)
+    enc_string_array(memory, &wasi_ctx.env, environ_ptr, environ_buf)
 }
 
 pub(crate) fn environ_sizes_get(
@@ -109,42 +80,77 @@ pub(crate) fn environ_sizes_get(
         environ_size_ptr,
     );
 
-    let environ_count = wasi_ctx.env.len();
-    let environ_size = wasi_ctx
-        .env
-        .iter()
-        .try_fold(0, |acc: u32, pair| {
-            acc.checked_add(pair.as_bytes_with_nul().len() as u32)
-        })
-        .ok_or(Error::EOVERFLOW)?;
+    let (environ_count, environ_size) = string_array_sizes(&wasi_ctx.env)?;
 
     trace!("     | *environ_count_ptr={:?}", environ_count);
 
-    enc_usize_byref(memory, environ_count_ptr, environ_count)?;
+    enc_int_byref(memory, environ_count_ptr, environ_count)?;
 
     trace!("     | *environ_size_ptr={:?}", environ_size);
 
-    enc_usize_byref(memory, environ_size_ptr, environ_size as usize)
+    enc_int_byref(memory, environ_size_ptr, environ_size)
+}
+
+/// The number of `strings`, and the size of the buffer they take up back to back, NUL
+/// terminators included, failing with `EOVERFLOW` if either doesn't fit a `wasi32::size_t`.
+pub(crate) fn string_array_sizes(strings: &[CString]) -> Result<(wasi32::size_t, wasi32::size_t)> {
+    let count = wasi32::size_t::try_from(strings.len())?;
+    let buf_size = strings.iter().try_fold(0, |size: wasi32::size_t, s| {
+        let len = wasi32::size_t::try_from(s.as_bytes_with_nul().len())?;
+        size.checked_add(len).ok_or(Error::EOVERFLOW)
+    })?;
+    Ok((count, buf_size))
+}
+
+/// Copy `strings` into the guest's buffer at `buf`, back to back and NUL-terminated, and
+/// their addresses into the guest's array at `ptrs`.
+///
+/// The guest sized both going by `string_array_sizes`, so nothing is written past what that
+/// reports, nor past the end of guest memory.
+fn enc_string_array(
+    memory: &mut [u8],
+    strings: &[CString],
+    ptrs: wasi32::uintptr_t,
+    buf: wasi32::uintptr_t,
+) -> Result<()> {
+    let (count, buf_size) = string_array_sizes(strings)?;
+
+    let mut offset: wasi32::size_t = 0;
+    let mut addrs = Vec::with_capacity(strings.len());
+
+    for s in strings {
+        let bytes = s.as_bytes_with_nul();
+        let ptr = buf.checked_add(offset).ok_or(Error::EFAULT)?;
+
+        enc_slice_of_u8(memory, bytes, ptr, buf_size - offset)?;
+
+        addrs.push(ptr);
+        // This can't overflow, as `buf_size` is the sum of all the lengths.
+        offset += wasi32::size_t::try_from(bytes.len())?;
+    }
+
+    enc_slice_of_wasi32_uintptr(memory, &addrs, ptrs, count)
 }
 
 pub(crate) fn random_get(
-    _wasi_ctx: &WasiCtx,
+    wasi_ctx: &WasiCtx,
     memory: &mut [u8],
     buf_ptr: wasi32::uintptr_t,
     buf_len: wasi32::size_t,
 ) -> Result<()> {
     trace!("random_get(buf_ptr={:#x?}, buf_len={:?})", buf_ptr, buf_len);
 
+    if buf_len == 0 {
+        return Ok(());
+    }
+
     let buf = dec_slice_of_mut_u8(memory, buf_ptr, buf_len)?;
 
-    getrandom::getrandom(buf).map_err(|err| {
-        error!("getrandom failure: {:?}", err);
-        Error::EIO
-    })
+    wasi_ctx.random().fill(buf)
 }
 
 pub(crate) fn clock_res_get(
-    _wasi_ctx: &WasiCtx,
+    wasi_ctx: &WasiCtx,
     memory: &mut [u8],
     clock_id: wasi::__wasi_clockid_t,
     resolution_ptr: wasi32::uintptr_t,
@@ -155,7 +161,7 @@ pub(crate) fn clock_res_get(
         resolution_ptr,
     );
 
-    let resolution = hostcalls_impl::clock_res_get(clock_id)?;
+    let resolution = wasi_ctx.clocks.resolution(clock_id)?;
 
     trace!("     | *resolution_ptr={:?}", resolution);
 
@@ -163,7 +169,7 @@ pub(crate) fn clock_res_get(
 }
 
 pub(crate) fn clock_time_get(
-    _wasi_ctx: &WasiCtx,
+    wasi_ctx: &WasiCtx,
     memory: &mut [u8],
     clock_id: wasi::__wasi_clockid_t,
     precision: wasi::__wasi_timestamp_t,
@@ -176,17 +182,17 @@ pub(crate) fn clock_time_get(
         time_ptr,
     );
 
-    let time = hostcalls_impl::clock_time_get(clock_id)?;
+    let time = wasi_ctx.clocks.time(clock_id)?;
 
     trace!("     | *time_ptr={:?}", time);
 
     enc_timestamp_byref(memory, time_ptr, time)
 }
 
-pub(crate) fn sched_yield(_wasi_ctx: &WasiCtx, _memory: &mut [u8]) -> Result<()> {
+pub(crate) fn sched_yield(wasi_ctx: &WasiCtx, _memory: &mut [u8]) -> Result<()> {
     trace!("sched_yield()");
 
-    std::thread::yield_now();
+    wasi_ctx.yield_now();
 
     Ok(())
 }
@@ -213,11 +219,17 @@ pub(crate) fn poll_oneoff(
 
     enc_int_byref(memory, nevents, 0)?;
 
+    let cancel = wasi_ctx.cancel_token();
+    if let Some(cancel) = cancel {
+        cancel.check()?;
+    }
+
     let subscriptions = dec_subscriptions(memory, input, nsubscriptions)?;
     let mut events = Vec::new();
 
     let mut timeout: Option<ClockEventData> = None;
     let mut fd_events = Vec::new();
+    let mut virtual_events = Vec::new();
 
     // As mandated by the WASI spec:
     // > If `nsubscriptions` is 0, returns `errno::inval`.
@@ -228,13 +240,14 @@ pub(crate) fn poll_oneoff(
         match subscription.r#type {
             wasi::__WASI_EVENTTYPE_CLOCK => {
                 let clock = unsafe { subscription.u.clock };
-                let delay = wasi_clock_to_relative_ns_delay(clock)?;
+                let (delay, deadline) = wasi_ctx.clocks.subscription_deadline(&clock)?;
 
                 log::debug!("poll_oneoff event.u.clock = {:?}", clock);
                 log::debug!("poll_oneoff delay = {:?}ns", delay);
 
                 let current = ClockEventData {
                     delay,
+                    deadline,
                     userdata: subscription.userdata,
                 };
                 let timeout = timeout.get_or_insert(current);
@@ -253,30 +266,54 @@ pub(crate) fn poll_oneoff(
                     wasi::__WASI_RIGHTS_FD_WRITE | wasi::__WASI_RIGHTS_POLL_FD_READWRITE
                 };
 
-                match unsafe {
-                    wasi_ctx
-                        .get_fd_entry(wasi_fd)
-                        .and_then(|fe| fe.as_descriptor(rights, 0))
-                } {
-                    Ok(descriptor) => fd_events.push(FdEventData {
-                        descriptor,
-                        r#type: subscription.r#type,
-                        userdata: subscription.userdata,
-                    }),
-                    Err(err) => {
-                        let event = wasi::__wasi_event_t {
-                            userdata: subscription.userdata,
+                let entry = unsafe {
+                    wasi_ctx.get_fd_entry(wasi_fd).and_then(|fe| {
+                        // Directories can't be read or written, so they never become ready.
+                        if fe.file_type == wasi::__WASI_FILETYPE_DIRECTORY {
+                            return Err(Error::EISDIR);
+                        }
+                        Ok((fe.file_type, fe.as_descriptor(rights, 0)?))
+                    })
+                };
+                match entry {
+                    // Virtual files report their own readiness, rather than being handed to
+                    // the host poller.
+                    Ok((_, Descriptor::VirtualFile(file))) => {
+                        virtual_events.push(VirtualEventData {
+                            file,
                             r#type,
-                            error: err.as_wasi_error().as_raw_errno(),
-                            u: wasi::__wasi_event_u_t {
-                                fd_readwrite: wasi::__wasi_event_fd_readwrite_t {
-                                    nbytes: 0,
-                                    flags: 0,
-                                },
-                            },
+                            userdata: subscription.userdata,
+                        })
+                    }
+                    // Regular files are always ready, and reading one won't block until its end.
+                    Ok((wasi::__WASI_FILETYPE_REGULAR_FILE, Descriptor::OsHandle(file))) => {
+                        let event = match regular_file_nbytes(file, r#type) {
+                            Ok(nbytes) => fd_readwrite_event(
+                                subscription.userdata,
+                                r#type,
+                                wasi::__WASI_ERRNO_SUCCESS,
+                                nbytes,
+                            ),
+                            Err(err) => fd_readwrite_event(
+                                subscription.userdata,
+                                r#type,
+                                err.as_wasi_error().as_raw_errno(),
+                                0,
+                            ),
                         };
                         events.push(event);
                     }
+                    Ok((_, descriptor)) => fd_events.push(FdEventData {
+                        descriptor,
+                        r#type: subscription.r#type,
+                        userdata: subscription.userdata,
+                    }),
+                    Err(err) => events.push(fd_readwrite_event(
+                        subscription.userdata,
+                        r#type,
+                        err.as_wasi_error().as_raw_errno(),
+                        0,
+                    )),
                 };
             }
             _ => unreachable!(),
@@ -286,10 +323,51 @@ pub(crate) fn poll_oneoff(
     log::debug!("poll_oneoff timeout = {:?}", timeout);
     log::debug!("poll_oneoff fd_events = {:?}", fd_events);
 
-    // The underlying implementation should successfully and immediately return
-    // if no events have been passed. Such situation may occur if all provided
-    // events have been filtered out as errors in the code above.
-    hostcalls_impl::poll_oneoff(timeout, fd_events, &mut events)?;
+    let start = Instant::now();
+    let waker = PollWaker::new();
+    poll_virtual_files(&mut virtual_events, &waker, &mut events);
+
+    // With nothing to wait for but a timeout, a virtual clock can do the sleeping itself.
+    let mut slept = false;
+    if let Some(timeout) = timeout {
+        if fd_events.is_empty() && virtual_events.is_empty() && events.is_empty() {
+            slept = wasi_ctx.clocks.sleep_until(timeout.deadline, cancel)?;
+            if slept {
+                events.push(clock_event(timeout));
+            }
+        }
+    }
+
+    match timeout {
+        _ if slept => {}
+        // Nothing is ready yet, but some virtual file may become ready at any moment.
+        _ if events.is_empty() && !virtual_events.is_empty() => wait_for_virtual_files(
+            start,
+            timeout,
+            fd_events,
+            &mut virtual_events,
+            &waker,
+            cancel,
+            &mut events,
+        )?,
+        // Waiting on stdin, the Windows poller has no way of checking on the token, so it has
+        // to keep coming back, much like the wait on virtual files.
+        _ if cfg!(windows) && events.is_empty() && !fd_events.is_empty() && cancel.is_some() => {
+            wait_for_virtual_files(
+                start,
+                timeout,
+                fd_events,
+                &mut virtual_events,
+                &waker,
+                cancel,
+                &mut events,
+            )?
+        }
+        // The underlying implementation should successfully and immediately return
+        // if no events have been passed. Such situation may occur if all provided
+        // events have been filtered out as errors in the code above.
+        _ => hostcalls_impl::poll_oneoff(timeout, fd_events, cancel, &mut events)?,
+    }
 
     let events_count = u32::try_from(events.len()).map_err(|_| Error::EOVERFLOW)?;
 
@@ -300,44 +378,1212 @@ pub(crate) fn poll_oneoff(
     enc_int_byref(memory, nevents, events_count)
 }
 
-fn wasi_clock_to_relative_ns_delay(wasi_clock: wasi::__wasi_subscription_clock_t) -> Result<u128> {
-    use std::time::SystemTime;
+/// The event for `timeout` expiring.
+fn clock_event(timeout: ClockEventData) -> wasi::__wasi_event_t {
+    wasi::__wasi_event_t {
+        userdata: timeout.userdata,
+        r#type: wasi::__WASI_EVENTTYPE_CLOCK,
+        error: wasi::__WASI_ERRNO_SUCCESS,
+        u: wasi::__wasi_event_u_t {
+            fd_readwrite: wasi::__wasi_event_fd_readwrite_t {
+                nbytes: 0,
+                flags: 0,
+            },
+        },
+    }
+}
+
+/// An `fd_read` or `fd_write` event for the subscription with `userdata`.
+fn fd_readwrite_event(
+    userdata: wasi::__wasi_userdata_t,
+    r#type: wasi::__wasi_eventtype_t,
+    error: wasi::__wasi_errno_t,
+    nbytes: wasi::__wasi_filesize_t,
+) -> wasi::__wasi_event_t {
+    wasi::__wasi_event_t {
+        userdata,
+        r#type,
+        error,
+        u: wasi::__wasi_event_u_t {
+            fd_readwrite: wasi::__wasi_event_fd_readwrite_t { nbytes, flags: 0 },
+        },
+    }
+}
+
+/// The `nbytes` to report for a regular file which is polled: what's left to read up to its
+/// end, going by its current offset, and nothing for writes.
+fn regular_file_nbytes(
+    file: &File,
+    r#type: wasi::__wasi_eventtype_t,
+) -> Result<wasi::__wasi_filesize_t> {
+    if r#type != wasi::__WASI_EVENTTYPE_FD_READ {
+        return Ok(0);
+    }
+    let size = file.metadata()?.len();
+    let offset = (&*file).seek(SeekFrom::Current(0))?;
+    Ok(size.saturating_sub(offset))
+}
+
+/// How long to block in the host poller at a time, when virtual files have to be checked on in
+/// between.
+const VIRTUAL_POLL_INTERVAL: u128 = 10_000_000;
+
+/// Push an event for each of `virtual_events` which is ready, or doesn't keep track of its
+/// readiness, leaving only those which aren't ready.
+fn poll_virtual_files(
+    virtual_events: &mut Vec<VirtualEventData>,
+    waker: &PollWaker,
+    events: &mut Vec<wasi::__wasi_event_t>,
+) {
+    virtual_events.retain(|event| {
+        let file = match lock_virtual_file(event.file) {
+            Ok(file) => file,
+            Err(err) => {
+                events.push(fd_readwrite_event(
+                    event.userdata,
+                    event.r#type,
+                    err.as_wasi_error().as_raw_errno(),
+                    0,
+                ));
+                return false;
+            }
+        };
+        let readiness = if event.r#type == wasi::__WASI_EVENTTYPE_FD_READ {
+            file.poll_readable(waker)
+        } else {
+            file.poll_writable(waker)
+        };
+        if readiness == Readiness::NotReady {
+            return true;
+        }
+        let nbytes = if event.r#type == wasi::__WASI_EVENTTYPE_FD_READ {
+            file.num_ready_bytes()
+        } else {
+            Ok(0)
+        };
+        events.push(wasi::__wasi_event_t {
+            userdata: event.userdata,
+            r#type: event.r#type,
+            error: nbytes.as_wasi_error().as_raw_errno(),
+            u: wasi::__wasi_event_u_t {
+                fd_readwrite: wasi::__wasi_event_fd_readwrite_t {
+                    nbytes: nbytes.unwrap_or_default(),
+                    flags: 0,
+                },
+            },
+        });
+        false
+    });
+}
+
+/// Block until one of `virtual_events` is woken and turns out to be ready, one of `fd_events`
+/// is ready, or `timeout` expires, as measured from `start`. If `cancel` is cancelled first,
+/// this fails with `Error::EINTR`.
+fn wait_for_virtual_files(
+    start: Instant,
+    timeout: Option<ClockEventData>,
+    fd_events: Vec<FdEventData>,
+    virtual_events: &mut Vec<VirtualEventData>,
+    waker: &PollWaker,
+    cancel: Option<&CancelToken>,
+    events: &mut Vec<wasi::__wasi_event_t>,
+) -> Result<()> {
+    let _watch = cancel.map(|cancel| cancel.watch(waker));
+    loop {
+        if let Some(cancel) = cancel {
+            cancel.check()?;
+        }
+
+        let remaining = timeout.map(|timeout| {
+            let elapsed = start.elapsed().as_nanos();
+            ClockEventData {
+                delay: timeout.delay.saturating_sub(elapsed),
+                ..timeout
+            }
+        });
+        if fd_events.is_empty() {
+            let delay = remaining.map(|remaining| {
+                Duration::from_nanos(u64::try_from(remaining.delay).unwrap_or(u64::max_value()))
+            });
+            if !waker.wait(delay) {
+                // The wait is what took up the timeout, so there's no more sleeping to do.
+                if let Some(timeout) = timeout {
+                    events.push(clock_event(timeout));
+                }
+                return Ok(());
+            }
+        } else {
+            // The host poller knows nothing of the waker, so keep coming back to check on the
+            // virtual files every so often.
+            let (interval, is_timeout) = match remaining {
+                Some(remaining) if remaining.delay <= VIRTUAL_POLL_INTERVAL => (remaining, true),
+                _ => (
+                    ClockEventData {
+                        delay: VIRTUAL_POLL_INTERVAL,
+                        deadline: None,
+                        userdata: 0,
+                    },
+                    false,
+                ),
+            };
+            hostcalls_impl::poll_oneoff(Some(interval), fd_events.clone(), cancel, events)?;
+            // Unless it's the guest's own timeout expiring, that's only the end of the interval.
+            if !is_timeout {
+                events.retain(|event| event.r#type != wasi::__WASI_EVENTTYPE_CLOCK);
+            }
+            if !events.is_empty() {
+                return Ok(());
+            }
+        }
 
-    if wasi_clock.flags != wasi::__WASI_SUBCLOCKFLAGS_SUBSCRIPTION_CLOCK_ABSTIME {
-        return Ok(u128::from(wasi_clock.timeout));
+        poll_virtual_files(virtual_events, waker, events);
+        if !events.is_empty() {
+            return Ok(());
+        }
     }
-    let now: u128 = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .map_err(|_| Error::ENOTCAPABLE)?
-        .as_nanos();
-    let deadline = u128::from(wasi_clock.timeout);
-    Ok(deadline.saturating_sub(now))
 }
 
 #[derive(Debug, Copy, Clone)]
 pub(crate) struct ClockEventData {
     pub(crate) delay: u128, // delay is expressed in nanoseconds
+    /// When the timeout expires on the virtual monotonic clock, if there is one.
+    pub(crate) deadline: Option<Duration>,
     pub(crate) userdata: wasi::__wasi_userdata_t,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub(crate) struct FdEventData<'a> {
     pub(crate) descriptor: &'a Descriptor,
     pub(crate) r#type: wasi::__wasi_eventtype_t,
     pub(crate) userdata: wasi::__wasi_userdata_t,
 }
 
-pub(crate) fn proc_exit(_wasi_ctx: &WasiCtx, _memory: &mut [u8], rval: wasi::__wasi_exitcode_t) {
+struct VirtualEventData<'a> {
+    file: &'a Mutex<Box<dyn VirtualFile>>,
+    r#type: wasi::__wasi_eventtype_t,
+    userdata: wasi::__wasi_userdata_t,
+}
+
+pub(crate) fn proc_exit(wasi_ctx: &WasiCtx, _memory: &mut [u8], rval: wasi::__wasi_exitcode_t) {
     trace!("proc_exit(rval={:?})", rval);
+    wasi_ctx.record_exit_status(ExitStatus::Exited(rval));
     // TODO: Rather than call std::process::exit here, we should trigger a
     // stack unwind similar to a trap.
     std::process::exit(rval as i32);
 }
 
 pub(crate) fn proc_raise(
-    _wasi_ctx: &WasiCtx,
+    wasi_ctx: &WasiCtx,
     _memory: &mut [u8],
-    _sig: wasi::__wasi_signal_t,
+    sig: wasi::__wasi_signal_t,
 ) -> Result<()> {
-    unimplemented!("proc_raise")
+    trace!("proc_raise(sig={:?})", sig);
+
+    match signal_action(sig)? {
+        SignalAction::Ignore => Ok(()),
+        SignalAction::Unsupported => Err(Error::ENOTSUP),
+        SignalAction::Terminate => {
+            // The signal is never delivered to the host process, which other instances may
+            // share. It's kept for the embedder to find with `WasiCtx::exit_status` instead,
+            // while the guest, which can't be stopped from here, gets an error back.
+            // TODO: Unwind the instance with a trap, once hostcalls can raise one.
+            wasi_ctx.record_exit_status(ExitStatus::Signaled(sig));
+            Err(Error::ENOTSUP)
+        }
+    }
+}
+
+/// What `proc_raise` does with a signal, following the signal's default action in POSIX.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SignalAction {
+    Ignore,
+    /// Stopping and continuing the guest aren't supported.
+    Unsupported,
+    Terminate,
+}
+
+fn signal_action(sig: wasi::__wasi_signal_t) -> Result<SignalAction> {
+    match sig {
+        wasi::__WASI_SIGNAL_NONE
+        | wasi::__WASI_SIGNAL_CHLD
+        | wasi::__WASI_SIGNAL_URG
+        | wasi::__WASI_SIGNAL_WINCH => Ok(SignalAction::Ignore),
+        wasi::__WASI_SIGNAL_CONT
+        | wasi::__WASI_SIGNAL_STOP
+        | wasi::__WASI_SIGNAL_TSTP
+        | wasi::__WASI_SIGNAL_TTIN
+        | wasi::__WASI_SIGNAL_TTOU => Ok(SignalAction::Unsupported),
+        wasi::__WASI_SIGNAL_HUP
+        | wasi::__WASI_SIGNAL_INT
+        | wasi::__WASI_SIGNAL_QUIT
+        | wasi::__WASI_SIGNAL_ILL
+        | wasi::__WASI_SIGNAL_TRAP
+        | wasi::__WASI_SIGNAL_ABRT
+        | wasi::__WASI_SIGNAL_BUS
+        | wasi::__WASI_SIGNAL_FPE
+        | wasi::__WASI_SIGNAL_KILL
+        | wasi::__WASI_SIGNAL_USR1
+        | wasi::__WASI_SIGNAL_SEGV
+        | wasi::__WASI_SIGNAL_USR2
+        | wasi::__WASI_SIGNAL_PIPE
+        | wasi::__WASI_SIGNAL_ALRM
+        | wasi::__WASI_SIGNAL_TERM
+        | wasi::__WASI_SIGNAL_XCPU
+        | wasi::__WASI_SIGNAL_XFSZ
+        | wasi::__WASI_SIGNAL_VTALRM
+        | wasi::__WASI_SIGNAL_PROF
+        | wasi::__WASI_SIGNAL_POLL
+        | wasi::__WASI_SIGNAL_PWR
+        | wasi::__WASI_SIGNAL_SYS => Ok(SignalAction::Terminate),
+        _ => Err(Error::EINVAL),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::{scratch_dir, with_memory};
+    use crate::{ManualClock, OffsetSign, WasiCtxBuilder};
+    use std::mem::size_of;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, SystemTime};
+
+    fn guest_clock_res(ctx: &WasiCtx, clock_id: wasi::__wasi_clockid_t) -> Result<u64> {
+        with_memory(8, |memory| {
+            clock_res_get(ctx, memory, clock_id, 0)?;
+            dec_int_byref(memory, 0)
+        })
+    }
+
+    fn guest_clock_time(ctx: &WasiCtx, clock_id: wasi::__wasi_clockid_t) -> Result<u64> {
+        with_memory(8, |memory| {
+            clock_time_get(ctx, memory, clock_id, 0, 0)?;
+            dec_int_byref(memory, 0)
+        })
+    }
+
+    fn clock_subscription(
+        clock_id: wasi::__wasi_clockid_t,
+        timeout: wasi::__wasi_timestamp_t,
+        flags: wasi::__wasi_subclockflags_t,
+    ) -> wasi::__wasi_subscription_t {
+        wasi::__wasi_subscription_t {
+            userdata: 0x1234,
+            r#type: wasi::__WASI_EVENTTYPE_CLOCK,
+            u: wasi::__wasi_subscription_u_t {
+                clock: wasi::__wasi_subscription_clock_t {
+                    id: clock_id,
+                    timeout,
+                    precision: 0,
+                    flags,
+                },
+            },
+        }
+    }
+
+    fn fd_read_subscription(
+        fd: wasi::__wasi_fd_t,
+        userdata: wasi::__wasi_userdata_t,
+    ) -> wasi::__wasi_subscription_t {
+        wasi::__wasi_subscription_t {
+            userdata,
+            r#type: wasi::__WASI_EVENTTYPE_FD_READ,
+            u: wasi::__wasi_subscription_u_t {
+                fd_readwrite: wasi::__wasi_subscription_fd_readwrite_t {
+                    file_descriptor: fd,
+                },
+            },
+        }
+    }
+
+    /// Wait in `poll_oneoff` on `subscriptions`, returning the events it reported.
+    fn guest_poll(
+        ctx: &WasiCtx,
+        subscriptions: &[wasi::__wasi_subscription_t],
+    ) -> Result<Vec<wasi::__wasi_event_t>> {
+        let output = subscriptions.len() * size_of::<wasi::__wasi_subscription_t>();
+        let nevents = output + subscriptions.len() * size_of::<wasi::__wasi_event_t>();
+        with_memory(nevents + 8, |memory| {
+            let input = memory.as_mut_ptr() as *mut wasi::__wasi_subscription_t;
+            for (i, subscription) in subscriptions.iter().enumerate() {
+                unsafe { input.add(i).write(*subscription) };
+            }
+            let nsubscriptions = subscriptions.len() as u32;
+            poll_oneoff(
+                ctx,
+                memory,
+                0,
+                output as u32,
+                nsubscriptions,
+                nevents as u32,
+            )?;
+            let nevents: u32 = dec_int_byref(memory, nevents as u32)?;
+            let events = unsafe { memory.as_ptr().add(output) as *const wasi::__wasi_event_t };
+            Ok((0..nevents as usize)
+                .map(|i| unsafe { events.add(i).read() })
+                .collect())
+        })
+    }
+
+    /// Wait in `poll_oneoff` on a single clock subscription, returning the events it reported.
+    fn guest_sleep(
+        ctx: &WasiCtx,
+        clock_id: wasi::__wasi_clockid_t,
+        timeout: wasi::__wasi_timestamp_t,
+        flags: wasi::__wasi_subclockflags_t,
+    ) -> Result<Vec<wasi::__wasi_event_t>> {
+        guest_poll(ctx, &[clock_subscription(clock_id, timeout, flags)])
+    }
+
+    const CLOCKS: [wasi::__wasi_clockid_t; 4] = [
+        wasi::__WASI_CLOCKID_REALTIME,
+        wasi::__WASI_CLOCKID_MONOTONIC,
+        wasi::__WASI_CLOCKID_PROCESS_CPUTIME_ID,
+        wasi::__WASI_CLOCKID_THREAD_CPUTIME_ID,
+    ];
+
+    #[test]
+    fn clock_resolutions() {
+        let ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        for &clock_id in CLOCKS.iter() {
+            let res = guest_clock_res(&ctx, clock_id).expect("supported clock");
+            assert!(res > 0, "clock {} has a zero resolution", clock_id);
+            assert!(
+                res <= 1_000_000_000,
+                "clock {} is coarser than 1s",
+                clock_id
+            );
+        }
+        let err = guest_clock_res(&ctx, 42).expect_err("unsupported clock");
+        assert_eq!(err.as_wasi_error(), crate::error::WasiError::EINVAL);
+    }
+
+    #[test]
+    fn coarsened_clocks() {
+        let ctx = WasiCtxBuilder::new()
+            .clock_coarsening(Duration::from_millis(100))
+            .build()
+            .expect("build WasiCtx");
+        for &clock_id in CLOCKS.iter() {
+            let res = guest_clock_res(&ctx, clock_id).expect("supported clock");
+            assert_eq!(res, 100_000_000);
+            let time = guest_clock_time(&ctx, clock_id).expect("supported clock");
+            assert_eq!(time % 100_000_000, 0);
+        }
+    }
+
+    #[test]
+    fn manual_clock_auto_advance() {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let ctx = WasiCtxBuilder::new()
+            .system_clock(clock.clone())
+            .monotonic_clock(clock.clone())
+            .clock_auto_advance(true)
+            .build()
+            .expect("build WasiCtx");
+
+        let realtime = guest_clock_time(&ctx, wasi::__WASI_CLOCKID_REALTIME).unwrap();
+        let monotonic = guest_clock_time(&ctx, wasi::__WASI_CLOCKID_MONOTONIC).unwrap();
+        assert_eq!(realtime, 1_000_000 * 1_000_000_000);
+        assert_eq!(monotonic, 0);
+
+        let start = Instant::now();
+        let events = guest_sleep(&ctx, wasi::__WASI_CLOCKID_MONOTONIC, 10_000_000_000, 0)
+            .expect("poll_oneoff");
+        assert!(
+            start.elapsed() < Duration::from_secs(1),
+            "slept in host time"
+        );
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].userdata, 0x1234);
+        assert_eq!(events[0].r#type, wasi::__WASI_EVENTTYPE_CLOCK);
+        assert_eq!(events[0].error, wasi::__WASI_ERRNO_SUCCESS);
+
+        let elapsed = guest_clock_time(&ctx, wasi::__WASI_CLOCKID_MONOTONIC).unwrap() - monotonic;
+        assert_eq!(elapsed, 10_000_000_000);
+        let elapsed = guest_clock_time(&ctx, wasi::__WASI_CLOCKID_REALTIME).unwrap() - realtime;
+        assert_eq!(elapsed, 10_000_000_000);
+    }
+
+    #[test]
+    fn manual_clock_waits_for_host() {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+        let ctx = WasiCtxBuilder::new()
+            .monotonic_clock(clock.clone())
+            .build()
+            .expect("build WasiCtx");
+
+        // Keep nudging the clock forward until the guest wakes up, as the guest's deadline is
+        // relative to whenever it entered `poll_oneoff`.
+        let done = Arc::new(AtomicBool::new(false));
+        let host = {
+            let done = done.clone();
+            std::thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    std::thread::sleep(Duration::from_millis(1));
+                    clock.advance(Duration::from_millis(100));
+                }
+            })
+        };
+        let events = guest_sleep(&ctx, wasi::__WASI_CLOCKID_MONOTONIC, 1_000_000_000, 0)
+            .expect("poll_oneoff");
+        done.store(true, Ordering::SeqCst);
+        host.join().unwrap();
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].r#type, wasi::__WASI_EVENTTYPE_CLOCK);
+        assert!(guest_clock_time(&ctx, wasi::__WASI_CLOCKID_MONOTONIC).unwrap() >= 1_000_000_000);
+    }
+
+    #[test]
+    fn fixed_system_clock() {
+        let date = SystemTime::UNIX_EPOCH + Duration::from_secs(1_234_567_890);
+        let ctx = WasiCtxBuilder::new()
+            .system_clock_fixed(date)
+            .build()
+            .expect("build WasiCtx");
+
+        let expected = 1_234_567_890 * 1_000_000_000;
+        assert_eq!(
+            guest_clock_time(&ctx, wasi::__WASI_CLOCKID_REALTIME).unwrap(),
+            expected
+        );
+
+        // The monotonic clock keeps going, so the timeout still expires.
+        let start = Instant::now();
+        let events =
+            guest_sleep(&ctx, wasi::__WASI_CLOCKID_MONOTONIC, 10_000_000, 0).expect("poll_oneoff");
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].r#type, wasi::__WASI_EVENTTYPE_CLOCK);
+
+        assert_eq!(
+            guest_clock_time(&ctx, wasi::__WASI_CLOCKID_REALTIME).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn offset_system_clock() {
+        let offset = Duration::from_secs(365 * 24 * 60 * 60);
+        let ctx = WasiCtxBuilder::new()
+            .system_clock_offset(offset, OffsetSign::Minus)
+            .build()
+            .expect("build WasiCtx");
+
+        let before = SystemTime::now() - offset;
+        let time = guest_clock_time(&ctx, wasi::__WASI_CLOCKID_REALTIME).unwrap();
+        let after = SystemTime::now() - offset;
+        let since_epoch = |time: SystemTime| {
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+        };
+        assert!(since_epoch(before) <= time && time <= since_epoch(after));
+    }
+
+    #[test]
+    fn offset_fixed_system_clock() {
+        let date = SystemTime::UNIX_EPOCH + Duration::from_secs(1_234_567_890);
+        let ctx = WasiCtxBuilder::new()
+            .system_clock_fixed(date)
+            .system_clock_offset(Duration::from_secs(10), OffsetSign::Plus)
+            .build()
+            .expect("build WasiCtx");
+
+        assert_eq!(
+            guest_clock_time(&ctx, wasi::__WASI_CLOCKID_REALTIME).unwrap(),
+            1_234_567_900 * 1_000_000_000
+        );
+    }
+
+    #[test]
+    fn monotonic_zero_at_creation() {
+        let created = Instant::now();
+        let ctx = WasiCtxBuilder::new()
+            .monotonic_zero_at_creation(true)
+            .build()
+            .expect("build WasiCtx");
+
+        let first = guest_clock_time(&ctx, wasi::__WASI_CLOCKID_MONOTONIC).unwrap();
+        assert!(
+            first < 1_000_000_000,
+            "monotonic clock starts at {}ns",
+            first
+        );
+
+        let events = guest_sleep(
+            &ctx,
+            wasi::__WASI_CLOCKID_MONOTONIC,
+            50_000_000,
+            wasi::__WASI_SUBCLOCKFLAGS_SUBSCRIPTION_CLOCK_ABSTIME,
+        )
+        .expect("poll_oneoff");
+        let elapsed = created.elapsed();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].r#type, wasi::__WASI_EVENTTYPE_CLOCK);
+        assert!(
+            elapsed >= Duration::from_millis(50),
+            "woke up after {:?}",
+            elapsed
+        );
+        assert!(
+            elapsed < Duration::from_secs(5),
+            "woke up after {:?}",
+            elapsed
+        );
+    }
+
+    #[test]
+    fn manual_clock_absolute_deadlines() {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+        clock.advance(Duration::from_secs(5));
+        let ctx = WasiCtxBuilder::new()
+            .system_clock(clock.clone())
+            .monotonic_clock(clock.clone())
+            .clock_auto_advance(true)
+            .build()
+            .expect("build WasiCtx");
+
+        for &clock_id in &[
+            wasi::__WASI_CLOCKID_MONOTONIC,
+            wasi::__WASI_CLOCKID_REALTIME,
+        ] {
+            let now = guest_clock_time(&ctx, clock_id).unwrap();
+            let deadline = now + 100_000_000;
+            let events = guest_sleep(
+                &ctx,
+                clock_id,
+                deadline,
+                wasi::__WASI_SUBCLOCKFLAGS_SUBSCRIPTION_CLOCK_ABSTIME,
+            )
+            .expect("poll_oneoff");
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].r#type, wasi::__WASI_EVENTTYPE_CLOCK);
+            assert_eq!(guest_clock_time(&ctx, clock_id).unwrap(), deadline);
+
+            // A deadline in the past expires without the clock moving at all.
+            let events = guest_sleep(
+                &ctx,
+                clock_id,
+                now,
+                wasi::__WASI_SUBCLOCKFLAGS_SUBSCRIPTION_CLOCK_ABSTIME,
+            )
+            .expect("poll_oneoff");
+            assert_eq!(events.len(), 1);
+            assert_eq!(guest_clock_time(&ctx, clock_id).unwrap(), deadline);
+        }
+    }
+
+    #[test]
+    fn host_clock_absolute_deadline_in_the_past() {
+        let ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        let start = Instant::now();
+        let events = guest_sleep(
+            &ctx,
+            wasi::__WASI_CLOCKID_REALTIME,
+            0,
+            wasi::__WASI_SUBCLOCKFLAGS_SUBSCRIPTION_CLOCK_ABSTIME,
+        )
+        .expect("poll_oneoff");
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].r#type, wasi::__WASI_EVENTTYPE_CLOCK);
+    }
+
+    fn guest_random(ctx: &WasiCtx, len: usize) -> Vec<u8> {
+        with_memory(len, |memory| {
+            random_get(ctx, memory, 0, len as u32).expect("random_get");
+            memory.to_vec()
+        })
+    }
+
+    #[test]
+    fn seeded_random_get() {
+        use rand_chacha::rand_core::SeedableRng;
+        use rand_chacha::ChaCha20Rng;
+
+        let seeded = || {
+            WasiCtxBuilder::new()
+                .random(ChaCha20Rng::seed_from_u64(42))
+                .build()
+                .expect("build WasiCtx")
+        };
+        let stream = guest_random(&seeded(), 1024 * 1024);
+        assert_eq!(stream, guest_random(&seeded(), 1024 * 1024));
+        assert!(stream.iter().any(|&byte| byte != 0));
+
+        let mut expected = vec![0; 1024 * 1024];
+        ChaCha20Rng::seed_from_u64(42).fill_bytes(&mut expected);
+        assert_eq!(stream, expected);
+    }
+
+    #[test]
+    fn random_get_bounds() {
+        let ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        with_memory(64, |memory| {
+            // An empty request succeeds wherever it points.
+            random_get(&ctx, memory, 1000, 0).expect("empty random_get");
+
+            let err = random_get(&ctx, memory, 32, 64).expect_err("out of bounds");
+            assert_eq!(err.as_wasi_error(), crate::error::WasiError::EFAULT);
+            let err = random_get(&ctx, memory, u32::max_value(), 2).expect_err("overflow");
+            assert_eq!(err.as_wasi_error(), crate::error::WasiError::EFAULT);
+            assert!(memory.iter().all(|&byte| byte == 0), "partial write");
+        });
+    }
+
+    #[test]
+    fn random_budget() {
+        use crate::RandomBudgetPolicy;
+
+        let ctx = WasiCtxBuilder::new()
+            .random_budget(1024)
+            .build()
+            .expect("build WasiCtx");
+        guest_random(&ctx, 1024);
+        let err =
+            with_memory(1, |memory| random_get(&ctx, memory, 0, 1)).expect_err("budget exhausted");
+        assert_eq!(err.as_wasi_error(), crate::error::WasiError::EAGAIN);
+        assert_eq!(ctx.random_bytes_consumed(), 1024);
+
+        let ctx = WasiCtxBuilder::new()
+            .random_budget(1024)
+            .random_budget_policy(RandomBudgetPolicy::Degrade)
+            .build()
+            .expect("build WasiCtx");
+        guest_random(&ctx, 1000);
+        // Straddles the end of the budget.
+        guest_random(&ctx, 25);
+        let fallback = guest_random(&ctx, 4096);
+        assert!(fallback.iter().any(|&byte| byte != 0));
+        assert_eq!(ctx.random_bytes_consumed(), 1000 + 25 + 4096);
+    }
+
+    /// A virtual file fed by the host, which only becomes readable once the host has sent it
+    /// something.
+    #[derive(Clone, Debug, Default)]
+    struct Channel {
+        state: Arc<Mutex<(Vec<u8>, Option<PollWaker>)>>,
+    }
+
+    impl Channel {
+        fn send(&self, data: &[u8]) {
+            let mut state = self.state.lock().unwrap();
+            state.0.extend_from_slice(data);
+            if let Some(waker) = state.1.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    impl VirtualFile for Channel {
+        fn filetype(&self) -> wasi::__wasi_filetype_t {
+            wasi::__WASI_FILETYPE_UNKNOWN
+        }
+
+        fn rights(&self) -> (wasi::__wasi_rights_t, wasi::__wasi_rights_t) {
+            (
+                wasi::__WASI_RIGHTS_FD_READ | wasi::__WASI_RIGHTS_POLL_FD_READWRITE,
+                0,
+            )
+        }
+
+        fn num_ready_bytes(&self) -> Result<u64> {
+            Ok(self.state.lock().unwrap().0.len() as u64)
+        }
+
+        fn poll_readable(&self, waker: &PollWaker) -> Readiness {
+            let mut state = self.state.lock().unwrap();
+            if state.0.is_empty() {
+                state.1 = Some(waker.clone());
+                Readiness::NotReady
+            } else {
+                Readiness::Ready
+            }
+        }
+    }
+
+    fn send_later(channel: &Channel, data: &'static [u8]) -> std::thread::JoinHandle<()> {
+        let channel = channel.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            channel.send(data);
+        })
+    }
+
+    #[test]
+    fn virtual_file_wakes_poll() {
+        let channel = Channel::default();
+        let ctx = WasiCtxBuilder::new()
+            .stdin_virtual(channel.clone())
+            .build()
+            .expect("build WasiCtx");
+
+        let host = send_later(&channel, b"ping");
+        let start = Instant::now();
+        let subscriptions = [
+            fd_read_subscription(0, 0x5678),
+            clock_subscription(wasi::__WASI_CLOCKID_MONOTONIC, 10_000_000_000, 0),
+        ];
+        let events = guest_poll(&ctx, &subscriptions).expect("poll_oneoff");
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "missed the wakeup"
+        );
+        host.join().unwrap();
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].userdata, 0x5678);
+        assert_eq!(events[0].r#type, wasi::__WASI_EVENTTYPE_FD_READ);
+        assert_eq!(events[0].error, wasi::__WASI_ERRNO_SUCCESS);
+        assert_eq!(unsafe { events[0].u.fd_readwrite.nbytes }, 4);
+    }
+
+    #[test]
+    fn virtual_file_poll_times_out() {
+        let ctx = WasiCtxBuilder::new()
+            .stdin_virtual(Channel::default())
+            .build()
+            .expect("build WasiCtx");
+
+        let subscriptions = [
+            fd_read_subscription(0, 0x5678),
+            clock_subscription(wasi::__WASI_CLOCKID_MONOTONIC, 100_000_000, 0),
+        ];
+        let start = Instant::now();
+        let events = guest_poll(&ctx, &subscriptions).expect("poll_oneoff");
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+        // Only the clock fires; the empty virtual stdin reports nothing.
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].userdata, 0x1234);
+        assert_eq!(events[0].r#type, wasi::__WASI_EVENTTYPE_CLOCK);
+        assert_eq!(events[0].error, wasi::__WASI_ERRNO_SUCCESS);
+    }
+
+    #[test]
+    fn virtual_files_default_to_ready() {
+        let ctx = WasiCtxBuilder::new()
+            .stdin_virtual(crate::ReadPipe::new("abc"))
+            .build()
+            .expect("build WasiCtx");
+
+        let events = guest_poll(&ctx, &[fd_read_subscription(0, 0x5678)]).expect("poll_oneoff");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].r#type, wasi::__WASI_EVENTTYPE_FD_READ);
+        assert_eq!(unsafe { events[0].u.fd_readwrite.nbytes }, 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn virtual_file_wakes_poll_on_host_fds() {
+        use std::os::unix::io::FromRawFd;
+
+        let channel = Channel::default();
+        let mut ctx = WasiCtxBuilder::new()
+            .stdin_virtual(channel.clone())
+            .build()
+            .expect("build WasiCtx");
+        // A host pipe which never becomes readable, so that the host poller is involved too.
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (reader, writer) = unsafe {
+            (
+                std::fs::File::from_raw_fd(fds[0]),
+                std::fs::File::from_raw_fd(fds[1]),
+            )
+        };
+        let pipe_fd = ctx
+            .insert_fd_entry(crate::fdentry::FdEntry::from(reader).unwrap())
+            .expect("insert pipe");
+
+        let host = send_later(&channel, b"ping");
+        let subscriptions = [
+            fd_read_subscription(pipe_fd, 0x9abc),
+            fd_read_subscription(0, 0x5678),
+        ];
+        let events = guest_poll(&ctx, &subscriptions).expect("poll_oneoff");
+        host.join().unwrap();
+        drop(writer);
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].userdata, 0x5678);
+        assert_eq!(events[0].r#type, wasi::__WASI_EVENTTYPE_FD_READ);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn event_loop_wakeups() {
+        use std::io::{Read, Write};
+        use std::os::unix::io::{FromRawFd, IntoRawFd};
+        use std::os::unix::net::UnixStream;
+        use std::thread;
+
+        let mut ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        let mut socket = || {
+            let (guest, host) = UnixStream::pair().expect("socket pair");
+            let drain = guest.try_clone().expect("duplicate socket");
+            let guest = unsafe { std::fs::File::from_raw_fd(guest.into_raw_fd()) };
+            let fd = ctx
+                .insert_fd_entry(crate::fdentry::FdEntry::from(guest).unwrap())
+                .expect("insert socket");
+            (fd, host, drain)
+        };
+        let (a, a_host, mut a_drain) = socket();
+        let (b, mut b_host, mut b_drain) = socket();
+
+        let timeout = Duration::from_millis(200);
+        let subscriptions = [
+            clock_subscription(wasi::__WASI_CLOCKID_MONOTONIC, timeout.as_nanos() as u64, 0),
+            fd_read_subscription(a, 0xa),
+            fd_read_subscription(b, 0xb),
+        ];
+        let write_later = |host: &UnixStream, data: &'static [u8]| {
+            let mut host = host.try_clone().unwrap();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                host.write_all(data).unwrap();
+            })
+        };
+
+        // Only the socket written to is reported, even though it's not the first one polled.
+        let writer = write_later(&b_host, b"xy");
+        let events = guest_poll(&ctx, &subscriptions).expect("poll_oneoff");
+        writer.join().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].userdata, 0xb);
+        assert_eq!(events[0].r#type, wasi::__WASI_EVENTTYPE_FD_READ);
+        assert_eq!(events[0].error, wasi::__WASI_ERRNO_SUCCESS);
+        assert_eq!(unsafe { events[0].u.fd_readwrite.nbytes }, 2);
+        b_drain.read_exact(&mut [0; 2]).unwrap();
+
+        let writer = write_later(&a_host, b"z");
+        let events = guest_poll(&ctx, &subscriptions).expect("poll_oneoff");
+        writer.join().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].userdata, 0xa);
+        a_drain.read_exact(&mut [0; 1]).unwrap();
+
+        // With neither socket readable, the timer fires.
+        let start = Instant::now();
+        let events = guest_poll(&ctx, &subscriptions).expect("poll_oneoff");
+        assert!(start.elapsed() >= timeout);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].userdata, 0x1234);
+        assert_eq!(events[0].r#type, wasi::__WASI_EVENTTYPE_CLOCK);
+
+        // The same socket may be subscribed to more than once.
+        b_host.write_all(b"w").unwrap();
+        let subscriptions = [fd_read_subscription(b, 0xb), fd_read_subscription(b, 0xc)];
+        let events = guest_poll(&ctx, &subscriptions).expect("poll_oneoff");
+        let userdata: Vec<_> = events.iter().map(|event| event.userdata).collect();
+        assert_eq!(userdata, [0xb, 0xc]);
+    }
+
+    #[test]
+    fn bad_fd_does_not_wait_for_timer() {
+        let ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        let subscriptions = [
+            clock_subscription(wasi::__WASI_CLOCKID_MONOTONIC, 5_000_000_000, 0),
+            fd_read_subscription(99, 0x99),
+        ];
+        let start = Instant::now();
+        let events = guest_poll(&ctx, &subscriptions).expect("poll_oneoff");
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].userdata, 0x99);
+        assert_eq!(events[0].error, wasi::__WASI_ERRNO_BADF);
+    }
+
+    #[test]
+    fn yield_hook_is_called_per_yield() {
+        let yields = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&yields);
+        let ctx = WasiCtxBuilder::new()
+            .yield_hook(move || *counter.lock().unwrap() += 1)
+            .build()
+            .expect("build WasiCtx");
+        for _ in 0..10 {
+            sched_yield(&ctx, &mut []).expect("sched_yield");
+        }
+        assert_eq!(*yields.lock().unwrap(), 10);
+
+        // Without a hook, yielding still works.
+        let ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        sched_yield(&ctx, &mut []).expect("sched_yield");
+    }
+
+    #[test]
+    fn raised_signals() {
+        let ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        proc_raise(&ctx, &mut [], wasi::__WASI_SIGNAL_CHLD).expect("proc_raise(SIGCHLD)");
+        proc_raise(&ctx, &mut [], wasi::__WASI_SIGNAL_NONE).expect("proc_raise(0)");
+        let err = proc_raise(&ctx, &mut [], wasi::__WASI_SIGNAL_STOP).unwrap_err();
+        assert_eq!(err.as_wasi_error(), crate::error::WasiError::ENOTSUP);
+        let err = proc_raise(&ctx, &mut [], 0xff).unwrap_err();
+        assert_eq!(err.as_wasi_error(), crate::error::WasiError::EINVAL);
+
+        assert_eq!(ctx.exit_status(), None);
+
+        // A fatal signal leaves the host process be, and is there for the embedder to find.
+        let sigterm = ExitStatus::Signaled(wasi::__WASI_SIGNAL_TERM);
+        let err = proc_raise(&ctx, &mut [], wasi::__WASI_SIGNAL_TERM).unwrap_err();
+        assert_eq!(err.as_wasi_error(), crate::error::WasiError::ENOTSUP);
+        assert_eq!(ctx.exit_status(), Some(sigterm));
+        // The first one is what ended the guest.
+        let err = proc_raise(&ctx, &mut [], wasi::__WASI_SIGNAL_KILL).unwrap_err();
+        assert_eq!(err.as_wasi_error(), crate::error::WasiError::ENOTSUP);
+        assert_eq!(ctx.exit_status(), Some(sigterm));
+
+        let ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        proc_raise(&ctx, &mut [], wasi::__WASI_SIGNAL_KILL).unwrap_err();
+        let sigkill = ExitStatus::Signaled(wasi::__WASI_SIGNAL_KILL);
+        assert_eq!(ctx.exit_status(), Some(sigkill));
+    }
+
+    #[test]
+    fn exit_statuses() {
+        // Codes are kept whole, even those a host process couldn't exit with.
+        for &code in &[0, 42, 126, 255, 256, u32::max_value()] {
+            let ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+            assert_eq!(ctx.exit_status(), None);
+            ctx.record_exit_status(ExitStatus::Exited(code));
+            assert_eq!(ctx.exit_status(), Some(ExitStatus::Exited(code)));
+        }
+
+        let ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        proc_raise(&ctx, &mut [], wasi::__WASI_SIGNAL_CHLD).expect("proc_raise(SIGCHLD)");
+        assert_eq!(ctx.exit_status(), None);
+        proc_raise(&ctx, &mut [], wasi::__WASI_SIGNAL_TERM).unwrap_err();
+        let sigterm = ExitStatus::Signaled(wasi::__WASI_SIGNAL_TERM);
+        assert_eq!(ctx.exit_status(), Some(sigterm));
+        // Whatever ended the guest first is what's kept.
+        ctx.record_exit_status(ExitStatus::Exited(0));
+        assert_eq!(ctx.exit_status(), Some(sigterm));
+    }
+
+    /// The environment variable which tells `proc_exit_ends_the_process`, run again in a child
+    /// process, what to exit with.
+    const PROC_EXIT_CODE_VAR: &str = "WASI_COMMON_TEST_PROC_EXIT_CODE";
+
+    #[test]
+    fn proc_exit_ends_the_process() {
+        if let Ok(code) = std::env::var(PROC_EXIT_CODE_VAR) {
+            let ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+            proc_exit(&ctx, &mut [], code.parse().unwrap());
+            unreachable!("proc_exit returned");
+        }
+
+        // `proc_exit` takes the test process down with it, so it's run in a child process,
+        // running only this test.
+        for &code in &[0, 42] {
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(&[
+                    "--exact",
+                    "hostcalls_impl::misc::test::proc_exit_ends_the_process",
+                    "--test-threads=1",
+                ])
+                .env(PROC_EXIT_CODE_VAR, code.to_string())
+                .stdout(std::process::Stdio::null())
+                .status()
+                .expect("run the test binary");
+            assert_eq!(status.code(), Some(code));
+        }
+    }
+
+    #[test]
+    fn args_get_stays_in_bounds() {
+        let ctx = WasiCtxBuilder::new()
+            .args(&["a", "0123456789"])
+            .build()
+            .expect("build WasiCtx");
+        with_memory(32, |memory| {
+            args_sizes_get(&ctx, memory, 0, 4).expect("args_sizes_get");
+            assert_eq!(dec_int_byref::<u32>(memory, 0).unwrap(), 2);
+            assert_eq!(dec_int_byref::<u32>(memory, 4).unwrap(), 13);
+
+            args_get(&ctx, memory, 0, 8).expect("args_get");
+            assert_eq!(dec_int_byref::<u32>(memory, 0).unwrap(), 8);
+            assert_eq!(dec_int_byref::<u32>(memory, 4).unwrap(), 10);
+            assert_eq!(&memory[8..21], b"a\00123456789\0");
+
+            // A guest that made less room than `args_sizes_get` asked for, whether up against
+            // the end of memory or of the address space.
+            for &argv_buf in &[24, u32::max_value()] {
+                let err = args_get(&ctx, memory, 0, argv_buf).unwrap_err();
+                assert_eq!(err.as_wasi_error(), crate::error::WasiError::EFAULT);
+            }
+        });
+    }
+
+    #[test]
+    fn regular_files_are_ready() {
+        let scratch = scratch_dir("poll_file");
+        let path = scratch.path().join("file");
+        std::fs::write(&path, &[0; 100][..]).expect("create scratch file");
+        let mut file = File::open(&path).expect("open scratch file");
+        file.seek(SeekFrom::Start(10)).unwrap();
+        let mut ctx = WasiCtxBuilder::new()
+            .preopened_dir(crate::preopen_dir(scratch.path()).unwrap(), "/tmp")
+            .build()
+            .expect("build WasiCtx");
+        let fd = ctx
+            .insert_fd_entry(crate::fdentry::FdEntry::from(file).unwrap())
+            .expect("insert file");
+
+        // A bad fd and a directory get error events of their own, and don't hold up the file.
+        let subscriptions = [
+            fd_read_subscription(99, 0x99),
+            fd_read_subscription(fd, 0xf),
+            fd_read_subscription(3, 0xd),
+        ];
+        let events = guest_poll(&ctx, &subscriptions).expect("poll_oneoff");
+        let outcomes: Vec<_> = events
+            .iter()
+            .map(|event| {
+                (event.userdata, event.error, unsafe {
+                    event.u.fd_readwrite.nbytes
+                })
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                (0x99, wasi::__WASI_ERRNO_BADF, 0),
+                (0xf, wasi::__WASI_ERRNO_SUCCESS, 90),
+                (0xd, wasi::__WASI_ERRNO_ISDIR, 0),
+            ]
+        );
+
+        // At the end of the file, it's still ready, with nothing left to read.
+        with_memory(8, |memory| unsafe {
+            crate::hostcalls_impl::fd_seek(&mut ctx, memory, fd, 0, wasi::__WASI_WHENCE_END, 0)
+        })
+        .expect("fd_seek");
+        let events = guest_poll(&ctx, &[fd_read_subscription(fd, 0xf)]).expect("poll_oneoff");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].error, wasi::__WASI_ERRNO_SUCCESS);
+        assert_eq!(unsafe { events[0].u.fd_readwrite.nbytes }, 0);
+    }
+
+    #[test]
+    fn notifier_wakes_poll() {
+        let notifier = crate::Notifier::new();
+        let mut ctx = WasiCtxBuilder::new()
+            .notify_fd(3, notifier.clone())
+            .build()
+            .expect("build WasiCtx");
+
+        let host = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            notifier.notify_with_value(7);
+        });
+        let start = Instant::now();
+        let subscriptions = [
+            fd_read_subscription(3, 0x3),
+            clock_subscription(wasi::__WASI_CLOCKID_MONOTONIC, 10_000_000_000, 0),
+        ];
+        let events = guest_poll(&ctx, &subscriptions).expect("poll_oneoff");
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "missed the wakeup"
+        );
+        host.join().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].userdata, 0x3);
+        assert_eq!(unsafe { events[0].u.fd_readwrite.nbytes }, 8);
+
+        // One iovec at 0, for 8 bytes at 16, with `nread` in between.
+        let value = with_memory(24, |memory| {
+            enc_int_byref(memory, 0, 16u32)?;
+            enc_int_byref(memory, 4, 8u32)?;
+            unsafe { crate::hostcalls_impl::fd_read(&mut ctx, memory, 3, 0, 1, 8) }?;
+            dec_int_byref::<u64>(memory, 16)
+        });
+        assert_eq!(value.expect("fd_read"), 7);
+    }
+
+    #[test]
+    fn cancelled_sleeps_return() {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+        for &virtual_clock in &[false, true] {
+            let token = CancelToken::new();
+            let mut builder = WasiCtxBuilder::new().cancel_token(token.clone());
+            if virtual_clock {
+                // The clock never moves, so only the cancellation ends the sleep.
+                builder = builder.monotonic_clock(clock.clone());
+            }
+            let ctx = builder.build().expect("build WasiCtx");
+
+            let host = std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                token.cancel();
+            });
+            let start = Instant::now();
+            let err = guest_sleep(&ctx, wasi::__WASI_CLOCKID_MONOTONIC, 60_000_000_000, 0)
+                .expect_err("cancelled");
+            assert_eq!(err.as_wasi_error(), crate::error::WasiError::EINTR);
+            assert!(
+                start.elapsed() < Duration::from_secs(1),
+                "missed the cancellation"
+            );
+            host.join().unwrap();
+
+            // The token stays cancelled.
+            let err =
+                guest_sleep(&ctx, wasi::__WASI_CLOCKID_MONOTONIC, 1, 0).expect_err("cancelled");
+            assert_eq!(err.as_wasi_error(), crate::error::WasiError::EINTR);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cancelled_polls_return() {
+        use std::os::unix::io::FromRawFd;
+
+        for &on_virtual_file in &[false, true] {
+            let token = CancelToken::new();
+            let mut ctx = WasiCtxBuilder::new()
+                .stdin_virtual(Channel::default())
+                .cancel_token(token.clone())
+                .build()
+                .expect("build WasiCtx");
+            // A host pipe which never becomes readable, or a virtual file which never does.
+            let mut fds = [0; 2];
+            assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+            let (reader, writer) = unsafe {
+                (
+                    std::fs::File::from_raw_fd(fds[0]),
+                    std::fs::File::from_raw_fd(fds[1]),
+                )
+            };
+            let fd = if on_virtual_file {
+                0
+            } else {
+                ctx.insert_fd_entry(crate::fdentry::FdEntry::from(reader).unwrap())
+                    .expect("insert pipe")
+            };
+
+            let host = std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                token.cancel();
+            });
+            let subscriptions = [
+                fd_read_subscription(fd, 0x5678),
+                clock_subscription(wasi::__WASI_CLOCKID_MONOTONIC, 60_000_000_000, 0),
+            ];
+            let start = Instant::now();
+            let err = guest_poll(&ctx, &subscriptions).expect_err("cancelled");
+            assert_eq!(err.as_wasi_error(), crate::error::WasiError::EINTR);
+            assert!(
+                start.elapsed() < Duration::from_secs(1),
+                "missed the cancellation"
+            );
+            host.join().unwrap();
+            drop(writer);
+        }
+    }
 }
@@ -5,5 +5,6 @@ mod sock;
 
 pub(crate) use self::fs::*;
 pub(crate) use self::fs_helpers::PathGet;
+pub use self::fs_helpers::SymlinkPolicy;
 pub(crate) use self::misc::*;
 pub(crate) use self::sock::*;
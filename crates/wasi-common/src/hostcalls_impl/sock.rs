@@ -1,36 +1,224 @@
 use crate::ctx::WasiCtx;
-use crate::{wasi, wasi32, Result};
+use crate::fdentry::Descriptor;
+use crate::memory::{dec_ciovec_views, dec_iovec_views, enc_int_byref, enc_usize_byref};
+use crate::sys::hostcalls_impl;
+use crate::{wasi, wasi32, Error, Result};
+use log::trace;
+use std::io;
+use std::net::Shutdown;
 
 pub fn sock_recv(
-    _wasi_ctx: &WasiCtx,
-    _memory: &mut [u8],
-    _sock: wasi::__wasi_fd_t,
-    _ri_data: wasi32::uintptr_t,
-    _ri_data_len: wasi32::size_t,
-    _ri_flags: wasi::__wasi_riflags_t,
-    _ro_datalen: wasi32::uintptr_t,
-    _ro_flags: wasi32::uintptr_t,
+    wasi_ctx: &WasiCtx,
+    memory: &mut [u8],
+    sock: wasi::__wasi_fd_t,
+    ri_data: wasi32::uintptr_t,
+    ri_data_len: wasi32::size_t,
+    ri_flags: wasi::__wasi_riflags_t,
+    ro_datalen: wasi32::uintptr_t,
+    ro_flags: wasi32::uintptr_t,
 ) -> Result<()> {
-    unimplemented!("sock_recv")
+    trace!(
+        "sock_recv(sock={:?}, ri_data={:#x?}, ri_data_len={}, ri_flags={:#x?}, ro_datalen={:#x?}, ro_flags={:#x?})",
+        sock,
+        ri_data,
+        ri_data_len,
+        ri_flags,
+        ro_datalen,
+        ro_flags
+    );
+
+    if ri_flags & !(wasi::__WASI_RIFLAGS_RECV_PEEK | wasi::__WASI_RIFLAGS_RECV_WAITALL) != 0 {
+        return Err(Error::EINVAL);
+    }
+    let descriptor =
+        unsafe { wasi_ctx.get_fd_entry(sock)? }.as_socket(wasi::__WASI_RIGHTS_FD_READ, 0)?;
+    let mut bufs: Vec<io::IoSliceMut> = dec_iovec_views(memory, ri_data, ri_data_len)?
+        .into_iter()
+        .map(io::IoSliceMut::new)
+        .collect();
+
+    let (host_nread, host_ro_flags) = match descriptor {
+        Descriptor::VirtualFile(_) => return Err(Error::ENOTSUP),
+        _ => hostcalls_impl::sock_recv(descriptor, &mut bufs, ri_flags)?,
+    };
+
+    trace!("     | *ro_datalen={:?}", host_nread);
+    trace!("     | *ro_flags={:#x?}", host_ro_flags);
+
+    enc_usize_byref(memory, ro_datalen, host_nread)?;
+    enc_int_byref(memory, ro_flags, host_ro_flags)
 }
 
 pub fn sock_send(
-    _wasi_ctx: &WasiCtx,
-    _memory: &mut [u8],
-    _sock: wasi::__wasi_fd_t,
-    _si_data: wasi32::uintptr_t,
-    _si_data_len: wasi32::size_t,
-    _si_flags: wasi::__wasi_siflags_t,
-    _so_datalen: wasi32::uintptr_t,
+    wasi_ctx: &WasiCtx,
+    memory: &mut [u8],
+    sock: wasi::__wasi_fd_t,
+    si_data: wasi32::uintptr_t,
+    si_data_len: wasi32::size_t,
+    si_flags: wasi::__wasi_siflags_t,
+    so_datalen: wasi32::uintptr_t,
 ) -> Result<()> {
-    unimplemented!("sock_send")
+    trace!(
+        "sock_send(sock={:?}, si_data={:#x?}, si_data_len={}, si_flags={:#x?}, so_datalen={:#x?})",
+        sock,
+        si_data,
+        si_data_len,
+        si_flags,
+        so_datalen
+    );
+
+    // No flags are defined for sending.
+    if si_flags != 0 {
+        return Err(Error::EINVAL);
+    }
+    let descriptor =
+        unsafe { wasi_ctx.get_fd_entry(sock)? }.as_socket(wasi::__WASI_RIGHTS_FD_WRITE, 0)?;
+    let bufs: Vec<io::IoSlice> = dec_ciovec_views(memory, si_data, si_data_len)?
+        .into_iter()
+        .map(io::IoSlice::new)
+        .collect();
+
+    let host_nwritten = match descriptor {
+        Descriptor::VirtualFile(_) => return Err(Error::ENOTSUP),
+        _ => hostcalls_impl::sock_send(descriptor, &bufs)?,
+    };
+
+    trace!("     | *so_datalen={:?}", host_nwritten);
+
+    enc_usize_byref(memory, so_datalen, host_nwritten)
 }
 
 pub fn sock_shutdown(
-    _wasi_ctx: &WasiCtx,
+    wasi_ctx: &WasiCtx,
     _memory: &mut [u8],
-    _sock: wasi::__wasi_fd_t,
-    _how: wasi::__wasi_sdflags_t,
+    sock: wasi::__wasi_fd_t,
+    how: wasi::__wasi_sdflags_t,
 ) -> Result<()> {
-    unimplemented!("sock_shutdown")
+    trace!("sock_shutdown(sock={:?}, how={:#x?})", sock, how);
+
+    let descriptor =
+        unsafe { wasi_ctx.get_fd_entry(sock)? }.as_socket(wasi::__WASI_RIGHTS_SOCK_SHUTDOWN, 0)?;
+    // Shutting down neither direction, or some direction which doesn't exist, is an error rather
+    // than a no-op, as `shutdown(2)` would have it.
+    let how = match how {
+        wasi::__WASI_SDFLAGS_RD => Shutdown::Read,
+        wasi::__WASI_SDFLAGS_WR => Shutdown::Write,
+        _ if how == wasi::__WASI_SDFLAGS_RD | wasi::__WASI_SDFLAGS_WR => Shutdown::Both,
+        _ => return Err(Error::EINVAL),
+    };
+
+    match descriptor {
+        Descriptor::VirtualFile(_) => Err(Error::ENOTSUP),
+        _ => hostcalls_impl::sock_shutdown(descriptor, how),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use crate::ctx::WasiCtxBuilder;
+    use crate::error::WasiError;
+    use crate::fdentry::FdEntry;
+    use crate::test_helpers::with_memory;
+    use std::io::{Read, Write};
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::{UnixDatagram, UnixStream};
+
+    fn insert_socket(ctx: &mut crate::WasiCtx, socket: impl IntoRawFd) -> wasi::__wasi_fd_t {
+        let socket = unsafe { std::fs::File::from_raw_fd(socket.into_raw_fd()) };
+        ctx.insert_fd_entry(FdEntry::from(socket).unwrap())
+            .expect("insert socket")
+    }
+
+    #[test]
+    fn half_close() {
+        let mut ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        let (guest, mut host) = UnixStream::pair().expect("socket pair");
+        let fd = insert_socket(&mut ctx, guest);
+        with_memory(8, |memory| {
+            let errno = |res: Result<()>| res.unwrap_err().as_wasi_error();
+
+            assert_eq!(errno(sock_shutdown(&ctx, memory, fd, 0)), WasiError::EINVAL);
+            assert_eq!(errno(sock_shutdown(&ctx, memory, fd, 4)), WasiError::EINVAL);
+
+            // Once the guest is done writing, the host sees the end of the stream, and can still
+            // answer.
+            sock_shutdown(&ctx, memory, fd, wasi::__WASI_SDFLAGS_WR).expect("shut down writes");
+            let mut request = vec![];
+            host.read_to_end(&mut request).unwrap();
+            assert!(request.is_empty());
+            host.write_all(b"response").unwrap();
+
+            let both = wasi::__WASI_SDFLAGS_RD | wasi::__WASI_SDFLAGS_WR;
+            sock_shutdown(&ctx, memory, fd, both).expect("shut down both");
+        });
+    }
+
+    #[test]
+    fn send_and_recv_through_views() {
+        let mut ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        let (guest, mut host) = UnixStream::pair().expect("socket pair");
+        let fd = insert_socket(&mut ctx, guest);
+        // iovecs for 5 bytes at 32 and 6 at 40, the `size_t` and `roflags` results at 16 and
+        // 20, and a ciovec for the 3 bytes at 48.
+        with_memory(64, |memory| {
+            for (i, &word) in [32u32, 5, 40, 6, 0, 0, 48, 3].iter().enumerate() {
+                memory[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+            }
+            memory[48..51].copy_from_slice(b"abc");
+            let datalen = |memory: &[u8]| {
+                u32::from_le_bytes([memory[16], memory[17], memory[18], memory[19]])
+            };
+
+            host.write_all(b"hello world").unwrap();
+            // Peeking leaves the data to be received again.
+            let peek = wasi::__WASI_RIFLAGS_RECV_PEEK;
+            sock_recv(&ctx, memory, fd, 0, 2, peek, 16, 20).expect("peek");
+            assert_eq!(datalen(memory), 11);
+            memory[32..51].iter_mut().for_each(|byte| *byte = 0);
+            memory[48..51].copy_from_slice(b"abc");
+            sock_recv(&ctx, memory, fd, 0, 2, 0, 16, 20).expect("sock_recv");
+            assert_eq!(datalen(memory), 11);
+            assert_eq!(&memory[32..37], b"hello");
+            assert_eq!(&memory[40..46], b" world");
+            assert_eq!(&memory[20..22], &[0, 0]);
+
+            sock_send(&ctx, memory, fd, 24, 1, 0, 16).expect("sock_send");
+            assert_eq!(datalen(memory), 3);
+            let mut response = [0; 3];
+            host.read_exact(&mut response).unwrap();
+            assert_eq!(&response, b"abc");
+
+            let errno = |res: Result<()>| res.unwrap_err().as_wasi_error();
+            assert_eq!(
+                errno(sock_recv(&ctx, memory, fd, 0, 2, 0x80, 16, 20)),
+                WasiError::EINVAL
+            );
+            assert_eq!(
+                errno(sock_send(&ctx, memory, fd, 24, 1, 1, 16)),
+                WasiError::EINVAL
+            );
+        });
+    }
+
+    #[test]
+    fn truncated_datagrams() {
+        let mut ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        let (guest, host) = UnixDatagram::pair().expect("socket pair");
+        let fd = insert_socket(&mut ctx, guest);
+        // An iovec for 4 bytes at 16, and the results at 8 and 12.
+        with_memory(32, |memory| {
+            memory[0..4].copy_from_slice(&16u32.to_le_bytes());
+            memory[4..8].copy_from_slice(&4u32.to_le_bytes());
+
+            host.send(b"datagram").unwrap();
+            sock_recv(&ctx, memory, fd, 0, 1, 0, 8, 12).expect("sock_recv");
+            assert_eq!(&memory[8..12], &4u32.to_le_bytes());
+            assert_eq!(
+                &memory[12..14],
+                &wasi::__WASI_ROFLAGS_RECV_DATA_TRUNCATED.to_le_bytes()
+            );
+            assert_eq!(&memory[16..20], b"data");
+        });
+    }
 }
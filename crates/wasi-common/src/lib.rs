@@ -21,17 +21,29 @@
     )
 )]
 
+mod call_filter;
+mod call_log;
+mod cancel;
+mod clock;
 mod ctx;
 mod error;
 mod fdentry;
+mod fdtable;
 pub mod fs;
 mod helpers;
 mod host;
 mod hostcalls_impl;
 mod memory;
+mod newline_translating_writer;
 pub mod old;
+mod quota;
+mod random;
 mod sandboxed_tty_writer;
 mod sys;
+#[cfg(test)]
+mod test_helpers;
+mod usage;
+mod virtfile;
 pub mod wasi;
 pub mod wasi32;
 
@@ -39,8 +51,16 @@ pub mod hostcalls {
     wig::define_hostcalls!("snapshot" "wasi_snapshot_preview1");
 }
 
-pub use ctx::{WasiCtx, WasiCtxBuilder};
+pub use call_log::CallRecord;
+pub use cancel::CancelToken;
+pub use clock::{ManualClock, OffsetSign, WasiMonotonicClock, WasiSystemClock};
+pub use ctx::{ExitStatus, WasiCtx, WasiCtxBuilder};
+pub use fdentry::{DescriptorInfo, DescriptorKind};
+pub use hostcalls_impl::SymlinkPolicy;
+pub use random::{CallbackRng, RandomBudgetPolicy, RandomLog, RecordingRng, ReplayRng};
 pub use sys::preopen_dir;
+pub use usage::UsageReport;
+pub use virtfile::{Notifier, PollWaker, ReadPipe, Readiness, VirtualFile, WritePipe};
 
 pub use error::Error;
 pub(crate) use error::Result;
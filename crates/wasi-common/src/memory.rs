@@ -14,6 +14,29 @@ use std::convert::TryFrom;
 use std::mem::{align_of, size_of};
 use std::{ptr, slice};
 
+/// Check the size and alignment of the structs crossing the guest boundary against their
+/// wasm32 layout, so that a mismatch fails the build rather than corrupting guest memory. Their
+/// field offsets are pinned by the layout tests next to their definitions.
+macro_rules! assert_guest_layout {
+    ($($ty:ty: size $size:expr, align $align:expr;)*) => {
+        $(
+            const _: [(); $size] = [(); size_of::<$ty>()];
+            const _: [(); $align] = [(); align_of::<$ty>()];
+        )*
+    };
+}
+
+assert_guest_layout! {
+    wasi::__wasi_dirent_t: size 24, align 8;
+    wasi::__wasi_event_t: size 32, align 8;
+    wasi::__wasi_fdstat_t: size 24, align 8;
+    wasi::__wasi_filestat_t: size 64, align 8;
+    wasi::__wasi_subscription_t: size 48, align 8;
+    wasi32::__wasi_ciovec_t: size 8, align 4;
+    wasi32::__wasi_iovec_t: size 8, align 4;
+    wasi32::__wasi_prestat_t: size 8, align 4;
+}
+
 fn dec_ptr(memory: &[u8], ptr: wasi32::uintptr_t, len: usize) -> Result<*const u8> {
     // check for overflow
     let checked_len = (ptr as usize).checked_add(len).ok_or(Error::EFAULT)?;
@@ -67,6 +90,16 @@ fn enc_raw_byref<T>(memory: &mut [u8], ptr: wasi32::uintptr_t, t: T) -> Result<(
     dec_ptr_to_mut::<T>(memory, ptr).map(|p| unsafe { ptr::write(p, t) })
 }
 
+/// Encode a struct by setting its fields one at a time, right where `raw` is in guest memory.
+/// The struct is zeroed first; writing it as a whole would also copy the host's padding bytes,
+/// whose values are undefined, into the guest.
+///
+/// This function does not perform endianness conversions!
+fn enc_raw_fields<T>(raw: &mut T, set_fields: impl FnOnce(&mut T)) {
+    unsafe { ptr::write_bytes(raw as *mut T, 0, 1) };
+    set_fields(raw)
+}
+
 pub(crate) fn dec_int_byref<T>(memory: &[u8], ptr: wasi32::uintptr_t) -> Result<T>
 where
     T: PrimInt,
@@ -116,15 +149,22 @@ fn dec_raw_slice_of_mut<'memory, T>(
     Ok(unsafe { slice::from_raw_parts_mut(ptr, len) })
 }
 
+/// Get the part of the guest's buffer at `ptr`, holding `capacity` elements of `T`, that
+/// `slice` is to be encoded into.
 fn raw_slice_for_enc<'memory, T>(
     memory: &'memory mut [u8],
     slice: &[T],
     ptr: wasi32::uintptr_t,
+    capacity: wasi32::size_t,
 ) -> Result<&'memory mut [T]> {
     // check alignment
     if ptr as usize % align_of::<T>() != 0 {
         return Err(Error::EINVAL);
     }
+    // check that the guest's buffer is big enough
+    if slice.len() > dec_usize(capacity) {
+        return Err(Error::EMSGSIZE);
+    }
     // check that length doesn't overflow
     let len_bytes = if let Some(len) = size_of::<T>().checked_mul(slice.len()) {
         len
@@ -146,6 +186,25 @@ pub(crate) fn dec_slice_of_u8<'memory>(
     dec_raw_slice_of::<u8>(memory, ptr, len)
 }
 
+/// Decode a string the guest passed at `ptr`, such as a path.
+///
+/// Every string input from the guest goes through here, so that they all fail the same way:
+/// bytes which aren't valid UTF-8, overlong encodings and unpaired surrogates included, are an
+/// encoding violation, and fail with `Error::EILSEQ`. A NUL byte is valid UTF-8, but can't be
+/// passed on to the host, so it fails with `Error::EINVAL`, like any other value which decodes
+/// fine but isn't acceptable. An empty string decodes fine; what it means is up to the caller.
+pub(crate) fn dec_str<'memory>(
+    memory: &'memory [u8],
+    ptr: wasi32::uintptr_t,
+    len: wasi32::size_t,
+) -> Result<&'memory str> {
+    let s = std::str::from_utf8(dec_slice_of_u8(memory, ptr, len)?).map_err(|_| Error::EILSEQ)?;
+    if s.contains('\0') {
+        return Err(Error::EINVAL);
+    }
+    Ok(s)
+}
+
 pub(crate) fn dec_slice_of_mut_u8<'memory>(
     memory: &'memory mut [u8],
     ptr: wasi32::uintptr_t,
@@ -154,24 +213,29 @@ pub(crate) fn dec_slice_of_mut_u8<'memory>(
     dec_raw_slice_of_mut::<u8>(memory, ptr, len)
 }
 
+/// Copy `slice` into the guest's buffer at `ptr`, which holds `capacity` bytes.
 pub(crate) fn enc_slice_of_u8(
     memory: &mut [u8],
     slice: &[u8],
     ptr: wasi32::uintptr_t,
+    capacity: wasi32::size_t,
 ) -> Result<()> {
-    let output = raw_slice_for_enc::<u8>(memory, slice, ptr)?;
+    let output = raw_slice_for_enc::<u8>(memory, slice, ptr, capacity)?;
 
     output.copy_from_slice(slice);
 
     Ok(())
 }
 
+/// Copy `slice` into the guest's array at `ptr`, which holds `capacity` pointers.
 pub(crate) fn enc_slice_of_wasi32_uintptr(
     memory: &mut [u8],
     slice: &[wasi32::uintptr_t],
     ptr: wasi32::uintptr_t,
+    capacity: wasi32::size_t,
 ) -> Result<()> {
-    let mut output_iter = raw_slice_for_enc::<wasi32::uintptr_t>(memory, slice, ptr)?.into_iter();
+    let mut output_iter =
+        raw_slice_for_enc::<wasi32::uintptr_t>(memory, slice, ptr, capacity)?.into_iter();
 
     for p in slice {
         *output_iter.next().unwrap() = PrimInt::to_le(*p);
@@ -196,44 +260,80 @@ macro_rules! dec_enc_scalar {
     };
 }
 
-pub(crate) fn dec_ciovec_slice(
+/// Decode the buffers of an iovec array, whose `(buf, buf_len)` pairs are still in wasm
+/// linear-memory order, into `(offset, len)` pairs. Every buffer has to lie within `memory`
+/// (`EFAULT` otherwise), and the lengths have to add up to something a `wasi32::size_t` can
+/// hold, since the number of bytes read or written is returned in one (`EINVAL` otherwise).
+fn dec_iovec_bufs(
     memory: &[u8],
+    raw_bufs: impl Iterator<Item = (wasi32::uintptr_t, wasi32::size_t)>,
+) -> Result<Vec<(usize, usize)>> {
+    let mut total_len: wasi32::size_t = 0;
+
+    raw_bufs
+        .map(|(buf, buf_len)| {
+            let buf = PrimInt::from_le(buf);
+            let buf_len = PrimInt::from_le(buf_len);
+            dec_ptr(memory, buf, dec_usize(buf_len))?;
+            total_len = total_len.checked_add(buf_len).ok_or(Error::EINVAL)?;
+            Ok((buf as usize, dec_usize(buf_len)))
+        })
+        .collect()
+}
+
+/// Borrow the buffers of a ciovec array straight out of `memory`, for the host to write from.
+///
+/// The views borrow `memory`, so they can't outlive the hostcall, nor can the memory be grown
+/// (and moved) while they're held.
+pub(crate) fn dec_ciovec_views<'memory>(
+    memory: &'memory [u8],
     ptr: wasi32::uintptr_t,
     len: wasi32::size_t,
-) -> Result<Vec<host::__wasi_ciovec_t>> {
+) -> Result<Vec<&'memory [u8]>> {
     let raw_slice = dec_raw_slice_of::<wasi32::__wasi_ciovec_t>(memory, ptr, len)?;
+    let bufs = dec_iovec_bufs(memory, raw_slice.iter().map(|iov| (iov.buf, iov.buf_len)))?;
 
-    raw_slice
-        .iter()
-        .map(|raw_iov| {
-            let len = dec_usize(PrimInt::from_le(raw_iov.buf_len));
-            let buf = PrimInt::from_le(raw_iov.buf);
-            Ok(host::__wasi_ciovec_t {
-                buf: dec_ptr(memory, buf, len)? as *const u8,
-                buf_len: len,
-            })
-        })
-        .collect()
+    Ok(bufs
+        .into_iter()
+        .map(|(offset, len)| &memory[offset..offset + len])
+        .collect())
 }
 
-pub(crate) fn dec_iovec_slice(
-    memory: &[u8],
+/// Borrow the buffers of an iovec array straight out of `memory`, for the host to read into,
+/// with the same lifetime as `dec_ciovec_views`.
+///
+/// The buffers become separate `&mut [u8]`s, so ones that overlap are rejected with `EFAULT`.
+/// Empty buffers overlap nothing.
+pub(crate) fn dec_iovec_views<'memory>(
+    memory: &'memory mut [u8],
     ptr: wasi32::uintptr_t,
     len: wasi32::size_t,
-) -> Result<Vec<host::__wasi_iovec_t>> {
-    let raw_slice = dec_raw_slice_of::<wasi32::__wasi_iovec_t>(memory, ptr, len)?;
-
-    raw_slice
-        .iter()
-        .map(|raw_iov| {
-            let len = dec_usize(PrimInt::from_le(raw_iov.buf_len));
-            let buf = PrimInt::from_le(raw_iov.buf);
-            Ok(host::__wasi_iovec_t {
-                buf: dec_ptr(memory, buf, len)? as *mut u8,
-                buf_len: len,
-            })
-        })
-        .collect()
+) -> Result<Vec<&'memory mut [u8]>> {
+    let bufs = {
+        let raw_slice = dec_raw_slice_of::<wasi32::__wasi_iovec_t>(memory, ptr, len)?;
+        dec_iovec_bufs(memory, raw_slice.iter().map(|iov| (iov.buf, iov.buf_len)))?
+    };
+
+    // Carve the buffers out of `memory` lowest first, then put them back in the guest's order.
+    let mut by_offset: Vec<_> = (0..bufs.len()).filter(|&i| bufs[i].1 > 0).collect();
+    by_offset.sort_unstable_by_key(|&i| bufs[i].0);
+
+    let mut views: Vec<&'memory mut [u8]> = bufs.iter().map(|_| Default::default()).collect();
+    let mut rest = memory;
+    let mut rest_offset = 0;
+    for i in by_offset {
+        let (offset, len) = bufs[i];
+        if offset < rest_offset {
+            return Err(Error::EFAULT);
+        }
+        let (_, tail) = std::mem::replace(&mut rest, &mut []).split_at_mut(offset - rest_offset);
+        let (view, tail) = tail.split_at_mut(len);
+        views[i] = view;
+        rest = tail;
+        rest_offset = offset + len;
+    }
+
+    Ok(views)
 }
 
 dec_enc_scalar!(__wasi_clockid_t, dec_clockid_byref, enc_clockid_byref);
@@ -268,18 +368,20 @@ pub(crate) fn enc_filestat_byref(
     filestat_ptr: wasi32::uintptr_t,
     filestat: wasi::__wasi_filestat_t,
 ) -> Result<()> {
-    let raw = wasi::__wasi_filestat_t {
-        dev: PrimInt::to_le(filestat.dev),
-        ino: PrimInt::to_le(filestat.ino),
-        filetype: PrimInt::to_le(filestat.filetype),
-        nlink: PrimInt::to_le(filestat.nlink),
-        size: PrimInt::to_le(filestat.size),
-        atim: PrimInt::to_le(filestat.atim),
-        mtim: PrimInt::to_le(filestat.mtim),
-        ctim: PrimInt::to_le(filestat.ctim),
-    };
+    let raw = dec_ptr_to_mut::<wasi::__wasi_filestat_t>(memory, filestat_ptr)?;
+
+    enc_raw_fields(raw, |raw| {
+        raw.dev = PrimInt::to_le(filestat.dev);
+        raw.ino = PrimInt::to_le(filestat.ino);
+        raw.filetype = PrimInt::to_le(filestat.filetype);
+        raw.nlink = PrimInt::to_le(filestat.nlink);
+        raw.size = PrimInt::to_le(filestat.size);
+        raw.atim = PrimInt::to_le(filestat.atim);
+        raw.mtim = PrimInt::to_le(filestat.mtim);
+        raw.ctim = PrimInt::to_le(filestat.ctim);
+    });
 
-    enc_raw_byref::<wasi::__wasi_filestat_t>(memory, filestat_ptr, raw)
+    Ok(())
 }
 
 pub(crate) fn dec_fdstat_byref(
@@ -301,14 +403,16 @@ pub(crate) fn enc_fdstat_byref(
     fdstat_ptr: wasi32::uintptr_t,
     fdstat: wasi::__wasi_fdstat_t,
 ) -> Result<()> {
-    let raw = wasi::__wasi_fdstat_t {
-        fs_filetype: PrimInt::to_le(fdstat.fs_filetype),
-        fs_flags: PrimInt::to_le(fdstat.fs_flags),
-        fs_rights_base: PrimInt::to_le(fdstat.fs_rights_base),
-        fs_rights_inheriting: PrimInt::to_le(fdstat.fs_rights_inheriting),
-    };
+    let raw = dec_ptr_to_mut::<wasi::__wasi_fdstat_t>(memory, fdstat_ptr)?;
+
+    enc_raw_fields(raw, |raw| {
+        raw.fs_filetype = PrimInt::to_le(fdstat.fs_filetype);
+        raw.fs_flags = PrimInt::to_le(fdstat.fs_flags);
+        raw.fs_rights_base = PrimInt::to_le(fdstat.fs_rights_base);
+        raw.fs_rights_inheriting = PrimInt::to_le(fdstat.fs_rights_inheriting);
+    });
 
-    enc_raw_byref::<wasi::__wasi_fdstat_t>(memory, fdstat_ptr, raw)
+    Ok(())
 }
 
 dec_enc_scalar!(__wasi_filedelta_t, dec_filedelta_byref, enc_filedelta_byref);
@@ -347,19 +451,22 @@ pub(crate) fn enc_prestat_byref(
     prestat_ptr: wasi32::uintptr_t,
     prestat: host::__wasi_prestat_t,
 ) -> Result<()> {
-    let raw = match prestat.pr_type {
-        wasi::__WASI_PREOPENTYPE_DIR => Ok(wasi32::__wasi_prestat_t {
-            pr_type: PrimInt::to_le(wasi::__WASI_PREOPENTYPE_DIR),
-            u: wasi32::__wasi_prestat_u_t {
-                dir: wasi32::__wasi_prestat_dir_t {
-                    pr_name_len: enc_usize(unsafe { prestat.u.dir.pr_name_len }),
-                },
+    if prestat.pr_type != wasi::__WASI_PREOPENTYPE_DIR {
+        return Err(Error::EINVAL);
+    }
+    let pr_name_len = enc_usize(unsafe { prestat.u.dir.pr_name_len });
+    let raw = dec_ptr_to_mut::<wasi32::__wasi_prestat_t>(memory, prestat_ptr)?;
+
+    enc_raw_fields(raw, |raw| {
+        raw.pr_type = PrimInt::to_le(wasi::__WASI_PREOPENTYPE_DIR);
+        raw.u = wasi32::__wasi_prestat_u_t {
+            dir: wasi32::__wasi_prestat_dir_t {
+                pr_name_len: PrimInt::to_le(pr_name_len),
             },
-        }),
-        _ => Err(Error::EINVAL),
-    }?;
+        };
+    });
 
-    enc_raw_byref::<wasi32::__wasi_prestat_t>(memory, prestat_ptr, raw)
+    Ok(())
 }
 
 dec_enc_scalar!(__wasi_rights_t, dec_rights_byref, enc_rights_byref);
@@ -453,22 +560,19 @@ pub(crate) fn enc_events(
         dec_raw_slice_of_mut::<wasi::__wasi_event_t>(memory, output, nsubscriptions)?.into_iter();
 
     for event in events.iter() {
-        *raw_output_iter
+        let raw = raw_output_iter
             .next()
-            .expect("the number of events cannot exceed the number of subscriptions") = {
-            let fd_readwrite = unsafe { event.u.fd_readwrite };
-            wasi::__wasi_event_t {
-                userdata: PrimInt::to_le(event.userdata),
-                r#type: PrimInt::to_le(event.r#type),
-                error: PrimInt::to_le(event.error),
-                u: wasi::__wasi_event_u_t {
-                    fd_readwrite: wasi::__wasi_event_fd_readwrite_t {
-                        nbytes: PrimInt::to_le(fd_readwrite.nbytes),
-                        flags: PrimInt::to_le(fd_readwrite.flags),
-                    },
-                },
-            }
-        };
+            .expect("the number of events cannot exceed the number of subscriptions");
+        let fd_readwrite = unsafe { event.u.fd_readwrite };
+
+        enc_raw_fields(raw, |raw| {
+            raw.userdata = PrimInt::to_le(event.userdata);
+            raw.r#type = PrimInt::to_le(event.r#type);
+            raw.error = PrimInt::to_le(event.error);
+            let raw_fd_readwrite = unsafe { &mut raw.u.fd_readwrite };
+            raw_fd_readwrite.nbytes = PrimInt::to_le(fd_readwrite.nbytes);
+            raw_fd_readwrite.flags = PrimInt::to_le(fd_readwrite.flags);
+        });
     }
 
     Ok(())
@@ -477,3 +581,384 @@ pub(crate) fn enc_events(
 dec_enc_scalar!(__wasi_advice_t, dec_advice_byref, enc_advice_byref);
 dec_enc_scalar!(__wasi_fstflags_t, dec_fstflags_byref, enc_fstflags_byref);
 dec_enc_scalar!(__wasi_dircookie_t, dec_dircookie_byref, enc_dircookie_byref);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::WasiError;
+    use crate::test_helpers::with_memory;
+
+    const MEMORY_LEN: usize = 4096;
+    /// Where the iovec arrays are placed.
+    const IOVS_PTR: wasi32::uintptr_t = 1024;
+
+    fn enc_iovecs(memory: &mut [u8], iovs: &[(wasi32::uintptr_t, wasi32::size_t)]) {
+        for (i, &(buf, buf_len)) in iovs.iter().enumerate() {
+            let ptr = IOVS_PTR + 8 * i as wasi32::uintptr_t;
+            enc_int_byref(memory, ptr, buf).unwrap();
+            enc_int_byref(memory, ptr + 4, buf_len).unwrap();
+        }
+    }
+
+    fn errno<T>(res: Result<T>) -> Option<WasiError> {
+        res.err().map(|err| err.as_wasi_error())
+    }
+
+    /// What decoding `iovs` should come to, going by the buffers one at a time.
+    fn expected(
+        iovs: &[(wasi32::uintptr_t, wasi32::size_t)],
+    ) -> std::result::Result<usize, WasiError> {
+        let mut total: wasi32::size_t = 0;
+        for &(buf, buf_len) in iovs {
+            if buf as u64 + buf_len as u64 > MEMORY_LEN as u64 {
+                return Err(WasiError::EFAULT);
+            }
+            total = total.checked_add(buf_len).ok_or(WasiError::EINVAL)?;
+        }
+        Ok(dec_usize(total))
+    }
+
+    fn overlap(iovs: &[(wasi32::uintptr_t, wasi32::size_t)]) -> bool {
+        iovs.iter().enumerate().any(|(i, &(a, a_len))| {
+            iovs[..i]
+                .iter()
+                .any(|&(b, b_len)| a_len > 0 && b_len > 0 && a < b + b_len && b < a + a_len)
+        })
+    }
+
+    #[test]
+    fn iovec_edge_cases() {
+        with_memory(MEMORY_LEN, |memory| {
+            let end = MEMORY_LEN as wasi32::uintptr_t;
+            let last = memory.as_ptr() as usize + MEMORY_LEN - 4;
+            enc_iovecs(memory, &[(end - 4, 4), (end, 0), (0, 0)]);
+            let mut iovs = dec_iovec_views(memory, IOVS_PTR, 3).unwrap();
+            let lens: Vec<_> = iovs.iter().map(|iov| iov.len()).collect();
+            assert_eq!(lens, [4, 0, 0]);
+            assert_eq!(iovs[0].as_ptr() as usize, last);
+            iovs[0].copy_from_slice(b"last");
+            assert_eq!(&memory[MEMORY_LEN - 4..], b"last");
+
+            enc_iovecs(memory, &[(end - 4, 5)]);
+            assert_eq!(
+                errno(dec_ciovec_views(memory, IOVS_PTR, 1)),
+                Some(WasiError::EFAULT)
+            );
+            enc_iovecs(memory, &[(end + 1, 0)]);
+            assert_eq!(
+                errno(dec_ciovec_views(memory, IOVS_PTR, 1)),
+                Some(WasiError::EFAULT)
+            );
+            enc_iovecs(memory, &[(wasi32::uintptr_t::max_value(), 2)]);
+            assert_eq!(
+                errno(dec_ciovec_views(memory, IOVS_PTR, 1)),
+                Some(WasiError::EFAULT)
+            );
+
+            // Overlapping buffers are fine to write from, but not to read into.
+            enc_iovecs(memory, &[(16, 8), (0, 0), (20, 8)]);
+            assert_eq!(dec_ciovec_views(memory, IOVS_PTR, 3).unwrap().len(), 3);
+            assert_eq!(
+                errno(dec_iovec_views(memory, IOVS_PTR, 3)),
+                Some(WasiError::EFAULT)
+            );
+            enc_iovecs(memory, &[(24, 8), (20, 0), (16, 8)]);
+            let iovs = dec_iovec_views(memory, IOVS_PTR, 3).unwrap();
+            let lens: Vec<_> = iovs.iter().map(|iov| iov.len()).collect();
+            assert_eq!(lens, [8, 0, 8]);
+
+            // A misaligned iovec array, and one running off the end of memory.
+            assert_eq!(
+                errno(dec_iovec_views(memory, IOVS_PTR + 2, 1)),
+                Some(WasiError::EINVAL)
+            );
+            assert_eq!(
+                errno(dec_iovec_views(
+                    memory,
+                    IOVS_PTR,
+                    wasi32::size_t::max_value()
+                )),
+                Some(WasiError::EFAULT)
+            );
+        })
+    }
+
+    #[test]
+    fn iovec_total_overflows_size_t() {
+        // Overflowing a `size_t` takes a lot of iovecs, so the buffers are fed to
+        // `dec_iovec_bufs` directly rather than laid out as an iovec array.
+        with_memory(MEMORY_LEN, |memory| {
+            let big = [(0, 0)]
+                .iter()
+                .copied()
+                .chain(std::iter::repeat((0, 2048)).take((1 << 21) - 1));
+            assert!(dec_iovec_bufs(memory, big).is_ok());
+            let too_big = std::iter::repeat((0, 2048)).take(1 << 21);
+            assert_eq!(
+                errno(dec_iovec_bufs(memory, too_big)),
+                Some(WasiError::EINVAL)
+            );
+        })
+    }
+
+    #[test]
+    fn enc_into_guest_buffer() {
+        with_memory(MEMORY_LEN, |memory| {
+            let end = MEMORY_LEN as wasi32::uintptr_t;
+            enc_slice_of_u8(memory, b"abc", 8, 3).unwrap();
+            assert_eq!(&memory[8..12], b"abc\0");
+            enc_slice_of_u8(memory, b"xy", 8, 3).unwrap();
+            assert_eq!(&memory[8..12], b"xyc\0");
+            assert_eq!(
+                errno(enc_slice_of_u8(memory, b"abcd", 16, 3)),
+                Some(WasiError::EMSGSIZE)
+            );
+            assert_eq!(&memory[16..20], [0; 4]);
+
+            // Right up to the end of memory, and straddling it.
+            enc_slice_of_u8(memory, b"abc", end - 3, 3).unwrap();
+            assert_eq!(&memory[MEMORY_LEN - 3..], b"abc");
+            assert_eq!(
+                errno(enc_slice_of_u8(memory, b"abc", end - 2, 3)),
+                Some(WasiError::EFAULT)
+            );
+            assert_eq!(
+                errno(enc_slice_of_u8(memory, b"abc", end - 2, 100)),
+                Some(WasiError::EFAULT)
+            );
+            enc_slice_of_u8(memory, b"", end, 0).unwrap();
+
+            enc_slice_of_wasi32_uintptr(memory, &[1, 2], 24, 2).unwrap();
+            assert_eq!(dec_int_byref::<u32>(memory, 28).unwrap(), 2);
+            assert_eq!(
+                errno(enc_slice_of_wasi32_uintptr(memory, &[1, 2], 24, 1)),
+                Some(WasiError::EMSGSIZE)
+            );
+            assert_eq!(
+                errno(enc_slice_of_wasi32_uintptr(memory, &[1, 2], end - 4, 2)),
+                Some(WasiError::EFAULT)
+            );
+            assert_eq!(
+                errno(enc_slice_of_wasi32_uintptr(memory, &[1], 26, 1)),
+                Some(WasiError::EINVAL)
+            );
+        })
+    }
+
+    /// Concatenate the little-endian encodings of a struct's fields, and its padding.
+    fn golden(fields: &[&[u8]]) -> Vec<u8> {
+        fields.concat()
+    }
+
+    #[test]
+    fn filestat_layout() {
+        let filestat = wasi::__wasi_filestat_t {
+            dev: 0x0102_0304_0506_0708,
+            ino: 0x1112_1314_1516_1718,
+            filetype: wasi::__WASI_FILETYPE_REGULAR_FILE,
+            nlink: 2,
+            size: 0x2122_2324,
+            atim: 5,
+            mtim: 6,
+            ctim: 7,
+        };
+        let expected = golden(&[
+            &0x0102_0304_0506_0708u64.to_le_bytes(),
+            &0x1112_1314_1516_1718u64.to_le_bytes(),
+            &[wasi::__WASI_FILETYPE_REGULAR_FILE, 0, 0, 0, 0, 0, 0, 0],
+            &2u64.to_le_bytes(),
+            &0x2122_2324u64.to_le_bytes(),
+            &5u64.to_le_bytes(),
+            &6u64.to_le_bytes(),
+            &7u64.to_le_bytes(),
+        ]);
+
+        with_memory(MEMORY_LEN, |memory| {
+            memory.iter_mut().for_each(|b| *b = 0xff);
+            enc_filestat_byref(memory, 64, filestat).unwrap();
+            assert_eq!(&memory[64..128], &expected[..]);
+            assert_eq!(memory[128], 0xff);
+
+            let decoded = dec_filestat_byref(memory, 64).unwrap();
+            assert_eq!(decoded.dev, filestat.dev);
+            assert_eq!(decoded.ino, filestat.ino);
+            assert_eq!(decoded.filetype, filestat.filetype);
+            assert_eq!(decoded.nlink, filestat.nlink);
+            assert_eq!(decoded.size, filestat.size);
+            assert_eq!(decoded.atim, filestat.atim);
+            assert_eq!(decoded.mtim, filestat.mtim);
+            assert_eq!(decoded.ctim, filestat.ctim);
+        })
+    }
+
+    #[test]
+    fn fdstat_layout() {
+        let fdstat = wasi::__wasi_fdstat_t {
+            fs_filetype: wasi::__WASI_FILETYPE_DIRECTORY,
+            fs_flags: 0x0102,
+            fs_rights_base: 0x1112_1314_1516_1718,
+            fs_rights_inheriting: 0x2122_2324_2526_2728,
+        };
+        let expected = golden(&[
+            &[wasi::__WASI_FILETYPE_DIRECTORY, 0],
+            &0x0102u16.to_le_bytes(),
+            &[0; 4],
+            &0x1112_1314_1516_1718u64.to_le_bytes(),
+            &0x2122_2324_2526_2728u64.to_le_bytes(),
+        ]);
+
+        with_memory(MEMORY_LEN, |memory| {
+            memory.iter_mut().for_each(|b| *b = 0xff);
+            enc_fdstat_byref(memory, 8, fdstat).unwrap();
+            assert_eq!(&memory[8..32], &expected[..]);
+
+            let decoded = dec_fdstat_byref(memory, 8).unwrap();
+            assert_eq!(decoded.fs_filetype, fdstat.fs_filetype);
+            assert_eq!(decoded.fs_flags, fdstat.fs_flags);
+            assert_eq!(decoded.fs_rights_base, fdstat.fs_rights_base);
+            assert_eq!(decoded.fs_rights_inheriting, fdstat.fs_rights_inheriting);
+
+            // A misaligned struct is rejected before anything is written.
+            assert_eq!(
+                errno(enc_fdstat_byref(memory, 36, fdstat)),
+                Some(WasiError::EINVAL)
+            );
+            assert_eq!(memory[36..64], [0xff; 28][..]);
+        })
+    }
+
+    #[test]
+    fn prestat_layout() {
+        let prestat = host::__wasi_prestat_t {
+            pr_type: wasi::__WASI_PREOPENTYPE_DIR,
+            u: host::__wasi_prestat_u_t {
+                dir: host::__wasi_prestat_dir_t {
+                    pr_name_len: 0x0102_0304,
+                },
+            },
+        };
+        let expected = golden(&[&[wasi::__WASI_PREOPENTYPE_DIR, 0, 0, 0], &[4, 3, 2, 1]]);
+
+        with_memory(MEMORY_LEN, |memory| {
+            memory.iter_mut().for_each(|b| *b = 0xff);
+            enc_prestat_byref(memory, 4, prestat).unwrap();
+            assert_eq!(&memory[4..12], &expected[..]);
+
+            let decoded = dec_prestat_byref(memory, 4).unwrap();
+            assert_eq!(decoded.pr_type, wasi::__WASI_PREOPENTYPE_DIR);
+            assert_eq!(unsafe { decoded.u.dir.pr_name_len }, 0x0102_0304);
+        })
+    }
+
+    #[test]
+    fn event_layout() {
+        let event = wasi::__wasi_event_t {
+            userdata: 0x0102_0304_0506_0708,
+            error: wasi::__WASI_ERRNO_BADF,
+            r#type: wasi::__WASI_EVENTTYPE_FD_READ,
+            u: wasi::__wasi_event_u_t {
+                fd_readwrite: wasi::__wasi_event_fd_readwrite_t {
+                    nbytes: 0x1112_1314,
+                    flags: wasi::__WASI_EVENTRWFLAGS_FD_READWRITE_HANGUP,
+                },
+            },
+        };
+        let expected = golden(&[
+            &0x0102_0304_0506_0708u64.to_le_bytes(),
+            &wasi::__WASI_ERRNO_BADF.to_le_bytes(),
+            &[wasi::__WASI_EVENTTYPE_FD_READ, 0, 0, 0, 0, 0],
+            &0x1112_1314u64.to_le_bytes(),
+            &wasi::__WASI_EVENTRWFLAGS_FD_READWRITE_HANGUP.to_le_bytes(),
+            &[0; 6],
+        ]);
+
+        with_memory(MEMORY_LEN, |memory| {
+            memory.iter_mut().for_each(|b| *b = 0xff);
+            enc_events(memory, 64, 2, vec![event]).unwrap();
+            assert_eq!(&memory[64..96], &expected[..]);
+            // Only as many events as there are get written.
+            assert_eq!(memory[96..128], [0xff; 32][..]);
+        })
+    }
+
+    #[test]
+    fn subscription_layout() {
+        let raw = golden(&[
+            &0x0102_0304_0506_0708u64.to_le_bytes(),
+            &[wasi::__WASI_EVENTTYPE_CLOCK, 0, 0, 0, 0, 0, 0, 0],
+            &wasi::__WASI_CLOCKID_MONOTONIC.to_le_bytes(),
+            &[0; 4],
+            &0x1112_1314_1516_1718u64.to_le_bytes(),
+            &0x2122_2324u64.to_le_bytes(),
+            &wasi::__WASI_SUBCLOCKFLAGS_SUBSCRIPTION_CLOCK_ABSTIME.to_le_bytes(),
+            &[0; 6],
+        ]);
+        assert_eq!(raw.len(), size_of::<wasi::__wasi_subscription_t>());
+
+        with_memory(MEMORY_LEN, |memory| {
+            memory[64..112].copy_from_slice(&raw);
+            let subscriptions = dec_subscriptions(memory, 64, 1).unwrap();
+            assert_eq!(subscriptions.len(), 1);
+            let subscription = &subscriptions[0];
+            assert_eq!(subscription.userdata, 0x0102_0304_0506_0708);
+            assert_eq!(subscription.r#type, wasi::__WASI_EVENTTYPE_CLOCK);
+            let clock = unsafe { subscription.u.clock };
+            assert_eq!(clock.id, wasi::__WASI_CLOCKID_MONOTONIC);
+            assert_eq!(clock.timeout, 0x1112_1314_1516_1718);
+            assert_eq!(clock.precision, 0x2122_2324);
+            assert_eq!(
+                clock.flags,
+                wasi::__WASI_SUBCLOCKFLAGS_SUBSCRIPTION_CLOCK_ABSTIME
+            );
+        })
+    }
+
+    #[test]
+    fn random_iovecs() {
+        // A fixed xorshift sequence standing in for arbitrary guest-supplied iovec arrays.
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        with_memory(MEMORY_LEN, |memory| {
+            let end = MEMORY_LEN as u64;
+            for _ in 0..10_000 {
+                let iovs: Vec<_> = (0..random() % 16)
+                    .map(|_| {
+                        let buf = random() % (end + 16);
+                        let buf_len = match random() % 5 {
+                            0 => 0,
+                            1 => end.saturating_sub(buf),
+                            2 => random() % 64,
+                            3 => random() % (end + 1),
+                            _ => random() % (1 << 32),
+                        };
+                        (buf as wasi32::uintptr_t, buf_len as wasi32::size_t)
+                    })
+                    .collect();
+                enc_iovecs(memory, &iovs);
+                let len = iovs.len() as wasi32::size_t;
+
+                let total = dec_ciovec_views(memory, IOVS_PTR, len)
+                    .map(|iovs| iovs.iter().map(|iov| iov.len()).sum::<usize>())
+                    .map_err(|err| err.as_wasi_error());
+                assert_eq!(total, expected(&iovs), "{:?}", iovs);
+                let total = dec_iovec_views(memory, IOVS_PTR, len)
+                    .map(|iovs| iovs.iter().map(|iov| iov.len()).sum::<usize>())
+                    .map_err(|err| err.as_wasi_error());
+                let expected = expected(&iovs).and_then(|total| {
+                    if overlap(&iovs) {
+                        Err(WasiError::EFAULT)
+                    } else {
+                        Ok(total)
+                    }
+                });
+                assert_eq!(total, expected, "{:?}", iovs);
+            }
+        })
+    }
+}
@@ -0,0 +1,140 @@
+use std::io::{IoSlice, Result, Write};
+
+/// Translation state carried over from one write to the next, so that a `\r\n` pair which
+/// happens to be split across two writes isn't turned into `\r\r\n`.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct NewlineTranslation {
+    after_cr: bool,
+}
+
+/// An adapter around a `Write` stream that translates bare `\n` into `\r\n`, for consoles
+/// which don't do that themselves.
+///
+/// The output is only ever split right before a `\n`, so multi-byte UTF-8 sequences are
+/// passed through to the inner writer intact.
+pub(crate) struct NewlineTranslatingWriter<'writer, Writer>
+where
+    Writer: Write,
+{
+    inner: &'writer mut Writer,
+    state: &'writer mut NewlineTranslation,
+}
+
+impl<'writer, Writer> NewlineTranslatingWriter<'writer, Writer>
+where
+    Writer: Write,
+{
+    /// Construct a new `NewlineTranslatingWriter` with the given inner `Writer`, picking up
+    /// where the last writer using `state` left off.
+    pub(crate) fn new(inner: &'writer mut Writer, state: &'writer mut NewlineTranslation) -> Self {
+        Self { inner, state }
+    }
+
+    /// Append the translation of `buf` to `out`.
+    fn translate(&mut self, buf: &[u8], out: &mut Vec<u8>) {
+        let mut start = 0;
+        for (i, &byte) in buf.iter().enumerate() {
+            if byte != b'\n' {
+                continue;
+            }
+            let after_cr = match i {
+                0 => self.state.after_cr,
+                _ => buf[i - 1] == b'\r',
+            };
+            if !after_cr {
+                out.extend_from_slice(&buf[start..i]);
+                out.push(b'\r');
+                start = i;
+            }
+        }
+        out.extend_from_slice(&buf[start..]);
+        if let Some(&last) = buf.last() {
+            self.state.after_cr = last == b'\r';
+        }
+    }
+}
+
+impl<'writer, Writer> Write for NewlineTranslatingWriter<'writer, Writer>
+where
+    Writer: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> Result<usize> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        // Translate everything up front and hand it over in one go; consoles are slow enough
+        // without being fed a line at a time.
+        let mut out = Vec::with_capacity(len + len / 16);
+        for buf in bufs {
+            self.translate(buf, &mut out);
+        }
+        self.inner.write_all(&out)?;
+
+        // Report the number of bytes consumed, not the number actually written, as our users
+        // don't know the translation is happening.
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NewlineTranslatingWriter, NewlineTranslation};
+    use std::io::{IoSlice, Result, Write};
+
+    #[test]
+    fn basic() -> Result<()> {
+        let mut buffer = Vec::new();
+        let mut state = NewlineTranslation::default();
+        let mut crlf = NewlineTranslatingWriter::new(&mut buffer, &mut state);
+        assert_eq!(crlf.write(b"\none\ntwo\r\nthree\n\n")?, 17);
+        assert_eq!(buffer, b"\r\none\r\ntwo\r\nthree\r\n\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn split_across_writes() -> Result<()> {
+        let mut buffer = Vec::new();
+        let mut state = NewlineTranslation::default();
+        NewlineTranslatingWriter::new(&mut buffer, &mut state).write_all(b"line")?;
+        NewlineTranslatingWriter::new(&mut buffer, &mut state).write_all(b"\nnext\r")?;
+        NewlineTranslatingWriter::new(&mut buffer, &mut state).write_all(b"\n")?;
+        NewlineTranslatingWriter::new(&mut buffer, &mut state).write_all(b"\n")?;
+        assert_eq!(buffer, b"line\r\nnext\r\n\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn split_across_iovecs() -> Result<()> {
+        let mut buffer = Vec::new();
+        let mut state = NewlineTranslation::default();
+        let mut crlf = NewlineTranslatingWriter::new(&mut buffer, &mut state);
+        let bufs = [
+            IoSlice::new(b"a\r"),
+            IoSlice::new(b"\nb"),
+            IoSlice::new(b"\n"),
+        ];
+        assert_eq!(crlf.write_vectored(&bufs)?, 5);
+        assert_eq!(buffer, b"a\r\nb\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn multibyte_utf8() -> Result<()> {
+        let mut buffer = Vec::new();
+        let mut state = NewlineTranslation::default();
+        let mut crlf = NewlineTranslatingWriter::new(&mut buffer, &mut state);
+        let text = "żółw\n🐢\nカメ";
+        assert_eq!(crlf.write(text.as_bytes())?, text.len());
+        assert_eq!(
+            std::str::from_utf8(&buffer).expect("valid UTF-8"),
+            "żółw\r\n🐢\r\nカメ"
+        );
+        Ok(())
+    }
+}
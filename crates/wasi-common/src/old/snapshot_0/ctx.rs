@@ -1,5 +1,7 @@
 use crate::old::snapshot_0::fdentry::FdEntry;
 use crate::old::snapshot_0::{wasi, Error, Result};
+use crate::random::{RandomSource, WasiCtxRandom};
+use rand_core::RngCore;
 use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::env;
@@ -64,6 +66,7 @@ pub struct WasiCtxBuilder {
     preopens: Vec<(PathBuf, File)>,
     args: Vec<PendingCString>,
     env: HashMap<PendingCString, PendingCString>,
+    random: RandomSource,
 }
 
 impl WasiCtxBuilder {
@@ -74,6 +77,7 @@ impl WasiCtxBuilder {
             preopens: Vec::new(),
             args: vec![],
             env: HashMap::new(),
+            random: RandomSource::os(),
         };
 
         builder.fds.insert(0, PendingFdEntry::Thunk(FdEntry::null));
@@ -136,6 +140,18 @@ impl WasiCtxBuilder {
         self
     }
 
+    /// Use `rng` as the source of the guest's `random_get`, instead of the host's CSPRNG.
+    pub fn random<R: RngCore + Send + 'static>(mut self, rng: R) -> Self {
+        self.random = RandomSource::custom(Box::new(rng));
+        self
+    }
+
+    /// Draw the guest's `random_get` from a CSPRNG seeded with `seed`, making it reproducible.
+    pub fn random_seed(mut self, seed: [u8; 32]) -> Self {
+        self.random = RandomSource::seeded(seed);
+        self
+    }
+
     /// Add an entry to the environment.
     ///
     /// Environment variable keys and values must be valid UTF-8 with no NUL bytes, or else
@@ -255,7 +271,12 @@ impl WasiCtxBuilder {
             log::debug!("WasiCtx fds = {:?}", fds);
         }
 
-        Ok(WasiCtx { args, env, fds })
+        Ok(WasiCtx {
+            args,
+            env,
+            fds,
+            random: WasiCtxRandom::new(self.random, None),
+        })
     }
 }
 
@@ -264,6 +285,7 @@ pub struct WasiCtx {
     fds: HashMap<wasi::__wasi_fd_t, FdEntry>,
     pub(crate) args: Vec<CString>,
     pub(crate) env: Vec<CString>,
+    random: WasiCtxRandom,
 }
 
 impl WasiCtx {
@@ -282,6 +304,10 @@ impl WasiCtx {
             .build()
     }
 
+    pub(crate) fn random(&self) -> &WasiCtxRandom {
+        &self.random
+    }
+
     /// Check if `WasiCtx` contains the specified raw WASI `fd`.
     pub(crate) unsafe fn contains_fd_entry(&self, fd: wasi::__wasi_fd_t) -> bool {
         self.fds.contains_key(&fd)
@@ -4,7 +4,7 @@ use crate::old::snapshot_0::fdentry::Descriptor;
 use crate::old::snapshot_0::memory::*;
 use crate::old::snapshot_0::sys::hostcalls_impl;
 use crate::old::snapshot_0::{wasi, wasi32, Error, Result};
-use log::{error, trace};
+use log::trace;
 use std::convert::TryFrom;
 
 pub(crate) fn args_get(
@@ -128,7 +128,7 @@ pub(crate) fn environ_sizes_get(
 }
 
 pub(crate) fn random_get(
-    _wasi_ctx: &WasiCtx,
+    wasi_ctx: &WasiCtx,
     memory: &mut [u8],
     buf_ptr: wasi32::uintptr_t,
     buf_len: wasi32::size_t,
@@ -137,10 +137,8 @@ pub(crate) fn random_get(
 
     let buf = dec_slice_of_mut_u8(memory, buf_ptr, buf_len)?;
 
-    getrandom::getrandom(buf).map_err(|err| {
-        error!("getrandom failure: {:?}", err);
-        Error::EIO
-    })
+    // Without a budget, the generator failing, which it has logged, is the only way to fail.
+    wasi_ctx.random().fill(buf).map_err(|_| Error::EIO)
 }
 
 pub(crate) fn clock_res_get(
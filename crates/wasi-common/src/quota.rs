@@ -0,0 +1,124 @@
+//! A cap on how much a guest may write to the host's files, as set with
+//! `WasiCtxBuilder::write_quota`.
+use crate::{Error, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug)]
+pub(crate) struct WriteQuota {
+    remaining: AtomicU64,
+}
+
+impl WriteQuota {
+    pub(crate) fn new(bytes: u64) -> Self {
+        Self {
+            remaining: AtomicU64::new(bytes),
+        }
+    }
+
+    pub(crate) fn remaining(&self) -> u64 {
+        self.remaining.load(Ordering::SeqCst)
+    }
+
+    /// Fail with `Error::EDQUOT` if the quota is used up.
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.remaining() == 0 {
+            Err(Error::EDQUOT)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Take as much of the `nbytes` a write wants as is left, which may be less, as with a
+    /// nearly full disk, and fail with `Error::EDQUOT` if there's nothing left at all.
+    ///
+    /// Whatever the write doesn't use is to be given back with `release`.
+    pub(crate) fn reserve(&self, nbytes: usize) -> Result<usize> {
+        if nbytes == 0 {
+            return Ok(0);
+        }
+        let mut remaining = self.remaining();
+        loop {
+            if remaining == 0 {
+                return Err(Error::EDQUOT);
+            }
+            let granted = remaining.min(nbytes as u64);
+            match self.remaining.compare_exchange(
+                remaining,
+                remaining - granted,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return Ok(granted as usize),
+                Err(actual) => remaining = actual,
+            }
+        }
+    }
+
+    pub(crate) fn release(&self, nbytes: usize) {
+        self.remaining.fetch_add(nbytes as u64, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::WasiError;
+    use crate::hostcalls_impl::{fd_pwrite, fd_write, path_open};
+    use crate::test_helpers::{scratch_dir, with_memory};
+    use crate::{wasi, WasiCtxBuilder, WritePipe};
+
+    #[test]
+    fn writes_stop_at_quota() {
+        let scratch = scratch_dir("quota");
+        let dir = scratch.path();
+        let stdout = WritePipe::new();
+        let mut ctx = WasiCtxBuilder::new()
+            .stdout_virtual(stdout.clone())
+            .preopened_dir(crate::preopen_dir(&dir).unwrap(), "/data")
+            .write_quota(10)
+            .build()
+            .expect("build WasiCtx");
+        // A ciovec for the 6 bytes at 48, the paths at 32 and 40, and the `fd` and `size_t`
+        // results at 0 and 8.
+        with_memory(64, |memory| {
+            memory[16..20].copy_from_slice(&48u32.to_le_bytes());
+            memory[20..24].copy_from_slice(&6u32.to_le_bytes());
+            memory[32..39].copy_from_slice(b"out.txt");
+            memory[40..47].copy_from_slice(b"new.txt");
+            memory[48..54].copy_from_slice(b"abcdef");
+            let rights = wasi::__WASI_RIGHTS_FD_WRITE | wasi::__WASI_RIGHTS_FD_SEEK;
+            let create = wasi::__WASI_OFLAGS_CREAT;
+            let errno = |res: crate::Result<()>| res.unwrap_err().as_wasi_error();
+            let nwritten =
+                |memory: &[u8]| u32::from_le_bytes([memory[8], memory[9], memory[10], memory[11]]);
+
+            unsafe {
+                path_open(&mut ctx, memory, 3, 0, 32, 7, create, rights, 0, 0, 0)
+                    .expect("path_open");
+                let fd = u32::from_le_bytes([memory[0], memory[1], memory[2], memory[3]]);
+                fd_write(&mut ctx, memory, fd, 16, 1, 8).expect("fd_write");
+                assert_eq!(nwritten(memory), 6);
+                assert_eq!(ctx.write_quota_remaining(), Some(4));
+
+                // The write which doesn't fit comes up short.
+                fd_pwrite(&ctx, memory, fd, 16, 1, 6, 8).expect("fd_pwrite");
+                assert_eq!(nwritten(memory), 4);
+                assert_eq!(ctx.write_quota_remaining(), Some(0));
+
+                let res = fd_write(&mut ctx, memory, fd, 16, 1, 8);
+                assert_eq!(errno(res), WasiError::EDQUOT);
+                let res = path_open(&mut ctx, memory, 3, 0, 40, 7, create, rights, 0, 0, 0);
+                assert_eq!(errno(res), WasiError::EDQUOT);
+
+                // Writes which don't land on the host's disk don't count.
+                fd_write(&mut ctx, memory, 1, 16, 1, 8).expect("fd_write to stdout");
+            }
+        });
+
+        assert_eq!(stdout.contents(), b"abcdef");
+        drop(ctx);
+        assert_eq!(std::fs::read(dir.join("out.txt")).unwrap(), b"abcdefabcd");
+        assert!(!dir.join("new.txt").exists());
+        let ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        assert_eq!(ctx.write_quota_remaining(), None);
+    }
+}
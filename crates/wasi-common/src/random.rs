@@ -0,0 +1,512 @@
+//! The source of randomness behind `random_get`.
+use crate::{Error, Result};
+use log::error;
+use rand_chacha::ChaCha20Rng;
+use rand_core::{impls, OsRng, RngCore, SeedableRng};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// What `random_get` does once a context's budget, set with `WasiCtxBuilder::random_budget`,
+/// is used up.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RandomBudgetPolicy {
+    /// Fail with `EAGAIN`.
+    Fail,
+    /// Carry on with a CSPRNG seeded once from the host, so that the host's entropy source is
+    /// left alone from then on.
+    Degrade,
+}
+
+impl Default for RandomBudgetPolicy {
+    fn default() -> Self {
+        Self::Fail
+    }
+}
+
+/// A cap on the number of bytes a context may draw from its generator.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RandomBudget {
+    pub(crate) bytes: u64,
+    pub(crate) policy: RandomBudgetPolicy,
+}
+
+/// The most `fill` asks the host's CSPRNG for at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A generator, along with the seed it was created from, if it was set up deterministically
+/// with `WasiCtxBuilder::random_seed`.
+pub(crate) struct RandomSource {
+    rng: Box<dyn RngCore + Send>,
+    seed: Option<[u8; 32]>,
+    /// Whether `rng` is the host's CSPRNG, which is asked a bounded chunk at a time. Any other
+    /// generator gets each request whole.
+    chunked: bool,
+}
+
+impl RandomSource {
+    /// The host's CSPRNG.
+    pub(crate) fn os() -> Self {
+        Self {
+            chunked: true,
+            ..Self::custom(Box::new(OsRng))
+        }
+    }
+
+    /// A generator provided by the embedder.
+    pub(crate) fn custom(rng: Box<dyn RngCore + Send>) -> Self {
+        Self {
+            rng,
+            seed: None,
+            chunked: false,
+        }
+    }
+
+    /// A CSPRNG producing the same stream for the same `seed`.
+    pub(crate) fn seeded(seed: [u8; 32]) -> Self {
+        Self {
+            rng: Box::new(ChaCha20Rng::from_seed(seed)),
+            seed: Some(seed),
+            chunked: false,
+        }
+    }
+}
+
+/// Derive the seed of the `fork`th child of a context seeded with `parent`.
+///
+/// The child's seed is the start of a ChaCha20 keystream keyed with the parent's seed, on a
+/// stream picked by the fork counter. Keyed this way ChaCha20 is a PRF over the counter: every
+/// fork gets an unrelated seed, and none of them tells anything about the parent's.
+fn derive_seed(parent: [u8; 32], fork: u64) -> [u8; 32] {
+    let mut rng = ChaCha20Rng::from_seed(parent);
+    // The parent draws its own bytes from stream 0, so the children count from one.
+    rng.set_stream(fork.wrapping_add(1));
+    let mut seed = [0; 32];
+    rng.fill_bytes(&mut seed);
+    seed
+}
+
+/// The random number generator of a `WasiCtx`.
+pub(crate) struct WasiCtxRandom {
+    source: RefCell<RandomSource>,
+    /// The number of times `fork` has been called.
+    forks: Cell<u64>,
+    /// The generator taking over from `rng` once the budget is used up, under
+    /// `RandomBudgetPolicy::Degrade`.
+    fallback: RefCell<Option<ChaCha20Rng>>,
+    budget: Option<RandomBudget>,
+    consumed: Cell<u64>,
+}
+
+impl WasiCtxRandom {
+    pub(crate) fn new(source: RandomSource, budget: Option<RandomBudget>) -> Self {
+        Self {
+            source: RefCell::new(source),
+            forks: Cell::new(0),
+            fallback: RefCell::new(None),
+            budget,
+            consumed: Cell::new(0),
+        }
+    }
+
+    /// The number of random bytes handed out so far.
+    pub(crate) fn consumed(&self) -> u64 {
+        self.consumed.get()
+    }
+
+    /// Set up the generator for a context forked off from this one.
+    ///
+    /// The child never shares a stream with its parent or its siblings: a seeded generator
+    /// gets a child seed derived from its own seed and the fork count, keeping forks
+    /// reproducible, while anything else is replaced by the host's CSPRNG.
+    pub(crate) fn fork(&self) -> RandomSource {
+        let fork = self.forks.get();
+        self.forks.set(fork + 1);
+        match self.source.borrow().seed {
+            Some(seed) => RandomSource::seeded(derive_seed(seed, fork)),
+            None => RandomSource::os(),
+        }
+    }
+
+    /// Replace the generator with one seeded from `seed`.
+    pub(crate) fn reseed(&mut self, seed: [u8; 32]) {
+        *self.source.get_mut() = RandomSource::seeded(seed);
+        self.forks.set(0);
+    }
+
+    /// Fill `buf` with random bytes, in a single request to the generator, so that an
+    /// embedder's generator sees the guest's requests as they were made. Only the host's CSPRNG
+    /// is asked a bounded chunk at a time, so that a huge request doesn't turn into a single
+    /// huge `getrandom`.
+    pub(crate) fn fill(&self, buf: &mut [u8]) -> Result<()> {
+        let len = buf.len() as u64;
+        let (from_rng, from_fallback) = match self.budget {
+            None => buf.split_at_mut(buf.len()),
+            Some(budget) => {
+                let remaining = budget.bytes.saturating_sub(self.consumed.get());
+                if len > remaining && budget.policy == RandomBudgetPolicy::Fail {
+                    return Err(Error::EAGAIN);
+                }
+                buf.split_at_mut(remaining.min(len) as usize)
+            }
+        };
+
+        let mut source = self.source.borrow_mut();
+        if source.chunked {
+            for chunk in from_rng.chunks_mut(CHUNK_SIZE) {
+                try_fill(&mut *source.rng, chunk)?;
+            }
+        } else if !from_rng.is_empty() {
+            try_fill(&mut *source.rng, from_rng)?;
+        }
+
+        if !from_fallback.is_empty() {
+            let mut fallback = self.fallback.borrow_mut();
+            if fallback.is_none() {
+                log::info!("random_get budget exhausted, switching to a fallback CSPRNG");
+                let rng = ChaCha20Rng::from_rng(OsRng).map_err(|err| {
+                    error!("failed to seed the fallback CSPRNG: {:?}", err);
+                    Error::EIO
+                })?;
+                *fallback = Some(rng);
+            }
+            try_fill(fallback.as_mut().unwrap(), from_fallback)?;
+        }
+
+        self.consumed.set(self.consumed.get().saturating_add(len));
+        Ok(())
+    }
+}
+
+fn try_fill(rng: &mut dyn RngCore, chunk: &mut [u8]) -> Result<()> {
+    rng.try_fill_bytes(chunk).map_err(|err| {
+        error!("random_get failure: {:?}", err);
+        Error::EIO
+    })
+}
+
+/// A generator handing every request over to a host callback, for embedders which want to
+/// decide on each random byte themselves.
+pub struct CallbackRng(pub Box<dyn FnMut(&mut [u8]) + Send>);
+
+impl RngCore for CallbackRng {
+    fn next_u32(&mut self) -> u32 {
+        impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        (self.0)(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> std::result::Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl fmt::Debug for CallbackRng {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.write_str("CallbackRng")
+    }
+}
+
+/// The draws made from a `RecordingRng`, one `(length, bytes)` entry per request, in order.
+///
+/// Clones share the same log, so the embedder can keep one while the `RecordingRng` is
+/// installed into the `WasiCtx`.
+#[derive(Clone, Debug, Default)]
+pub struct RandomLog {
+    draws: Arc<Mutex<Vec<(usize, Vec<u8>)>>>,
+}
+
+impl RandomLog {
+    /// Return a copy of the draws recorded so far.
+    pub fn draws(&self) -> Vec<(usize, Vec<u8>)> {
+        self.draws.lock().unwrap().clone()
+    }
+}
+
+/// A generator recording everything drawn from the generator it wraps, so that it can later be
+/// played back with a `ReplayRng`.
+///
+/// Each `random_get` is a single request, whatever its length. Only once a budget set with
+/// `WasiCtxBuilder::random_budget` runs out partway through a call under
+/// `RandomBudgetPolicy::Degrade` does the generator see just the part of it within budget.
+#[derive(Debug)]
+pub struct RecordingRng<R> {
+    inner: R,
+    log: RandomLog,
+}
+
+impl<R: RngCore> RecordingRng<R> {
+    /// Record the draws made from `inner`.
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            log: RandomLog::default(),
+        }
+    }
+
+    /// A handle on the log of draws.
+    pub fn log(&self) -> RandomLog {
+        self.log.clone()
+    }
+}
+
+impl<R: RngCore> RngCore for RecordingRng<R> {
+    fn next_u32(&mut self) -> u32 {
+        impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.try_fill_bytes(dest)
+            .expect("RecordingRng: inner generator failed")
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> std::result::Result<(), rand_core::Error> {
+        self.inner.try_fill_bytes(dest)?;
+        self.log
+            .draws
+            .lock()
+            .unwrap()
+            .push((dest.len(), dest.to_vec()));
+        Ok(())
+    }
+}
+
+/// A generator playing back draws recorded with a `RecordingRng`.
+///
+/// Every request has to match the length of the next recorded draw; once the guest asks for
+/// anything else, replay has diverged, and every request fails from then on.
+#[derive(Debug)]
+pub struct ReplayRng {
+    draws: VecDeque<(usize, Vec<u8>)>,
+    diverged: bool,
+}
+
+impl ReplayRng {
+    /// Play back `draws`, such as those returned by `RandomLog::draws`.
+    pub fn new<I: IntoIterator<Item = (usize, Vec<u8>)>>(draws: I) -> Self {
+        Self {
+            draws: draws.into_iter().collect(),
+            diverged: false,
+        }
+    }
+}
+
+impl RngCore for ReplayRng {
+    fn next_u32(&mut self) -> u32 {
+        impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.try_fill_bytes(dest)
+            .expect("ReplayRng: replay diverged")
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> std::result::Result<(), rand_core::Error> {
+        if !self.diverged {
+            match self.draws.front() {
+                Some((len, bytes)) if *len == dest.len() => {
+                    dest.copy_from_slice(bytes);
+                    self.draws.pop_front();
+                    return Ok(());
+                }
+                Some((len, _)) => error!(
+                    "ReplayRng: replay diverged, {} bytes requested where {} were recorded",
+                    dest.len(),
+                    len
+                ),
+                None => error!(
+                    "ReplayRng: replay diverged, {} bytes requested past the end of the recording",
+                    dest.len()
+                ),
+            }
+            self.diverged = true;
+        }
+        Err(rand_core::Error::new("replay diverged from the recording"))
+    }
+}
+
+impl fmt::Debug for WasiCtxRandom {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The generators' state is best kept out of logs.
+        fmt.debug_struct("WasiCtxRandom")
+            .field("seeded", &self.source.borrow().seed.is_some())
+            .field("forks", &self.forks)
+            .field("budget", &self.budget)
+            .field("consumed", &self.consumed)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::WasiError;
+    use crate::test_helpers::with_memory;
+    use crate::{hostcalls_impl, WasiCtx, WasiCtxBuilder};
+
+    /// Issue `random_get` requests of the given sizes, returning everything the guest got.
+    fn guest_run(ctx: &WasiCtx, sizes: &[usize]) -> Result<Vec<Vec<u8>>> {
+        sizes
+            .iter()
+            .map(|&len| {
+                with_memory(len, |memory| {
+                    hostcalls_impl::random_get(ctx, memory, 0, len as u32)?;
+                    Ok(memory.to_vec())
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn callback_rng() {
+        let ctx = WasiCtxBuilder::new()
+            .random(CallbackRng(Box::new(|buf| {
+                for (i, byte) in buf.iter_mut().enumerate() {
+                    *byte = i as u8;
+                }
+            })))
+            .build()
+            .expect("build WasiCtx");
+        let output = guest_run(&ctx, &[4]).expect("random_get");
+        assert_eq!(output, vec![vec![0, 1, 2, 3]]);
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let sizes = [16, 100_000, 3];
+        let recording = RecordingRng::new(OsRng);
+        let log = recording.log();
+        let ctx = WasiCtxBuilder::new()
+            .random(recording)
+            .build()
+            .expect("build WasiCtx");
+        let recorded = guest_run(&ctx, &sizes).expect("random_get");
+
+        // One draw per call, however long.
+        let draws = log.draws();
+        let lengths: Vec<_> = draws.iter().map(|(len, _)| *len).collect();
+        assert_eq!(lengths, sizes);
+        assert_eq!(
+            draws.iter().map(|(_, bytes)| bytes).collect::<Vec<_>>(),
+            recorded.iter().collect::<Vec<_>>()
+        );
+
+        let ctx = WasiCtxBuilder::new()
+            .random(ReplayRng::new(draws.clone()))
+            .build()
+            .expect("build WasiCtx");
+        let replayed = guest_run(&ctx, &sizes).expect("random_get");
+        assert_eq!(recorded, replayed);
+
+        let ctx = WasiCtxBuilder::new()
+            .random(ReplayRng::new(draws))
+            .build()
+            .expect("build WasiCtx");
+        let err = guest_run(&ctx, &[16, 99_999]).expect_err("diverging replay");
+        assert_eq!(err.as_wasi_error(), WasiError::EIO);
+        // Once diverged, the replay doesn't pick up again.
+        let err = guest_run(&ctx, &[65536]).expect_err("diverged replay");
+        assert_eq!(err.as_wasi_error(), WasiError::EIO);
+    }
+
+    #[test]
+    fn forks_are_independent() {
+        let parent = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        let fork = || {
+            WasiCtxBuilder::new()
+                .random_forked_from(&parent)
+                .build()
+                .expect("build WasiCtx")
+        };
+        let first = guest_run(&fork(), &[64]).expect("random_get");
+        let second = guest_run(&fork(), &[64]).expect("random_get");
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn seeded_forks_are_reproducible() {
+        let forks = || {
+            let parent = WasiCtxBuilder::new()
+                .random_seed([7; 32])
+                .build()
+                .expect("build WasiCtx");
+            let mut streams = Vec::new();
+            for _ in 0..2 {
+                let child = WasiCtxBuilder::new()
+                    .random_forked_from(&parent)
+                    .build()
+                    .expect("build WasiCtx");
+                streams.push(guest_run(&child, &[64]).expect("random_get"));
+            }
+            streams.push(guest_run(&parent, &[64]).expect("random_get"));
+            streams
+        };
+        let streams = forks();
+        assert_ne!(streams[0], streams[1]);
+        assert_ne!(streams[0], streams[2]);
+        assert_ne!(streams[1], streams[2]);
+        assert_eq!(streams, forks());
+    }
+
+    #[test]
+    fn derived_seeds_are_unrelated() {
+        let mut parent = [7; 32];
+        let mut parent_output = [0; 32];
+        ChaCha20Rng::from_seed(parent).fill_bytes(&mut parent_output);
+        assert_ne!(derive_seed(parent, 0), parent_output);
+
+        // Parents differing only where the fork counter would land must not share children.
+        let first = derive_seed(parent, 1);
+        parent[0] ^= 3;
+        assert_ne!(first, derive_seed(parent, 0));
+        assert_ne!(first, derive_seed(parent, 1));
+    }
+
+    #[test]
+    fn reseed_random() {
+        let mut ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        ctx.reseed_random([1; 32]);
+        let reseeded = guest_run(&ctx, &[64]).expect("random_get");
+        let seeded = WasiCtxBuilder::new()
+            .random_seed([1; 32])
+            .build()
+            .expect("build WasiCtx");
+        assert_eq!(reseeded, guest_run(&seeded, &[64]).expect("random_get"));
+    }
+
+    #[test]
+    fn old_snapshot_uses_context_rng() {
+        use crate::old::snapshot_0;
+        let mut old = snapshot_0::WasiCtxBuilder::new()
+            .random_seed([3; 32])
+            .build()
+            .expect("build old WasiCtx");
+        let memory = with_memory(64, |memory| {
+            let errno = unsafe { snapshot_0::hostcalls::random_get(&mut old, memory, 0, 64) };
+            assert_eq!(errno, snapshot_0::wasi::__WASI_ERRNO_SUCCESS);
+            memory.to_vec()
+        });
+
+        let seeded = WasiCtxBuilder::new()
+            .random_seed([3; 32])
+            .build()
+            .expect("build WasiCtx");
+        assert_eq!(vec![memory], guest_run(&seeded, &[64]).expect("random_get"));
+    }
+}
@@ -1,9 +1,7 @@
-use crate::fdentry::{Descriptor, OsHandleRef};
+use crate::fdentry::Descriptor;
 use crate::{sys::unix::sys_impl, wasi, Error, Result};
-use std::fs::File;
 use std::io;
-use std::mem::ManuallyDrop;
-use std::os::unix::prelude::{AsRawFd, FileTypeExt, FromRawFd, RawFd};
+use std::os::unix::prelude::{AsRawFd, RawFd};
 
 pub(crate) use sys_impl::oshandle::*;
 
@@ -14,16 +12,17 @@ impl AsRawFd for Descriptor {
             Self::Stdin => io::stdin().as_raw_fd(),
             Self::Stdout => io::stdout().as_raw_fd(),
             Self::Stderr => io::stderr().as_raw_fd(),
+            // Callers are expected to dispatch virtual files before reaching for a host handle.
+            Self::VirtualFile(_) => unreachable!("virtual files have no host file descriptor"),
         }
     }
 }
 
-pub(crate) fn descriptor_as_oshandle<'lifetime>(
-    desc: &'lifetime Descriptor,
-) -> OsHandleRef<'lifetime> {
-    OsHandleRef::new(ManuallyDrop::new(OsHandle::from(unsafe {
-        File::from_raw_fd(desc.as_raw_fd())
-    })))
+/// Returns whether `desc` refers to a console which needs LF translated into CRLF.
+///
+/// That's never the case on Unix, where the terminal's line discipline takes care of it.
+pub(crate) fn descriptor_is_console(_desc: &Descriptor) -> bool {
+    false
 }
 
 /// Returns the set of all possible rights that are both relevant for the file
@@ -62,17 +61,18 @@ pub(crate) unsafe fn determine_type_rights<Fd: AsRawFd>(
     wasi::__wasi_rights_t,
 )> {
     let (file_type, rights_base, rights_inheriting) = {
-        // we just make a `File` here for convenience; we don't want it to close when it drops
-        let file = std::mem::ManuallyDrop::new(std::fs::File::from_raw_fd(fd.as_raw_fd()));
-        let ft = file.metadata()?.file_type();
-        if ft.is_block_device() {
+        // Go through `fstat` rather than `File::metadata`, so that no `File` (which would
+        // close the descriptor when dropped) ever gets constructed for borrowed fds like stdio.
+        let stat = yanix::file::fstat(fd.as_raw_fd())?;
+        let ft = stat.st_mode & libc::S_IFMT;
+        if ft == libc::S_IFBLK {
             log::debug!("Host fd {:?} is a block device", fd.as_raw_fd());
             (
                 wasi::__WASI_FILETYPE_BLOCK_DEVICE,
                 wasi::RIGHTS_BLOCK_DEVICE_BASE,
                 wasi::RIGHTS_BLOCK_DEVICE_INHERITING,
             )
-        } else if ft.is_char_device() {
+        } else if ft == libc::S_IFCHR {
             log::debug!("Host fd {:?} is a char device", fd.as_raw_fd());
             use yanix::file::isatty;
             if isatty(fd.as_raw_fd())? {
@@ -88,21 +88,21 @@ pub(crate) unsafe fn determine_type_rights<Fd: AsRawFd>(
                     wasi::RIGHTS_CHARACTER_DEVICE_INHERITING,
                 )
             }
-        } else if ft.is_dir() {
+        } else if ft == libc::S_IFDIR {
             log::debug!("Host fd {:?} is a directory", fd.as_raw_fd());
             (
                 wasi::__WASI_FILETYPE_DIRECTORY,
                 wasi::RIGHTS_DIRECTORY_BASE,
                 wasi::RIGHTS_DIRECTORY_INHERITING,
             )
-        } else if ft.is_file() {
+        } else if ft == libc::S_IFREG {
             log::debug!("Host fd {:?} is a file", fd.as_raw_fd());
             (
                 wasi::__WASI_FILETYPE_REGULAR_FILE,
                 wasi::RIGHTS_REGULAR_FILE_BASE,
                 wasi::RIGHTS_REGULAR_FILE_INHERITING,
             )
-        } else if ft.is_socket() {
+        } else if ft == libc::S_IFSOCK {
             log::debug!("Host fd {:?} is a socket", fd.as_raw_fd());
             use yanix::socket::{get_socket_type, SockType};
             match get_socket_type(fd.as_raw_fd())? {
@@ -118,12 +118,12 @@ pub(crate) unsafe fn determine_type_rights<Fd: AsRawFd>(
                 ),
                 _ => return Err(Error::EINVAL),
             }
-        } else if ft.is_fifo() {
+        } else if ft == libc::S_IFIFO {
             log::debug!("Host fd {:?} is a fifo", fd.as_raw_fd());
             (
                 wasi::__WASI_FILETYPE_UNKNOWN,
-                wasi::RIGHTS_REGULAR_FILE_BASE,
-                wasi::RIGHTS_REGULAR_FILE_INHERITING,
+                wasi::RIGHTS_PIPE_BASE,
+                wasi::RIGHTS_PIPE_INHERITING,
             )
         } else {
             log::debug!("Host fd {:?} is unknown", fd.as_raw_fd());
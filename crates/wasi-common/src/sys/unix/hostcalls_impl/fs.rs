@@ -1,13 +1,15 @@
 #![allow(non_camel_case_types)]
 #![allow(unused_unsafe)]
+use crate::error::ErrorContext;
 use crate::host::Dirent;
 use crate::hostcalls_impl::PathGet;
 use crate::sys::{fdentry_impl::OsHandle, host_impl, unix::sys_impl};
 use crate::{wasi, Error, Result};
 use std::convert::TryInto;
 use std::fs::File;
+use std::io;
 use std::os::unix::fs::FileExt;
-use std::os::unix::prelude::{AsRawFd, FromRawFd};
+use std::os::unix::prelude::{AsRawFd, FromRawFd, RawFd};
 
 pub(crate) use sys_impl::hostcalls_impl::*;
 
@@ -23,14 +25,35 @@ pub(crate) fn fd_pwrite(file: &File, buf: &[u8], offset: wasi::__wasi_filesize_t
     file.write_at(buf, offset).map_err(Into::into)
 }
 
-pub(crate) fn fd_fdstat_get(fd: &File) -> Result<wasi::__wasi_fdflags_t> {
+/// Read from the host's stdin, bypassing the buffer kept by `io::stdin()`.
+///
+/// Bytes parked in libstd's buffer are invisible to `poll`, so a guest which polls stdin before
+/// reading it could be told there's nothing to read while input is in fact pending. Reading
+/// the raw fd keeps the two in agreement, and lets `O_NONBLOCK` (as set through
+/// `fd_fdstat_set_flags`) surface as `EAGAIN` rather than sleeping on the buffer refill.
+pub(crate) fn stdin_read_vectored(iovs: &mut [io::IoSliceMut]) -> Result<usize> {
+    unsafe { read_vectored_unbuffered(io::stdin().as_raw_fd(), iovs) }
+}
+
+unsafe fn read_vectored_unbuffered(fd: RawFd, iovs: &mut [io::IoSliceMut]) -> Result<usize> {
+    // `IoSliceMut` is guaranteed to be ABI-compatible with `iovec` on Unix.
+    let iovcnt = iovs.len().try_into().map_err(|_| Error::EINVAL)?;
+    let nread = libc::readv(fd, iovs.as_ptr() as *const libc::iovec, iovcnt);
+    if nread < 0 {
+        Err(io::Error::last_os_error().into())
+    } else {
+        Ok(nread as usize)
+    }
+}
+
+pub(crate) fn fd_fdstat_get<Fd: AsRawFd>(fd: &Fd) -> Result<wasi::__wasi_fdflags_t> {
     unsafe { yanix::fcntl::get_status_flags(fd.as_raw_fd()) }
         .map(host_impl::fdflags_from_nix)
         .map_err(Into::into)
 }
 
-pub(crate) fn fd_fdstat_set_flags(
-    fd: &File,
+pub(crate) fn fd_fdstat_set_flags<Fd: AsRawFd>(
+    fd: &Fd,
     fdflags: wasi::__wasi_fdflags_t,
 ) -> Result<Option<OsHandle>> {
     let nix_flags = host_impl::nix_from_fdflags(fdflags);
@@ -60,6 +83,26 @@ pub(crate) fn fd_advise(
     unsafe { posix_fadvise(file.as_raw_fd(), offset, len, host_advice) }.map_err(Into::into)
 }
 
+pub(crate) fn fd_allocate(
+    file: &File,
+    offset: wasi::__wasi_filesize_t,
+    len: wasi::__wasi_filesize_t,
+) -> Result<()> {
+    use yanix::{fallocate::posix_fallocate, Errno, YanixError};
+    match unsafe { posix_fallocate(file.as_raw_fd(), offset.try_into()?, len.try_into()?) } {
+        // Where the platform, or the filesystem, can't allocate storage up front, which yanix
+        // reports as ENOSYS, growing the file is the next best thing
+        Err(YanixError::Errno(Errno::ENOSYS)) => {
+            let wanted_size = offset + len;
+            if wanted_size > file.metadata()?.len() {
+                file.set_len(wanted_size)?;
+            }
+            Ok(())
+        }
+        res => res.map_err(Into::into),
+    }
+}
+
 pub(crate) fn path_create_directory(resolved: PathGet) -> Result<()> {
     use yanix::file::{mkdirat, Mode};
     unsafe {
@@ -69,7 +112,7 @@ pub(crate) fn path_create_directory(resolved: PathGet) -> Result<()> {
             Mode::from_bits_truncate(0o777),
         )
     }
-    .map_err(Into::into)
+    .with_context(|| format!("mkdirat {:?}", resolved.path()))
 }
 
 pub(crate) fn path_link(resolved_old: PathGet, resolved_new: PathGet) -> Result<()> {
@@ -83,7 +126,7 @@ pub(crate) fn path_link(resolved_old: PathGet, resolved_new: PathGet) -> Result<
             AtFlag::SYMLINK_FOLLOW,
         )
     }
-    .map_err(Into::into)
+    .with_context(|| format!("linkat {:?} {:?}", resolved_old.path(), resolved_new.path()))
 }
 
 pub(crate) fn path_open(
@@ -132,7 +175,7 @@ pub(crate) fn path_open(
     } {
         Ok(fd) => fd,
         Err(e) => {
-            if let yanix::YanixError::Errno(errno) = e {
+            let err = if let yanix::YanixError::Errno(errno) = e {
                 match errno {
                     // Linux returns ENXIO instead of EOPNOTSUPP when opening a socket
                     Errno::ENXIO => {
@@ -144,12 +187,12 @@ pub(crate) fn path_open(
                             )
                         } {
                             if SFlag::from_bits_truncate(stat.st_mode).contains(SFlag::IFSOCK) {
-                                return Err(Error::ENOTSUP);
+                                Error::ENOTSUP
                             } else {
-                                return Err(Error::ENXIO);
+                                Error::ENXIO
                             }
                         } else {
-                            return Err(Error::ENXIO);
+                            Error::ENXIO
                         }
                     }
                     // Linux returns ENOTDIR instead of ELOOP when using O_NOFOLLOW|O_DIRECTORY
@@ -157,29 +200,31 @@ pub(crate) fn path_open(
                     Errno::ENOTDIR
                         if !(nix_all_oflags & (OFlag::NOFOLLOW | OFlag::DIRECTORY)).is_empty() =>
                     {
-                        if let Ok(stat) = unsafe {
+                        match unsafe {
                             fstatat(
                                 resolved.dirfd().as_raw_fd(),
                                 resolved.path(),
                                 AtFlag::SYMLINK_NOFOLLOW,
                             )
                         } {
-                            if SFlag::from_bits_truncate(stat.st_mode).contains(SFlag::IFLNK) {
-                                return Err(Error::ELOOP);
+                            Ok(stat)
+                                if SFlag::from_bits_truncate(stat.st_mode)
+                                    .contains(SFlag::IFLNK) =>
+                            {
+                                Error::ELOOP
                             }
+                            _ => Error::ENOTDIR,
                         }
-                        return Err(Error::ENOTDIR);
                     }
                     // FreeBSD returns EMLINK instead of ELOOP when using O_NOFOLLOW on
                     // a symlink.
-                    Errno::EMLINK if !(nix_all_oflags & OFlag::NOFOLLOW).is_empty() => {
-                        return Err(Error::ELOOP);
-                    }
-                    errno => return Err(errno.into()),
+                    Errno::EMLINK if !(nix_all_oflags & OFlag::NOFOLLOW).is_empty() => Error::ELOOP,
+                    errno => errno.into(),
                 }
             } else {
-                return Err(e.into());
-            }
+                e.into()
+            };
+            return Err(err.context(format!("openat {:?}", resolved.path())));
         }
     };
 
@@ -193,7 +238,7 @@ pub(crate) fn path_readlink(resolved: PathGet, buf: &mut [u8]) -> Result<usize>
     use std::cmp::min;
     use yanix::file::readlinkat;
     let read_link = unsafe { readlinkat(resolved.dirfd().as_raw_fd(), resolved.path()) }
-        .map_err(Into::into)
+        .with_context(|| format!("readlinkat {:?}", resolved.path()))
         .and_then(host_impl::path_from_host)?;
     let copy_len = min(read_link.len(), buf.len());
     if copy_len > 0 {
@@ -219,7 +264,7 @@ pub(crate) fn path_filestat_get(
         _ => AtFlag::SYMLINK_NOFOLLOW,
     };
     unsafe { fstatat(resolved.dirfd().as_raw_fd(), resolved.path(), atflags) }
-        .map_err(Into::into)
+        .with_context(|| format!("fstatat {:?}", resolved.path()))
         .and_then(host_impl::filestat_from_nix)
 }
 
@@ -279,7 +324,7 @@ pub(crate) fn path_remove_directory(resolved: PathGet) -> Result<()> {
             AtFlag::REMOVEDIR,
         )
     }
-    .map_err(Into::into)
+    .with_context(|| format!("unlinkat {:?}", resolved.path()))
 }
 
 pub(crate) fn fd_readdir<'a>(
@@ -317,3 +362,112 @@ pub(crate) fn fd_readdir<'a>(
         })
     }))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::WasiError;
+    use crate::fdentry::Descriptor;
+    use crate::hostcalls_impl::{self, ClockEventData, FdEventData};
+    use crate::memory::dec_fdstat_byref;
+    use crate::sys::hostcalls_impl::poll_oneoff;
+    use crate::test_helpers::with_memory;
+    use crate::{WasiCtx, WasiCtxBuilder};
+    use std::io::Write;
+    use yanix::fcntl::{get_status_flags, set_status_flags};
+    use yanix::file::OFlag;
+
+    fn pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0, "pipe failed");
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+    }
+
+    fn set_nonblocking(fd: RawFd) {
+        unsafe {
+            let flags = get_status_flags(fd).expect("F_GETFL");
+            set_status_flags(fd, flags | OFlag::NONBLOCK).expect("F_SETFL");
+        }
+    }
+
+    fn poll_read(descriptor: &Descriptor) -> Vec<wasi::__wasi_event_t> {
+        let mut events = Vec::new();
+        let timeout = ClockEventData {
+            delay: 10_000_000,
+            deadline: None,
+            userdata: 1,
+        };
+        let fd_event = FdEventData {
+            descriptor,
+            r#type: wasi::__WASI_EVENTTYPE_FD_READ,
+            userdata: 2,
+        };
+        poll_oneoff(Some(timeout), vec![fd_event], None, &mut events).expect("poll_oneoff");
+        events
+    }
+
+    #[test]
+    fn nonblocking_read_and_poll() {
+        let (reader, mut writer) = pipe();
+        let fd = reader.as_raw_fd();
+        set_nonblocking(fd);
+        let descriptor = Descriptor::OsHandle(OsHandle::from(reader));
+        let mut buf = [0; 16];
+
+        let res = unsafe { read_vectored_unbuffered(fd, &mut [io::IoSliceMut::new(&mut buf)]) };
+        assert_eq!(
+            res.expect_err("nothing to read yet").as_wasi_error(),
+            WasiError::EAGAIN
+        );
+
+        let events = poll_read(&descriptor);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].r#type, wasi::__WASI_EVENTTYPE_CLOCK);
+
+        writer.write_all(b"hello").expect("write to pipe");
+
+        let events = poll_read(&descriptor);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].r#type, wasi::__WASI_EVENTTYPE_FD_READ);
+        assert_eq!(events[0].userdata, 2);
+        assert_eq!(unsafe { events[0].u.fd_readwrite.nbytes }, 5);
+
+        let nread = unsafe { read_vectored_unbuffered(fd, &mut [io::IoSliceMut::new(&mut buf)]) }
+            .expect("read from pipe");
+        assert_eq!(&buf[..nread], b"hello");
+    }
+
+    fn guest_fdstat(ctx: &WasiCtx, fd: wasi::__wasi_fd_t) -> wasi::__wasi_fdstat_t {
+        with_memory(24, |memory| {
+            unsafe { hostcalls_impl::fd_fdstat_get(ctx, memory, fd, 0) }.expect("fd_fdstat_get");
+            dec_fdstat_byref(memory, 0).expect("decode fdstat")
+        })
+    }
+
+    #[test]
+    fn fdstat_tracks_host_flags() {
+        let (_reader, writer) = pipe();
+        let fd = writer.as_raw_fd();
+        let host_flags = || host_impl::fdflags_from_nix(unsafe { get_status_flags(fd) }.unwrap());
+        let ctx = WasiCtxBuilder::new()
+            .stdout(writer)
+            .build()
+            .expect("build WasiCtx");
+
+        let fdstat = guest_fdstat(&ctx, 1);
+        assert_eq!(fdstat.fs_filetype, wasi::__WASI_FILETYPE_UNKNOWN);
+        assert_eq!(fdstat.fs_flags, host_flags());
+        assert_eq!(fdstat.fs_flags & wasi::__WASI_FDFLAGS_NONBLOCK, 0);
+        assert_eq!(
+            fdstat.fs_rights_base & (wasi::__WASI_RIGHTS_FD_SEEK | wasi::__WASI_RIGHTS_FD_TELL),
+            0,
+            "pipes can't be seeked"
+        );
+
+        set_nonblocking(fd);
+
+        let fdstat = guest_fdstat(&ctx, 1);
+        assert_eq!(fdstat.fs_flags, host_flags());
+        assert_ne!(fdstat.fs_flags & wasi::__WASI_FDFLAGS_NONBLOCK, 0);
+    }
+}
@@ -1,7 +1,11 @@
 #![allow(non_camel_case_types)]
 #![allow(unused_unsafe)]
+use crate::cancel::CancelToken;
+use crate::fdentry::Descriptor;
 use crate::hostcalls_impl::{ClockEventData, FdEventData};
 use crate::{wasi, Error, Result};
+use std::io;
+use std::net::Shutdown;
 use yanix::clock::{clock_getres, clock_gettime, ClockId};
 
 fn wasi_clock_id_to_unix(clock_id: wasi::__wasi_clockid_t) -> Result<ClockId> {
@@ -48,12 +52,15 @@ pub(crate) fn clock_time_get(clock_id: wasi::__wasi_clockid_t) -> Result<wasi::_
         .map_or(Err(Error::EOVERFLOW), Ok)
 }
 
+/// Wait for `fd_events` or `timeout`, failing with `Error::EINTR` if `cancel` is cancelled
+/// first.
 pub(crate) fn poll_oneoff(
     timeout: Option<ClockEventData>,
     fd_events: Vec<FdEventData>,
+    cancel: Option<&CancelToken>,
     events: &mut Vec<wasi::__wasi_event_t>,
 ) -> Result<()> {
-    use std::{convert::TryInto, os::unix::prelude::AsRawFd};
+    use std::{convert::TryInto, os::unix::prelude::AsRawFd, time::Instant};
     use yanix::{
         poll::{poll, PollFd, PollFlags},
         Errno,
@@ -62,6 +69,9 @@ pub(crate) fn poll_oneoff(
     if fd_events.is_empty() && timeout.is_none() {
         return Ok(());
     }
+    // With some events in hand already, such as errors for fds which couldn't be polled, only
+    // check what else is ready as well, rather than blocking.
+    let nonblocking = !events.is_empty();
 
     let mut poll_fds: Vec<_> = fd_events
         .iter()
@@ -78,14 +88,30 @@ pub(crate) fn poll_oneoff(
             unsafe { PollFd::new(event.descriptor.as_raw_fd(), flags) }
         })
         .collect();
+    // The token's wakeup fd goes last, so that it doesn't get in the way of matching the
+    // others up with `fd_events`.
+    let wakeup_fd = cancel.map(CancelToken::wakeup_fd).transpose()?;
+    if let Some(fd) = wakeup_fd {
+        poll_fds.push(unsafe { PollFd::new(fd, PollFlags::POLLIN) });
+    }
 
-    let poll_timeout = timeout.map_or(-1, |timeout| {
-        let delay = timeout.delay / 1_000_000; // poll syscall requires delay to expressed in milliseconds
-        delay.try_into().unwrap_or(libc::c_int::max_value())
-    });
-    log::debug!("poll_oneoff poll_timeout = {:?}", poll_timeout);
-
+    let start = Instant::now();
     let ready = loop {
+        // If `poll` gets interrupted, only wait for whatever is left of the timeout, as measured
+        // against the monotonic clock, rather than starting it over.
+        let poll_timeout = match timeout {
+            _ if nonblocking => 0,
+            Some(timeout) => {
+                let remaining = timeout.delay.saturating_sub(start.elapsed().as_nanos());
+                // poll syscall requires delay to expressed in milliseconds; round up so that we
+                // never wake up before the requested deadline.
+                let delay = (remaining + 999_999) / 1_000_000;
+                delay.try_into().unwrap_or(libc::c_int::max_value())
+            }
+            None => -1,
+        };
+        log::debug!("poll_oneoff poll_timeout = {:?}", poll_timeout);
+
         match poll(&mut poll_fds, poll_timeout) {
             Err(_) => {
                 if Errno::last() == Errno::EINTR {
@@ -97,10 +123,27 @@ pub(crate) fn poll_oneoff(
         }
     };
 
+    if wakeup_fd.is_some() {
+        let woken = poll_fds
+            .pop()
+            .and_then(PollFd::revents)
+            .map_or(false, |revents| !revents.is_empty());
+        if woken {
+            return Err(Error::EINTR);
+        }
+    }
+
     Ok(if ready == 0 {
-        poll_oneoff_handle_timeout_event(timeout.expect("timeout should not be None"), events)
+        // Without `nonblocking`, the timeout is what ended the wait; otherwise it only counts if
+        // it has, in fact, expired.
+        if let Some(timeout) = timeout {
+            if !nonblocking || start.elapsed().as_nanos() >= timeout.delay {
+                poll_oneoff_handle_timeout_event(timeout, events)
+            }
+        }
     } else {
-        let ready_events = fd_events.into_iter().zip(poll_fds.into_iter()).take(ready);
+        // `ready` counts the fds with any events, which needn't be the first ones.
+        let ready_events = fd_events.into_iter().zip(poll_fds.into_iter());
         poll_oneoff_handle_fd_event(ready_events, events)?
     })
 }
@@ -203,3 +246,43 @@ fn poll_oneoff_handle_fd_event<'a>(
 
     Ok(())
 }
+
+pub(crate) fn sock_shutdown(desc: &Descriptor, how: Shutdown) -> Result<()> {
+    use std::os::unix::prelude::AsRawFd;
+    use yanix::socket::{shutdown, ShutdownHow};
+    let how = match how {
+        Shutdown::Read => ShutdownHow::Read,
+        Shutdown::Write => ShutdownHow::Write,
+        Shutdown::Both => ShutdownHow::Both,
+    };
+    unsafe { shutdown(desc.as_raw_fd(), how) }.map_err(Into::into)
+}
+
+pub(crate) fn sock_recv(
+    desc: &Descriptor,
+    bufs: &mut [io::IoSliceMut],
+    ri_flags: wasi::__wasi_riflags_t,
+) -> Result<(usize, wasi::__wasi_roflags_t)> {
+    use std::os::unix::prelude::AsRawFd;
+    use yanix::socket::{recvmsg, MsgFlag};
+    let mut flags = MsgFlag::empty();
+    if ri_flags & wasi::__WASI_RIFLAGS_RECV_PEEK != 0 {
+        flags.insert(MsgFlag::PEEK);
+    }
+    if ri_flags & wasi::__WASI_RIFLAGS_RECV_WAITALL != 0 {
+        flags.insert(MsgFlag::WAITALL);
+    }
+    let (nread, msg_flags) = unsafe { recvmsg(desc.as_raw_fd(), bufs, flags) }?;
+    let ro_flags = if msg_flags.contains(MsgFlag::TRUNC) {
+        wasi::__WASI_ROFLAGS_RECV_DATA_TRUNCATED
+    } else {
+        0
+    };
+    Ok((nread, ro_flags))
+}
+
+pub(crate) fn sock_send(desc: &Descriptor, bufs: &[io::IoSlice]) -> Result<usize> {
+    use std::os::unix::prelude::AsRawFd;
+    use yanix::socket::{sendmsg, MsgFlag};
+    unsafe { sendmsg(desc.as_raw_fd(), bufs, MsgFlag::empty()) }.map_err(Into::into)
+}
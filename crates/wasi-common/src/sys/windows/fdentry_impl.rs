@@ -1,8 +1,7 @@
-use crate::fdentry::{Descriptor, OsHandleRef};
+use crate::fdentry::Descriptor;
 use crate::{wasi, Error, Result};
 use std::fs::File;
 use std::io;
-use std::mem::ManuallyDrop;
 use std::ops::{Deref, DerefMut};
 use std::os::windows::prelude::{AsRawHandle, FromRawHandle, RawHandle};
 
@@ -42,16 +41,15 @@ impl AsRawHandle for Descriptor {
             Self::Stdin => io::stdin().as_raw_handle(),
             Self::Stdout => io::stdout().as_raw_handle(),
             Self::Stderr => io::stderr().as_raw_handle(),
+            // Callers are expected to dispatch virtual files before reaching for a host handle.
+            Self::VirtualFile(_) => unreachable!("virtual files have no host handle"),
         }
     }
 }
 
-pub(crate) fn descriptor_as_oshandle<'lifetime>(
-    desc: &'lifetime Descriptor,
-) -> OsHandleRef<'lifetime> {
-    OsHandleRef::new(ManuallyDrop::new(OsHandle::from(unsafe {
-        File::from_raw_handle(desc.as_raw_handle())
-    })))
+/// Returns whether `desc` refers to a console which needs LF translated into CRLF.
+pub(crate) fn descriptor_is_console(desc: &Descriptor) -> bool {
+    unsafe { winx::file::is_console(desc.as_raw_handle()) }
 }
 
 /// Returns the set of all possible rights that are both relevant for the file
@@ -101,13 +99,22 @@ pub(crate) unsafe fn determine_type_rights<Handle: AsRawHandle>(
     let (file_type, rights_base, rights_inheriting) = {
         let file_type = winx::file::get_file_type(handle.as_raw_handle())?;
         if file_type.is_char() {
-            // character file: LPT device or console
-            // TODO: rule out LPT device
-            (
-                wasi::__WASI_FILETYPE_CHARACTER_DEVICE,
-                wasi::RIGHTS_TTY_BASE,
-                wasi::RIGHTS_TTY_BASE,
-            )
+            // character file: LPT device, NUL or console
+            if winx::file::is_console(handle.as_raw_handle()) {
+                // Writes to a console go through `io::stdout`/`io::stderr`, which take
+                // care of converting UTF-8 into UTF-16 for the console API.
+                (
+                    wasi::__WASI_FILETYPE_CHARACTER_DEVICE,
+                    wasi::RIGHTS_TTY_BASE,
+                    wasi::RIGHTS_TTY_BASE,
+                )
+            } else {
+                (
+                    wasi::__WASI_FILETYPE_CHARACTER_DEVICE,
+                    wasi::RIGHTS_CHARACTER_DEVICE_BASE,
+                    wasi::RIGHTS_CHARACTER_DEVICE_INHERITING,
+                )
+            }
         } else if file_type.is_disk() {
             // disk file: file, dir or disk device
             let file = std::mem::ManuallyDrop::new(File::from_raw_handle(handle.as_raw_handle()));
@@ -129,12 +136,21 @@ pub(crate) unsafe fn determine_type_rights<Handle: AsRawHandle>(
             }
         } else if file_type.is_pipe() {
             // pipe object: socket, named pipe or anonymous pipe
-            // TODO: what about pipes, etc?
-            (
-                wasi::__WASI_FILETYPE_SOCKET_STREAM,
-                wasi::RIGHTS_SOCKET_BASE,
-                wasi::RIGHTS_SOCKET_INHERITING,
-            )
+            if winx::file::is_named_pipe(handle.as_raw_handle()) {
+                // Treat pipes the same way as fifos are treated on *nix, so that
+                // redirected stdio looks the same to the guest on both platforms.
+                (
+                    wasi::__WASI_FILETYPE_UNKNOWN,
+                    wasi::RIGHTS_PIPE_BASE,
+                    wasi::RIGHTS_PIPE_INHERITING,
+                )
+            } else {
+                (
+                    wasi::__WASI_FILETYPE_SOCKET_STREAM,
+                    wasi::RIGHTS_SOCKET_BASE,
+                    wasi::RIGHTS_SOCKET_INHERITING,
+                )
+            }
         } else {
             return Err(Error::EINVAL);
         }
@@ -2,6 +2,7 @@
 #![allow(unused)]
 use super::fs_helpers::*;
 use crate::ctx::WasiCtx;
+use crate::error::ErrorContext;
 use crate::fdentry::FdEntry;
 use crate::host::{Dirent, FileType};
 use crate::hostcalls_impl::{fd_filestat_set_times_impl, PathGet};
@@ -52,11 +53,24 @@ pub(crate) fn fd_pwrite(file: &File, buf: &[u8], offset: wasi::__wasi_filesize_t
     write_at(file, buf, offset).map_err(Into::into)
 }
 
-pub(crate) fn fd_fdstat_get(fd: &File) -> Result<wasi::__wasi_fdflags_t> {
+pub(crate) fn fd_fdstat_get<Handle: AsRawHandle>(fd: &Handle) -> Result<wasi::__wasi_fdflags_t> {
     let mut fdflags = 0;
 
     let handle = unsafe { fd.as_raw_handle() };
 
+    if handle == io::stdin().as_raw_handle() {
+        if super::stdin_nonblocking() {
+            fdflags |= wasi::__WASI_FDFLAGS_NONBLOCK;
+        }
+        return Ok(fdflags);
+    }
+
+    // Consoles can't be queried for file access or mode information, and there are no flags
+    // which could apply to them anyway.
+    if unsafe { winx::file::is_console(handle) } {
+        return Ok(fdflags);
+    }
+
     let access_mode = winx::file::query_access_information(handle)?;
     let mode = winx::file::query_mode_information(handle)?;
 
@@ -78,12 +92,27 @@ pub(crate) fn fd_fdstat_get(fd: &File) -> Result<wasi::__wasi_fdflags_t> {
     Ok(fdflags)
 }
 
-pub(crate) fn fd_fdstat_set_flags(
-    fd: &File,
+pub(crate) fn fd_fdstat_set_flags<Handle: AsRawHandle>(
+    fd: &Handle,
     fdflags: wasi::__wasi_fdflags_t,
 ) -> Result<Option<OsHandle>> {
     let handle = unsafe { fd.as_raw_handle() };
 
+    // stdin can't be reopened, but non-blocking reads are emulated for it.
+    if handle == io::stdin().as_raw_handle() {
+        if fdflags & !wasi::__WASI_FDFLAGS_NONBLOCK != 0 {
+            return Err(Error::ENOTSUP);
+        }
+        super::set_stdin_nonblocking(fdflags & wasi::__WASI_FDFLAGS_NONBLOCK != 0);
+        return Ok(None);
+    }
+
+    // Reopening stdout or stderr would replace the host's stream with a new handle behind
+    // the guest's back, bypassing `io::stdout`/`io::stderr`, so refuse to do it.
+    if handle == io::stdout().as_raw_handle() || handle == io::stderr().as_raw_handle() {
+        return Err(Error::ENOTSUP);
+    }
+
     let access_mode = winx::file::query_access_information(handle)?;
 
     let new_access_mode = file_access_mode_from_fdflags(
@@ -119,9 +148,24 @@ pub(crate) fn fd_advise(
     Ok(())
 }
 
+pub(crate) fn fd_allocate(
+    file: &File,
+    offset: wasi::__wasi_filesize_t,
+    len: wasi::__wasi_filesize_t,
+) -> Result<()> {
+    // Moving the end of the file with SetEndOfFile allocates the clusters up to it, unless the
+    // file is sparse, so growing it is as good as posix_fallocate. The clusters of any holes
+    // below the end of the file are left be.
+    let wanted_size = offset + len;
+    if wanted_size > file.metadata()?.len() {
+        file.set_len(wanted_size)?;
+    }
+    Ok(())
+}
+
 pub(crate) fn path_create_directory(resolved: PathGet) -> Result<()> {
     let path = resolved.concatenate()?;
-    std::fs::create_dir(&path).map_err(Into::into)
+    std::fs::create_dir(&path).with_context(|| format!("CreateDirectory {:?}", path))
 }
 
 pub(crate) fn path_link(resolved_old: PathGet, resolved_new: PathGet) -> Result<()> {
@@ -207,7 +251,7 @@ pub(crate) fn path_open(
     opts.access_mode(access_mode.bits())
         .custom_flags(file_flags_from_fdflags(fdflags).bits())
         .open(&path)
-        .map_err(Into::into)
+        .with_context(|| format!("CreateFile {:?}", path))
 }
 
 fn creation_disposition_from_oflags(oflags: wasi::__wasi_oflags_t) -> CreationDisposition {
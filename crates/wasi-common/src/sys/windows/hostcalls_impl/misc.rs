@@ -1,6 +1,7 @@
 #![allow(non_camel_case_types)]
 #![allow(unused_unsafe)]
 #![allow(unused)]
+use crate::cancel::CancelToken;
 use crate::fdentry::Descriptor;
 use crate::hostcalls_impl::{ClockEventData, FdEventData};
 use crate::memory::*;
@@ -11,7 +12,10 @@ use lazy_static::lazy_static;
 use log::{debug, error, trace, warn};
 use std::convert::TryInto;
 use std::io;
+use std::io::Read;
+use std::net::Shutdown;
 use std::os::windows::io::AsRawHandle;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
 use std::sync::Mutex;
 use std::thread;
@@ -106,44 +110,46 @@ lazy_static! {
     };
 }
 
+// Windows has no notion of a non-blocking console or anonymous pipe handle, so
+// `__WASI_FDFLAGS_NONBLOCK` on stdin is emulated on top of `STDIN_POLL`. Like `O_NONBLOCK`
+// on Unix, the flag is shared by everything referring to the host's stdin.
+static STDIN_NONBLOCK: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn stdin_nonblocking() -> bool {
+    STDIN_NONBLOCK.load(Ordering::SeqCst)
+}
+
+pub(crate) fn set_stdin_nonblocking(nonblocking: bool) {
+    STDIN_NONBLOCK.store(nonblocking, Ordering::SeqCst)
+}
+
+pub(crate) fn stdin_read_vectored(iovs: &mut [io::IoSliceMut]) -> Result<usize> {
+    // `StdinPoll` only ever fills libstd's buffer, so once it reports readiness the data
+    // is guaranteed to be picked up by the read below rather than lost.
+    if stdin_nonblocking() {
+        match STDIN_POLL.lock().unwrap().poll(WaitMode::Immediate) {
+            PollState::Ready => {}
+            PollState::NotReady | PollState::TimedOut => return Err(Error::EAGAIN),
+            PollState::Error(e) => return Err(Error::Wasi(e)),
+        }
+    }
+    io::stdin().read_vectored(iovs).map_err(Into::into)
+}
+
 // Timer resolution on Windows is really hard. We may consider exposing the resolution of the respective
 // timers as an associated function in the future.
 pub(crate) fn clock_res_get(clock_id: wasi::__wasi_clockid_t) -> Result<wasi::__wasi_timestamp_t> {
     Ok(match clock_id {
-        // This is the best that we can do with std::time::SystemTime.
-        // Rust uses GetSystemTimeAsFileTime, which is said to have the resolution of
-        // 10ms or 55ms, [1] but MSDN doesn't confirm this in any way.
-        // Even the MSDN article on high resolution timestamps doesn't even mention the precision
-        // for this method. [3]
-        //
-        // The timer resolution can be queried using one of the functions: [2, 5]
-        // * NtQueryTimerResolution, which is undocumented and thus not exposed by the winapi crate
-        // * timeGetDevCaps, which returns the upper and lower bound for the precision, in ms.
-        // While the upper bound seems like something we could use, it's typically too high to be meaningful.
-        // For instance, the intervals return by the syscall are:
-        // * [1, 65536] on Wine
-        // * [1, 1000000] on Windows 10, which is up to (sic) 1000 seconds.
+        // std::time::SystemTime uses GetSystemTimePreciseAsFileTime where it's available
+        // (Windows 8 and later), which combines the system time with QueryPerformanceCounter, [1]
+        // so the effective resolution is that of a FILETIME, 100ns.
         //
-        // It's possible to manually set the timer resolution, but this sounds like something which should
-        // only be done temporarily. [5]
+        // On earlier versions it falls back to GetSystemTimeAsFileTime, which only ticks every
+        // 10ms to 55ms, [2] but there is no documented way of telling which one is in use.
         //
-        // Alternatively, we could possibly use GetSystemTimePreciseAsFileTime in clock_time_get, but
-        // this syscall is only available starting from Windows 8.
-        // (we could possibly emulate it on earlier versions of Windows, see [4])
-        // The MSDN are not clear on the resolution of GetSystemTimePreciseAsFileTime either, but a
-        // Microsoft devblog entry [1] suggests that it kind of combines GetSystemTimeAsFileTime with
-        // QueryPeformanceCounter, which probably means that those two should have the same resolution.
-        //
-        // See also this discussion about the use of GetSystemTimePreciseAsFileTime in Python stdlib,
-        // which in particular contains some resolution benchmarks.
-        //
-        // [1] https://devblogs.microsoft.com/oldnewthing/20170921-00/?p=97057
-        // [2] http://www.windowstimestamp.com/description
-        // [3] https://docs.microsoft.com/en-us/windows/win32/sysinfo/acquiring-high-resolution-time-stamps?redirectedfrom=MSDN
-        // [4] https://www.codeproject.com/Tips/1011902/High-Resolution-Time-For-Windows
-        // [5] https://stackoverflow.com/questions/7685762/windows-7-timing-functions-how-to-use-getsystemtimeadjustment-correctly
-        // [6] https://bugs.python.org/issue19007
-        wasi::__WASI_CLOCKID_REALTIME => 55_000_000,
+        // [1] https://docs.microsoft.com/en-us/windows/win32/api/sysinfoapi/nf-sysinfoapi-getsystemtimepreciseasfiletime
+        // [2] https://devblogs.microsoft.com/oldnewthing/20170921-00/?p=97057
+        wasi::__WASI_CLOCKID_REALTIME => 100,
         // std::time::Instant uses QueryPerformanceCounter & QueryPerformanceFrequency internally
         wasi::__WASI_CLOCKID_MONOTONIC => *PERF_COUNTER_RES,
         // The best we can do is to hardcode the value from the docs.
@@ -158,8 +164,8 @@ pub(crate) fn clock_res_get(clock_id: wasi::__wasi_clockid_t) -> Result<wasi::__
 
 pub(crate) fn clock_time_get(clock_id: wasi::__wasi_clockid_t) -> Result<wasi::__wasi_timestamp_t> {
     let duration = match clock_id {
-        wasi::__WASI_CLOCKID_REALTIME => get_monotonic_time(),
-        wasi::__WASI_CLOCKID_MONOTONIC => get_realtime_time()?,
+        wasi::__WASI_CLOCKID_REALTIME => get_realtime_time()?,
+        wasi::__WASI_CLOCKID_MONOTONIC => get_monotonic_time(),
         wasi::__WASI_CLOCKID_PROCESS_CPUTIME_ID => get_proc_cputime()?,
         wasi::__WASI_CLOCKID_THREAD_CPUTIME_ID => get_thread_cputime()?,
         _ => return Err(Error::EINVAL),
@@ -198,10 +204,15 @@ fn make_timeout_event(timeout: &ClockEventData) -> wasi::__wasi_event_t {
 fn handle_timeout(
     timeout_event: ClockEventData,
     timeout: Duration,
+    cancel: Option<&CancelToken>,
     events: &mut Vec<wasi::__wasi_event_t>,
-) {
-    thread::sleep(timeout);
+) -> Result<()> {
+    match cancel {
+        Some(cancel) => cancel.sleep(timeout)?,
+        None => thread::sleep(timeout),
+    }
     handle_timeout_event(timeout_event, events);
+    Ok(())
 }
 
 fn handle_timeout_event(timeout_event: ClockEventData, events: &mut Vec<wasi::__wasi_event_t>) {
@@ -226,6 +237,8 @@ fn handle_rw_event(event: FdEventData, out_events: &mut Vec<wasi::__wasi_event_t
         Descriptor::Stdin => Ok(1),
         // On Unix, ioctl(FIONREAD) will return 0 for stdout/stderr. Emulate the same behavior on Windows.
         Descriptor::Stdout | Descriptor::Stderr => Ok(0),
+        // Virtual files are handled directly by the platform-independent `poll_oneoff`.
+        Descriptor::VirtualFile(_) => unreachable!(),
     };
 
     let new_event = make_rw_event(&event, size);
@@ -241,9 +254,13 @@ fn handle_error_event(
     out_events.push(new_event);
 }
 
+/// Wait for `fd_events` or `timeout`. Only a sleep with no `fd_events` fails with
+/// `Error::EINTR` when `cancel` is cancelled; the caller has to check on `cancel` while
+/// waiting on stdin.
 pub(crate) fn poll_oneoff(
     timeout: Option<ClockEventData>,
     fd_events: Vec<FdEventData>,
+    cancel: Option<&CancelToken>,
     events: &mut Vec<wasi::__wasi_event_t>,
 ) -> Result<()> {
     use std::fs::Metadata;
@@ -259,10 +276,14 @@ pub(crate) fn poll_oneoff(
         })
         .transpose()?;
 
-    // With no events to listen, poll_oneoff just becomes a sleep.
+    // With no events to listen, poll_oneoff just becomes a sleep, unless there are some
+    // events to return already.
     if fd_events.is_empty() {
         match timeout {
-            Some((event, dur)) => return Ok(handle_timeout(event, dur, events)),
+            Some((event, dur)) if events.is_empty() => {
+                return handle_timeout(event, dur, cancel, events)
+            }
+            Some(_) => return Ok(()),
             // The implementation has to return Ok(()) in this case,
             // cf. the comment in src/hostcalls_impl/misc.rs
             None => return Ok(()),
@@ -299,6 +320,7 @@ pub(crate) fn poll_oneoff(
                     unreachable!();
                 }
             }
+            Descriptor::VirtualFile(_) => unreachable!(),
         }
     }
 
@@ -359,7 +381,7 @@ pub(crate) fn poll_oneoff(
                 // In the tests stdin is replaced with a dummy pipe, so for now
                 // we just time out. Support for pipes will be decided later on.
                 warn!("Polling pipes not supported on Windows, will just time out.");
-                handle_timeout(event, dur, events);
+                handle_timeout(event, dur, cancel, events)?;
             }
             None => {
                 error!("Polling only pipes with no timeout not supported on Windows.");
@@ -403,3 +425,37 @@ fn get_perf_counter_resolution_ns() -> u64 {
     let epsilon = NANOS_PER_SEC / freq;
     epsilon
 }
+
+/// Borrow the socket behind `desc` as a `TcpStream`. A socket's handle is its `SOCKET`, and
+/// `shutdown`, `WSARecv` and `WSASend` don't care whether it's TCP. The stream is only
+/// borrowed, so it mustn't be closed on drop.
+fn borrow_socket(desc: &Descriptor) -> std::mem::ManuallyDrop<std::net::TcpStream> {
+    use std::os::windows::io::{FromRawSocket, RawSocket};
+    let socket = desc.as_raw_handle() as RawSocket;
+    std::mem::ManuallyDrop::new(unsafe { std::net::TcpStream::from_raw_socket(socket) })
+}
+
+pub(crate) fn sock_shutdown(desc: &Descriptor, how: Shutdown) -> Result<()> {
+    borrow_socket(desc).shutdown(how).map_err(Into::into)
+}
+
+pub(crate) fn sock_recv(
+    desc: &Descriptor,
+    bufs: &mut [io::IoSliceMut],
+    ri_flags: wasi::__wasi_riflags_t,
+) -> Result<(usize, wasi::__wasi_roflags_t)> {
+    // libstd can't peek into more than one buffer, nor wait for all of them to fill up, nor
+    // tell of datagrams which didn't fit.
+    if ri_flags != 0 {
+        return Err(Error::ENOTSUP);
+    }
+    let nread = (&*borrow_socket(desc)).read_vectored(bufs)?;
+    Ok((nread, 0))
+}
+
+pub(crate) fn sock_send(desc: &Descriptor, bufs: &[io::IoSlice]) -> Result<usize> {
+    use std::io::Write;
+    (&*borrow_socket(desc))
+        .write_vectored(bufs)
+        .map_err(Into::into)
+}
@@ -0,0 +1,21 @@
+//! Fixtures shared by the unit tests.
+
+use tempfile::TempDir;
+
+/// Run `f` against a fake guest memory of `len` zeroed bytes. The memory is backed by `u64`s,
+/// so that anything placed at a multiple of 8 is suitably aligned, the way it would be in a
+/// wasm linear memory.
+pub(crate) fn with_memory<T>(len: usize, f: impl FnOnce(&mut [u8]) -> T) -> T {
+    let mut backing = vec![0u64; (len + 7) / 8];
+    let memory = unsafe { std::slice::from_raw_parts_mut(backing.as_mut_ptr() as *mut u8, len) };
+    f(memory)
+}
+
+/// Create a fresh scratch directory on the host, named after `name`, which is removed along
+/// with its contents once the returned handle is dropped.
+pub(crate) fn scratch_dir(name: &str) -> TempDir {
+    tempfile::Builder::new()
+        .prefix(&format!("wasi_common_{}_", name))
+        .tempdir()
+        .expect("create scratch dir")
+}
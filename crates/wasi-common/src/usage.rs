@@ -0,0 +1,136 @@
+//! Resource usage statistics of a guest, collected with `WasiCtxBuilder::usage_stats`.
+use crate::hostcalls;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// A summary of the resources a guest used, as returned by `WasiCtx::usage_report`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct UsageReport {
+    /// How many times each hostcall was made, leaving out the ones which never were.
+    pub calls: BTreeMap<&'static str, u64>,
+    /// How many hostcalls were made in each category, named after the prefix the calls share,
+    /// like `"fd"` for `fd_read` and `fd_write`.
+    pub categories: BTreeMap<&'static str, u64>,
+    /// The bytes read through `fd_read` and `fd_pread`.
+    pub bytes_read: u64,
+    /// The bytes written through `fd_write` and `fd_pwrite`.
+    pub bytes_written: u64,
+    /// The time spent inside hostcalls.
+    pub host_time: Duration,
+    /// The time since the `WasiCtx` was built, of which whatever isn't `host_time` was spent in
+    /// the guest, or in the embedder.
+    pub wall_time: Duration,
+    /// The most descriptors that were open at once, stdio and preopens included.
+    pub peak_open_fds: usize,
+}
+
+/// The counters behind a `UsageReport`, bumped as the guest goes.
+#[derive(Debug)]
+pub(crate) struct Usage {
+    /// The number of calls made to each hostcall, by its index in `hostcalls::NAMES`.
+    calls: Vec<AtomicU64>,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    host_nanos: AtomicU64,
+    peak_open_fds: usize,
+    created: Instant,
+}
+
+impl Usage {
+    pub(crate) fn new() -> Self {
+        Self {
+            calls: hostcalls::NAMES.iter().map(|_| AtomicU64::new(0)).collect(),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            host_nanos: AtomicU64::new(0),
+            peak_open_fds: 0,
+            created: Instant::now(),
+        }
+    }
+
+    /// Count a call to the hostcall at `index` in `hostcalls::NAMES`, which started at `start`.
+    pub(crate) fn record_call(&self, index: usize, start: Instant) {
+        self.calls[index].fetch_add(1, Ordering::Relaxed);
+        let nanos = start.elapsed().as_nanos() as u64;
+        self.host_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_read(&self, nbytes: usize) {
+        self.bytes_read.fetch_add(nbytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_written(&self, nbytes: usize) {
+        self.bytes_written
+            .fetch_add(nbytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_open_fds(&mut self, count: usize) {
+        self.peak_open_fds = self.peak_open_fds.max(count);
+    }
+
+    pub(crate) fn report(&self) -> UsageReport {
+        let mut report = UsageReport {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            host_time: Duration::from_nanos(self.host_nanos.load(Ordering::Relaxed)),
+            wall_time: self.created.elapsed(),
+            peak_open_fds: self.peak_open_fds,
+            ..UsageReport::default()
+        };
+        for (&name, count) in hostcalls::NAMES.iter().zip(&self.calls) {
+            let count = count.load(Ordering::Relaxed);
+            if count == 0 {
+                continue;
+            }
+            report.calls.insert(name, count);
+            let category = name.split('_').next().unwrap_or(name);
+            *report.categories.entry(category).or_insert(0) += count;
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_helpers::with_memory;
+    use crate::{ReadPipe, WasiCtxBuilder, WritePipe};
+
+    #[test]
+    fn report_matches_workload() {
+        let stdout = WritePipe::new();
+        let mut ctx = WasiCtxBuilder::new()
+            .stdin_virtual(ReadPipe::new("input"))
+            .stdout_virtual(stdout.clone())
+            .usage_stats(true)
+            .build()
+            .expect("build WasiCtx");
+        // A ciovec for the 3 bytes at 16, and a spare `size_t` at 0.
+        with_memory(24, |memory| {
+            memory[8..12].copy_from_slice(&16u32.to_le_bytes());
+            memory[12..16].copy_from_slice(&3u32.to_le_bytes());
+            memory[16..19].copy_from_slice(b"abc");
+
+            for _ in 0..5 {
+                unsafe { crate::hostcalls::fd_write(&mut ctx, memory, 1, 8, 1, 0) };
+            }
+            unsafe { crate::hostcalls::fd_read(&mut ctx, memory, 0, 8, 1, 0) };
+            // Failed calls count, too.
+            unsafe { crate::hostcalls::fd_close(&mut ctx, memory, 42) };
+        });
+
+        let report = ctx.usage_report().expect("usage stats are enabled");
+        let calls: Vec<_> = report.calls.into_iter().collect();
+        assert_eq!(calls, [("fd_close", 1), ("fd_read", 1), ("fd_write", 5)]);
+        let categories: Vec<_> = report.categories.into_iter().collect();
+        assert_eq!(categories, [("fd", 7)]);
+        assert_eq!(report.bytes_written, 15);
+        assert_eq!(report.bytes_read, 3);
+        assert_eq!(report.peak_open_fds, 3);
+        assert!(report.host_time <= report.wall_time);
+        assert_eq!(stdout.contents().len(), 15);
+
+        let ctx = WasiCtxBuilder::new().build().expect("build WasiCtx");
+        assert_eq!(ctx.usage_report(), None);
+    }
+}
@@ -0,0 +1,472 @@
+//! In-memory files which can be installed into a `WasiCtx` in place of host handles.
+//!
+//! The most common use is capturing a guest's output: hand a `WritePipe` to
+//! `WasiCtxBuilder::stdout_virtual`, keep a clone of it, and read the captured bytes back
+//! with `WritePipe::contents` once the guest has run.
+use crate::{wasi, Error};
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// A file implemented by the embedder rather than backed by a host file descriptor.
+///
+/// Reads and writes are performed with the `WasiCtx` borrowed mutably, so each call to
+/// `read_vectored` or `write_vectored` corresponds to exactly one `fd_read` or `fd_write`
+/// issued by the guest.
+pub trait VirtualFile: fmt::Debug + Send {
+    /// The WASI file type reported for this file by `fd_fdstat_get`.
+    fn filetype(&self) -> wasi::__wasi_filetype_t;
+
+    /// The base and inheriting rights the file is installed with.
+    fn rights(&self) -> (wasi::__wasi_rights_t, wasi::__wasi_rights_t);
+
+    /// Read into `iovs`, returning the number of bytes read.
+    fn read_vectored(&mut self, _iovs: &mut [io::IoSliceMut]) -> Result<usize, Error> {
+        Err(Error::EBADF)
+    }
+
+    /// Write all of `iovs`, returning the number of bytes written.
+    fn write_vectored(&mut self, _iovs: &[io::IoSlice]) -> Result<usize, Error> {
+        Err(Error::EBADF)
+    }
+
+    /// The number of bytes which can be read without blocking, as reported by `poll_oneoff`.
+    fn num_ready_bytes(&self) -> Result<u64, Error> {
+        Ok(0)
+    }
+
+    /// Whether a read would go ahead without blocking, as asked by `poll_oneoff`.
+    ///
+    /// A file returning `Readiness::NotReady` has to hold on to `waker` and call
+    /// `PollWaker::wake` once that may have changed; the guest is then asked again.
+    fn poll_readable(&self, _waker: &PollWaker) -> Readiness {
+        Readiness::Unsupported
+    }
+
+    /// Like `poll_readable`, but for writes.
+    fn poll_writable(&self, _waker: &PollWaker) -> Readiness {
+        Readiness::Unsupported
+    }
+}
+
+/// Whether a `VirtualFile` can be read from or written to without blocking.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Readiness {
+    /// The operation can go ahead.
+    Ready,
+    /// The guest is to keep waiting until the `PollWaker` it was polled with is woken.
+    NotReady,
+    /// The file doesn't keep track of readiness, and is treated as always ready.
+    Unsupported,
+}
+
+/// A handle with which a `VirtualFile` wakes up a guest waiting on it in `poll_oneoff`.
+///
+/// Clones wake the same waiter, and waking one which isn't being waited upon anymore is
+/// harmless.
+#[derive(Clone, Debug, Default)]
+pub struct PollWaker {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl PollWaker {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Let the waiting guest know it should poll its subscriptions again.
+    pub fn wake(&self) {
+        let (woken, condvar) = &*self.inner;
+        *woken.lock().unwrap() = true;
+        condvar.notify_all();
+    }
+
+    /// Wait until woken, or until `timeout` has passed, returning whether this was woken.
+    pub(crate) fn wait(&self, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let (woken, condvar) = &*self.inner;
+        let mut woken = woken.lock().unwrap();
+        while !*woken {
+            woken = match deadline {
+                None => condvar.wait(woken).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    condvar.wait_timeout(woken, deadline - now).unwrap().0
+                }
+            };
+        }
+        *woken = false;
+        true
+    }
+}
+
+/// A readable in-memory pipe, serving a fixed buffer to the guest.
+#[derive(Debug)]
+pub struct ReadPipe {
+    cursor: io::Cursor<Vec<u8>>,
+}
+
+impl ReadPipe {
+    /// Create a pipe which yields `contents` and then reports end-of-file.
+    pub fn new<T: Into<Vec<u8>>>(contents: T) -> Self {
+        Self {
+            cursor: io::Cursor::new(contents.into()),
+        }
+    }
+}
+
+impl VirtualFile for ReadPipe {
+    fn filetype(&self) -> wasi::__wasi_filetype_t {
+        // Host pipes are reported as `FILETYPE_UNKNOWN` too, cf. `determine_type_rights`.
+        wasi::__WASI_FILETYPE_UNKNOWN
+    }
+
+    fn rights(&self) -> (wasi::__wasi_rights_t, wasi::__wasi_rights_t) {
+        (
+            wasi::__WASI_RIGHTS_FD_READ | wasi::__WASI_RIGHTS_POLL_FD_READWRITE,
+            0,
+        )
+    }
+
+    fn read_vectored(&mut self, iovs: &mut [io::IoSliceMut]) -> Result<usize, Error> {
+        self.cursor.read_vectored(iovs).map_err(Into::into)
+    }
+
+    fn num_ready_bytes(&self) -> Result<u64, Error> {
+        let len = self.cursor.get_ref().len() as u64;
+        Ok(len.saturating_sub(self.cursor.position()))
+    }
+}
+
+/// A writable in-memory pipe, collecting everything the guest writes to it.
+///
+/// Clones share the same buffer, so the embedder can keep one handle while another is
+/// installed into the `WasiCtx`.
+#[derive(Clone, Default)]
+pub struct WritePipe {
+    buf: Arc<Mutex<Vec<u8>>>,
+}
+
+impl WritePipe {
+    /// Create an empty pipe.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return a copy of the bytes written so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.buf.lock().unwrap().clone()
+    }
+}
+
+impl fmt::Debug for WritePipe {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't dump the captured bytes, they may be arbitrarily large.
+        let len = self.buf.lock().map(|buf| buf.len()).unwrap_or(0);
+        write!(fmt, "WritePipe {{ len: {} }}", len)
+    }
+}
+
+impl VirtualFile for WritePipe {
+    fn filetype(&self) -> wasi::__wasi_filetype_t {
+        wasi::__WASI_FILETYPE_UNKNOWN
+    }
+
+    fn rights(&self) -> (wasi::__wasi_rights_t, wasi::__wasi_rights_t) {
+        (
+            wasi::__WASI_RIGHTS_FD_WRITE | wasi::__WASI_RIGHTS_POLL_FD_READWRITE,
+            0,
+        )
+    }
+
+    fn write_vectored(&mut self, iovs: &[io::IoSlice]) -> Result<usize, Error> {
+        // Hold the lock across all of the iovecs, so that a single `fd_write` is never
+        // interleaved with writes made through another clone of this pipe.
+        let mut buf = self.buf.lock().unwrap();
+        let mut nwritten = 0;
+        for iov in iovs {
+            buf.extend_from_slice(iov);
+            nwritten += iov.len();
+        }
+        Ok(nwritten)
+    }
+}
+
+/// A counter the host bumps to wake up the guest, working like Linux's `eventfd`.
+///
+/// Installed into a `WasiCtx` with `WasiCtxBuilder::notify_fd`, it's readable in `poll_oneoff`
+/// whenever the counter isn't 0. Reading it yields the counter as 8 little-endian bytes and
+/// resets it. Clones share the same counter, so the embedder, or any other host thread, can
+/// keep one to notify the guest with.
+#[derive(Clone, Debug, Default)]
+pub struct Notifier {
+    state: Arc<Mutex<NotifierState>>,
+}
+
+#[derive(Debug, Default)]
+struct NotifierState {
+    count: u64,
+    waker: Option<PollWaker>,
+}
+
+impl Notifier {
+    /// Create a notifier whose counter is 0.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add 1 to the counter.
+    pub fn notify(&self) {
+        self.notify_with_value(1)
+    }
+
+    /// Add `value` to the counter, saturating at `u64::max_value()`.
+    pub fn notify_with_value(&self, value: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.count = state.count.saturating_add(value);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl VirtualFile for Notifier {
+    fn filetype(&self) -> wasi::__wasi_filetype_t {
+        wasi::__WASI_FILETYPE_UNKNOWN
+    }
+
+    fn rights(&self) -> (wasi::__wasi_rights_t, wasi::__wasi_rights_t) {
+        (
+            wasi::__WASI_RIGHTS_FD_READ | wasi::__WASI_RIGHTS_POLL_FD_READWRITE,
+            0,
+        )
+    }
+
+    fn read_vectored(&mut self, iovs: &mut [io::IoSliceMut]) -> Result<usize, Error> {
+        // Like an `eventfd`, the counter is read whole or not at all.
+        if iovs.iter().map(|iov| iov.len()).sum::<usize>() < std::mem::size_of::<u64>() {
+            return Err(Error::EINVAL);
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.count == 0 {
+            // Blocking would never end, as the guest is the one holding up the host thread.
+            return Err(Error::EAGAIN);
+        }
+        let count = std::mem::replace(&mut state.count, 0);
+        (&count.to_le_bytes()[..])
+            .read_vectored(iovs)
+            .map_err(Into::into)
+    }
+
+    fn num_ready_bytes(&self) -> Result<u64, Error> {
+        match self.state.lock().unwrap().count {
+            0 => Ok(0),
+            _ => Ok(8),
+        }
+    }
+
+    fn poll_readable(&self, waker: &PollWaker) -> Readiness {
+        let mut state = self.state.lock().unwrap();
+        if state.count == 0 {
+            state.waker = Some(waker.clone());
+            Readiness::NotReady
+        } else {
+            Readiness::Ready
+        }
+    }
+}
+
+/// Lock `file` for a hostcall.
+///
+/// A file which panicked in an earlier call may have been left in an inconsistent state, so
+/// rather than being called into again, it fails with `EIO` from then on.
+pub(crate) fn lock_virtual_file(
+    file: &Mutex<Box<dyn VirtualFile>>,
+) -> Result<MutexGuard<'_, Box<dyn VirtualFile>>, Error> {
+    file.lock().map_err(|_| Error::EIO)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::WasiError;
+    use crate::test_helpers::with_memory;
+    use crate::{hostcalls_impl, WasiCtxBuilder};
+
+    /// Run `f` against a fake guest memory holding `bufs`, preceded by their ciovec array,
+    /// along with the iovec array's address and the address of a spare `size_t` for the result.
+    fn with_iovs<T>(bufs: &[&[u8]], f: impl FnOnce(&mut [u8], u32, u32) -> T) -> T {
+        let iovs_ptr = 8;
+        let mut data_ptr = iovs_ptr + 8 * bufs.len();
+        let len = data_ptr + bufs.iter().map(|b| b.len()).sum::<usize>();
+        with_memory(len, |memory| {
+            for (i, buf) in bufs.iter().enumerate() {
+                let iov = iovs_ptr + 8 * i;
+                memory[iov..iov + 4].copy_from_slice(&(data_ptr as u32).to_le_bytes());
+                memory[iov + 4..iov + 8].copy_from_slice(&(buf.len() as u32).to_le_bytes());
+                memory[data_ptr..data_ptr + buf.len()].copy_from_slice(buf);
+                data_ptr += buf.len();
+            }
+            f(memory, iovs_ptr as u32, 0)
+        })
+    }
+
+    fn result_size(memory: &[u8], ptr: u32) -> usize {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&memory[ptr as usize..ptr as usize + 4]);
+        u32::from_le_bytes(bytes) as usize
+    }
+
+    #[test]
+    fn capture_stdout_and_stderr() {
+        let stdout = WritePipe::new();
+        let stderr = WritePipe::new();
+        let mut ctx = WasiCtxBuilder::new()
+            .stdout_virtual(stdout.clone())
+            .stderr_virtual(stderr.clone())
+            .build()
+            .expect("ctx builds");
+
+        with_iovs(&[b"hello, ", b"world\n"], |memory, iovs, nwritten| {
+            unsafe { hostcalls_impl::fd_write(&mut ctx, memory, 1, iovs, 2, nwritten) }
+                .expect("fd_write to stdout");
+            assert_eq!(result_size(memory, nwritten), 13);
+        });
+
+        with_iovs(&[b"oops\n"], |memory, iovs, nwritten| {
+            unsafe { hostcalls_impl::fd_write(&mut ctx, memory, 2, iovs, 1, nwritten) }
+                .expect("fd_write to stderr");
+        });
+
+        assert_eq!(stdout.contents(), b"hello, world\n");
+        assert_eq!(stderr.contents(), b"oops\n");
+    }
+
+    #[test]
+    fn capture_large_write() {
+        let stdout = WritePipe::new();
+        let mut ctx = WasiCtxBuilder::new()
+            .stdout_virtual(stdout.clone())
+            .build()
+            .expect("ctx builds");
+
+        let big: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        with_iovs(
+            &[&big[..70_000], &big[70_000..]],
+            |memory, iovs, nwritten| {
+                unsafe { hostcalls_impl::fd_write(&mut ctx, memory, 1, iovs, 2, nwritten) }
+                    .expect("fd_write to stdout");
+
+                assert_eq!(result_size(memory, nwritten), big.len());
+            },
+        );
+        assert_eq!(stdout.contents(), big);
+    }
+
+    #[test]
+    fn read_from_stdin() {
+        let mut ctx = WasiCtxBuilder::new()
+            .stdin_virtual(ReadPipe::new("some input"))
+            .build()
+            .expect("ctx builds");
+
+        with_iovs(&[&[0; 4], &[0; 4]], |memory, iovs, nread| {
+            unsafe { hostcalls_impl::fd_read(&mut ctx, memory, 0, iovs, 2, nread) }
+                .expect("fd_read from stdin");
+            assert_eq!(result_size(memory, nread), 8);
+            assert_eq!(&memory[24..32], b"some inp");
+
+            unsafe { hostcalls_impl::fd_read(&mut ctx, memory, 0, iovs, 2, nread) }
+                .expect("fd_read from stdin");
+            assert_eq!(result_size(memory, nread), 2);
+            assert_eq!(&memory[24..26], b"ut");
+
+            unsafe { hostcalls_impl::fd_read(&mut ctx, memory, 0, iovs, 2, nread) }
+                .expect("fd_read at end-of-file");
+            assert_eq!(result_size(memory, nread), 0);
+        });
+    }
+
+    #[test]
+    fn pipes_are_one_way() {
+        let mut ctx = WasiCtxBuilder::new()
+            .stdin_virtual(ReadPipe::new("input"))
+            .stdout_virtual(WritePipe::new())
+            .build()
+            .expect("ctx builds");
+
+        with_iovs(&[b"data"], |memory, iovs, nbytes| {
+            let res = unsafe { hostcalls_impl::fd_write(&mut ctx, memory, 0, iovs, 1, nbytes) };
+            assert_eq!(res.unwrap_err().as_wasi_error(), WasiError::ENOTCAPABLE);
+            let res = unsafe { hostcalls_impl::fd_read(&mut ctx, memory, 1, iovs, 1, nbytes) };
+            assert_eq!(res.unwrap_err().as_wasi_error(), WasiError::ENOTCAPABLE);
+        });
+    }
+
+    /// A file whose reads panic, standing in for a buggy embedder.
+    #[derive(Debug)]
+    struct PanickingFile;
+
+    impl VirtualFile for PanickingFile {
+        fn filetype(&self) -> wasi::__wasi_filetype_t {
+            wasi::__WASI_FILETYPE_UNKNOWN
+        }
+
+        fn rights(&self) -> (wasi::__wasi_rights_t, wasi::__wasi_rights_t) {
+            (wasi::__WASI_RIGHTS_FD_READ, 0)
+        }
+
+        fn read_vectored(&mut self, _iovs: &mut [io::IoSliceMut]) -> Result<usize, Error> {
+            panic!("read from a PanickingFile")
+        }
+    }
+
+    #[test]
+    fn panicking_read_fails_call() {
+        let stdout = WritePipe::new();
+        let mut ctx = WasiCtxBuilder::new()
+            .stdin_virtual(PanickingFile)
+            .stdout_virtual(stdout.clone())
+            .build()
+            .expect("build WasiCtx");
+        with_iovs(&[b"ok"], |memory, iovs, nbytes| {
+            // The second read finds the file's lock poisoned, and doesn't call into it again.
+            for _ in 0..2 {
+                let errno =
+                    unsafe { crate::hostcalls::fd_read(&mut ctx, memory, 0, iovs, 1, nbytes) };
+                assert_eq!(errno, wasi::__WASI_ERRNO_IO);
+            }
+
+            let errno = unsafe { crate::hostcalls::fd_write(&mut ctx, memory, 1, iovs, 1, nbytes) };
+            assert_eq!(errno, wasi::__WASI_ERRNO_SUCCESS);
+            assert_eq!(stdout.contents(), b"ok");
+        });
+    }
+
+    #[test]
+    fn notifier_read_resets_counter() {
+        let notifier = Notifier::new();
+        let mut ctx = WasiCtxBuilder::new()
+            .notify_fd(3, notifier.clone())
+            .build()
+            .expect("build WasiCtx");
+        with_iovs(&[&[0; 4], &[0; 4]], |memory, iovs, nread| {
+            let res = unsafe { hostcalls_impl::fd_read(&mut ctx, memory, 3, iovs, 2, nread) };
+            assert_eq!(res.unwrap_err().as_wasi_error(), WasiError::EAGAIN);
+
+            notifier.notify();
+            notifier.notify_with_value(0x0102_0304_0506);
+            unsafe { hostcalls_impl::fd_read(&mut ctx, memory, 3, iovs, 2, nread) }
+                .expect("fd_read");
+            assert_eq!(result_size(memory, nread), 8);
+            assert_eq!(&memory[24..], &0x0102_0304_0507u64.to_le_bytes());
+
+            let res = unsafe { hostcalls_impl::fd_read(&mut ctx, memory, 3, iovs, 2, nread) };
+            assert_eq!(res.unwrap_err().as_wasi_error(), WasiError::EAGAIN);
+        });
+    }
+}
@@ -73,6 +73,20 @@ pub(crate) const RIGHTS_DIRECTORY_BASE: __wasi_rights_t = __WASI_RIGHTS_FD_FDSTA
 pub(crate) const RIGHTS_DIRECTORY_INHERITING: __wasi_rights_t =
     RIGHTS_DIRECTORY_BASE | RIGHTS_REGULAR_FILE_BASE;
 
+// The directory operations which leave the tree as it is, for read-only preopens, and which
+// only yield read-only file descriptors.
+pub(crate) const RIGHTS_DIRECTORY_READONLY_BASE: __wasi_rights_t = __WASI_RIGHTS_FD_FDSTAT_SET_FLAGS
+    | __WASI_RIGHTS_FD_SYNC
+    | __WASI_RIGHTS_FD_ADVISE
+    | __WASI_RIGHTS_PATH_OPEN
+    | __WASI_RIGHTS_FD_READDIR
+    | __WASI_RIGHTS_PATH_READLINK
+    | __WASI_RIGHTS_PATH_FILESTAT_GET
+    | __WASI_RIGHTS_FD_FILESTAT_GET
+    | __WASI_RIGHTS_POLL_FD_READWRITE;
+pub(crate) const RIGHTS_DIRECTORY_READONLY_INHERITING: __wasi_rights_t =
+    RIGHTS_DIRECTORY_READONLY_BASE | RIGHTS_REGULAR_FILE_READONLY_BASE;
+
 // Operations that apply to regular files.
 pub(crate) const RIGHTS_REGULAR_FILE_BASE: __wasi_rights_t = __WASI_RIGHTS_FD_DATASYNC
     | __WASI_RIGHTS_FD_READ
@@ -89,6 +103,17 @@ pub(crate) const RIGHTS_REGULAR_FILE_BASE: __wasi_rights_t = __WASI_RIGHTS_FD_DA
     | __WASI_RIGHTS_POLL_FD_READWRITE;
 pub(crate) const RIGHTS_REGULAR_FILE_INHERITING: __wasi_rights_t = 0;
 
+// The operations on regular files which don't change them.
+pub(crate) const RIGHTS_REGULAR_FILE_READONLY_BASE: __wasi_rights_t = __WASI_RIGHTS_FD_READ
+    | __WASI_RIGHTS_FD_SEEK
+    | __WASI_RIGHTS_FD_FDSTAT_SET_FLAGS
+    | __WASI_RIGHTS_FD_SYNC
+    | __WASI_RIGHTS_FD_DATASYNC
+    | __WASI_RIGHTS_FD_TELL
+    | __WASI_RIGHTS_FD_ADVISE
+    | __WASI_RIGHTS_FD_FILESTAT_GET
+    | __WASI_RIGHTS_POLL_FD_READWRITE;
+
 // Operations that apply to sockets and socket pairs.
 pub(crate) const RIGHTS_SOCKET_BASE: __wasi_rights_t = __WASI_RIGHTS_FD_READ
     | __WASI_RIGHTS_FD_FDSTAT_SET_FLAGS
@@ -107,6 +132,18 @@ pub(crate) const RIGHTS_TTY_BASE: __wasi_rights_t = __WASI_RIGHTS_FD_READ
 #[allow(unused)]
 pub(crate) const RIGHTS_TTY_INHERITING: __wasi_rights_t = 0;
 
+// Pipes and FIFOs behave like regular files, except that they can't be seeked.
+pub(crate) const RIGHTS_PIPE_BASE: __wasi_rights_t =
+    RIGHTS_REGULAR_FILE_BASE & !(__WASI_RIGHTS_FD_SEEK | __WASI_RIGHTS_FD_TELL);
+pub(crate) const RIGHTS_PIPE_INHERITING: __wasi_rights_t = RIGHTS_REGULAR_FILE_INHERITING;
+
+// Operations that the host's standard streams support, whatever they
+// happen to be redirected to.
+pub(crate) const RIGHTS_STDIO_BASE: __wasi_rights_t = __WASI_RIGHTS_FD_READ
+    | __WASI_RIGHTS_FD_FDSTAT_SET_FLAGS
+    | __WASI_RIGHTS_FD_WRITE
+    | __WASI_RIGHTS_POLL_FD_READWRITE;
+
 pub fn whence_to_str(whence: __wasi_whence_t) -> &'static str {
     match whence {
         __WASI_WHENCE_CUR => "__WASI_WHENCE_CUR",
@@ -19,19 +19,34 @@ pub fn define(args: TokenStream) -> TokenStream {
         s => panic!("unsupported phase: {}", s),
     };
 
+    let mut names = Vec::new();
     for module in doc.modules() {
         for func in module.funcs() {
-            ret.extend(generate_wrappers(&func, old));
+            ret.extend(generate_wrappers(&func, names.len(), old));
+            names.push(func.name.as_str().to_string());
         }
     }
 
+    // The current snapshot's hostcalls are numbered in the order they're defined in, for
+    // `WasiCtxBuilder::deny_calls` to refer to.
+    if !old {
+        ret.extend(quote! {
+            /// The names of all of the hostcalls, each at the index `deny_calls` knows it by.
+            pub(crate) const NAMES: &[&str] = &[#(#names),*];
+        });
+    }
+
     return ret;
 }
 
-fn generate_wrappers(func: &witx::InterfaceFunc, old: bool) -> TokenStream {
+fn generate_wrappers(func: &witx::InterfaceFunc, index: usize, old: bool) -> TokenStream {
     let name = format_ident!("{}", func.name.as_str());
     let mut arg_declarations = Vec::new();
     let mut arg_names = Vec::new();
+    // How each argument is summarized in the `CallRecord`s of the call log: paths are spelled
+    // out, unless they're redacted, but of other buffers, only the length is given.
+    let mut summary_formats = Vec::new();
+    let mut summary_args = Vec::new();
 
     for param in func.params.iter() {
         let name = utils::param_name(param);
@@ -41,11 +56,26 @@ fn generate_wrappers(func: &witx::InterfaceFunc, old: bool) -> TokenStream {
             let len = format_ident!("{}_len", name);
             arg_declarations.push(quote! { #ptr: super::wasi32::uintptr_t });
             arg_declarations.push(quote! { #len: super::wasi32::size_t });
+            if let witx::Type::Builtin(witx::BuiltinType::String) = &*param.tref.type_() {
+                summary_formats.push(format!("{}={{}}", param.name.as_str()));
+                summary_args.push(quote! { call_log.path(&*memory, #ptr, #len) });
+            } else {
+                summary_formats.push(format!("{}_len={{}}", param.name.as_str()));
+                summary_args.push(quote! { #len });
+            }
             arg_names.push(ptr);
             arg_names.push(len);
             continue;
         }
 
+        match &*param.tref.type_() {
+            witx::Type::Flags(_) | witx::Type::ConstPointer(_) | witx::Type::Pointer(_) => {
+                summary_formats.push(format!("{}={{:#x}}", param.name.as_str()));
+            }
+            _ => summary_formats.push(format!("{}={{}}", param.name.as_str())),
+        }
+        summary_args.push(quote! { #name });
+
         match &param.tref {
             witx::TypeRef::Name(n) => {
                 if n.name.as_str() == "size" {
@@ -86,19 +116,121 @@ fn generate_wrappers(func: &witx::InterfaceFunc, old: bool) -> TokenStream {
     let call = quote! {
         super::hostcalls_impl::#name(wasi_ctx, memory, #(#arg_names,)*)
     };
+    let name_str = func.name.as_str();
+
+    // With the `tracing` feature, each call gets a span of its own, nested under the one of
+    // the `WasiCtx` it's made on. The old snapshot's `WasiCtx` doesn't have a span to nest under.
+    let (enter_span, record_errno) = if old {
+        (quote! {}, quote! {})
+    } else {
+        (
+            quote! {
+                #[cfg(feature = "tracing")]
+                let span = tracing::trace_span!(
+                    parent: wasi_ctx.span(),
+                    #name_str,
+                    #(#arg_names = #arg_names,)*
+                    errno = tracing::field::Empty,
+                );
+                #[cfg(feature = "tracing")]
+                let _enter = span.enter();
+            },
+            quote! {
+                #[cfg(feature = "tracing")]
+                span.record("errno", &ret.as_raw_errno());
+            },
+        )
+    };
+
+    // Only the current snapshot's `WasiCtx` has a call log, and calls without a result, like
+    // `proc_exit`, don't return to be logged.
+    let summary_format = summary_formats.join(", ");
+    let (start_call_log, record_call_log) = if old || func.results.len() == 0 {
+        (quote! {}, quote! {})
+    } else {
+        (
+            quote! {
+                let call_log_start = wasi_ctx.call_log.as_ref().map(|call_log| {
+                    let args = format!(#summary_format, #(#summary_args),*);
+                    (std::time::Instant::now(), args)
+                });
+            },
+            quote! {
+                if let (Some(call_log), Some((start, args))) = (&wasi_ctx.call_log, call_log_start) {
+                    call_log.record(#name_str, args, start, ret.as_raw_errno());
+                }
+            },
+        )
+    };
+
+    // A denied call fails before anything else happens, leaving only its name in the call log.
+    // A call without a result has no way to fail, so it can't be denied.
+    let deny = if old || func.results.len() == 0 {
+        quote! {}
+    } else {
+        quote! {
+            if wasi_ctx.call_denied(#index) {
+                log::debug!("{} denied", #name_str);
+                if let Some(call_log) = &wasi_ctx.call_log {
+                    call_log.record_denied(#name_str);
+                }
+                return super::wasi::__WASI_ERRNO_NOTCAPABLE;
+            }
+        }
+    };
+
+    // With `WasiCtxBuilder::usage_stats`, each call is counted, along with the time it took.
+    let (start_usage, record_usage) = if old || func.results.len() == 0 {
+        (quote! {}, quote! {})
+    } else {
+        (
+            quote! {
+                let usage_start = wasi_ctx.usage.as_ref().map(|_| std::time::Instant::now());
+            },
+            quote! {
+                if let (Some(usage), Some(start)) = (&wasi_ctx.usage, usage_start) {
+                    usage.record_call(#index, start);
+                }
+            },
+        )
+    };
+
     let body = if func.results.len() == 0 {
-        call
+        quote! {
+            #enter_span
+            #call
+        }
     } else {
+        // Only the errno makes it to the guest, so log the error as a whole, along with any
+        // context the host attached to it, for the embedder's sake.
         quote! {
-            let ret = #call
-                .err()
-                .unwrap_or(super::Error::ESUCCESS)
-                .as_wasi_error();
+            #deny
+            #enter_span
+            #start_call_log
+            #start_usage
+            let ret = match #call {
+                Ok(_) => super::Error::ESUCCESS.as_wasi_error(),
+                Err(err) => {
+                    log::debug!("{} failed: {}", #name_str, err);
+                    err.as_wasi_error()
+                }
+            };
             log::trace!("     | errno={}", ret);
+            #record_errno
+            #record_call_log
+            #record_usage
             ret.as_raw_errno()
         }
     };
 
+    // A panic mustn't unwind into the guest's frames, so it fails the call with `EIO` instead.
+    // Whatever the hostcall had borrowed, it let go of on the way out.
+    let panic_ret = if func.results.len() == 0 {
+        quote! { () }
+    } else {
+        quote! { super::wasi::__WASI_ERRNO_IO }
+    };
+
     let c_abi_name = if old {
         format_ident!("old_wasi_common_{}", name)
     } else {
@@ -111,7 +243,17 @@ fn generate_wrappers(func: &witx::InterfaceFunc, old: bool) -> TokenStream {
             memory: &mut [u8],
             #(#arg_declarations,)*
         ) -> #ret {
-            #body
+            match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| { #body })) {
+                Ok(ret) => ret,
+                Err(panic) => {
+                    log::error!(
+                        "{} panicked: {}",
+                        #name_str,
+                        crate::helpers::panic_message(&*panic)
+                    );
+                    #panic_ret
+                }
+            }
         }
 
         #[no_mangle]
@@ -15,7 +15,10 @@ use winapi::shared::{
     minwindef::{self, DWORD},
     ntstatus,
 };
-use winapi::um::{fileapi, fileapi::GetFileType, minwinbase, winbase, winnt};
+use winapi::um::{
+    consoleapi::GetConsoleMode, fileapi, fileapi::GetFileType, minwinbase,
+    namedpipeapi::GetNamedPipeInfo, winbase, winnt,
+};
 
 /// Maximum total path length for Unicode in Windows.
 /// [Maximum path length limitation]: https://docs.microsoft.com/en-us/windows/desktop/FileIO/naming-a-file#maximum-path-length-limitation
@@ -65,6 +68,27 @@ pub unsafe fn get_file_type(handle: RawHandle) -> Result<FileType> {
     }
 }
 
+/// Returns true if the handle refers to a console screen buffer or input buffer.
+///
+/// Any other character device (such as `NUL` or an LPT device) will make
+/// `GetConsoleMode` fail.
+pub unsafe fn is_console(handle: RawHandle) -> bool {
+    let mut mode: DWORD = 0;
+    GetConsoleMode(handle, &mut mode) != 0
+}
+
+/// Returns true if the handle of type `FILE_TYPE_PIPE` refers to a named or anonymous
+/// pipe rather than a socket.
+pub unsafe fn is_named_pipe(handle: RawHandle) -> bool {
+    GetNamedPipeInfo(
+        handle,
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+    ) != 0
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 #[repr(u32)]
 pub enum CreationDisposition {
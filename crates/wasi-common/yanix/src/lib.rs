@@ -23,6 +23,10 @@ pub mod fadvise {
     pub use super::sys::fadvise::*;
 }
 
+pub mod fallocate {
+    pub use super::sys::fallocate::*;
+}
+
 pub use errno::Errno;
 use std::{ffi, num};
 use thiserror::Error;
@@ -1,5 +1,10 @@
 use crate::{Errno, Result};
-use std::os::unix::prelude::*;
+use bitflags::bitflags;
+use std::{
+    io::{IoSlice, IoSliceMut},
+    mem,
+    os::unix::prelude::*,
+};
 
 #[derive(Debug, Clone, Copy)]
 #[repr(i32)]
@@ -29,3 +34,47 @@ pub unsafe fn get_socket_type(fd: RawFd) -> Result<SockType> {
     );
     Ok(buffer)
 }
+
+#[derive(Debug, Clone, Copy)]
+#[repr(i32)]
+pub enum ShutdownHow {
+    Read = libc::SHUT_RD,
+    Write = libc::SHUT_WR,
+    Both = libc::SHUT_RDWR,
+}
+
+pub unsafe fn shutdown(fd: RawFd, how: ShutdownHow) -> Result<()> {
+    Errno::from_success_code(libc::shutdown(fd, how as libc::c_int))
+}
+
+bitflags! {
+    pub struct MsgFlag: libc::c_int {
+        const PEEK = libc::MSG_PEEK;
+        const TRUNC = libc::MSG_TRUNC;
+        const WAITALL = libc::MSG_WAITALL;
+    }
+}
+
+/// Receive into `bufs`, returning the number of bytes received and the flags `recvmsg` set
+/// on the message, such as `MsgFlag::TRUNC` for a datagram which didn't fit.
+pub unsafe fn recvmsg(
+    fd: RawFd,
+    bufs: &mut [IoSliceMut],
+    flags: MsgFlag,
+) -> Result<(usize, MsgFlag)> {
+    // `IoSliceMut` is guaranteed to be ABI compatible with `iovec`
+    let mut msg: libc::msghdr = mem::zeroed();
+    msg.msg_iov = bufs.as_mut_ptr() as *mut libc::iovec;
+    msg.msg_iovlen = bufs.len() as _;
+    let nread = Errno::from_result(libc::recvmsg(fd, &mut msg, flags.bits()))?;
+    Ok((nread as usize, MsgFlag::from_bits_truncate(msg.msg_flags)))
+}
+
+pub unsafe fn sendmsg(fd: RawFd, bufs: &[IoSlice], flags: MsgFlag) -> Result<usize> {
+    // `IoSlice` is guaranteed to be ABI compatible with `iovec`
+    let mut msg: libc::msghdr = mem::zeroed();
+    msg.msg_iov = bufs.as_ptr() as *mut libc::iovec;
+    msg.msg_iovlen = bufs.len() as _;
+    let nwritten = Errno::from_result(libc::sendmsg(fd, &msg, flags.bits()))?;
+    Ok(nwritten as usize)
+}
@@ -13,29 +13,48 @@ pub enum PosixFadviseAdvice {
 }
 
 // There's no posix_fadvise on macOS but we can use fcntl with F_RDADVISE
-// command instead to achieve the same
+// and F_RDAHEAD commands instead to achieve the same
 #[cfg(any(target_os = "macos", target_os = "ios"))]
 pub unsafe fn posix_fadvise(
     fd: RawFd,
     offset: libc::off_t,
     len: libc::off_t,
-    _advice: PosixFadviseAdvice,
+    advice: PosixFadviseAdvice,
 ) -> Result<()> {
-    // From macOS man pages:
-    // F_RDADVISE   Issue an advisory read async with no copy to user.
-    //
-    // The F_RDADVISE command operates on the following structure which holds information passed from
-    // the user to the system:
-    //
-    // struct radvisory {
-    //      off_t   ra_offset;  /* offset into the file */
-    //      int     ra_count;   /* size of the read     */
-    // };
-    let advisory = libc::radvisory {
-        ra_offset: offset,
-        ra_count: len.try_into()?,
-    };
-    Errno::from_success_code(libc::fcntl(fd, libc::F_RDADVISE, &advisory))
+    match advice {
+        PosixFadviseAdvice::WillNeed => {
+            // From macOS man pages:
+            // F_RDADVISE   Issue an advisory read async with no copy to user.
+            //
+            // The F_RDADVISE command operates on the following structure which holds information passed from
+            // the user to the system:
+            //
+            // struct radvisory {
+            //      off_t   ra_offset;  /* offset into the file */
+            //      int     ra_count;   /* size of the read     */
+            // };
+            //
+            // As with posix_fadvise, a `len` of 0 stands for the rest of the file.
+            let len = if len == 0 {
+                crate::file::fstat(fd)?.st_size.saturating_sub(offset)
+            } else {
+                len
+            };
+            let advisory = libc::radvisory {
+                ra_offset: offset,
+                ra_count: len.min(libc::c_int::max_value().into()).try_into()?,
+            };
+            Errno::from_success_code(libc::fcntl(fd, libc::F_RDADVISE, &advisory))
+        }
+        // F_RDAHEAD turns read-ahead, which is on by default, on or off for the whole file
+        PosixFadviseAdvice::Normal | PosixFadviseAdvice::Sequential => {
+            Errno::from_success_code(libc::fcntl(fd, libc::F_RDAHEAD, 1))
+        }
+        PosixFadviseAdvice::Random => Errno::from_success_code(libc::fcntl(fd, libc::F_RDAHEAD, 0)),
+        // There's no way to drop a range from the cache; F_NOCACHE would turn off caching for
+        // everything read through `fd` from then on
+        PosixFadviseAdvice::NoReuse | PosixFadviseAdvice::DontNeed => Ok(()),
+    }
 }
 
 // TODO
@@ -0,0 +1,39 @@
+use crate::{Errno, Result};
+use std::os::unix::prelude::*;
+
+// There's no posix_fallocate on macOS but we can use fcntl with F_PREALLOCATE
+// command, followed by ftruncate, instead to achieve the same
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub unsafe fn posix_fallocate(fd: RawFd, offset: libc::off_t, len: libc::off_t) -> Result<()> {
+    let wanted = match offset.checked_add(len) {
+        Some(wanted) => wanted,
+        None => return Err(Errno::EFBIG.into()),
+    };
+    let size = crate::file::fstat(fd)?.st_size;
+    if wanted <= size {
+        return Ok(());
+    }
+    // With F_PEOFPOSMODE, F_PREALLOCATE allocates from the physical end of the file, so only
+    // the growth is asked for, contiguously if the filesystem can manage it
+    let mut store = libc::fstore_t {
+        fst_flags: libc::F_ALLOCATECONTIG,
+        fst_posmode: libc::F_PEOFPOSMODE,
+        fst_offset: 0,
+        fst_length: wanted - size,
+        fst_bytesalloc: 0,
+    };
+    if libc::fcntl(fd, libc::F_PREALLOCATE, &store) == -1 {
+        store.fst_flags = libc::F_ALLOCATEALL;
+        Errno::from_result(libc::fcntl(fd, libc::F_PREALLOCATE, &store))?;
+    }
+    // F_PREALLOCATE leaves the file's size be
+    Errno::from_success_code(libc::ftruncate(fd, wanted))
+}
+
+// TODO
+// On non-macOS BSD's we report ENOSYS for now, and leave it to the caller to fall back on
+// growing the file
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+pub unsafe fn posix_fallocate(_fd: RawFd, _offset: libc::off_t, _len: libc::off_t) -> Result<()> {
+    Err(Errno::ENOSYS.into())
+}
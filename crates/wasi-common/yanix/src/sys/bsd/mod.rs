@@ -1,3 +1,4 @@
 pub(crate) mod dir;
 pub(crate) mod fadvise;
+pub(crate) mod fallocate;
 pub(crate) mod file;
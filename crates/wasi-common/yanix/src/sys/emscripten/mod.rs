@@ -2,6 +2,8 @@
 pub(crate) mod dir;
 #[path = "../linux/fadvise.rs"]
 pub(crate) mod fadvise;
+#[path = "../linux/fallocate.rs"]
+pub(crate) mod fallocate;
 #[path = "../linux/file.rs"]
 pub(crate) mod file;
 
@@ -18,5 +18,9 @@ pub unsafe fn posix_fadvise(
     len: libc::off_t,
     advice: PosixFadviseAdvice,
 ) -> Result<()> {
-    Errno::from_success_code(libc::posix_fadvise(fd, offset, len, advice as libc::c_int))
+    // posix_fadvise returns the error number rather than setting errno
+    match libc::posix_fadvise(fd, offset, len, advice as libc::c_int) {
+        0 => Ok(()),
+        errno => Err(Errno::from_i32(errno).into()),
+    }
 }
@@ -0,0 +1,10 @@
+use crate::{Errno, Result};
+use std::os::unix::prelude::*;
+
+pub unsafe fn posix_fallocate(fd: RawFd, offset: libc::off_t, len: libc::off_t) -> Result<()> {
+    // posix_fallocate returns the error number rather than setting errno
+    match libc::posix_fallocate(fd, offset, len) {
+        0 => Ok(()),
+        errno => Err(Errno::from_i32(errno).into()),
+    }
+}
@@ -1,5 +1,6 @@
 pub(crate) mod dir;
 pub(crate) mod fadvise;
+pub(crate) mod fallocate;
 pub(crate) mod file;
 
 use crate::{dir::SeekLoc, Result};
@@ -55,7 +55,12 @@ pub fn instantiate_wasi(
 
 /// Return an instance implementing the "wasi" interface.
 ///
-/// The wasi context is configured by
+/// The wasi context is configured by `wasi_ctx`, as built with a `WasiCtxBuilder`.
+///
+/// The instance can be linked into any number of modules in the same store, which then share
+/// `wasi_ctx`: its fds, preopens and everything else. Each hostcall works on the memory
+/// exported by the module making it, so an fd one module opens can be passed to another, and
+/// read into its own memory. A `proc_exit` from any of the modules exits the host process.
 pub fn instantiate_wasi_with_context(
     wasi_ctx: WasiCtx,
 ) -> Result<InstanceHandle, InstantiationError> {
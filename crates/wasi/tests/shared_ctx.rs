@@ -0,0 +1,91 @@
+use wasi_common::{preopen_dir, WasiCtxBuilder};
+use wasmtime::*;
+
+const OPENER: &str = r#"
+(module
+  (import "wasi_snapshot_preview1" "path_open"
+    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 16) "shared.txt")
+  ;; Open "shared.txt" for reading in the first preopen, returning the fd, or the negated errno.
+  (func (export "open") (result i32)
+    (local $errno i32)
+    (local.set $errno
+      (call $path_open
+        (i32.const 3) (i32.const 0) (i32.const 16) (i32.const 10) (i32.const 0)
+        (i64.const 2) (i64.const 0) (i32.const 0) (i32.const 8)))
+    (if (result i32) (local.get $errno)
+      (then (i32.sub (i32.const 0) (local.get $errno)))
+      (else (i32.load (i32.const 8)))))
+)
+"#;
+
+const READER: &str = r#"
+(module
+  (import "wasi_snapshot_preview1" "fd_read"
+    (func $fd_read (param i32 i32 i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  ;; Read up to 64 bytes from `fd` to 16, along with the count at 8, returning the errno.
+  (func (export "read") (param $fd i32) (result i32)
+    (i32.store (i32.const 0) (i32.const 16))
+    (i32.store (i32.const 4) (i32.const 64))
+    (call $fd_read (local.get $fd) (i32.const 0) (i32.const 1) (i32.const 8)))
+)
+"#;
+
+fn instantiate(store: &Store, wasi: &Instance, wat: &str) -> Instance {
+    let module = Module::new(store, wat).expect("compile module");
+    let imports: Vec<Extern> = module
+        .imports()
+        .iter()
+        .map(|import| {
+            wasi.get_export(import.name())
+                .unwrap_or_else(|| panic!("no hostcall named {}", import.name()))
+                .clone()
+        })
+        .collect();
+    Instance::new(&module, &imports).expect("instantiate module")
+}
+
+fn call(instance: &Instance, name: &str, params: &[Val]) -> i32 {
+    let results = instance
+        .get_export(name)
+        .and_then(Extern::func)
+        .expect("exported function")
+        .call(params)
+        .expect("no trap");
+    results[0].unwrap_i32()
+}
+
+/// Both modules link against the same WASI instance, so they share its `WasiCtx`, while each
+/// hostcall works on the memory of the module making it.
+#[test]
+fn modules_share_fds() {
+    let dir = std::env::temp_dir().join(format!("wasmtime_wasi_shared_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("create scratch dir");
+    std::fs::write(dir.join("shared.txt"), b"hello from A").expect("create scratch file");
+
+    let store = Store::default();
+    let ctx = WasiCtxBuilder::new()
+        .preopened_dir(preopen_dir(&dir).expect("open scratch dir"), "/sandbox")
+        .build()
+        .expect("build WasiCtx");
+    let wasi = wasmtime_wasi::instantiate_wasi_with_context(ctx).expect("instantiate WASI");
+    let wasi = Instance::from_handle(&store, wasi);
+    let opener = instantiate(&store, &wasi, OPENER);
+    let reader = instantiate(&store, &wasi, READER);
+
+    let fd = call(&opener, "open", &[]);
+    assert!(fd > 3, "path_open failed with errno {}", -fd);
+    assert_eq!(call(&reader, "read", &[Val::I32(fd)]), 0);
+
+    let memory = reader
+        .get_export("memory")
+        .and_then(Extern::memory)
+        .expect("exported memory");
+    let memory = unsafe { memory.data_unchecked() };
+    assert_eq!(&memory[8..12], &12u32.to_le_bytes());
+    assert_eq!(&memory[16..28], b"hello from A");
+
+    std::fs::remove_dir_all(&dir).expect("remove scratch dir");
+}
@@ -10,12 +10,13 @@ use std::{
     path::{Component, Path, PathBuf},
 };
 use structopt::{clap::AppSettings, StructOpt};
-use wasi_common::preopen_dir;
+use wasi_common::{preopen_dir, WasiCtxBuilder};
 use wasmtime::{Config, Engine, Instance, Module, Store};
 use wasmtime_environ::cache_init;
 use wasmtime_interface_types::ModuleData;
 use wasmtime_wasi::{
-    create_wasi_instance, old::snapshot_0::create_wasi_instance as create_wasi_instance_snapshot_0,
+    instantiate_wasi_with_context,
+    old::snapshot_0::create_wasi_instance as create_wasi_instance_snapshot_0,
 };
 
 fn parse_module(s: &OsStr) -> Result<PathBuf, OsString> {
@@ -41,6 +42,9 @@ fn parse_map_dirs(s: &str) -> Result<(String, String)> {
     if parts.len() != 2 {
         bail!("must contain exactly one double colon ('::')");
     }
+    if !parts[0].starts_with('/') {
+        bail!("the guest directory must be an absolute path");
+    }
     Ok((parts[0].into(), parts[1].into()))
 }
 
@@ -143,8 +147,7 @@ impl RunCommand {
         let wasi_unstable =
             create_wasi_instance_snapshot_0(&store, &preopen_dirs, &argv, &self.vars)?;
 
-        let wasi_snapshot_preview1 =
-            create_wasi_instance(&store, &preopen_dirs, &argv, &self.vars)?;
+        let wasi_snapshot_preview1 = self.create_wasi_instance(&store, &preopen_dirs, &argv)?;
 
         module_registry.insert("wasi_unstable".to_owned(), wasi_unstable);
         module_registry.insert("wasi_snapshot_preview1".to_owned(), wasi_snapshot_preview1);
@@ -183,6 +186,35 @@ impl RunCommand {
         Ok(preopen_dirs)
     }
 
+    /// Creates the `wasi_snapshot_preview1` instance, where the `--mapdir` directories are
+    /// preopened at virtual guest paths, which are checked, and the `--dir` ones as given.
+    fn create_wasi_instance(
+        &self,
+        store: &Store,
+        preopen_dirs: &[(String, File)],
+        argv: &[String],
+    ) -> Result<Instance> {
+        let mut builder = WasiCtxBuilder::new()
+            .inherit_stdio()
+            .args(argv)
+            .envs(&self.vars);
+
+        // `compute_preopen_dirs` puts the `--dir` directories before the `--mapdir` ones.
+        let (dirs, map_dirs) = preopen_dirs.split_at(self.dirs.len());
+        for (guest, file) in dirs {
+            builder = builder.preopened_dir(file.try_clone()?, guest);
+        }
+        for (guest, file) in map_dirs {
+            builder = builder.preopened_dir_at(file.try_clone()?, guest);
+        }
+
+        let wasi_ctx = builder
+            .build()
+            .context("failed to assemble the WASI context")?;
+        let wasi = instantiate_wasi_with_context(wasi_ctx)?;
+        Ok(Instance::from_handle(store, wasi))
+    }
+
     fn compute_argv(&self) -> Vec<String> {
         let mut result = Vec::new();
 